@@ -5,7 +5,7 @@ use std::path::Path;
 use syntect::parsing::SyntaxSet;
 use syntect::highlighting::{Theme, ThemeSet, Style};
 use syntect::util::as_24_bit_terminal_escaped;
-use syntect::easy::HighlightFile;
+use syntect::easy::{BinaryFileError, HighlightFile};
 use syntect::dumps::{from_dump_file, dump_to_file};
 
 fn load_theme(tm_file: &String, enable_caching: bool) -> Theme {
@@ -62,7 +62,7 @@ fn main() {
     if matches.opt_present("list-file-types") {
         println!("Supported file types:");
 
-        for sd in ss.syntaxes() {
+        for sd in ss.visible_syntaxes() {
             println!("- {} (.{})", sd.name, sd.file_extensions.join(", ."));
         }
 
@@ -90,7 +90,13 @@ fn main() {
                 println!("==> {} <==", src);
             }
 
-            let mut highlighter = HighlightFile::new(src, &ss, &theme).unwrap();
+            let mut highlighter = match HighlightFile::new(src, &ss, &theme) {
+                Err(ref e) if e.get_ref().map_or(false, |e| e.is::<BinaryFileError>()) => {
+                    println!("{}: skipping binary file", src);
+                    continue;
+                }
+                result => result.unwrap(),
+            };
 
             // We use read_line instead of `for line in highlighter.reader.lines()` because that
             // doesn't return strings with a `\n`, and including the `\n` gets us more robust highlighting.
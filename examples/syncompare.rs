@@ -0,0 +1,67 @@
+//! Compares how two syntax sets parse a set of files, for checking a grammar upgrade before
+//! shipping it. Each syntax set is either a folder of `.sublime-syntax` files or a `.packdump`
+//! created with `dump_to_file`, and both are used in the mode with newlines.
+//!
+//! Prints the files that parse differently, with the ranges whose scopes changed.
+use syntect::parsing::{SyntaxSet, SyntaxSetBuilder};
+use syntect::easy::FileComparison;
+use syntect::dumps::from_dump_file;
+use walkdir::WalkDir;
+
+fn load_syntax_set(path: &str) -> SyntaxSet {
+    if path.ends_with(".packdump") {
+        from_dump_file(path).unwrap()
+    } else {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_plain_text_syntax();
+        builder.add_from_folder(path, true).unwrap();
+        builder.build()
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 4 {
+        println!("USAGE: {} OLD_SYNTAXES NEW_SYNTAXES FILES_OR_FOLDERS...", args[0]);
+        return;
+    }
+
+    let old = load_syntax_set(&args[1]);
+    let new = load_syntax_set(&args[2]);
+
+    let mut compared = 0;
+    let mut changed = 0;
+    for root in &args[3..] {
+        for entry in WalkDir::new(root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.path();
+            let comparison = match FileComparison::new(path, &old, &new, true) {
+                Ok(comparison) => comparison,
+                Err(e) => {
+                    println!("{}: skipped, {}", path.display(), e);
+                    continue;
+                }
+            };
+            compared += 1;
+            if comparison.is_identical() {
+                continue;
+            }
+            changed += 1;
+
+            if comparison.old_syntax == comparison.new_syntax {
+                println!("{} ({}):", path.display(), comparison.old_syntax);
+            } else {
+                println!("{} ({} -> {}):", path.display(), comparison.old_syntax, comparison.new_syntax);
+            }
+            for difference in &comparison.differences {
+                println!("  {}:{}-{}", difference.line + 1, difference.range.start, difference.range.end);
+                println!("    - {}", difference.old);
+                println!("    + {}", difference.new);
+            }
+        }
+    }
+
+    println!("{} of {} files parse differently", changed, compared);
+}
@@ -1,7 +1,7 @@
 //! Prints highlighted HTML for a file to stdout.
 //! Basically just wraps a body around `highlighted_html_for_file`
 use syntect::parsing::SyntaxSet;
-use syntect::highlighting::{Color, ThemeSet};
+use syntect::highlighting::ThemeSet;
 use syntect::html::highlighted_html_for_file;
 
 fn main() {
@@ -21,7 +21,7 @@ fn main() {
         }";
     println!("<head><title>{}</title><style>{}</style></head>", &args[1], style);
     let theme = &ts.themes["base16-ocean.dark"];
-    let c = theme.settings.background.unwrap_or(Color::WHITE);
+    let c = theme.background();
     println!("<body style=\"background-color:#{:02x}{:02x}{:02x};\">\n", c.r, c.g, c.b);
     let html = highlighted_html_for_file(&args[1], &ss, theme).unwrap();
     println!("{}", html);
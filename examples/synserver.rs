@@ -0,0 +1,17 @@
+//! Runs syntect as a sidecar process, answering requests in the protocol of the `server` module
+//! on stdin and stdout, with the default syntaxes and themes.
+//!
+//! Run with `cargo run --features server --example synserver`
+use syntect::parsing::SyntaxSet;
+use syntect::highlighting::ThemeSet;
+use syntect::server::Server;
+use std::io;
+
+fn main() {
+    let mut server = Server::new(SyntaxSet::load_defaults_newlines(), ThemeSet::load_defaults());
+    let stdin = io::stdin();
+    if let Err(e) = server.serve(stdin.lock(), io::stdout()) {
+        eprintln!("synserver: {}", e);
+        std::process::exit(1);
+    }
+}
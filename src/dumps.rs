@@ -26,7 +26,7 @@ use crate::parsing::SyntaxSet;
 #[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
 use crate::highlighting::ThemeSet;
 use std::path::Path;
-#[cfg(feature = "dump-create")]
+#[cfg(any(feature = "dump-create", feature = "dump-create-rs"))]
 use flate2::write::ZlibEncoder;
 #[cfg(any(feature = "dump-load", feature = "dump-load-rs"))]
 use flate2::bufread::ZlibDecoder;
@@ -2,11 +2,18 @@
 //! files without caring about intermediate semantic representation
 //! and caching.
 
-use crate::parsing::{ScopeStack, ParseState, SyntaxReference, SyntaxSet, ScopeStackOp};
-use crate::highlighting::{Highlighter, HighlightState, HighlightIterator, Theme, Style};
-use std::io::{self, BufReader};
+use crate::parsing::{Scope, ScopeStack, NewlinePolicy, ParseBudget, ParseState, SyntaxReference, SyntaxSet, ScopeStackOp, ClearAmount};
+use crate::highlighting::{Highlighter, HighlightState, HighlightIterator, RangedHighlightIterator, Theme, Style, ScopeSelectors};
+use crate::util::{looks_binary, LinesWithEndings};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::collections::HashMap;
 use std::fs::File;
+use std::ops::Range;
 use std::path::Path;
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
 // use util::debug_print_ops;
 
 /// Simple way to go directly from lines of text to colored tokens.
@@ -43,6 +50,30 @@ pub struct HighlightLines<'a> {
     highlighter: Highlighter<'a>,
     parse_state: ParseState,
     highlight_state: HighlightState,
+    limits: HighlightLimits,
+    lines_highlighted: usize,
+    bytes_highlighted: usize,
+    merge_styles: bool,
+}
+
+/// Limits on how much of a file [`HighlightLines`] highlights.
+///
+/// Once a limit is reached, the following lines are returned without highlighting, in the
+/// default style of the theme. This keeps tools responsive on enormous (e.g. generated) files,
+/// while still highlighting their beginning. By default there are no limits.
+///
+/// [`HighlightLines`]: struct.HighlightLines.html
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HighlightLimits {
+    /// The number of lines to highlight
+    pub max_lines: Option<usize>,
+    /// The number of bytes to highlight, the line where this is exceeded is still highlighted
+    pub max_bytes: Option<usize>,
+    /// Lines longer than this many bytes, e.g. in minified files, are not parsed.
+    ///
+    /// Such a line gets the style of the scopes active at its start, and the lines after it are
+    /// highlighted as if it wasn't there.
+    pub max_line_length: Option<usize>,
 }
 
 impl<'a> HighlightLines<'a> {
@@ -53,21 +84,160 @@ impl<'a> HighlightLines<'a> {
             highlighter,
             parse_state: ParseState::new(syntax),
             highlight_state,
+            limits: HighlightLimits::default(),
+            lines_highlighted: 0,
+            bytes_highlighted: 0,
+            merge_styles: false,
         }
     }
 
+    /// Sets the limits after which lines aren't highlighted anymore.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::{HighlightLimits, HighlightLines};
+    /// use syntect::parsing::SyntaxSet;
+    /// use syntect::highlighting::ThemeSet;
+    ///
+    /// let ps = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// let syntax = ps.find_syntax_by_extension("rs").unwrap();
+    /// let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+    /// h.set_limits(HighlightLimits { max_lines: Some(1), ..Default::default() });
+    ///
+    /// assert!(h.highlight("let a = 1;\n", &ps).len() > 1);
+    /// assert!(h.is_past_limits());
+    /// assert_eq!(h.highlight("let b = 2;\n", &ps).len(), 1);
+    /// ```
+    pub fn set_limits(&mut self, limits: HighlightLimits) {
+        self.limits = limits;
+    }
+
+    /// Sets how the line endings of the highlighted lines are treated, see
+    /// [`ParseState::set_newline_policy`]. Regions always cover the whole line that was passed in.
+    ///
+    /// [`ParseState::set_newline_policy`]: ../parsing/struct.ParseState.html#method.set_newline_policy
+    pub fn set_newline_policy(&mut self, policy: NewlinePolicy) {
+        self.parse_state.set_newline_policy(policy);
+    }
+
+    /// Sets whether adjacent regions with the same `Style` are returned as one region, which
+    /// makes much less spans or escape codes when the regions are rendered.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::HighlightLines;
+    /// use syntect::parsing::SyntaxSet;
+    /// use syntect::highlighting::ThemeSet;
+    ///
+    /// let ps = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// let syntax = ps.find_syntax_by_extension("rs").unwrap();
+    /// let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+    /// let line = "foo(bar, baz);\n";
+    /// let regions = h.highlight(line, &ps);
+    ///
+    /// let mut merged = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+    /// merged.set_merge_styles(true);
+    /// let merged_regions = merged.highlight(line, &ps);
+    /// assert!(merged_regions.len() < regions.len());
+    /// assert!(merged_regions.windows(2).all(|pair| pair[0].0 != pair[1].0));
+    /// ```
+    pub fn set_merge_styles(&mut self, merge: bool) {
+        self.merge_styles = merge;
+    }
+
+    /// Returns true if a limit was reached, so further lines won't be highlighted.
+    pub fn is_past_limits(&self) -> bool {
+        let past = |count, limit: Option<usize>| limit.map(|max| count >= max).unwrap_or(false);
+        past(self.lines_highlighted, self.limits.max_lines) ||
+            past(self.bytes_highlighted, self.limits.max_bytes)
+    }
+
     /// Highlights a line of a file
     pub fn highlight<'b>(&mut self, line: &'b str, syntax_set: &SyntaxSet) -> Vec<(Style, &'b str)> {
-        // println!("{}", self.highlight_state.path);
-        let ops = self.parse_state.parse_line(line, syntax_set);
+        self.highlight_in(line, None, syntax_set)
+    }
+
+    /// Highlights a line of a file, but only returns the regions of a byte range of it, cut to
+    /// the range. This is for editors drawing only the visible columns of very long lines.
+    ///
+    /// The whole line is still parsed, so the following lines are highlighted correctly. The
+    /// range has to start and end on character boundaries.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::HighlightLines;
+    /// use syntect::parsing::SyntaxSet;
+    /// use syntect::highlighting::ThemeSet;
+    ///
+    /// let ps = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// let syntax = ps.find_syntax_by_extension("rs").unwrap();
+    /// let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+    ///
+    /// let visible = h.highlight_range("let s = \"a /* b\";\n", 11..16, &ps);
+    /// assert_eq!(visible.iter().map(|(_, text)| *text).collect::<String>(), "/* b\"");
+    /// // the comment start was in a string, so the next line isn't in a comment
+    /// let next = h.highlight("x */\n", &ps);
+    /// assert!(next.len() > 1);
+    /// ```
+    pub fn highlight_range<'b>(&mut self, line: &'b str, range: Range<usize>, syntax_set: &SyntaxSet) -> Vec<(Style, &'b str)> {
+        self.highlight_in(line, Some(range), syntax_set)
+    }
+
+    fn highlight_in<'b>(&mut self, line: &'b str, range: Option<Range<usize>>, syntax_set: &SyntaxSet) -> Vec<(Style, &'b str)> {
+        if self.is_past_limits() {
+            return match range {
+                Some(range) if range.start >= range.end => Vec::new(),
+                Some(range) => vec![(self.highlighter.get_default(), &line[range])],
+                None => vec![(self.highlighter.get_default(), line)],
+            };
+        }
+        self.lines_highlighted += 1;
+        self.bytes_highlighted += line.len();
+        let ops = if self.limits.max_line_length.is_some() {
+            let budget = ParseBudget { max_line_length: self.limits.max_line_length, ..Default::default() };
+            self.parse_state.parse_line_with_budget(line, syntax_set, budget).ops
+        } else {
+            // println!("{}", self.highlight_state.path);
+            self.parse_state.parse_line(line, syntax_set)
+        };
         // use util::debug_print_ops;
         // debug_print_ops(line, &ops);
-        let iter =
+        let mut iter =
             HighlightIterator::new(&mut self.highlight_state, &ops[..], line, &self.highlighter);
-        iter.collect()
+        if let Some(range) = range {
+            iter = iter.in_range(range);
+        }
+        if self.merge_styles {
+            iter.merge_styles().collect()
+        } else {
+            iter.collect()
+        }
+    }
+}
+
+/// The error inside the `io::Error` returned by [`HighlightFile::new`] for binary files.
+///
+/// Check for it using `error.get_ref()` and `downcast_ref`, like in the example of
+/// [`HighlightFile::new`].
+///
+/// [`HighlightFile::new`]: struct.HighlightFile.html#method.new
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BinaryFileError;
+
+impl fmt::Display for BinaryFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "File appears to be binary")
     }
 }
 
+impl Error for BinaryFileError {}
+
 /// Convenience struct containing everything you need to highlight a file
 ///
 /// Use the `reader` to get the lines of the file and the `highlight_lines` to highlight them. See
@@ -117,6 +287,28 @@ impl<'a> HighlightFile<'a> {
     /// # }
     /// ```
     ///
+    /// Files that look like binary data (see [`util::looks_binary`]) aren't highlighted, instead
+    /// an error of kind `InvalidData` containing a [`BinaryFileError`] is returned:
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// use syntect::highlighting::ThemeSet;
+    /// use syntect::easy::{BinaryFileError, HighlightFile};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// # let path = std::env::temp_dir().join(format!("syntect_doctest_binary_{}.bin", std::process::id()));
+    /// # std::fs::write(&path, b"\x00\x01\x02").unwrap();
+    /// match HighlightFile::new(&path, &ss, &ts.themes["base16-ocean.dark"]) {
+    ///     Err(ref e) if e.get_ref().map_or(false, |e| e.is::<BinaryFileError>()) => {
+    ///         println!("skipping binary file");
+    ///     }
+    ///     # Ok(_) => panic!("binary file wasn't detected"),
+    ///     other => { other.unwrap(); }
+    /// }
+    /// # std::fs::remove_file(&path).unwrap();
+    /// ```
+    ///
     /// This example uses `reader.lines()` to get lines without a newline character, it's simpler but may break on rare tricky cases.
     ///
     /// ```
@@ -142,150 +334,2267 @@ impl<'a> HighlightFile<'a> {
                                -> io::Result<HighlightFile<'a>> {
         let path: &Path = path_obj.as_ref();
         let f = File::open(path)?;
+        let mut reader = BufReader::new(f);
+        if looks_binary(reader.fill_buf()?) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, BinaryFileError));
+        }
         let syntax = ss.find_syntax_for_file(path)?
             .unwrap_or_else(|| ss.find_syntax_plain_text());
 
         Ok(HighlightFile {
-            reader: BufReader::new(f),
+            reader,
             highlight_lines: HighlightLines::new(syntax, theme),
         })
     }
+
+    /// Highlights the rest of the file line by line, passing the regions of each line and the
+    /// progress so far to `f`.
+    ///
+    /// If `f` returns `false`, highlighting stops before the next line, which allows cancelling
+    /// it from a GUI. Returns `Ok(true)` if the whole file was highlighted and `Ok(false)` if
+    /// it was cancelled.
+    ///
+    /// Lines are passed including their `\n`, so the syntax set should be loaded with newlines.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// use syntect::highlighting::ThemeSet;
+    /// use syntect::easy::HighlightFile;
+    /// use std::sync::atomic::{AtomicBool, Ordering};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// // e.g. set from the UI thread when the preview is closed
+    /// let cancelled = AtomicBool::new(false);
+    ///
+    /// let mut highlighter = HighlightFile::new("testdata/highlight_test.erb", &ss, &ts.themes["base16-ocean.dark"]).unwrap();
+    /// let finished = highlighter.highlight_with_progress(&ss, |_regions, progress| {
+    ///     if let Some(total) = progress.total_bytes {
+    ///         println!("{}%", progress.bytes as u64 * 100 / total);
+    ///     }
+    ///     !cancelled.load(Ordering::Relaxed)
+    /// }).unwrap();
+    /// assert!(finished);
+    /// ```
+    pub fn highlight_with_progress<F>(&mut self, ss: &SyntaxSet, mut f: F) -> io::Result<bool>
+        where F: FnMut(&[(Style, &str)], HighlightProgress) -> bool
+    {
+        let mut progress = HighlightProgress {
+            lines: 0,
+            bytes: 0,
+            total_bytes: self.reader.get_ref().metadata().ok().map(|m| m.len()),
+        };
+        let mut line = String::new();
+        while self.reader.read_line(&mut line)? > 0 {
+            progress.lines += 1;
+            progress.bytes += line.len();
+            let regions = self.highlight_lines.highlight(&line, ss);
+            if !f(&regions, progress) {
+                return Ok(false);
+            }
+            line.clear();
+        }
+        Ok(true)
+    }
 }
 
-/// Iterator over the regions of a line which a given the operation from the parser applies.
+/// Progress information passed to the callback of [`HighlightFile::highlight_with_progress`].
 ///
-/// To use, just keep your own [`ScopeStack`] and then `ScopeStack.apply(op)` the operation that is
-/// yielded at the top of your `for` loop over this iterator. Now you have a substring of the line
-/// and the scope stack for that token.
+/// [`HighlightFile::highlight_with_progress`]: struct.HighlightFile.html#method.highlight_with_progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HighlightProgress {
+    /// Number of lines highlighted so far, including the current one
+    pub lines: usize,
+    /// Number of bytes highlighted so far, including the current line
+    pub bytes: usize,
+    /// Size of the file, if it could be determined
+    pub total_bytes: Option<u64>,
+}
+
+/// A line and the operations of the parser for it
+type ParsedLine<'a> = (&'a str, Vec<(usize, ScopeStackOp)>);
+
+/// The default for [`ParseReader::set_max_line_length`], 64 KiB.
 ///
-/// See the `synstats.rs` example for an example of using this iterator.
+/// [`ParseReader::set_max_line_length`]: struct.ParseReader.html#method.set_max_line_length
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Parses the lines of any `BufRead` one at a time, without loading the whole input.
 ///
-/// **Note:** This will often return empty regions, just `continue` after applying the op if you
-/// don't want them.
+/// Lines are read the way [`LinesWithEndings`] would split the decoded text, with these fixes
+/// for real files:
 ///
-/// [`ScopeStack`]: ../parsing/struct.ScopeStack.html
+/// - A UTF-8 byte order mark at the start is removed
+/// - `\r\n` line endings are turned into `\n`, so syntaxes loaded with newlines match as usual
+/// - Invalid UTF-8 is converted lossily instead of being an error
+/// - Lines longer than the [maximum line length] are parsed in chunks of at most that many bytes,
+///   so memory use doesn't depend on the input. Chunks are only split between characters, and
+///   all but the last chunk of a line don't end with `\n`.
+///
+/// The syntax set should be loaded with newlines, like `SyntaxSet::load_defaults_newlines`.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::ParseReader;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("py").unwrap();
+/// let input = "\u{feff}x = 1\r\ny = 2".as_bytes();
+///
+/// let mut reader = ParseReader::new(input, syntax);
+/// let mut lines = Vec::new();
+/// while let Some((line, ops)) = reader.read_line(&ss).unwrap() {
+///     assert!(!ops.is_empty());
+///     lines.push(line.to_owned());
+/// }
+/// assert_eq!(lines, vec!["x = 1\n", "y = 2"]);
+/// ```
+///
+/// [`LinesWithEndings`]: ../util/struct.LinesWithEndings.html
+/// [maximum line length]: #method.set_max_line_length
 #[derive(Debug)]
-pub struct ScopeRegionIterator<'a> {
-    ops: &'a [(usize, ScopeStackOp)],
-    line: &'a str,
-    index: usize,
-    last_str_index: usize,
+pub struct ParseReader<R> {
+    reader: R,
+    state: ParseState,
+    max_line_length: usize,
+    /// Bytes that were read but belong to the next chunk
+    pending: Vec<u8>,
+    line: String,
+    at_start: bool,
 }
 
-impl<'a> ScopeRegionIterator<'a> {
-    pub fn new(ops: &'a [(usize, ScopeStackOp)], line: &'a str) -> ScopeRegionIterator<'a> {
-        ScopeRegionIterator {
-            ops,
-            line,
-            index: 0,
-            last_str_index: 0,
-        }
+impl<R: BufRead> ParseReader<R> {
+    pub fn new(reader: R, syntax: &SyntaxReference) -> ParseReader<R> {
+        ParseReader::with_state(reader, ParseState::new(syntax))
     }
-}
-
-static NOOP_OP: ScopeStackOp = ScopeStackOp::Noop;
 
-impl<'a> Iterator for ScopeRegionIterator<'a> {
-    type Item = (&'a str, &'a ScopeStackOp);
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.index > self.ops.len() {
-            return None;
+    /// Continues parsing with an existing state, for example one with match limits set.
+    pub fn with_state(reader: R, state: ParseState) -> ParseReader<R> {
+        ParseReader {
+            reader,
+            state,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            pending: Vec::new(),
+            line: String::new(),
+            at_start: true,
         }
-
-        // region extends up to next operation (ops[index]) or string end if there is none
-        // note the next operation may be at, last_str_index, in which case the region is empty
-        let next_str_i = if self.index == self.ops.len() {
-            self.line.len()
-        } else {
-            self.ops[self.index].0
-        };
-        let substr = &self.line[self.last_str_index..next_str_i];
-        self.last_str_index = next_str_i;
-
-        // the first region covers everything before the first op, which may be empty
-        let op = if self.index == 0 {
-            &NOOP_OP
-        } else {
-            &self.ops[self.index-1].1
-        };
-
-        self.index += 1;
-        Some((substr, op))
     }
-}
 
-#[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::parsing::{SyntaxSet, ParseState, ScopeStack};
-    use crate::highlighting::ThemeSet;
-    use std::str::FromStr;
+    /// Sets the number of bytes after which a line is parsed in chunks, at least 4 so a chunk
+    /// always fits a character. The default is [`DEFAULT_MAX_LINE_LENGTH`].
+    ///
+    /// [`DEFAULT_MAX_LINE_LENGTH`]: constant.DEFAULT_MAX_LINE_LENGTH.html
+    pub fn set_max_line_length(&mut self, max_line_length: usize) {
+        self.max_line_length = max_line_length.max(4);
+    }
 
-    #[test]
-    fn can_highlight_lines() {
-        let ss = SyntaxSet::load_defaults_nonewlines();
-        let ts = ThemeSet::load_defaults();
-        let syntax = ss.find_syntax_by_extension("rs").unwrap();
-        let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
-        let ranges = h.highlight("pub struct Wow { hi: u64 }", &ss);
-        assert!(ranges.len() > 4);
+    /// The parse state after the lines read so far
+    pub fn state(&self) -> &ParseState {
+        &self.state
     }
 
-    #[test]
-    fn can_highlight_file() {
-        let ss = SyntaxSet::load_defaults_nonewlines();
-        let ts = ThemeSet::load_defaults();
-        HighlightFile::new("testdata/highlight_test.erb",
-                           &ss,
-                           &ts.themes["base16-ocean.dark"])
-            .unwrap();
+    /// Returns the underlying reader, the bytes of a chunk that was split in the middle of a
+    /// character or line ending are lost.
+    pub fn into_inner(self) -> R {
+        self.reader
     }
 
-    #[test]
-    fn can_find_regions() {
-        let ss = SyntaxSet::load_defaults_nonewlines();
-        let mut state = ParseState::new(ss.find_syntax_by_extension("rb").unwrap());
-        let line = "lol =5+2";
-        let ops = state.parse_line(line, &ss);
+    /// Reads and parses the next line, or chunk of a long line, and returns it along with the
+    /// operations of the parser. Returns `None` at the end of the input.
+    pub fn read_line(&mut self, syntax_set: &SyntaxSet) -> io::Result<Option<ParsedLine<'_>>> {
+        let mut bytes = Vec::new();
+        let mut complete = false;
+        while bytes.is_empty() && !complete {
+            bytes = std::mem::take(&mut self.pending);
+            complete = self.fill_chunk(&mut bytes)?;
+            if self.at_start {
+                self.at_start = false;
+                if bytes.starts_with(b"\xEF\xBB\xBF") {
+                    bytes.drain(..3);
+                    complete = complete || self.fill_chunk(&mut bytes)?;
+                }
+            }
+            if !complete {
+                // keep a `\r` that may start a `\r\n` and a split character for the next chunk
+                let keep = if bytes.ends_with(b"\r") { 1 } else { incomplete_char_len(&bytes) };
+                self.pending.extend_from_slice(&bytes[bytes.len() - keep..]);
+                bytes.truncate(bytes.len() - keep);
+            }
+        }
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        if bytes.ends_with(b"\r\n") {
+            bytes.remove(bytes.len() - 2);
+        }
 
-        let mut stack = ScopeStack::new();
-        let mut token_count = 0;
-        for (s, op) in ScopeRegionIterator::new(&ops, line) {
-            stack.apply(op);
-            if s.is_empty() { // in this case we don't care about blank tokens
-                continue;
+        self.line = match String::from_utf8(bytes) {
+            Ok(line) => line,
+            Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+        };
+        let ops = self.state.parse_line(&self.line, syntax_set);
+        Ok(Some((&self.line, ops)))
+    }
+
+    /// Reads up to the end of the line into `bytes`, or until it is as long as the maximum line
+    /// length. Returns true if the line or the input ended.
+    fn fill_chunk(&mut self, bytes: &mut Vec<u8>) -> io::Result<bool> {
+        while bytes.len() < self.max_line_length {
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                return Ok(true);
             }
-            if token_count == 1 {
-                assert_eq!(stack, ScopeStack::from_str("source.ruby keyword.operator.assignment.ruby").unwrap());
-                assert_eq!(s, "=");
+            let wanted = &available[..available.len().min(self.max_line_length - bytes.len())];
+            let (used, newline) = match wanted.iter().position(|&b| b == b'\n') {
+                Some(i) => (i + 1, true),
+                None => (wanted.len(), false),
+            };
+            bytes.extend_from_slice(&wanted[..used]);
+            self.reader.consume(used);
+            if newline {
+                return Ok(true);
             }
-            token_count += 1;
-            println!("{:?} {}", s, stack);
         }
-        assert_eq!(token_count, 5);
+        Ok(false)
     }
+}
 
-    #[test]
-    fn can_find_regions_with_trailing_newline() {
-        let ss = SyntaxSet::load_defaults_newlines();
-        let mut state = ParseState::new(ss.find_syntax_by_extension("rb").unwrap());
-        let lines = ["# hello world\n", "lol=5+2\n"];
-        let mut stack = ScopeStack::new();
+/// Returns the number of bytes at the end that are the start of a character that is cut off
+fn incomplete_char_len(bytes: &[u8]) -> usize {
+    for len in 1..=bytes.len().min(3) {
+        let b = bytes[bytes.len() - len];
+        if b & 0xC0 != 0x80 {
+            let char_len = match b {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if char_len > len { len } else { 0 };
+        }
+    }
+    0
+}
 
-        for line in lines.iter() {
-            let ops = state.parse_line(&line, &ss);
-            println!("{:?}", ops);
+/// Highlights a file by memory-mapping it instead of reading it into memory.
+///
+/// Lines are highlighted straight from the mapped file, so memory use only depends on the length
+/// of the longest line, not the size of the file. This is useful for multi-gigabyte logs.
+///
+/// Lines that aren't valid UTF-8 are converted lossily. Note that memory-mapping a file that is
+/// modified or truncated by another process while highlighting can lead to garbage output or a
+/// crash, like with all memory maps.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::SyntaxSet;
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::util::as_24_bit_terminal_escaped;
+/// use syntect::easy::HighlightMmapFile;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+///
+/// let mut highlighter = HighlightMmapFile::new("testdata/highlight_test.erb", &ss, &ts.themes["base16-ocean.dark"]).unwrap();
+/// highlighter.for_each_line(&ss, true, |regions| {
+///     print!("{}", as_24_bit_terminal_escaped(regions, true));
+/// });
+/// ```
+#[cfg(feature = "mmap")]
+pub struct HighlightMmapFile<'a> {
+    /// `None` for empty files, which can't be mapped
+    mmap: Option<Mmap>,
+    pub highlight_lines: HighlightLines<'a>,
+}
 
-            let mut iterated_ops: Vec<&ScopeStackOp> = Vec::new();
-            for (_, op) in ScopeRegionIterator::new(&ops, &line) {
-                stack.apply(op);
-                iterated_ops.push(&op);
-                println!("{:?}", op);
+#[cfg(feature = "mmap")]
+impl<'a> HighlightMmapFile<'a> {
+    /// Maps the file and constructs a line highlighter with the syntax detected for it, like
+    /// [`HighlightFile::new`] does.
+    ///
+    /// [`HighlightFile::new`]: struct.HighlightFile.html#method.new
+    pub fn new<P: AsRef<Path>>(path_obj: P,
+                               ss: &SyntaxSet,
+                               theme: &'a Theme)
+                               -> io::Result<HighlightMmapFile<'a>> {
+        let path: &Path = path_obj.as_ref();
+        let f = File::open(path)?;
+        let mmap = if f.metadata()?.len() == 0 {
+            None
+        } else {
+            // Safety: see the struct docs, changes to the file while mapped are the caller's problem
+            Some(unsafe { Mmap::map(&f)? })
+        };
+        if let Some(ref m) = mmap {
+            if looks_binary(m) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, BinaryFileError));
             }
+        }
+        let syntax = ss.find_syntax_for_file(path)?
+            .unwrap_or_else(|| ss.find_syntax_plain_text());
 
-            let all_ops: Vec<&ScopeStackOp> = ops.iter().map(|t|&t.1).collect();
-            assert_eq!(all_ops.len(), iterated_ops.len() - 1); // -1 because we want to ignore the NOOP
+        Ok(HighlightMmapFile {
+            mmap,
+            highlight_lines: HighlightLines::new(syntax, theme),
+        })
+    }
+
+    /// Highlights every line of the file in order and passes the regions to `f`.
+    ///
+    /// If `include_newline` is true the lines include their trailing `\n`, which is what syntax
+    /// sets loaded with newlines expect, otherwise it is stripped.
+    pub fn for_each_line<F>(&mut self, ss: &SyntaxSet, include_newline: bool, mut f: F)
+        where F: FnMut(&[(Style, &str)])
+    {
+        let content: &[u8] = self.mmap.as_ref().map_or(&[], |m| &m[..]);
+        let mut rest = content;
+        while !rest.is_empty() {
+            let end = rest.iter().position(|&b| b == b'\n').map_or(rest.len(), |i| i + 1);
+            let (mut line, next) = rest.split_at(end);
+            rest = next;
+            if !include_newline && line.ends_with(b"\n") {
+                line = &line[..line.len() - 1];
+            }
+            let line = String::from_utf8_lossy(line);
+            let regions = self.highlight_lines.highlight(&line, ss);
+            f(&regions);
         }
     }
 }
+
+/// Highlighter for a document that is being edited, which only re-highlights the lines affected
+/// by an edit.
+///
+/// This keeps the parse and highlight state at the start of every line as a checkpoint. After a
+/// range of lines is replaced, lines are re-parsed starting from the checkpoint before the edit
+/// until the state at the start of a line following the edit is the same as before, at which
+/// point the rest of the document can't have changed.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::IncrementalHighlighter;
+/// use syntect::parsing::SyntaxSet;
+/// use syntect::highlighting::ThemeSet;
+///
+/// let ss = SyntaxSet::load_defaults_nonewlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+/// let mut h = IncrementalHighlighter::new(syntax, &ts.themes["base16-ocean.dark"]);
+///
+/// let mut lines = vec!["fn main() {", "    let a = 1;", "    let b = 2;", "}"];
+/// assert_eq!(h.edit(&lines, 0..0, lines.len(), &ss), 0..4);
+///
+/// // replace the second line, only that line needs to be highlighted again
+/// lines[1] = "    let a = 3;";
+/// let changed = h.edit(&lines, 1..2, 1, &ss);
+/// assert_eq!(changed, 1..2);
+/// for i in changed {
+///     let regions = h.highlight_line(i, lines[i], &ss);
+///     assert!(regions.len() > 1);
+/// }
+/// ```
+pub struct IncrementalHighlighter<'a> {
+    checkpoints: LineCheckpoints<'a>,
+}
+
+impl<'a> IncrementalHighlighter<'a> {
+    /// Creates a highlighter for an empty document.
+    pub fn new(syntax: &SyntaxReference, theme: &'a Theme) -> IncrementalHighlighter<'a> {
+        IncrementalHighlighter { checkpoints: LineCheckpoints::new(syntax, theme) }
+    }
+
+    /// The number of lines the document had after the last edit
+    pub fn line_count(&self) -> usize {
+        self.checkpoints.states.len() - 1
+    }
+
+    /// The parse and highlight state at the start of line `index`, or after the last line if
+    /// `index` is the line count.
+    pub fn state_at(&self, index: usize) -> Option<&(ParseState, HighlightState)> {
+        self.checkpoints.states.get(index)
+    }
+
+    /// Updates the checkpoints after the lines in `replaced` were replaced by `inserted` lines.
+    ///
+    /// `lines` is the full document after the edit, and `replaced` refers to line indices before
+    /// the edit, so `lines` has to have `line_count() - replaced.len() + inserted` lines. To
+    /// highlight a whole new document, use `0..line_count()` as the range.
+    ///
+    /// Returns the range of lines (after the edit) whose highlighting may have changed. These
+    /// can then be highlighted with [`highlight_line`].
+    ///
+    /// [`highlight_line`]: #method.highlight_line
+    ///
+    /// # Panics
+    ///
+    /// Panics if `replaced` isn't a range of lines of the document before the edit, or if the
+    /// number of `lines` doesn't match the edit.
+    pub fn edit<S: AsRef<str>>(&mut self,
+                               lines: &[S],
+                               replaced: Range<usize>,
+                               inserted: usize,
+                               syntax_set: &SyntaxSet)
+                               -> Range<usize> {
+        assert!(replaced.start <= replaced.end && replaced.end <= self.line_count(),
+                "edited lines {:?} out of range", replaced);
+        assert_eq!(lines.len(), self.line_count() - replaced.len() + inserted,
+                   "the number of lines doesn't match the edit");
+        self.checkpoints.replace(replaced.clone(), inserted);
+        // if lines were only removed, the line after them has a different state at its start now
+        let dirty_end = replaced.start + inserted.max(1);
+
+        let mut index = replaced.start;
+        while index < lines.len() {
+            let (_, state) = self.checkpoints.highlight(index, lines[index].as_ref(), syntax_set);
+            index += 1;
+            if self.checkpoints.converges(index, state, dirty_end) {
+                break;
+            }
+        }
+        replaced.start..index
+    }
+
+    /// Highlights line `index` of the document starting from its checkpoint.
+    ///
+    /// The line should be the same as the one passed to the last [`edit`].
+    ///
+    /// [`edit`]: #method.edit
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is not smaller than the line count.
+    pub fn highlight_line<'b>(&self, index: usize, line: &'b str, syntax_set: &SyntaxSet) -> Vec<(Style, &'b str)> {
+        assert!(index < self.line_count(), "line {} out of range", index);
+        let (regions, _) = self.checkpoints.highlight(index, line, syntax_set);
+        regions.into_iter().map(|(style, range)| (style, &line[range])).collect()
+    }
+}
+
+/// The parse and highlight states at the start of each line of a document, shared by
+/// [`IncrementalHighlighter`] and [`HighlightCache`] to find out where the highlighting after an
+/// edit stops changing.
+///
+/// [`IncrementalHighlighter`]: struct.IncrementalHighlighter.html
+/// [`HighlightCache`]: struct.HighlightCache.html
+struct LineCheckpoints<'a> {
+    highlighter: Highlighter<'a>,
+    /// The states at the start of each line, plus the state at the end of the last line
+    states: Vec<Checkpoint>,
+}
+
+/// The parse and highlight state at the start of a line
+type Checkpoint = (ParseState, HighlightState);
+
+impl<'a> LineCheckpoints<'a> {
+    fn new(syntax: &SyntaxReference, theme: &'a Theme) -> LineCheckpoints<'a> {
+        let highlighter = Highlighter::new(theme);
+        let highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+        LineCheckpoints {
+            highlighter,
+            states: vec![(ParseState::new(syntax), highlight_state)],
+        }
+    }
+
+    /// Replaces the states of the lines in `range` by those of `inserted` lines.
+    ///
+    /// The new lines get placeholder states, they are outdated until highlighted, but the state
+    /// after the replaced lines is kept to find out when the highlighting converges.
+    fn replace(&mut self, range: Range<usize>, inserted: usize) {
+        let placeholder = self.states[range.start].clone();
+        let mut new_states = vec![placeholder; inserted.saturating_sub(1)];
+        if inserted > 0 {
+            new_states.push(self.states[range.end].clone());
+        }
+        self.states.splice(range.start + 1..range.end + 1, new_states);
+    }
+
+    /// Highlights line `index` from the state at its start, returning its styled regions and
+    /// the state at its end
+    fn highlight(&self, index: usize, line: &str, syntax_set: &SyntaxSet)
+                 -> (Vec<(Style, Range<usize>)>, Checkpoint) {
+        let (mut parse_state, mut highlight_state) = self.states[index].clone();
+        let ops = parse_state.parse_line(line, syntax_set);
+        let regions = RangedHighlightIterator::new(&mut highlight_state, &ops, line, &self.highlighter)
+            .map(|(style, _, range)| (style, range))
+            .collect();
+        (regions, (parse_state, highlight_state))
+    }
+
+    /// Stores `state` as the state at the start of line `index`.
+    ///
+    /// Returns `true` without storing it if the lines from `dirty_end` on weren't changed and
+    /// the state is the same as before, so the rest of the document is highlighted like before.
+    fn converges(&mut self, index: usize, state: Checkpoint, dirty_end: usize) -> bool {
+        if index >= dirty_end && self.states[index] == state {
+            return true;
+        }
+        self.states[index] = state;
+        false
+    }
+}
+
+/// A document with the highlighting of each of its lines cached, for editors and viewers that
+/// show a document while it is being changed.
+///
+/// The cache keeps the text of the lines, the parse and highlight states at the start of each
+/// line, and the styled regions of the lines that were highlighted. Lines are only highlighted
+/// when [`get_line`] asks for them, starting from the closest line before them with a known
+/// state. After an edit, the lines following it are highlighted again until the state at the
+/// start of a line is the same as before the edit, like [`IncrementalHighlighter`] does.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::HighlightCache;
+/// use syntect::parsing::SyntaxSet;
+/// use syntect::highlighting::ThemeSet;
+///
+/// let ss = SyntaxSet::load_defaults_nonewlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+/// let mut cache = HighlightCache::new(syntax, &ts.themes["base16-ocean.dark"]);
+/// cache.replace_lines(0..0, &["fn main() {", "    let a = 1;", "}"]);
+///
+/// let before = cache.get_line(1, &ss).unwrap();
+/// assert_eq!(before.iter().map(|(_, text)| *text).collect::<String>(), "    let a = 1;");
+///
+/// // opening a block comment on the first line changes the highlighting of the lines after it
+/// cache.set_line(0, "/* fn main() {");
+/// assert_eq!(cache.get_line(1, &ss).unwrap().len(), 1);
+/// assert!(cache.get_line(3, &ss).is_none());
+/// ```
+///
+/// [`get_line`]: #method.get_line
+/// [`IncrementalHighlighter`]: struct.IncrementalHighlighter.html
+pub struct HighlightCache<'a> {
+    checkpoints: LineCheckpoints<'a>,
+    lines: Vec<CachedLine>,
+    /// The first line whose following states may be outdated, the state at its start is known
+    stale_from: Option<usize>,
+    /// The end of the invalidated lines, states after it are compared with the new ones to find
+    /// out where the highlighting stops changing
+    dirty_end: usize,
+}
+
+struct CachedLine {
+    text: String,
+    /// The styled regions of the text, `None` if the line wasn't highlighted since it changed
+    regions: Option<Vec<(Style, Range<usize>)>>,
+}
+
+impl<'a> HighlightCache<'a> {
+    /// Creates a cache for an empty document
+    pub fn new(syntax: &SyntaxReference, theme: &'a Theme) -> HighlightCache<'a> {
+        HighlightCache {
+            checkpoints: LineCheckpoints::new(syntax, theme),
+            lines: Vec::new(),
+            stale_from: None,
+            dirty_end: 0,
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.lines.len()
+    }
+
+    /// The text of line `index`, `None` if the document doesn't have that line
+    pub fn line_text(&self, index: usize) -> Option<&str> {
+        self.lines.get(index).map(|line| line.text.as_str())
+    }
+
+    /// Replaces the lines in `range` with new lines and invalidates the lines whose highlighting
+    /// can change. Lines have to be passed with or without their newlines depending on how the
+    /// syntax set passed to [`get_line`] was loaded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `range` isn't a range of lines of the document.
+    ///
+    /// [`get_line`]: #method.get_line
+    pub fn replace_lines<S: AsRef<str>>(&mut self, range: Range<usize>, lines: &[S]) {
+        assert!(range.start <= range.end && range.end <= self.lines.len(),
+                "replaced lines {:?} out of range", range);
+        let new_lines = lines.iter().map(|line| CachedLine { text: line.as_ref().to_owned(), regions: None });
+        self.lines.splice(range.clone(), new_lines);
+        self.checkpoints.replace(range.clone(), lines.len());
+        // a pending invalidation after the replaced lines moved with them
+        if let Some(from) = self.stale_from {
+            let shift = lines.len() as isize - range.len() as isize;
+            let moved = |i: usize| if i >= range.end { (i as isize + shift) as usize } else { i };
+            self.stale_from = Some(moved(from));
+            self.dirty_end = moved(self.dirty_end);
+        }
+        // if lines were only removed, the line after them has a different state at its start now
+        let end = (range.start + lines.len()).max(range.start + 1).min(self.lines.len());
+        self.invalidate_lines(range.start..end);
+    }
+
+    /// Replaces the text of line `index`, see [`replace_lines`]
+    ///
+    /// [`replace_lines`]: #method.replace_lines
+    pub fn set_line(&mut self, index: usize, line: &str) {
+        self.replace_lines(index..index + 1, &[line]);
+    }
+
+    /// Forgets the highlighting of the lines in `range` and the states after them, so they are
+    /// highlighted again when they are needed.
+    ///
+    /// Editing the lines with [`replace_lines`] does this already. Lines past the end of the
+    /// document are ignored.
+    ///
+    /// [`replace_lines`]: #method.replace_lines
+    pub fn invalidate_lines(&mut self, range: Range<usize>) {
+        let end = range.end.min(self.lines.len());
+        if range.start >= end {
+            return;
+        }
+        for line in &mut self.lines[range.start..end] {
+            line.regions = None;
+        }
+        match self.stale_from {
+            Some(from) => {
+                self.stale_from = Some(from.min(range.start));
+                self.dirty_end = self.dirty_end.max(end);
+            }
+            None => {
+                self.stale_from = Some(range.start);
+                self.dirty_end = end;
+            }
+        }
+    }
+
+    /// Returns the highlighted regions of line `index`, highlighting the lines up to it that
+    /// aren't highlighted yet. Returns `None` if the document doesn't have the line.
+    pub fn get_line(&mut self, index: usize, syntax_set: &SyntaxSet) -> Option<Vec<(Style, &str)>> {
+        if index >= self.lines.len() {
+            return None;
+        }
+        if let Some(from) = self.stale_from {
+            if from <= index {
+                self.update_states(from, index, syntax_set);
+            }
+        }
+        if self.lines[index].regions.is_none() {
+            self.highlight_line(index, syntax_set);
+        }
+        let line = &self.lines[index];
+        let regions = line.regions.as_ref().unwrap();
+        Some(regions.iter().map(|(style, range)| (*style, &line.text[range.clone()])).collect())
+    }
+
+    /// Highlights the lines from `from` until the states converge or line `index` is done
+    fn update_states(&mut self, from: usize, index: usize, syntax_set: &SyntaxSet) {
+        let mut i = from;
+        loop {
+            let state = self.highlight_line(i, syntax_set);
+            i += 1;
+            if self.checkpoints.converges(i, state, self.dirty_end) || i == self.lines.len() {
+                // the rest of the document is highlighted like before
+                self.stale_from = None;
+                return;
+            }
+            self.lines[i].regions = None;
+            if i > index {
+                self.stale_from = Some(i);
+                return;
+            }
+        }
+    }
+
+    /// Highlights line `index` from the state at its start and returns the state at its end
+    fn highlight_line(&mut self, index: usize, syntax_set: &SyntaxSet) -> (ParseState, HighlightState) {
+        let line = &mut self.lines[index];
+        let (regions, state) = self.checkpoints.highlight(index, &line.text, syntax_set);
+        line.regions = Some(regions);
+        state
+    }
+}
+
+/// Iterator over the regions of a line which a given the operation from the parser applies.
+///
+/// To use, just keep your own [`ScopeStack`] and then `ScopeStack.apply(op)` the operation that is
+/// yielded at the top of your `for` loop over this iterator. Now you have a substring of the line
+/// and the scope stack for that token.
+///
+/// See the `synstats.rs` example for an example of using this iterator.
+///
+/// **Note:** This will often return empty regions, just `continue` after applying the op if you
+/// don't want them.
+///
+/// [`ScopeStack`]: ../parsing/struct.ScopeStack.html
+#[derive(Debug)]
+pub struct ScopeRegionIterator<'a> {
+    ops: &'a [(usize, ScopeStackOp)],
+    line: &'a str,
+    index: usize,
+    last_str_index: usize,
+}
+
+impl<'a> ScopeRegionIterator<'a> {
+    pub fn new(ops: &'a [(usize, ScopeStackOp)], line: &'a str) -> ScopeRegionIterator<'a> {
+        ScopeRegionIterator {
+            ops,
+            line,
+            index: 0,
+            last_str_index: 0,
+        }
+    }
+}
+
+static NOOP_OP: ScopeStackOp = ScopeStackOp::Noop;
+
+impl<'a> Iterator for ScopeRegionIterator<'a> {
+    type Item = (&'a str, &'a ScopeStackOp);
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index > self.ops.len() {
+            return None;
+        }
+
+        // region extends up to next operation (ops[index]) or string end if there is none
+        // note the next operation may be at, last_str_index, in which case the region is empty
+        let next_str_i = if self.index == self.ops.len() {
+            self.line.len()
+        } else {
+            self.ops[self.index].0
+        };
+        let substr = &self.line[self.last_str_index..next_str_i];
+        self.last_str_index = next_str_i;
+
+        // the first region covers everything before the first op, which may be empty
+        let op = if self.index == 0 {
+            &NOOP_OP
+        } else {
+            &self.ops[self.index-1].1
+        };
+
+        self.index += 1;
+        Some((substr, op))
+    }
+}
+
+/// Iterator over the tokens of a line, as byte ranges along with the scope stack for each.
+///
+/// This applies the operations of the parser to a [`ScopeStack`] which it borrows, so the stack
+/// carries over to the next line. Unlike [`ScopeRegionIterator`], tokens are never empty.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::ScopeTokenIterator;
+/// use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+/// let mut stack = ScopeStack::new();
+/// let line = "x = 'a'\n";
+/// let ops = state.parse_line(line, &ss);
+/// for (range, scopes) in ScopeTokenIterator::new(&ops, line, &mut stack) {
+///     println!("{:?} {}", &line[range], scopes);
+/// }
+/// ```
+///
+/// [`ScopeStack`]: ../parsing/struct.ScopeStack.html
+/// [`ScopeRegionIterator`]: struct.ScopeRegionIterator.html
+#[derive(Debug)]
+pub struct ScopeTokenIterator<'a, 'b> {
+    regions: ScopeRegionIterator<'a>,
+    stack: &'b mut ScopeStack,
+    offset: usize,
+}
+
+impl<'a, 'b> ScopeTokenIterator<'a, 'b> {
+    pub fn new(ops: &'a [(usize, ScopeStackOp)], line: &'a str, stack: &'b mut ScopeStack) -> ScopeTokenIterator<'a, 'b> {
+        ScopeTokenIterator {
+            regions: ScopeRegionIterator::new(ops, line),
+            stack,
+            offset: 0,
+        }
+    }
+}
+
+impl<'a, 'b> Iterator for ScopeTokenIterator<'a, 'b> {
+    type Item = (Range<usize>, ScopeStack);
+    fn next(&mut self) -> Option<Self::Item> {
+        for (text, op) in &mut self.regions {
+            self.stack.apply(op);
+            if !text.is_empty() {
+                let range = self.offset..self.offset + text.len();
+                self.offset = range.end;
+                return Some((range, self.stack.clone()));
+            }
+        }
+        None
+    }
+}
+
+/// A range of text with the same scopes, see [`TokenList`].
+///
+/// [`TokenList`]: struct.TokenList.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// The byte offset of the start of the span in the whole text
+    pub start: usize,
+    pub end: usize,
+    /// The index of the scope stack of the span in `TokenList::stacks`
+    pub stack: usize,
+}
+
+/// The tokens of a whole text as a flat list of spans, which is easier to analyze or serialize
+/// than the operations of the parser.
+///
+/// Each distinct scope stack is only stored once, and spans refer to it by index. Adjacent
+/// tokens with the same scopes are merged into one span, and there are no empty spans.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::TokenList;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("py").unwrap();
+/// let text = "a = 'x'\nb = 'y'\n";
+/// let tokens = TokenList::parse(text, syntax, &ss);
+///
+/// let strings: Vec<&str> = tokens.spans.iter()
+///     .filter(|span| tokens.stack(span).to_string().ends_with("string.quoted.single.python "))
+///     .map(|span| &text[span.start..span.end])
+///     .collect();
+/// assert_eq!(strings, vec!["x", "y"]);
+/// // both strings have the same scope stack
+/// assert!(tokens.stacks.len() < tokens.spans.len());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct TokenList {
+    /// The spans in the order of the text, covering all of it
+    pub spans: Vec<Span>,
+    pub stacks: Vec<ScopeStack>,
+}
+
+impl TokenList {
+    /// Parses the text with the syntax, which has to be from a syntax set with newlines.
+    pub fn parse(text: &str, syntax: &SyntaxReference, syntax_set: &SyntaxSet) -> TokenList {
+        let mut state = ParseState::new(syntax);
+        let mut stack = ScopeStack::new();
+        let mut ids: HashMap<Vec<Scope>, usize> = HashMap::new();
+        let mut spans: Vec<Span> = Vec::new();
+        let mut stacks = Vec::new();
+        let mut offset = 0;
+        for line in LinesWithEndings::from(text) {
+            let ops = state.parse_line(line, syntax_set);
+            for (range, scopes) in ScopeTokenIterator::new(&ops, line, &mut stack) {
+                let id = match ids.get(&scopes.scopes) {
+                    Some(&id) => id,
+                    None => {
+                        stacks.push(ScopeStack::from_vec(scopes.scopes.clone()));
+                        ids.insert(scopes.scopes, stacks.len() - 1);
+                        stacks.len() - 1
+                    }
+                };
+                let (start, end) = (offset + range.start, offset + range.end);
+                match spans.last_mut() {
+                    Some(last) if last.stack == id => last.end = end,
+                    _ => spans.push(Span { start, end, stack: id }),
+                }
+            }
+            offset += line.len();
+        }
+        TokenList { spans, stacks }
+    }
+
+    /// The scope stack of a span
+    pub fn stack(&self, span: &Span) -> &ScopeStack {
+        &self.stacks[span.stack]
+    }
+
+    /// Finds the span that contains a byte offset of the text
+    pub fn span_at(&self, index: usize) -> Option<&Span> {
+        let i = match self.spans.binary_search_by(|span| span.start.cmp(&index)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        self.spans.get(i).filter(|span| index < span.end)
+    }
+}
+
+/// An event in the structured output of [`ScopeEventGenerator`].
+///
+/// [`ScopeEventGenerator`]: struct.ScopeEventGenerator.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScopeEvent<'a> {
+    /// A scope starts, it stays open until the matching `Close`
+    Open(Scope),
+    /// The innermost open scope ends
+    Close(Scope),
+    /// Text inside all the currently open scopes
+    Text(&'a str),
+}
+
+/// Turns the operations of the parser into properly nested open and close events.
+///
+/// Unlike [`ScopeRegionIterator`], scopes that are still on the stack at the end of a line
+/// are kept open across line boundaries, and scopes are only opened right before the first
+/// text they contain, so there are no events for empty scopes. Each scope opened is closed
+/// exactly once, in reverse order, which makes this suitable for rendering structurally nested
+/// output like nested `<span>` tags.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::{ScopeEvent, ScopeEventGenerator};
+/// use syntect::parsing::{ParseState, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let mut state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+/// let mut events = ScopeEventGenerator::new();
+/// let mut html = String::new();
+/// for line in &["/* multi\n", "line */\n"] {
+///     let ops = state.parse_line(line, &ss);
+///     for event in events.line_events(line, &ops).into_iter() {
+///         match event {
+///             ScopeEvent::Open(scope) => html.push_str(&format!("<span class=\"{}\">", scope)),
+///             ScopeEvent::Close(_) => html.push_str("</span>"),
+///             ScopeEvent::Text(text) => html.push_str(text),
+///         }
+///     }
+/// }
+/// for _ in events.finish() {
+///     html.push_str("</span>");
+/// }
+/// assert_eq!(html, "<span class=\"source.rust\"><span class=\"comment.block.rust\">\
+///     /* multi\nline */</span>\n</span>");
+/// ```
+///
+/// [`ScopeRegionIterator`]: struct.ScopeRegionIterator.html
+#[derive(Debug, Clone, Default)]
+pub struct ScopeEventGenerator {
+    /// The scopes that have been opened and not closed yet
+    open: ScopeStack,
+    /// The scope stack according to the parser, which is only synced to `open` before text
+    current: ScopeStack,
+}
+
+impl ScopeEventGenerator {
+    pub fn new() -> ScopeEventGenerator {
+        ScopeEventGenerator::default()
+    }
+
+    /// Returns the events for a line given the operations the parser returned for it.
+    pub fn line_events<'b>(&mut self, line: &'b str, ops: &[(usize, ScopeStackOp)]) -> Vec<ScopeEvent<'b>> {
+        let mut events = Vec::with_capacity(ops.len() * 2 + 1);
+        let mut last_index = 0;
+        for &(i, ref op) in ops {
+            if i > last_index {
+                self.sync(&mut events);
+                events.push(ScopeEvent::Text(&line[last_index..i]));
+                last_index = i;
+            }
+            self.current.apply(op);
+        }
+        if line.len() > last_index {
+            self.sync(&mut events);
+            events.push(ScopeEvent::Text(&line[last_index..]));
+        }
+        events
+    }
+
+    /// Closes all scopes that are still open at the end of the text.
+    pub fn finish(mut self) -> Vec<ScopeEvent<'static>> {
+        let mut events = Vec::with_capacity(self.open.len());
+        self.current = ScopeStack::new();
+        self.sync(&mut events);
+        events
+    }
+
+    fn sync<'b>(&mut self, events: &mut Vec<ScopeEvent<'b>>) {
+        for op in self.open.diff(&self.current) {
+            match op {
+                ScopeStackOp::Pop(n) => {
+                    for _ in 0..n {
+                        let scope = self.open.as_slice()[self.open.len() - 1];
+                        self.open.pop();
+                        events.push(ScopeEvent::Close(scope));
+                    }
+                }
+                ScopeStackOp::Push(scope) => {
+                    self.open.push(scope);
+                    events.push(ScopeEvent::Open(scope));
+                }
+                _ => unreachable!("diff only returns pushes and pops"),
+            }
+        }
+    }
+}
+
+/// A scope in a [`ScopeTree`], with the scopes nested inside it as children.
+///
+/// [`ScopeTree`]: struct.ScopeTree.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeNode {
+    pub scope: Scope,
+    /// The byte range of the text in the scope
+    pub range: Range<usize>,
+    /// The scopes that start inside this one, in the order of the text
+    pub children: Vec<ScopeNode>,
+}
+
+impl ScopeNode {
+    /// Finds the nodes below this one, not including itself, whose scope starts with `prefix`,
+    /// in the order of the text. A node found inside another found node is included as well.
+    pub fn find_all(&self, prefix: Scope) -> Vec<&ScopeNode> {
+        let mut found = Vec::new();
+        find_nodes(&self.children, prefix, &mut found);
+        found
+    }
+}
+
+fn find_nodes<'a>(nodes: &'a [ScopeNode], prefix: Scope, found: &mut Vec<&'a ScopeNode>) {
+    for node in nodes {
+        if prefix.is_prefix_of(node.scope) {
+            found.push(node);
+        }
+        find_nodes(&node.children, prefix, found);
+    }
+}
+
+/// The scopes of a text as a tree, where each scope is a node containing the scopes nested in
+/// it, so the structure can be queried without replaying the operations of the parser.
+///
+/// The tree is built from the events of a [`ScopeEventGenerator`], so scopes that don't contain
+/// any text have no node, and a scope that is popped and pushed again without text in between
+/// is one node.
+///
+/// # Examples
+///
+/// Finding the strings that contain interpolation:
+///
+/// ```
+/// use syntect::easy::ScopeTree;
+/// use syntect::parsing::{Scope, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("rb").unwrap();
+/// let text = "a = \"x#{y}\"\nb = \"z\"\n";
+/// let tree = ScopeTree::parse(text, syntax, &ss);
+///
+/// let string = Scope::new("meta.string").unwrap();
+/// let interpolation = Scope::new("meta.interpolation").unwrap();
+/// let interpolated: Vec<&str> = tree.find_all(string).into_iter()
+///     .filter(|node| !node.find_all(interpolation).is_empty())
+///     .map(|node| &text[node.range.clone()])
+///     .collect();
+/// assert_eq!(interpolated, vec!["\"x#{y}\""]);
+/// ```
+///
+/// [`ScopeEventGenerator`]: struct.ScopeEventGenerator.html
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScopeTree {
+    /// The outermost scopes, usually just the scope of the syntax
+    pub roots: Vec<ScopeNode>,
+}
+
+impl ScopeTree {
+    /// Parses the text with the syntax, which has to be from a syntax set with newlines, and
+    /// builds the tree of its scopes.
+    pub fn parse(text: &str, syntax: &SyntaxReference, syntax_set: &SyntaxSet) -> ScopeTree {
+        let mut state = ParseState::new(syntax);
+        let mut builder = ScopeTreeBuilder::new();
+        for line in LinesWithEndings::from(text) {
+            let ops = state.parse_line(line, syntax_set);
+            builder.add_line(line, &ops);
+        }
+        builder.finish()
+    }
+
+    /// Finds all nodes whose scope starts with `prefix`, in the order of the text.
+    pub fn find_all(&self, prefix: Scope) -> Vec<&ScopeNode> {
+        let mut found = Vec::new();
+        find_nodes(&self.roots, prefix, &mut found);
+        found
+    }
+}
+
+/// Builds a [`ScopeTree`] from the lines of a text and their operations, for reusing a parse
+/// that is done anyway. Otherwise [`ScopeTree::parse`] is simpler.
+///
+/// [`ScopeTree`]: struct.ScopeTree.html
+/// [`ScopeTree::parse`]: struct.ScopeTree.html#method.parse
+#[derive(Debug, Default)]
+pub struct ScopeTreeBuilder {
+    events: ScopeEventGenerator,
+    /// The nodes that are still open, innermost last
+    open: Vec<ScopeNode>,
+    roots: Vec<ScopeNode>,
+    offset: usize,
+}
+
+impl ScopeTreeBuilder {
+    pub fn new() -> ScopeTreeBuilder {
+        ScopeTreeBuilder::default()
+    }
+
+    /// Adds the next line, with the operations `ParseState::parse_line` returned for it
+    pub fn add_line(&mut self, line: &str, ops: &[(usize, ScopeStackOp)]) {
+        let events = self.events.line_events(line, ops);
+        self.apply(events);
+    }
+
+    /// Closes the scopes that are still open and returns the tree.
+    pub fn finish(mut self) -> ScopeTree {
+        let events = std::mem::take(&mut self.events).finish();
+        self.apply(events);
+        ScopeTree { roots: self.roots }
+    }
+
+    fn apply(&mut self, events: Vec<ScopeEvent<'_>>) {
+        for event in events {
+            match event {
+                ScopeEvent::Open(scope) => self.open.push(ScopeNode {
+                    scope,
+                    range: self.offset..self.offset,
+                    children: Vec::new(),
+                }),
+                ScopeEvent::Close(_) => {
+                    let mut node = self.open.pop().expect("events close only open scopes");
+                    node.range.end = self.offset;
+                    match self.open.last_mut() {
+                        Some(parent) => parent.children.push(node),
+                        None => self.roots.push(node),
+                    }
+                }
+                ScopeEvent::Text(text) => self.offset += text.len(),
+            }
+        }
+    }
+}
+
+/// Text together with the scope operations the parser produced for it, independent of any theme.
+///
+/// This allows doing the expensive parsing once, for example in a build step, storing the result
+/// with [`write_to`] and rendering it later under any theme after loading it with [`read_from`].
+///
+/// The format is a small line based text format: a `syntect-scoped-text 1` header, and for each
+/// line of text a `<text length> <op count>` line followed by the raw text, a newline and one
+/// line per operation like `4 +string.quoted.double.rust` or `11 -1`.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::ScopedText;
+/// use syntect::parsing::SyntaxSet;
+/// use syntect::highlighting::ThemeSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+/// let scoped = ScopedText::parse("let s = \"hi\";\n", syntax, &ss, true);
+///
+/// let mut dump = Vec::new();
+/// scoped.write_to(&mut dump).unwrap();
+///
+/// // later, possibly in another process
+/// let loaded = ScopedText::read_from(&dump[..]).unwrap();
+/// let ts = ThemeSet::load_defaults();
+/// let lines = loaded.highlight(&ts.themes["InspiredGitHub"]);
+/// assert_eq!(lines.len(), 1);
+/// ```
+///
+/// [`write_to`]: #method.write_to
+/// [`read_from`]: #method.read_from
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScopedText {
+    pub lines: Vec<ScopedLine>,
+}
+
+/// A single line of a [`ScopedText`].
+///
+/// [`ScopedText`]: struct.ScopedText.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopedLine {
+    /// The text of the line, including the newline if the syntax set uses them
+    pub text: String,
+    /// The operations returned by [`ParseState::parse_line`] for the line
+    ///
+    /// [`ParseState::parse_line`]: ../parsing/struct.ParseState.html#method.parse_line
+    pub ops: Vec<(usize, ScopeStackOp)>,
+}
+
+const SCOPED_TEXT_HEADER: &str = "syntect-scoped-text 1";
+
+impl ScopedText {
+    /// Parses the text line by line with the given syntax.
+    ///
+    /// Lines are split after each `\n`, which is only kept if `include_newline` is true. This
+    /// should match how the syntax set was loaded, see [`SyntaxSetBuilder::add_from_folder`].
+    ///
+    /// [`SyntaxSetBuilder::add_from_folder`]: ../parsing/struct.SyntaxSetBuilder.html#method.add_from_folder
+    pub fn parse(text: &str, syntax: &SyntaxReference, syntax_set: &SyntaxSet, include_newline: bool) -> ScopedText {
+        let mut state = ParseState::new(syntax);
+        let lines = LinesWithEndings::from(text).map(|line| {
+            let line = if include_newline { line } else { line.trim_end_matches('\n') };
+            let ops = state.parse_line(line, syntax_set);
+            ScopedLine { text: line.to_owned(), ops }
+        }).collect();
+        ScopedText { lines }
+    }
+
+    /// Writes the scoped text in the format described in the type docs.
+    pub fn write_to<W: Write>(&self, mut out: W) -> io::Result<()> {
+        writeln!(out, "{}", SCOPED_TEXT_HEADER)?;
+        for line in &self.lines {
+            writeln!(out, "{} {}", line.text.len(), line.ops.len())?;
+            out.write_all(line.text.as_bytes())?;
+            out.write_all(b"\n")?;
+            for &(index, ref op) in &line.ops {
+                match *op {
+                    ScopeStackOp::Push(scope) => writeln!(out, "{} +{}", index, scope.build_string())?,
+                    ScopeStackOp::Pop(n) => writeln!(out, "{} -{}", index, n)?,
+                    ScopeStackOp::Clear(ClearAmount::TopN(n)) => writeln!(out, "{} c{}", index, n)?,
+                    ScopeStackOp::Clear(ClearAmount::All) => writeln!(out, "{} C", index)?,
+                    ScopeStackOp::Restore => writeln!(out, "{} r", index)?,
+                    ScopeStackOp::Noop => writeln!(out, "{} n", index)?,
+                }
+            }
+        }
+        out.flush()
+    }
+
+    /// Reads scoped text written by [`write_to`].
+    ///
+    /// Returns an error of kind `InvalidData` if the input is malformed.
+    ///
+    /// [`write_to`]: #method.write_to
+    pub fn read_from<R: BufRead>(mut input: R) -> io::Result<ScopedText> {
+        let mut buf = String::new();
+        input.read_line(&mut buf)?;
+        if buf.trim_end() != SCOPED_TEXT_HEADER {
+            return Err(invalid_scoped_text("missing header"));
+        }
+
+        let mut lines = Vec::new();
+        loop {
+            buf.clear();
+            if input.read_line(&mut buf)? == 0 {
+                break;
+            }
+            let mut counts = buf.split_whitespace().map(|n| n.parse::<usize>());
+            let (text_len, op_count) = match (counts.next(), counts.next()) {
+                (Some(Ok(len)), Some(Ok(count))) => (len, count),
+                _ => return Err(invalid_scoped_text("bad line header")),
+            };
+
+            // the header isn't trusted, so the text is read up to the length it claims instead of
+            // allocating that much up front
+            let len_with_newline = text_len.checked_add(1)
+                .ok_or_else(|| invalid_scoped_text("bad line header"))?;
+            let mut text = Vec::new();
+            (&mut input).take(len_with_newline as u64).read_to_end(&mut text)?;
+            if text.len() != len_with_newline {
+                return Err(invalid_scoped_text("text is shorter than its header says"));
+            }
+            if text.pop() != Some(b'\n') {
+                return Err(invalid_scoped_text("missing newline after text"));
+            }
+            let text = String::from_utf8(text).map_err(|_| invalid_scoped_text("text isn't UTF-8"))?;
+
+            let mut ops = Vec::new();
+            for _ in 0..op_count {
+                buf.clear();
+                input.read_line(&mut buf)?;
+                ops.push(parse_scoped_text_op(buf.trim_end_matches('\n'))
+                    .ok_or_else(|| invalid_scoped_text("bad operation"))?);
+            }
+            lines.push(ScopedLine { text, ops });
+        }
+        Ok(ScopedText { lines })
+    }
+
+    /// Styles every line with the given theme, without parsing anything again.
+    pub fn highlight<'a>(&'a self, theme: &Theme) -> Vec<Vec<(Style, &'a str)>> {
+        let highlighter = Highlighter::new(theme);
+        let mut state = HighlightState::new(&highlighter, ScopeStack::new());
+        self.lines.iter().map(|line| {
+            HighlightIterator::new(&mut state, &line.ops, &line.text, &highlighter).collect()
+        }).collect()
+    }
+
+    /// Finds the places where `other`, a parse of the same text, assigns different scopes.
+    ///
+    /// Adjacent ranges that differ in the same way are reported as one difference.
+    pub fn diff(&self, other: &ScopedText) -> Vec<ScopeDifference> {
+        let mut differences: Vec<ScopeDifference> = Vec::new();
+        let mut old_stack = ScopeStack::new();
+        let mut new_stack = ScopeStack::new();
+        for (line, (old_line, new_line)) in self.lines.iter().zip(&other.lines).enumerate() {
+            let old_regions = old_line.scope_regions(&mut old_stack);
+            let new_regions = new_line.scope_regions(&mut new_stack);
+            let (mut i, mut j) = (0, 0);
+            while i < old_regions.len() && j < new_regions.len() {
+                let (old_range, old) = &old_regions[i];
+                let (new_range, new) = &new_regions[j];
+                let start = old_range.start.max(new_range.start);
+                let end = old_range.end.min(new_range.end);
+                if start < end && old != new {
+                    match differences.last_mut() {
+                        Some(last) if last.line == line && last.range.end == start
+                                      && last.old == *old && last.new == *new => last.range.end = end,
+                        _ => differences.push(ScopeDifference {
+                            line,
+                            range: start..end,
+                            old: old.clone(),
+                            new: new.clone(),
+                        }),
+                    }
+                }
+                if old_range.end <= end {
+                    i += 1;
+                }
+                if new_range.end <= end {
+                    j += 1;
+                }
+            }
+        }
+        differences
+    }
+
+    /// Reconstructs the text with the parts matching the selector removed or replaced, like
+    /// stripping comments or redacting string literals before sharing code.
+    ///
+    /// Newlines are always kept, so the result has the same lines as the original. Lines that
+    /// were parsed without their newline are joined with `\n`. Selectors can exclude parts of
+    /// a match, like `string - punctuation` to keep the quotes around strings.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::{Redaction, ScopedText};
+    /// use syntect::highlighting::ScopeSelectors;
+    /// use syntect::parsing::SyntaxSet;
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("py").unwrap();
+    /// let scoped = ScopedText::parse("# setup\ntoken = 'hunter2'  # secret\n", syntax, &ss, true);
+    ///
+    /// let comments = "comment".parse::<ScopeSelectors>().unwrap();
+    /// assert_eq!(scoped.redact(&comments, &Redaction::Remove), "\ntoken = 'hunter2'\n");
+    ///
+    /// let strings = "string - punctuation".parse::<ScopeSelectors>().unwrap();
+    /// assert_eq!(scoped.redact(&strings, &Redaction::Mask('*')), "# setup\ntoken = '*******'  # secret\n");
+    /// ```
+    pub fn redact(&self, selector: &ScopeSelectors, redaction: &Redaction<'_>) -> String {
+        let mut out = String::new();
+        let mut stack = ScopeStack::new();
+        for (index, line) in self.lines.iter().enumerate() {
+            let body_len = line.text.trim_end_matches(&['\r', '\n'][..]).len();
+            let mut in_match = false;
+            for (range, scopes) in line.scope_regions(&mut stack) {
+                let range = range.start.min(body_len)..range.end.min(body_len);
+                if range.start == range.end {
+                    continue;
+                }
+                let text = &line.text[range];
+                let matched = selector.does_match(scopes.as_slice()).is_some();
+                match *redaction {
+                    _ if !matched => out.push_str(text),
+                    Redaction::Remove => {}
+                    // adjacent matching regions are replaced only once
+                    Redaction::Replace(replacement) => if !in_match {
+                        out.push_str(replacement);
+                    },
+                    Redaction::Mask(c) => out.extend(text.chars().map(|_| c)),
+                }
+                in_match = matched;
+            }
+            if in_match && *redaction == Redaction::Remove {
+                let trimmed = out.trim_end_matches(&[' ', '\t'][..]).len();
+                out.truncate(trimmed);
+            }
+            out.push_str(&line.text[body_len..]);
+            if body_len == line.text.len() && index + 1 < self.lines.len() {
+                out.push('\n');
+            }
+        }
+        out
+    }
+
+    /// The scopes of the character at a position, given as a line number and a byte index in the
+    /// line. At the end of a line, these are the scopes of its last character.
+    ///
+    /// With the `metadata` feature, this gives the metadata for a position, like the comment
+    /// markers to use there, through [`Metadata::metadata_for_scope`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::ScopedText;
+    /// use syntect::parsing::{Scope, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("html").unwrap();
+    /// let scoped = ScopedText::parse("<p>\n<script>x = 1;</script>\n", syntax, &ss, true);
+    /// let scopes = scoped.scope_stack_at((1, 8)).unwrap();
+    /// assert!(scopes.as_slice().contains(&Scope::new("source.js.embedded.html").unwrap()));
+    /// assert!(scoped.scope_stack_at((5, 0)).is_none());
+    /// ```
+    ///
+    /// [`Metadata::metadata_for_scope`]: ../parsing/struct.Metadata.html#method.metadata_for_scope
+    pub fn scope_stack_at(&self, position: (usize, usize)) -> Option<ScopeStack> {
+        let (line, index) = position;
+        if index > self.lines.get(line)?.text.len() {
+            return None;
+        }
+        let mut stack = ScopeStack::new();
+        let mut found = None;
+        for (i, scoped_line) in self.lines[..=line].iter().enumerate() {
+            let regions = scoped_line.scope_regions(&mut stack);
+            if i == line {
+                found = regions.into_iter()
+                    .filter(|(range, _)| !range.is_empty())
+                    .take_while(|(range, _)| range.start <= index)
+                    .last()
+                    .map(|(_, scopes)| scopes);
+            }
+        }
+        Some(found.unwrap_or(stack))
+    }
+
+    /// Finds the bracket matching the one at a position, like an editor does for the cursor.
+    ///
+    /// Positions are a line number and a byte index in the line. If there is no bracket at the
+    /// position, the character before it is tried. The pairs in [`BRACKET_PAIRS`] are matched,
+    /// and brackets in strings and comments are ignored, unless the bracket at the position is
+    /// in one itself. Then only the brackets of the same string or comment are matched. Returns
+    /// `None` if there is no bracket or it's unbalanced.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::easy::ScopedText;
+    /// use syntect::parsing::SyntaxSet;
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("py").unwrap();
+    /// let scoped = ScopedText::parse("f(\")\", [1],\n  2)\n", syntax, &ss, true);
+    /// assert_eq!(scoped.matching_bracket((0, 1)), Some((1, 3)));
+    /// assert_eq!(scoped.matching_bracket((1, 4)), Some((0, 1)));
+    /// assert_eq!(scoped.matching_bracket((0, 0)), None);
+    /// ```
+    ///
+    /// [`BRACKET_PAIRS`]: constant.BRACKET_PAIRS.html
+    pub fn matching_bracket(&self, position: (usize, usize)) -> Option<(usize, usize)> {
+        let (line, index) = position;
+        let brackets = self.brackets();
+        let find = |index| brackets.iter().position(|b| b.line == line && b.index == index);
+        let i = find(index).or_else(|| {
+            let before = self.lines.get(line)?.text.get(..index)?.chars().next_back()?;
+            find(index - before.len_utf8())
+        })?;
+        let bracket = &brackets[i];
+        let (opening, closing, forward) = BRACKET_PAIRS.iter().find_map(|&(open, close)| {
+            if bracket.c == open {
+                Some((open, close, true))
+            } else if bracket.c == close {
+                Some((close, open, false))
+            } else {
+                None
+            }
+        })?;
+        let candidates: Box<dyn Iterator<Item = &Bracket>> = if forward {
+            Box::new(brackets[i..].iter())
+        } else {
+            Box::new(brackets[..=i].iter().rev())
+        };
+        let mut depth = 0;
+        for b in candidates.filter(|b| b.region == bracket.region) {
+            if b.c == opening {
+                depth += 1;
+            } else if b.c == closing {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((b.line, b.index));
+                }
+            }
+        }
+        None
+    }
+
+    /// All brackets of the text in order
+    fn brackets(&self) -> Vec<Bracket> {
+        let ignored_scopes = [Scope::new("string").unwrap(), Scope::new("comment").unwrap()];
+        let mut brackets = Vec::new();
+        let mut stack = ScopeStack::new();
+        let mut regions = 0;
+        let mut was_ignored = false;
+        for (line_index, line) in self.lines.iter().enumerate() {
+            for (range, scopes) in line.scope_regions(&mut stack) {
+                let ignored = scopes.as_slice().iter()
+                    .any(|&s| ignored_scopes.iter().any(|i| i.is_prefix_of(s)));
+                if ignored && !was_ignored {
+                    regions += 1;
+                }
+                was_ignored = ignored;
+                let is_bracket = |c| BRACKET_PAIRS.iter().any(|&(open, close)| c == open || c == close);
+                for (i, c) in line.text[range.clone()].char_indices().filter(|&(_, c)| is_bracket(c)) {
+                    brackets.push(Bracket {
+                        line: line_index,
+                        index: range.start + i,
+                        c,
+                        region: if ignored { regions } else { 0 },
+                    });
+                }
+            }
+        }
+        brackets
+    }
+}
+
+/// The opening and closing brackets that [`ScopedText::matching_bracket`] matches
+///
+/// [`ScopedText::matching_bracket`]: struct.ScopedText.html#method.matching_bracket
+pub const BRACKET_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}')];
+
+/// A bracket in a scoped text
+#[derive(Debug)]
+struct Bracket {
+    line: usize,
+    index: usize,
+    c: char,
+    /// The number of the string or comment the bracket is in, counting from 1, or 0 if it isn't
+    /// in one
+    region: usize,
+}
+
+impl ScopedLine {
+    /// The byte ranges of the line with the scope stack that applies to each, continuing from
+    /// the stack at the end of the previous line
+    fn scope_regions(&self, stack: &mut ScopeStack) -> Vec<(Range<usize>, ScopeStack)> {
+        let mut offset = 0;
+        ScopeRegionIterator::new(&self.ops, &self.text).map(|(s, op)| {
+            stack.apply(op);
+            let range = offset..offset + s.len();
+            offset = range.end;
+            (range, stack.clone())
+        }).collect()
+    }
+}
+
+/// A range of a line that two parses assign different scopes to, see [`ScopedText::diff`].
+///
+/// [`ScopedText::diff`]: struct.ScopedText.html#method.diff
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScopeDifference {
+    /// The zero-based line number
+    pub line: usize,
+    /// The byte range within the line
+    pub range: Range<usize>,
+    /// The scopes of the range in the first parse
+    pub old: ScopeStack,
+    /// The scopes of the range in the second parse
+    pub new: ScopeStack,
+}
+
+/// What [`ScopedText::redact`] does with the text matching the selector.
+///
+/// [`ScopedText::redact`]: struct.ScopedText.html#method.redact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redaction<'a> {
+    /// Removes the text, along with whitespace that would be left at the end of the line
+    Remove,
+    /// Replaces each run of matching text within a line with this string
+    Replace(&'a str),
+    /// Replaces every character with this one, which keeps the columns of the rest of the line
+    Mask(char),
+}
+
+/// The scope-level differences between parsing a file with two syntax sets.
+///
+/// This is meant for validating grammar upgrades: parse a corpus of files with the old and the
+/// updated syntax set, and look at the files where the result changed.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::FileComparison;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let old = SyntaxSet::load_defaults_newlines();
+/// let new = SyntaxSet::load_defaults_newlines();
+/// let comparison = FileComparison::new("testdata/highlight_test.erb", &old, &new, true).unwrap();
+/// assert_eq!(comparison.old_syntax, "HTML (Rails)");
+/// assert!(comparison.is_identical());
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileComparison {
+    /// The name of the syntax the old set picked for the file
+    pub old_syntax: String,
+    /// The name of the syntax the new set picked for the file
+    pub new_syntax: String,
+    pub differences: Vec<ScopeDifference>,
+}
+
+impl FileComparison {
+    /// Parses the file with the syntax each set finds for it, falling back to plain text, and
+    /// compares the results.
+    ///
+    /// `include_newline` should match how both syntax sets were loaded, see
+    /// [`SyntaxSetBuilder::add_from_folder`].
+    ///
+    /// [`SyntaxSetBuilder::add_from_folder`]: ../parsing/struct.SyntaxSetBuilder.html#method.add_from_folder
+    pub fn new<P: AsRef<Path>>(path_obj: P,
+                               old: &SyntaxSet,
+                               new: &SyntaxSet,
+                               include_newline: bool)
+                               -> io::Result<FileComparison> {
+        let path = path_obj.as_ref();
+        let text = std::fs::read_to_string(path)?;
+        let old_syntax = old.find_syntax_for_file(path)?.unwrap_or_else(|| old.find_syntax_plain_text());
+        let new_syntax = new.find_syntax_for_file(path)?.unwrap_or_else(|| new.find_syntax_plain_text());
+        let old_text = ScopedText::parse(&text, old_syntax, old, include_newline);
+        let new_text = ScopedText::parse(&text, new_syntax, new, include_newline);
+        Ok(FileComparison {
+            old_syntax: old_syntax.name.clone(),
+            new_syntax: new_syntax.name.clone(),
+            differences: old_text.diff(&new_text),
+        })
+    }
+
+    /// Whether both sets picked the same syntax and parsed the file the same way
+    pub fn is_identical(&self) -> bool {
+        self.old_syntax == self.new_syntax && self.differences.is_empty()
+    }
+}
+
+fn invalid_scoped_text(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid scoped text: {}", msg))
+}
+
+fn parse_scoped_text_op(s: &str) -> Option<(usize, ScopeStackOp)> {
+    let space = s.find(' ')?;
+    let index = s[..space].parse().ok()?;
+    let op = &s[space + 1..];
+    let arg = op.get(1..).unwrap_or("");
+    let op = match op.chars().next()? {
+        '+' => ScopeStackOp::Push(Scope::new(arg).ok()?),
+        '-' => ScopeStackOp::Pop(arg.parse().ok()?),
+        'c' => ScopeStackOp::Clear(ClearAmount::TopN(arg.parse().ok()?)),
+        'C' => ScopeStackOp::Clear(ClearAmount::All),
+        'r' => ScopeStackOp::Restore,
+        'n' => ScopeStackOp::Noop,
+        _ => return None,
+    };
+    Some((index, op))
+}
+
+#[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{SyntaxSet, ParseState, ScopeStack};
+    use crate::highlighting::ThemeSet;
+    use crate::util::TestDir;
+    use std::str::FromStr;
+
+    #[test]
+    fn can_highlight_lines() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+        let ranges = h.highlight("pub struct Wow { hi: u64 }", &ss);
+        assert!(ranges.len() > 4);
+    }
+
+    #[test]
+    fn can_highlight_ranges_of_lines() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let lines = ["fn wow(hi: u64) { /* é\n", "still a comment */ }\n", "let x = 1;\n"];
+        let mut full = HighlightLines::new(syntax, theme);
+        let mut ranged = HighlightLines::new(syntax, theme);
+        for (&line, range) in lines.iter().zip(&[5..23, 3..3, 0..11]) {
+            let all = full.highlight(line, &ss);
+            let part = ranged.highlight_range(line, range.clone(), &ss);
+            assert_eq!(part.iter().map(|(_, text)| *text).collect::<String>(), &line[range.clone()]);
+            let mut start = 0;
+            for (style, text) in all {
+                let end = start + text.len();
+                let (from, to) = (start.max(range.start), end.min(range.end));
+                if from < to {
+                    assert!(part.contains(&(style, &line[from..to])));
+                }
+                start = end;
+            }
+        }
+    }
+
+    #[test]
+    fn stops_highlighting_past_byte_limit() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let mut h = HighlightLines::new(syntax, theme);
+        h.set_limits(HighlightLimits { max_bytes: Some(20), ..Default::default() });
+
+        assert!(h.highlight("let a = 1;", &ss).len() > 1);
+        assert!(!h.is_past_limits());
+        // the line crossing the limit is still highlighted
+        assert!(h.highlight("let b = \"a /* b\";", &ss).len() > 1);
+        assert!(h.is_past_limits());
+        let plain = h.highlight("let c = 3;", &ss);
+        assert_eq!(plain, vec![(Highlighter::new(theme).get_default(), "let c = 3;")]);
+    }
+
+    #[test]
+    fn skips_long_lines() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+        h.set_limits(HighlightLimits { max_line_length: Some(12), ..Default::default() });
+
+        let comment = h.highlight("/* comment", &ss);
+        let long = h.highlight("let a = \"a long line\";", &ss);
+        assert_eq!(long, vec![(comment.last().unwrap().0, "let a = \"a long line\";")]);
+        assert!(h.highlight("let b = 1;", &ss).len() == 1);
+        assert!(!h.is_past_limits());
+
+        // the lines after a long first line still get the scope of the syntax
+        let mut h = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+        h.set_limits(HighlightLimits { max_line_length: Some(12), ..Default::default() });
+        h.highlight("let a = \"a long line\";", &ss);
+        assert_eq!(h.highlight_state.path.as_slice(), &[Scope::new("source.rust").unwrap()]);
+        let mut unlimited = HighlightLines::new(syntax, &ts.themes["base16-ocean.dark"]);
+        unlimited.highlight("", &ss);
+        assert_eq!(h.highlight("let b = 1;", &ss), unlimited.highlight("let b = 1;", &ss));
+    }
+
+    #[test]
+    fn can_highlight_file() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        HighlightFile::new("testdata/highlight_test.erb",
+                           &ss,
+                           &ts.themes["base16-ocean.dark"])
+            .unwrap();
+    }
+
+    #[test]
+    fn refuses_to_highlight_binary_file() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let dir = TestDir::new("refuses_to_highlight_binary_file");
+        let path = dir.path().join("binary.png");
+        std::fs::write(&path, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").unwrap();
+        let err = HighlightFile::new(&path, &ss, &ts.themes["base16-ocean.dark"])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().is::<BinaryFileError>());
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn can_highlight_mmap_file() {
+        use crate::util::LinesWithEndings;
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let path = "testdata/highlight_test.erb";
+
+        let mut mapped = Vec::new();
+        let mut highlighter = HighlightMmapFile::new(path, &ss, theme).unwrap();
+        highlighter.for_each_line(&ss, true, |regions| {
+            mapped.push(regions.iter().map(|&(style, s)| (style, s.to_owned())).collect::<Vec<_>>());
+        });
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let syntax = ss.find_syntax_by_extension("erb").unwrap();
+        let mut h = HighlightLines::new(syntax, theme);
+        let expected: Vec<_> = LinesWithEndings::from(&content)
+            .map(|line| h.highlight(line, &ss).into_iter().map(|(style, s)| (style, s.to_owned())).collect::<Vec<_>>())
+            .collect();
+        assert_eq!(mapped, expected);
+
+        let dir = TestDir::new("can_highlight_mmap_file");
+        let empty = dir.path().join("empty.txt");
+        std::fs::write(&empty, b"").unwrap();
+        let mut highlighter = HighlightMmapFile::new(&empty, &ss, theme).unwrap();
+        let mut lines = 0;
+        highlighter.for_each_line(&ss, true, |_| lines += 1);
+        assert_eq!(lines, 0);
+    }
+
+    #[test]
+    fn can_cancel_highlighting_file() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let path = "testdata/highlight_test.erb";
+        let total = std::fs::metadata(path).unwrap().len();
+
+        let mut highlighter = HighlightFile::new(path, &ss, &ts.themes["base16-ocean.dark"]).unwrap();
+        let mut last = None;
+        let finished = highlighter.highlight_with_progress(&ss, |_, progress| {
+            last = Some(progress);
+            progress.lines < 3
+        }).unwrap();
+        assert!(!finished);
+        let last = last.unwrap();
+        assert_eq!(last.lines, 3);
+        assert_eq!(last.total_bytes, Some(total));
+
+        // the rest can still be highlighted afterwards
+        let mut rest = None;
+        let finished = highlighter.highlight_with_progress(&ss, |_, progress| {
+            rest = Some(progress);
+            true
+        }).unwrap();
+        assert!(finished);
+        assert_eq!((last.bytes + rest.unwrap().bytes) as u64, total);
+    }
+
+    #[test]
+    fn can_find_regions() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let mut state = ParseState::new(ss.find_syntax_by_extension("rb").unwrap());
+        let line = "lol =5+2";
+        let ops = state.parse_line(line, &ss);
+
+        let mut stack = ScopeStack::new();
+        let mut token_count = 0;
+        for (s, op) in ScopeRegionIterator::new(&ops, line) {
+            stack.apply(op);
+            if s.is_empty() { // in this case we don't care about blank tokens
+                continue;
+            }
+            if token_count == 1 {
+                assert_eq!(stack, ScopeStack::from_str("source.ruby keyword.operator.assignment.ruby").unwrap());
+                assert_eq!(s, "=");
+            }
+            token_count += 1;
+            println!("{:?} {}", s, stack);
+        }
+        assert_eq!(token_count, 5);
+    }
+
+    #[test]
+    fn can_find_regions_with_trailing_newline() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let mut state = ParseState::new(ss.find_syntax_by_extension("rb").unwrap());
+        let lines = ["# hello world\n", "lol=5+2\n"];
+        let mut stack = ScopeStack::new();
+
+        for line in lines.iter() {
+            let ops = state.parse_line(&line, &ss);
+            println!("{:?}", ops);
+
+            let mut iterated_ops: Vec<&ScopeStackOp> = Vec::new();
+            for (_, op) in ScopeRegionIterator::new(&ops, &line) {
+                stack.apply(op);
+                iterated_ops.push(&op);
+                println!("{:?}", op);
+            }
+
+            let all_ops: Vec<&ScopeStackOp> = ops.iter().map(|t|&t.1).collect();
+            assert_eq!(all_ops.len(), iterated_ops.len() - 1); // -1 because we want to ignore the NOOP
+        }
+    }
+
+    #[test]
+    fn incremental_highlighter_stops_when_states_converge() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let mut h = IncrementalHighlighter::new(syntax, &ts.themes["base16-ocean.dark"]);
+
+        let mut lines = vec!["fn a() {}", "let x = 1;", "let y = 2;", "fn b() {}"];
+        assert_eq!(h.edit(&lines, 0..0, 4, &ss), 0..4);
+        assert_eq!(h.line_count(), 4);
+
+        // opening a block comment changes the rest of the document
+        lines.insert(1, "/*");
+        assert_eq!(h.edit(&lines, 1..1, 1, &ss), 1..5);
+        let comment = h.highlight_line(3, lines[3], &ss);
+        assert_eq!(comment.len(), 1);
+
+        // closing it again affects everything that was commented out
+        lines.insert(3, "*/");
+        assert_eq!(h.edit(&lines, 3..3, 1, &ss), 3..6);
+        assert_eq!(h.line_count(), 6);
+
+        // deleting lines
+        lines.drain(1..4);
+        assert_eq!(h.edit(&lines, 1..4, 0, &ss), 1..2);
+        assert_eq!(h.line_count(), 3);
+
+        let mut fresh = IncrementalHighlighter::new(syntax, &ts.themes["base16-ocean.dark"]);
+        fresh.edit(&lines, 0..0, lines.len(), &ss);
+        for i in 0..lines.len() {
+            assert_eq!(h.highlight_line(i, lines[i], &ss), fresh.highlight_line(i, lines[i], &ss));
+        }
+    }
+
+    #[test]
+    fn highlight_cache_updates_edited_lines() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let mut cache = HighlightCache::new(syntax, theme);
+        let mut lines = vec!["fn a() {}", "let x = 1;", "let y = 2;", "fn b() {}"];
+        cache.replace_lines(0..0, &lines);
+
+        let check = |cache: &mut HighlightCache<'_>, lines: &[&str]| {
+            assert_eq!(cache.line_count(), lines.len());
+            let mut h = HighlightLines::new(syntax, theme);
+            for (i, line) in lines.iter().enumerate() {
+                assert_eq!(cache.get_line(i, &ss).unwrap(), h.highlight(line, &ss), "line {}", i);
+            }
+            assert!(cache.get_line(lines.len(), &ss).is_none());
+        };
+        check(&mut cache, &lines);
+
+        // a line that doesn't change the state after it only updates that line
+        lines[1] = "let x = 3;";
+        cache.set_line(1, lines[1]);
+        cache.get_line(1, &ss);
+        assert_eq!(cache.stale_from, None);
+        assert!(cache.lines[2].regions.is_some());
+
+        // opening a block comment changes the rest of the document, but only up to the line
+        // that is asked for is highlighted
+        lines.insert(1, "/*");
+        cache.replace_lines(1..1, &["/*"]);
+        assert_eq!(cache.get_line(2, &ss).unwrap().len(), 1);
+        assert_eq!(cache.stale_from, Some(3));
+        check(&mut cache, &lines);
+
+        lines.insert(3, "*/");
+        cache.replace_lines(3..3, &["*/"]);
+        check(&mut cache, &lines);
+
+        lines.drain(1..4);
+        cache.replace_lines(1..4, &[] as &[&str]);
+        check(&mut cache, &lines);
+
+        cache.invalidate_lines(0..10);
+        assert!(cache.lines.iter().all(|line| line.regions.is_none()));
+        check(&mut cache, &lines);
+        assert_eq!(cache.line_text(0), Some("fn a() {}"));
+
+        // replacing the line that opens a comment changes the lines after it back
+        let mut lines = vec!["let z = 0;", "/*", "let a = 1;", "*/"];
+        let mut cache = HighlightCache::new(syntax, theme);
+        cache.replace_lines(0..0, &lines);
+        check(&mut cache, &lines);
+        lines[1] = "let b = 2;";
+        cache.set_line(1, lines[1]);
+        assert_eq!(cache.get_line(2, &ss).unwrap().len(), 6);
+        assert_eq!(cache.get_line(3, &ss).unwrap().len(), 2);
+        check(&mut cache, &lines);
+
+        // and so does replacing it with several lines
+        lines.splice(1..2, vec!["/*", "*/", "/*"]);
+        cache.replace_lines(1..2, &["/*", "*/", "/*"]);
+        check(&mut cache, &lines);
+    }
+
+    #[test]
+    fn highlight_cache_moves_pending_invalidations() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let mut cache = HighlightCache::new(syntax, theme);
+        let mut lines = vec!["let x = 0;", "let x = 1;", "let x = 2;", "let x = 3;", "let x = 4;", "let x = 5;"];
+        cache.replace_lines(0..0, &lines);
+        for i in 0..lines.len() {
+            cache.get_line(i, &ss);
+        }
+
+        // inserting lines above an edit that wasn't highlighted yet
+        lines[3] = "/*";
+        cache.set_line(3, lines[3]);
+        lines.splice(0..0, vec!["let q = 0;", "let r = 0;"]);
+        cache.replace_lines(0..0, &["let q = 0;", "let r = 0;"]);
+        assert_eq!(cache.get_line(6, &ss).unwrap().len(), 1);
+
+        let mut h = HighlightLines::new(syntax, theme);
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(cache.get_line(i, &ss).unwrap(), h.highlight(line, &ss), "line {}", i);
+        }
+
+        // and removing lines above it
+        lines[6] = "*/";
+        cache.set_line(6, lines[6]);
+        lines.drain(0..2);
+        cache.replace_lines(0..2, &[] as &[&str]);
+        let mut h = HighlightLines::new(syntax, theme);
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(cache.get_line(i, &ss).unwrap(), h.highlight(line, &ss), "line {}", i);
+        }
+    }
+
+    #[test]
+    fn scope_events_are_nested() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let mut state = ParseState::new(ss.find_syntax_by_extension("rb").unwrap());
+        let mut events = ScopeEventGenerator::new();
+        let mut all = Vec::new();
+        for line in &["x = \"a", "b\" + 1", ""] {
+            let ops = state.parse_line(line, &ss);
+            all.extend(events.line_events(line, &ops));
+        }
+        all.extend(events.finish());
+
+        let mut open = Vec::new();
+        let mut text = String::new();
+        for event in &all {
+            match *event {
+                ScopeEvent::Open(scope) => open.push(scope),
+                ScopeEvent::Close(scope) => assert_eq!(open.pop(), Some(scope)),
+                ScopeEvent::Text(t) => {
+                    assert!(!t.is_empty());
+                    text.push_str(t);
+                }
+            }
+        }
+        assert!(open.is_empty());
+        assert_eq!(text, "x = \"ab\" + 1");
+
+        // the string stays open across the line break
+        let string = Scope::new("string.quoted.double.ruby").unwrap();
+        let opened = all.iter().position(|e| *e == ScopeEvent::Open(string)).unwrap();
+        let closed = all.iter().position(|e| *e == ScopeEvent::Close(string)).unwrap();
+        assert!(all[opened..closed].contains(&ScopeEvent::Text("a")));
+        assert!(all[opened..closed].contains(&ScopeEvent::Text("b")));
+    }
+
+    #[test]
+    fn scoped_text_round_trips() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("rs").unwrap();
+        let text = "fn main() {\n    let s = \"a\nb\";\n}\n";
+        let scoped = ScopedText::parse(text, syntax, &ss, false);
+        assert_eq!(scoped.lines.len(), 4);
+
+        let mut dump = Vec::new();
+        scoped.write_to(&mut dump).unwrap();
+        let loaded = ScopedText::read_from(&dump[..]).unwrap();
+        assert_eq!(loaded, scoped);
+
+        for theme in ts.themes.values() {
+            let mut h = HighlightLines::new(syntax, theme);
+            let expected: Vec<_> = text.lines().map(|line| h.highlight(line, &ss)).collect();
+            assert_eq!(loaded.highlight(theme), expected);
+        }
+
+        dump.truncate(dump.len() - 3);
+        let err = ScopedText::read_from(&dump[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // counts in line headers that can't be right don't overflow or allocate
+        for header in &["18446744073709551615 0\n", "1000000000000 0\nab\n", "2 18446744073709551615\nab\n"] {
+            let dump = format!("{}\n{}", SCOPED_TEXT_HEADER, header);
+            let err = ScopedText::read_from(dump.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn can_diff_scoped_text() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let text = "let x = 1;\n// done\n";
+        let rust = ScopedText::parse(text, ss.find_syntax_by_extension("rs").unwrap(), &ss, false);
+        assert!(rust.diff(&rust).is_empty());
+
+        let plain = ScopedText::parse(text, ss.find_syntax_plain_text(), &ss, false);
+        let differences = plain.diff(&rust);
+        assert_eq!(differences[0].line, 0);
+        assert_eq!(differences[0].range.start, 0);
+        assert_eq!(differences[0].old.as_slice(), &[Scope::new("text.plain").unwrap()]);
+        assert_eq!(differences[0].new.as_slice()[0], Scope::new("source.rust").unwrap());
+        // the whole comment line has the same scopes in each parse, so it's one difference
+        let comment: Vec<_> = differences.iter().filter(|d| d.line == 1).collect();
+        assert_eq!(comment.len(), 1);
+        assert_eq!(comment[0].range, 0..7);
+    }
+
+    #[test]
+    fn can_redact_scoped_text() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let text = "x = 'a' + 'b'  # sum\n# note\n\ny = x\n";
+        let scoped = ScopedText::parse(text, syntax, &ss, false);
+
+        let nothing = "markup.heading".parse::<ScopeSelectors>().unwrap();
+        assert_eq!(scoped.redact(&nothing, &Redaction::Remove), text.trim_end());
+        let comments = "comment".parse::<ScopeSelectors>().unwrap();
+        assert_eq!(scoped.redact(&comments, &Redaction::Remove), "x = 'a' + 'b'\n\n\ny = x");
+        assert_eq!(scoped.redact(&comments, &Redaction::Replace("#")), "x = 'a' + 'b'  #\n#\n\ny = x");
+        let strings = "string".parse::<ScopeSelectors>().unwrap();
+        assert_eq!(scoped.redact(&strings, &Redaction::Replace("s")), "x = s + s  # sum\n# note\n\ny = x");
+        assert_eq!(scoped.redact(&strings, &Redaction::Mask('.')), "x = ... + ...  # sum\n# note\n\ny = x");
+    }
+
+    #[test]
+    fn can_find_scopes_at_positions() {
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let scoped = ScopedText::parse("x = 'a'\n\n", syntax, &ss, false);
+        let top = |position| scoped.scope_stack_at(position).map(|s| s.as_slice().last().unwrap().build_string());
+        assert_eq!(top((0, 0)).unwrap(), "meta.generic-name.python");
+        assert_eq!(top((0, 4)).unwrap(), "punctuation.definition.string.begin.python");
+        assert_eq!(top((0, 5)).unwrap(), "string.quoted.single.python");
+        assert_eq!(top((0, 7)), top((0, 6)));
+        assert_eq!(top((1, 0)).unwrap(), "source.python");
+        assert_eq!(top((0, 8)), None);
+        assert_eq!(top((2, 0)), None);
+    }
+
+    #[test]
+    fn can_iterate_scope_tokens() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+        let mut stack = ScopeStack::new();
+        let mut tokens = Vec::new();
+        for line in &["'a\\\n", "b' c\n"] {
+            let ops = state.parse_line(line, &ss);
+            for (range, scopes) in ScopeTokenIterator::new(&ops, line, &mut stack) {
+                tokens.push((&line[range], scopes.len(), scopes.as_slice().last().unwrap().build_string()));
+            }
+        }
+        let token = |text, len, scope: &str| (text, len, scope.to_string());
+        assert_eq!(tokens, vec![
+            token("'", 4, "punctuation.definition.string.begin.python"),
+            token("a", 3, "string.quoted.single.python"),
+            token("\\", 4, "punctuation.separator.continuation.line.python"),
+            token("\n", 3, "string.quoted.single.python"),
+            token("b", 3, "string.quoted.single.python"),
+            token("'", 4, "punctuation.definition.string.end.python"),
+            token(" ", 1, "source.python"),
+            token("c", 3, "meta.generic-name.python"),
+            token("\n", 1, "source.python"),
+        ]);
+    }
+
+    #[test]
+    fn can_build_token_lists() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let text = "# a\nb = 'c'\n";
+        let tokens = TokenList::parse(text, syntax, &ss);
+
+        let spans: Vec<(&str, String)> = tokens.spans.iter()
+            .map(|span| (&text[span.start..span.end], tokens.stack(span).as_slice().last().unwrap().build_string()))
+            .collect();
+        assert_eq!(spans[0], ("#", "punctuation.definition.comment.python".to_string()));
+        // the comment including its newline is one span
+        assert_eq!(spans[1], (" a\n", "comment.line.number-sign.python".to_string()));
+        assert_eq!(spans.last().unwrap(), &("\n", "source.python".to_string()));
+        assert_eq!(tokens.spans.last().unwrap().end, text.len());
+        assert!(tokens.spans.windows(2).all(|w| w[0].end == w[1].start && w[0].stack != w[1].stack));
+
+        assert_eq!(tokens.span_at(2), Some(&tokens.spans[1]));
+        assert_eq!(tokens.span_at(4).map(|span| span.start), Some(4));
+        assert_eq!(tokens.span_at(text.len()), None);
+
+        let json = serde_json::to_string(&tokens).unwrap();
+        assert_eq!(serde_json::from_str::<TokenList>(&json).unwrap(), tokens);
+    }
+
+    #[test]
+    fn can_build_scope_trees() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let text = "x = '''a\nb''' # c\n";
+        let tree = ScopeTree::parse(text, syntax, &ss);
+
+        fn describe(node: &ScopeNode, text: &str, out: &mut Vec<String>, depth: usize) {
+            out.push(format!("{}{} {:?}", "  ".repeat(depth), node.scope, &text[node.range.clone()]));
+            for child in &node.children {
+                describe(child, text, out, depth + 1);
+            }
+        }
+        let mut out = Vec::new();
+        for root in &tree.roots {
+            describe(root, text, &mut out, 0);
+        }
+        assert_eq!(out, vec![
+            "source.python \"x = '''a\\nb''' # c\\n\"",
+            "  meta.qualified-name.python \"x\"",
+            "    meta.generic-name.python \"x\"",
+            "  keyword.operator.assignment.python \"=\"",
+            "  meta.string.python \"'''a\\nb'''\"",
+            "    string.quoted.single.block.python \"'''a\\nb'''\"",
+            "      punctuation.definition.string.begin.python \"'''\"",
+            "      punctuation.definition.string.end.python \"'''\"",
+            "  comment.line.number-sign.python \"# c\\n\"",
+            "    punctuation.definition.comment.python \"#\"",
+        ]);
+
+        let strings = tree.find_all(Scope::new("string").unwrap());
+        assert_eq!(strings.len(), 1);
+        assert_eq!(tree.find_all(Scope::new("meta").unwrap()).len(), 3);
+        assert_eq!(strings[0].find_all(Scope::new("punctuation").unwrap()).len(), 2);
+    }
+
+    #[test]
+    fn can_match_brackets() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let syntax = ss.find_syntax_by_extension("js").unwrap();
+        let text = "f(a[0], \"(}\", {\n  // )\n  b: '[x]'\n})é\n";
+        let scoped = ScopedText::parse(text, syntax, &ss, true);
+
+        assert_eq!(scoped.matching_bracket((0, 1)), Some((3, 1)));
+        assert_eq!(scoped.matching_bracket((3, 1)), Some((0, 1)));
+        assert_eq!(scoped.matching_bracket((3, 2)), Some((0, 1)));
+        assert_eq!(scoped.matching_bracket((0, 3)), Some((0, 5)));
+        assert_eq!(scoped.matching_bracket((0, 14)), Some((3, 0)));
+        // brackets in strings only match brackets in the same string
+        assert_eq!(scoped.matching_bracket((0, 9)), None);
+        assert_eq!(scoped.matching_bracket((2, 6)), Some((2, 8)));
+        assert_eq!(scoped.matching_bracket((2, 9)), Some((2, 6)));
+        assert_eq!(scoped.matching_bracket((1, 5)), None);
+        assert_eq!(scoped.matching_bracket((0, 0)), None);
+        assert_eq!(scoped.matching_bracket((3, 3)), None);
+        assert_eq!(scoped.matching_bracket((9, 0)), None);
+    }
+
+    #[test]
+    fn can_parse_from_reader() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let read_lines = |input: &[u8], max| {
+            let mut reader = ParseReader::new(io::BufReader::with_capacity(3, input), syntax);
+            reader.set_max_line_length(max);
+            let mut lines = Vec::new();
+            while let Some((line, ops)) = reader.read_line(&ss).unwrap() {
+                lines.push((line.to_owned(), ops));
+            }
+            lines
+        };
+
+        let lines = read_lines(b"\xEF\xBB\xBFa = 'x\r\r\n# \xFF\n\r\n", 100);
+        let text: Vec<&str> = lines.iter().map(|l| l.0.as_str()).collect();
+        assert_eq!(text, vec!["a = 'x\r\n", "# \u{FFFD}\n", "\n"]);
+        let mut state = ParseState::new(syntax);
+        for (line, ops) in &lines {
+            assert_eq!(*ops, state.parse_line(line, &ss));
+        }
+
+        let lines = read_lines("\u{feff}aé€\r\nbc\r\n".as_bytes(), 4);
+        let text: Vec<&str> = lines.iter().map(|l| l.0.as_str()).collect();
+        assert_eq!(text, vec!["aé", "€", "\n", "bc\n"]);
+        assert_eq!(read_lines(b"", 4), vec![]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Decoding text that isn't UTF-8 before parsing it.
+//!
+//! The parser only works on `&str`, but files in the wild are also encoded as Latin-1,
+//! Windows-1252, Shift-JIS, UTF-16 and so on. [`DecodedText`] detects the encoding of some
+//! bytes, decodes them, and remembers where each part of the text came from, so the operations of
+//! the parser can be mapped back to offsets in the original bytes, for example to highlight the
+//! file in place.
+//!
+//! Detection first looks for a byte order mark, then checks if the bytes are valid UTF-8, and
+//! otherwise guesses the most likely legacy encoding from the bytes with `chardetng`, like web
+//! browsers do.
+//!
+//! # Examples
+//!
+//! ```
+//! use syntect::encoding::DecodedText;
+//! use syntect::parsing::{ParseState, SyntaxSet};
+//! use syntect::util::LinesWithEndings;
+//!
+//! let ss = SyntaxSet::load_defaults_newlines();
+//! let syntax = ss.find_syntax_by_extension("py").unwrap();
+//! // "# café\nx = 'é'\n" in Windows-1252
+//! let bytes = b"# caf\xE9\nx = '\xE9'\n";
+//!
+//! let decoded = DecodedText::decode(bytes);
+//! assert_eq!(decoded.encoding.name(), "windows-1252");
+//! assert_eq!(decoded.text, "# café\nx = 'é'\n");
+//!
+//! let mut state = ParseState::new(syntax);
+//! let mut line_start = 0;
+//! for line in LinesWithEndings::from(&decoded.text) {
+//!     let ops = state.parse_line(line, &ss);
+//!     for (index, _op) in decoded.map_ops(line_start, &ops) {
+//!         assert!(index <= bytes.len());
+//!     }
+//!     line_start += line.len();
+//! }
+//! // `é` takes two bytes in the text but only one in the original
+//! assert_eq!(decoded.source_offset(decoded.text.len() - 1), bytes.len() - 1);
+//! ```
+//!
+//! [`DecodedText`]: struct.DecodedText.html
+
+use crate::parsing::ScopeStackOp;
+use chardetng::EncodingDetector;
+pub use encoding_rs::Encoding;
+
+/// Guesses the encoding of the bytes, which should be the whole file or at least a few
+/// kilobytes of it.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::encoding::detect_encoding;
+///
+/// assert_eq!(detect_encoding(b"plain").name(), "UTF-8");
+/// assert_eq!(detect_encoding(b"\xFF\xFEa\x00").name(), "UTF-16LE");
+/// ```
+pub fn detect_encoding(bytes: &[u8]) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+    if std::str::from_utf8(bytes).is_ok() {
+        return encoding_rs::UTF_8;
+    }
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+}
+
+/// Text decoded from bytes, with the mapping from offsets in the text to offsets in the bytes.
+#[derive(Debug, Clone)]
+pub struct DecodedText {
+    /// The decoded text, without a byte order mark
+    pub text: String,
+    /// The encoding the bytes were decoded with
+    pub encoding: &'static Encoding,
+    /// True if some bytes weren't valid in the encoding and were replaced with U+FFFD
+    pub had_errors: bool,
+    /// Pairs of a text offset and the source offset it came from. Offsets between two pairs map
+    /// one to one, which is the case for runs of ASCII in most encodings.
+    anchors: Vec<(usize, usize)>,
+    source_len: usize,
+}
+
+impl DecodedText {
+    /// Detects the encoding of the bytes with [`detect_encoding`] and decodes them.
+    ///
+    /// [`detect_encoding`]: fn.detect_encoding.html
+    pub fn decode(bytes: &[u8]) -> DecodedText {
+        DecodedText::decode_with(bytes, detect_encoding(bytes))
+    }
+
+    /// Decodes the bytes with the given encoding, removing its byte order mark if there is one.
+    /// Invalid bytes are replaced with U+FFFD.
+    pub fn decode_with(bytes: &[u8], encoding: &'static Encoding) -> DecodedText {
+        let bom_len = match Encoding::for_bom(bytes) {
+            Some((bom_encoding, len)) if bom_encoding == encoding => len,
+            _ => 0,
+        };
+        let mut decoder = encoding.new_decoder_without_bom_handling();
+        let mut decoded = DecodedText {
+            text: String::with_capacity(bytes.len()),
+            encoding,
+            had_errors: false,
+            anchors: vec![(0, bom_len)],
+            source_len: bytes.len(),
+        };
+
+        // bytes are fed one at a time, except for runs of ASCII, so it is known which bytes
+        // each character was decoded from
+        let mut i = bom_len;
+        let mut char_start = bom_len;
+        while i < bytes.len() {
+            let end = if encoding.is_ascii_compatible() && char_start == i {
+                i + Encoding::ascii_valid_up_to(&bytes[i..]).max(1)
+            } else {
+                i + 1
+            };
+            let text_start = decoded.text.len();
+            let last = end == bytes.len();
+            decoded.text.reserve(decoder.max_utf8_buffer_length(end - i).unwrap_or(end - i));
+            let (_, _, had_errors) = decoder.decode_to_string(&bytes[i..end], &mut decoded.text, last);
+            decoded.had_errors |= had_errors;
+            i = end;
+            if decoded.text.len() > text_start {
+                decoded.anchor(text_start, char_start);
+                decoded.anchor(decoded.text.len(), i);
+                char_start = i;
+            }
+        }
+        decoded
+    }
+
+    /// Returns the offset in the original bytes of the character that starts at `index` in the
+    /// text, or the length of the bytes for the end of the text.
+    pub fn source_offset(&self, index: usize) -> usize {
+        if index >= self.text.len() {
+            return self.source_len;
+        }
+        let anchor = match self.anchors.binary_search_by_key(&index, |&(text, _)| text) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        };
+        let (text_offset, source_offset) = self.anchors[anchor];
+        source_offset + (index - text_offset)
+    }
+
+    /// Maps the operations of the parser for a line starting at `line_start` in the text to
+    /// offsets in the original bytes.
+    pub fn map_ops(&self, line_start: usize, ops: &[(usize, ScopeStackOp)]) -> Vec<(usize, ScopeStackOp)> {
+        ops.iter().map(|(index, op)| (self.source_offset(line_start + index), op.clone())).collect()
+    }
+
+    /// Adds an anchor, unless the offsets already map to each other
+    fn anchor(&mut self, text_offset: usize, source_offset: usize) {
+        let &(last_text, last_source) = self.anchors.last().unwrap();
+        if last_source + (text_offset - last_text) != source_offset {
+            self.anchors.push((text_offset, source_offset));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_map_decoded_offsets() {
+        let bytes = b"\x82\xa0a\x82\xa2\n";
+        let decoded = DecodedText::decode_with(bytes, encoding_rs::SHIFT_JIS);
+        assert_eq!(decoded.text, "あaい\n");
+        assert!(!decoded.had_errors);
+        let offsets: Vec<usize> = decoded.text.char_indices().map(|(i, _)| decoded.source_offset(i)).collect();
+        assert_eq!(offsets, vec![0, 2, 3, 5]);
+        assert_eq!(decoded.source_offset(decoded.text.len()), bytes.len());
+
+        let decoded = DecodedText::decode_with(b"\xEF\xBB\xBFa\xFFb", encoding_rs::UTF_8);
+        assert_eq!(decoded.text, "a\u{FFFD}b");
+        assert!(decoded.had_errors);
+        let offsets: Vec<usize> = decoded.text.char_indices().map(|(i, _)| decoded.source_offset(i)).collect();
+        assert_eq!(offsets, vec![3, 4, 5]);
+
+        let decoded = DecodedText::decode(b"\xFE\xFF\x00a\x00\n");
+        assert_eq!(decoded.encoding, encoding_rs::UTF_16BE);
+        assert_eq!(decoded.text, "a\n");
+        assert_eq!(decoded.source_offset(1), 4);
+        let ops = decoded.map_ops(1, &[(0, ScopeStackOp::Noop), (1, ScopeStackOp::Noop)]);
+        assert_eq!(ops, vec![(4, ScopeStackOp::Noop), (6, ScopeStackOp::Noop)]);
+    }
+
+    #[test]
+    fn can_detect_legacy_encodings() {
+        let bytes = "日本語のテキストです。これはテストです。".chars()
+            .flat_map(|c| encoding_rs::SHIFT_JIS.encode(&c.to_string()).0.into_owned())
+            .collect::<Vec<u8>>();
+        assert_eq!(detect_encoding(&bytes), encoding_rs::SHIFT_JIS);
+        assert_eq!(detect_encoding("é".as_bytes()), encoding_rs::UTF_8);
+    }
+}
@@ -0,0 +1,255 @@
+use std::str::FromStr;
+
+use super::style::{Color, FontStyle, StyleModifier};
+use super::theme::{ParseThemeError, Theme, ThemeSettings};
+use super::theme_builder::ThemeBuilder;
+
+/// The colors of a base16 or base24 scheme, `base00` to `base0F` and for base24 also `base10` to
+/// `base17`.
+///
+/// [base16](https://github.com/chriskempson/base16) schemes are 16 colors with fixed roles, which
+/// many terminals, shells and editors are configured with. Converting the scheme of the user with
+/// [`Theme::from_base16`] lets a tool highlight code in the colors the user already picked,
+/// without shipping a `.tmTheme` file for each scheme.
+///
+/// Parse a scheme file with `from_str`, both the classic format with `scheme:` and `baseXX:` at
+/// the top level and the newer one with a `palette:` section are understood.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{Base16Palette, Color, Theme};
+/// use std::str::FromStr;
+///
+/// let palette = Base16Palette::from_str(r#"
+/// scheme: "Ocean"
+/// author: "Chris Kempson (http://chriskempson.com)"
+/// base00: "2b303b"
+/// base01: "343d46"
+/// base02: "4f5b66"
+/// base03: "65737e"
+/// base04: "a7adba"
+/// base05: "c0c5ce"
+/// base06: "dfe1e8"
+/// base07: "eff1f5"
+/// base08: "bf616a"
+/// base09: "d08770"
+/// base0A: "ebcb8b"
+/// base0B: "a3be8c"
+/// base0C: "96b5b4"
+/// base0D: "8fa1b3"
+/// base0E: "b48ead"
+/// base0F: "ab7967"
+/// "#).unwrap();
+///
+/// let theme = Theme::from_base16(&palette);
+/// assert_eq!(theme.name.as_ref().unwrap(), "Ocean");
+/// assert_eq!(theme.settings.background, Some(Color { r: 0x2b, g: 0x30, b: 0x3b, a: 0xff }));
+/// ```
+///
+/// [`Theme::from_base16`]: struct.Theme.html#method.from_base16
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Base16Palette {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    colors: Vec<Color>,
+}
+
+impl Base16Palette {
+    /// Creates a palette from 16 base16 or 24 base24 colors, in order of their names
+    pub fn new(colors: Vec<Color>) -> Result<Base16Palette, ParseThemeError> {
+        if colors.len() != 16 && colors.len() != 24 {
+            return Err(ParseThemeError::UndefinedSettings);
+        }
+        Ok(Base16Palette { name: None, author: None, colors })
+    }
+
+    /// The color `baseXX` for index `0xXX`, panics if the palette doesn't have it
+    pub fn base(&self, index: usize) -> Color {
+        self.colors[index]
+    }
+
+    /// All the colors, 16 or 24 of them
+    pub fn colors(&self) -> &[Color] {
+        &self.colors
+    }
+
+    pub fn is_base24(&self) -> bool {
+        self.colors.len() == 24
+    }
+}
+
+impl FromStr for Base16Palette {
+    type Err = ParseThemeError;
+
+    fn from_str(s: &str) -> Result<Base16Palette, Self::Err> {
+        let mut name = None;
+        let mut author = None;
+        let mut colors: [Option<Color>; 24] = [None; 24];
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = match line.find(':') {
+                Some(i) => (line[..i].trim(), scheme_value(&line[i + 1..])),
+                None => return Err(ParseThemeError::IncorrectSyntax),
+            };
+            match key {
+                "scheme" | "name" => name = Some(value.to_owned()),
+                "author" => author = Some(value.to_owned()),
+                _ if key.len() == 6 && key.starts_with("base") => {
+                    let index = usize::from_str_radix(&key[4..], 16).map_err(|_| ParseThemeError::IncorrectSyntax)?;
+                    let slot = colors.get_mut(index).ok_or(ParseThemeError::IncorrectSyntax)?;
+                    *slot = Some(hex_color(value)?);
+                }
+                // `palette:`, `system:`, `variant:` and so on
+                _ => {}
+            }
+        }
+
+        let count = if colors[16..].iter().any(Option::is_some) { 24 } else { 16 };
+        let colors = colors[..count].iter().cloned().collect::<Option<Vec<Color>>>()
+            .ok_or(ParseThemeError::UndefinedSettings)?;
+        Ok(Base16Palette { name, author, colors })
+    }
+}
+
+/// The value of a line of a scheme file without quotes or a trailing comment
+fn scheme_value(value: &str) -> &str {
+    let value = value.trim();
+    for &quote in &['"', '\''] {
+        if value.starts_with(quote) {
+            let rest = &value[1..];
+            return rest.find(quote).map_or(rest, |end| &rest[..end]);
+        }
+    }
+    value.find(" #").map_or(value, |end| value[..end].trim_end())
+}
+
+/// Parses a color written as `RRGGBB`, with or without a `#`
+fn hex_color(value: &str) -> Result<Color, ParseThemeError> {
+    let hex = value.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(ParseThemeError::IncorrectColor);
+    }
+    Color::from_str(&format!("#{}", hex))
+}
+
+impl Theme {
+    /// Creates a theme that uses the colors of a base16 scheme like the base16 TextMate template
+    /// does: `base08` for variables and tags, `base09` for constants,
+    /// `base0A` for types, `base0B` for strings, `base0C` for escapes and regexes, `base0D` for
+    /// functions, `base0E` for keywords and `base0F` for embedded code.
+    ///
+    /// For base24 schemes the darker backgrounds `base10` and `base11` are used for the gutter and
+    /// shadows, the bright colors are only meant for terminals.
+    pub fn from_base16(palette: &Base16Palette) -> Theme {
+        let base = |index| palette.base(index);
+        let fg = |index| StyleModifier::default().with_foreground(base(index));
+        let mut settings = ThemeSettings {
+            foreground: Some(base(0x05)),
+            background: Some(base(0x00)),
+            caret: Some(base(0x05)),
+            line_highlight: Some(base(0x01)),
+            selection: Some(base(0x02)),
+            gutter: Some(base(0x01)),
+            gutter_foreground: Some(base(0x03)),
+            guide: Some(base(0x02)),
+            active_guide: Some(base(0x04)),
+            stack_guide: Some(base(0x02)),
+            find_highlight: Some(base(0x0A)),
+            find_highlight_foreground: Some(base(0x00)),
+            ..ThemeSettings::default()
+        };
+        if palette.is_base24() {
+            settings.gutter = Some(base(0x10));
+            settings.shadow = Some(base(0x11));
+        }
+
+        let mut builder = ThemeBuilder::new().settings(settings);
+        if let Some(ref name) = palette.name {
+            builder = builder.name(name);
+        }
+        if let Some(ref author) = palette.author {
+            builder = builder.author(author);
+        }
+        let rules = [
+            ("comment, punctuation.definition.comment", fg(0x03)),
+            ("variable, keyword.operator, punctuation", fg(0x05)),
+            ("variable.other.member, variable.language, entity.name.tag, meta.tag, \
+              punctuation.definition.tag, markup.deleted, markup.list", fg(0x08)),
+            ("constant, support.constant, variable.parameter, keyword.other.unit, \
+              entity.other.attribute-name", fg(0x09)),
+            ("entity.name.class, entity.name.type, entity.name.struct, entity.name.enum, \
+              support.class, support.type, storage.type.class, entity.other.inherited-class, \
+              markup.changed", fg(0x0A)),
+            ("string, constant.other.symbol, markup.inserted, markup.raw", fg(0x0B)),
+            ("string.regexp, constant.character.escape, support.other.regex, markup.quote", fg(0x0C)),
+            ("entity.name.function, variable.function, support.function, entity.name.section, \
+              markup.heading", fg(0x0D).bold()),
+            ("keyword, storage, keyword.declaration", fg(0x0E)),
+            ("meta.embedded, punctuation.section.embedded, source.php.embedded, \
+              keyword.other.special-method, invalid.deprecated", fg(0x0F)),
+            ("markup.bold", StyleModifier { font_style: Some(FontStyle::BOLD), ..fg(0x0A) }),
+            ("markup.italic", StyleModifier { font_style: Some(FontStyle::ITALIC), ..fg(0x0E) }),
+            ("markup.underline.link, string.other.link", fg(0x09).underline()),
+            ("invalid, invalid.illegal", fg(0x07).with_background(base(0x08))),
+        ];
+        for &(selector, style) in &rules {
+            builder = builder.scope(selector, style);
+        }
+        builder.build().expect("the base16 rules are valid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlighting::Highlighter;
+    use crate::parsing::ScopeStack;
+
+    fn gray(level: u8) -> Color {
+        Color { r: level, g: level, b: level, a: 0xff }
+    }
+
+    #[test]
+    fn can_convert_base16_palettes() {
+        let palette = Base16Palette::new((0..16).map(|i| gray(i * 16)).collect()).unwrap();
+        assert!(!palette.is_base24());
+        let theme = Theme::from_base16(&palette);
+        assert_eq!(theme.name, None);
+        assert_eq!(theme.settings.foreground, Some(gray(0x50)));
+        assert_eq!(theme.settings.gutter, Some(gray(0x10)));
+
+        let highlighter = Highlighter::new(&theme);
+        let style = |s| highlighter.style_for_stack(ScopeStack::from_str(s).unwrap().as_slice());
+        assert_eq!(style("source.rust keyword.control").foreground, gray(0xE0));
+        assert_eq!(style("source.rust string.quoted.double").foreground, gray(0xB0));
+        assert_eq!(style("source.rust string.quoted constant.character.escape").foreground, gray(0xC0));
+        assert_eq!(style("source.rust entity.name.function").font_style, FontStyle::BOLD);
+        assert_eq!(style("source.rust invalid.illegal").background, gray(0x80));
+
+        assert!(Base16Palette::new(vec![gray(0); 8]).is_err());
+        let base24 = Base16Palette::new((0..24).map(|i| gray(i * 8)).collect()).unwrap();
+        assert_eq!(Theme::from_base16(&base24).settings.gutter, Some(gray(0x80)));
+    }
+
+    #[test]
+    fn can_parse_scheme_files() {
+        let mut scheme = String::from("system: \"base24\"\nname: 'Gray' # comment\nauthor: someone\npalette:\n");
+        for i in 0..24 {
+            scheme.push_str(&format!("  base{:02X}: \"#{:02x}{:02x}{:02x}\" # color {}\n", i, i, i, i, i));
+        }
+        let palette = Base16Palette::from_str(&scheme).unwrap();
+        assert_eq!(palette.name.as_ref().unwrap(), "Gray");
+        assert_eq!(palette.author.as_ref().unwrap(), "someone");
+        assert!(palette.is_base24());
+        assert_eq!(palette.base(0x17), gray(0x17));
+
+        let without_base0f = scheme.lines().filter(|l| !l.contains("base0F")).collect::<Vec<_>>().join("\n");
+        assert!(matches!(Base16Palette::from_str(&without_base0f), Err(ParseThemeError::UndefinedSettings)));
+        assert!(matches!(Base16Palette::from_str("base00: 12345"), Err(ParseThemeError::IncorrectColor)));
+        assert!(matches!(Base16Palette::from_str("base00 2b303b"), Err(ParseThemeError::IncorrectSyntax)));
+    }
+}
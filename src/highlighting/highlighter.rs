@@ -3,34 +3,86 @@
 // Code based on https://github.com/defuz/sublimate/blob/master/src/core/syntax/highlighter.rs
 // released under the MIT license by @defuz
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::iter::Iterator;
 use std::ops::Range;
+use std::sync::RwLock;
 
-use crate::parsing::{Scope, ScopeStack, BasicScopeStackOp, ScopeStackOp, MatchPower, ATOM_LEN_BITS};
+use crate::parsing::{Scope, ScopeStack, BasicScopeStackOp, CompactOps, ScopeStackOp, MatchPower, ATOM_LEN_BITS};
 use super::selector::ScopeSelector;
-use super::theme::{Theme, ThemeItem};
+use super::theme::{color_string, Theme, ThemeItem};
 use super::style::{Color, FontStyle, Style, StyleModifier};
 
 /// Basically a wrapper around a [`Theme`] preparing it to be used for highlighting.
 ///
-/// This is part of the API to preserve the possibility of caching matches of the
-/// selectors of the theme on various scope paths or setting up some kind of
-/// accelerator structure.
+/// It can also layer several themes, see [`with_themes`].
 ///
-/// So for now this does very little but eventually if you keep it around between
-/// highlighting runs it will preserve its cache.
+/// It caches the styles of the scope stacks it has seen, since the same stacks come up again and
+/// again in a file, so keep it around between highlighting runs with the same themes.
 ///
 /// [`Theme`]: struct.Theme.html
+/// [`with_themes`]: #method.with_themes
 #[derive(Debug)]
 pub struct Highlighter<'a> {
-    theme: &'a Theme,
+    themes: Vec<&'a Theme>,
     /// Cache of the selectors in the theme that are only one scope
     /// In most themes this is the majority, hence the usefullness
     single_selectors: Vec<(Scope, StyleModifier)>,
     multi_selectors: Vec<(ScopeSelector, StyleModifier)>,
+    /// The styles of the scope stacks seen so far, with the scores they're made of
+    style_cache: StyleCache,
     // TODO single_cache: HashMap<Scope, StyleModifier, BuildHasherDefault<FnvHasher>>,
 }
 
+/// How many styles of scope stacks a `Highlighter` caches at most
+const STYLE_CACHE_CAPACITY: usize = 16 * 1024;
+
+/// How many parts the style cache is split into, each with its own lock
+const STYLE_CACHE_SHARDS: usize = 16;
+
+/// The styles of scope stacks, split into shards by the hash of the stack so that threads sharing
+/// a `Highlighter` rarely wait for each other
+#[derive(Debug, Default)]
+struct StyleCache {
+    shards: [RwLock<StyleCacheShard>; STYLE_CACHE_SHARDS],
+}
+
+type StyleCacheShard = HashMap<Vec<Scope>, (ScoredStyle, Style)>;
+
+impl StyleCache {
+    fn shard(&self, path: &[Scope]) -> &RwLock<StyleCacheShard> {
+        let mut hasher = DefaultHasher::new();
+        path.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % STYLE_CACHE_SHARDS]
+    }
+
+    fn get(&self, path: &[Scope]) -> Option<(ScoredStyle, Style)> {
+        self.shard(path).read().ok()?.get(path).cloned()
+    }
+
+    fn insert(&self, path: &[Scope], entry: (ScoredStyle, Style)) {
+        if let Ok(mut shard) = self.shard(path).write() {
+            // the stacks of files are a small set, but some generated ones may have endless ones,
+            // so make room by dropping whichever stack comes first
+            if shard.len() >= STYLE_CACHE_CAPACITY / STYLE_CACHE_SHARDS {
+                let evicted = shard.keys().next().cloned();
+                if let Some(evicted) = evicted {
+                    shard.remove(&evicted);
+                }
+            }
+            shard.insert(path.to_vec(), entry);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+}
+
 /// Keeps a stack of scopes and styles as state between highlighting different lines.
 ///
 /// If you are highlighting an entire file you create one of these at the start and use it
@@ -72,10 +124,32 @@ pub struct HighlightState {
 pub struct RangedHighlightIterator<'a, 'b> {
     index: usize,
     pos: usize,
-    changes: &'a [(usize, ScopeStackOp)],
+    changes: Changes<'a>,
     text: &'b str,
     highlighter: &'a Highlighter<'a>,
     state: &'a mut HighlightState,
+    merge_styles: bool,
+    /// The token after a run of merged tokens, which has a different style
+    pending: Option<(Style, &'b str, Range<usize>)>,
+    /// The part of the line to yield tokens for
+    clip: Option<Range<usize>>,
+}
+
+/// The changes from the parser, in either of the forms it can return them
+#[derive(Debug, Clone, Copy)]
+enum Changes<'a> {
+    Ops(&'a [(usize, ScopeStackOp)]),
+    Compact(&'a CompactOps),
+}
+
+impl<'a> Changes<'a> {
+    /// Returns the position and operations of the change with the given index
+    fn get(self, index: usize) -> Option<(usize, &'a [ScopeStackOp])> {
+        match self {
+            Changes::Ops(ops) => ops.get(index).map(|(pos, op)| (*pos, std::slice::from_ref(op))),
+            Changes::Compact(compact) => compact.run(index),
+        }
+    }
 }
 
 /// Highlights a line of parsed code given a [`HighlightState`] and line of changes from the parser.
@@ -107,8 +181,8 @@ impl HighlightState {
         let mut single_caches = vec![ScoredStyle::from_style(styles[0])];
         for i in 0..initial_stack.len() {
             let prefix = initial_stack.bottom_n(i + 1);
-            let new_cache = highlighter.update_single_cache_for_push(&single_caches[i], prefix);
-            styles.push(highlighter.finalize_style_with_multis(&new_cache, prefix));
+            let (new_cache, style) = highlighter.push_style(&single_caches[i], prefix);
+            styles.push(style);
             single_caches.push(new_cache);
         }
 
@@ -118,6 +192,29 @@ impl HighlightState {
             path: initial_stack,
         }
     }
+
+    /// A hash of the state, for keys of caches and for quickly checking if highlighting reached a
+    /// state it was in before.
+    ///
+    /// Equal states have the same fingerprint, and different states almost certainly don't. Like
+    /// [`ParseState::fingerprint`], it is the same every time it is computed by the same program,
+    /// but it can change with the versions of syntect and Rust, so it shouldn't be persisted.
+    ///
+    /// [`ParseState::fingerprint`]: ../parsing/struct.ParseState.html#method.fingerprint
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Hash for HighlightState {
+    /// Hashes the scopes and their styles, the scores of the styles follow from those and are
+    /// left out
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path.hash(state);
+        self.styles.hash(state);
+    }
 }
 
 impl<'a, 'b> RangedHighlightIterator<'a, 'b> {
@@ -129,66 +226,149 @@ impl<'a, 'b> RangedHighlightIterator<'a, 'b> {
         RangedHighlightIterator {
             index: 0,
             pos: 0,
-            changes,
+            changes: Changes::Ops(changes),
             text,
             highlighter,
             state,
+            merge_styles: false,
+            pending: None,
+            clip: None,
         }
     }
-}
 
-impl<'a, 'b> Iterator for RangedHighlightIterator<'a, 'b> {
-    type Item = (Style, &'b str, Range<usize>);
+    /// Like [`new`], but with the changes for the line in the form of [`CompactOps`].
+    ///
+    /// [`new`]: #method.new
+    /// [`CompactOps`]: ../parsing/struct.CompactOps.html
+    pub fn from_compact(state: &'a mut HighlightState,
+                        changes: &'a CompactOps,
+                        text: &'b str,
+                        highlighter: &'a Highlighter<'_>)
+                        -> RangedHighlightIterator<'a, 'b> {
+        RangedHighlightIterator {
+            index: 0,
+            pos: 0,
+            changes: Changes::Compact(changes),
+            text,
+            highlighter,
+            state,
+            merge_styles: false,
+            pending: None,
+            clip: None,
+        }
+    }
 
-    /// Yields the next token of text and the associated `Style` to render that text with.
-    /// the concatenation of the strings in each token will make the original string.
-    fn next(&mut self) -> Option<(Style, &'b str, Range<usize>)> {
-        if self.pos == self.text.len() && self.index >= self.changes.len() {
+    /// Makes the iterator yield adjacent tokens with the same `Style` as one token.
+    ///
+    /// Neighbouring tokens often have the same style, e.g. the punctuation and identifiers that
+    /// a theme doesn't color differently, so merging them makes much fewer spans in HTML and
+    /// escape codes for terminals. The state after the line is the same either way.
+    pub fn merge_styles(mut self) -> RangedHighlightIterator<'a, 'b> {
+        self.merge_styles = true;
+        self
+    }
+
+    /// Makes the iterator only yield the tokens of a byte range of the line, cut to the range,
+    /// like for drawing only the visible columns of a very long line.
+    ///
+    /// The whole line is still gone through to update the state, so the iterator has to be run
+    /// to the end before the next line. The range has to start and end on character boundaries.
+    pub fn in_range(mut self, range: Range<usize>) -> RangedHighlightIterator<'a, 'b> {
+        self.clip = Some(range);
+        self
+    }
+
+    /// Yields the next token, with the runs of tokens with the same style merged if
+    /// `merge_styles` is set
+    fn next_region(&mut self) -> Option<(Style, &'b str, Range<usize>)> {
+        let (style, text, mut range) = match self.pending.take() {
+            Some(token) => token,
+            None => self.next_token(false).map(|(style, _, text, range)| (style, text, range))?,
+        };
+        if !self.merge_styles {
+            return Some((style, text, range));
+        }
+        while let Some((next_style, _, next_text, next_range)) = self.next_token(false) {
+            if next_style != style {
+                self.pending = Some((next_style, next_text, next_range));
+                break;
+            }
+            range.end = next_range.end;
+        }
+        Some((style, &self.text[range.clone()], range))
+    }
+}
+
+impl<'a, 'b> RangedHighlightIterator<'a, 'b> {
+    /// Returns the next token, with the scope stack it has if `with_stack` is true
+    fn next_token(&mut self, with_stack: bool) -> Option<(Style, Option<ScopeStack>, &'b str, Range<usize>)> {
+        let change = self.changes.get(self.index);
+        if self.pos == self.text.len() && change.is_none() {
             return None;
         }
-        let (end, command) = if self.index < self.changes.len() {
-            self.changes[self.index].clone()
-        } else {
-            (self.text.len(), ScopeStackOp::Noop)
-        };
+        let (end, commands) = change.unwrap_or((self.text.len(), &[]));
         // println!("{} - {:?}   {}:{}", self.index, self.pos, self.state.path.len(), self.state.styles.len());
         let style = *self.state.styles.last().unwrap_or(&Style::default());
         let text = &self.text[self.pos..end];
         let range = Range { start: self.pos, end: end };
+        let stack = if with_stack && !text.is_empty() { Some(self.state.path.clone()) } else { None };
         {
             // closures mess with the borrow checker's ability to see different struct fields
             let m_path = &mut self.state.path;
             let m_styles = &mut self.state.styles;
             let m_caches = &mut self.state.single_caches;
             let highlighter = &self.highlighter;
-            m_path.apply_with_hook(&command, |op, cur_stack| {
-                // println!("{:?} - {:?}", op, cur_stack);
-                match op {
-                    BasicScopeStackOp::Push(_) => {
-                        // we can push multiple times so this might have changed
-                        let new_cache = {
-                            if let Some(prev_cache) = m_caches.last() {
-                                highlighter.update_single_cache_for_push(prev_cache, cur_stack)
-                            } else {
-                                highlighter.update_single_cache_for_push(&ScoredStyle::from_style(highlighter.get_default()), cur_stack)
-                            }
-                        };
-                        m_styles.push(highlighter.finalize_style_with_multis(&new_cache, cur_stack));
-                        m_caches.push(new_cache);
-                    }
-                    BasicScopeStackOp::Pop => {
-                        m_styles.pop();
-                        m_caches.pop();
+            for command in commands {
+                m_path.apply_with_hook(command, |op, cur_stack| {
+                    // println!("{:?} - {:?}", op, cur_stack);
+                    match op {
+                        BasicScopeStackOp::Push(_) => {
+                            // we can push multiple times so this might have changed
+                            let (new_cache, style) = {
+                                if let Some(prev_cache) = m_caches.last() {
+                                    highlighter.push_style(prev_cache, cur_stack)
+                                } else {
+                                    highlighter.push_style(&ScoredStyle::from_style(highlighter.get_default()), cur_stack)
+                                }
+                            };
+                            m_styles.push(style);
+                            m_caches.push(new_cache);
+                        }
+                        BasicScopeStackOp::Pop => {
+                            m_styles.pop();
+                            m_caches.pop();
+                        }
                     }
-                }
-            });
+                });
+            }
         }
         self.pos = end;
         self.index += 1;
         if text.is_empty() {
-            self.next()
+            self.next_token(with_stack)
         } else {
-            Some((style, text, range))
+            Some((style, stack, text, range))
+        }
+    }
+}
+
+impl<'a, 'b> Iterator for RangedHighlightIterator<'a, 'b> {
+    type Item = (Style, &'b str, Range<usize>);
+
+    /// Yields the next token of text and the associated `Style` to render that text with.
+    /// the concatenation of the strings in each token will make the original string.
+    fn next(&mut self) -> Option<(Style, &'b str, Range<usize>)> {
+        loop {
+            let (style, text, range) = self.next_region()?;
+            let clip = match self.clip {
+                Some(ref clip) => clip.clone(),
+                None => return Some((style, text, range)),
+            };
+            let start = range.start.max(clip.start);
+            let end = range.end.min(clip.end);
+            if start < end {
+                return Some((style, &self.text[start..end], start..end));
+            }
         }
     }
 }
@@ -199,16 +379,79 @@ impl<'a, 'b> HighlightIterator<'a, 'b> {
                highlighter: &'a Highlighter<'_>)
         -> HighlightIterator<'a, 'b> {
             HighlightIterator {
-                ranged_iterator: RangedHighlightIterator {
-                    index: 0,
-                    pos: 0,
-                    changes,
-                    text,
-                    highlighter,
-                    state
-                }
+                ranged_iterator: RangedHighlightIterator::new(state, changes, text, highlighter)
             }
     }
+
+    /// Like [`new`], but with the changes for the line in the form of [`CompactOps`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{HighlightIterator, HighlightState, Highlighter, ThemeSet};
+    /// use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+    /// let mut parse_state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+    ///
+    /// let line = "x = 'a'\n";
+    /// let ops = parse_state.parse_line_compact(line, &ss);
+    /// let tokens = HighlightIterator::from_compact(&mut highlight_state, &ops, line, &highlighter);
+    /// assert_eq!(tokens.map(|(_, text)| text).collect::<String>(), line);
+    /// ```
+    ///
+    /// [`new`]: #method.new
+    /// [`CompactOps`]: ../parsing/struct.CompactOps.html
+    pub fn from_compact(state: &'a mut HighlightState,
+                        changes: &'a CompactOps,
+                        text: &'b str,
+                        highlighter: &'a Highlighter<'_>)
+                        -> HighlightIterator<'a, 'b> {
+        HighlightIterator {
+            ranged_iterator: RangedHighlightIterator::from_compact(state, changes, text, highlighter)
+        }
+    }
+
+    /// Makes the iterator yield adjacent tokens with the same `Style` as one token, see
+    /// [`RangedHighlightIterator::merge_styles`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{HighlightIterator, HighlightState, Highlighter, ThemeSet};
+    /// use syntect::parsing::{ParseState, ScopeStack, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let ts = ThemeSet::load_defaults();
+    /// let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+    /// let mut parse_state = ParseState::new(ss.find_syntax_by_extension("md").unwrap());
+    ///
+    /// let line = "Some text, with punctuation.\n";
+    /// let ops = parse_state.parse_line(line, &ss);
+    /// let mut state = HighlightState::new(&highlighter, ScopeStack::new());
+    /// let merged: Vec<_> = HighlightIterator::new(&mut state, &ops, line, &highlighter).merge_styles().collect();
+    /// assert_eq!(merged.len(), 1);
+    /// ```
+    ///
+    /// [`RangedHighlightIterator::merge_styles`]: struct.RangedHighlightIterator.html#method.merge_styles
+    pub fn merge_styles(self) -> HighlightIterator<'a, 'b> {
+        HighlightIterator {
+            ranged_iterator: self.ranged_iterator.merge_styles()
+        }
+    }
+
+    /// Makes the iterator only yield the tokens of a byte range of the line, see
+    /// [`RangedHighlightIterator::in_range`].
+    ///
+    /// [`RangedHighlightIterator::in_range`]: struct.RangedHighlightIterator.html#method.in_range
+    pub fn in_range(self, range: Range<usize>) -> HighlightIterator<'a, 'b> {
+        HighlightIterator {
+            ranged_iterator: self.ranged_iterator.in_range(range)
+        }
+    }
 }
 
 impl<'a, 'b> Iterator for HighlightIterator<'a, 'b> {
@@ -221,6 +464,76 @@ impl<'a, 'b> Iterator for HighlightIterator<'a, 'b> {
     }
 }
 
+/// Highlights a line of parsed code like [`HighlightIterator`], but also yields the scope stack
+/// of each token.
+///
+/// This lets a tool render the tokens and classify them in the same pass, for example to make
+/// only the names of functions clickable. Cloning the stack for every token makes this a bit
+/// slower than [`HighlightIterator`].
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{HighlightState, Highlighter, ScopedHighlightIterator, ThemeSet};
+/// use syntect::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+/// let mut parse_state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+/// let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+///
+/// let line = "fn main() {}\n";
+/// let ops = parse_state.parse_line(line, &ss);
+/// let function_name = Scope::new("entity.name.function").unwrap();
+/// let names: Vec<&str> = ScopedHighlightIterator::new(&mut highlight_state, &ops, line, &highlighter)
+///     .filter(|(_, stack, _)| stack.as_slice().iter().any(|s| function_name.is_prefix_of(*s)))
+///     .map(|(_, _, text)| text)
+///     .collect();
+/// assert_eq!(names, vec!["main"]);
+/// ```
+///
+/// [`HighlightIterator`]: struct.HighlightIterator.html
+#[derive(Debug)]
+pub struct ScopedHighlightIterator<'a, 'b> {
+    ranged_iterator: RangedHighlightIterator<'a, 'b>
+}
+
+impl<'a, 'b> ScopedHighlightIterator<'a, 'b> {
+    pub fn new(state: &'a mut HighlightState,
+               changes: &'a [(usize, ScopeStackOp)],
+               text: &'b str,
+               highlighter: &'a Highlighter<'_>)
+               -> ScopedHighlightIterator<'a, 'b> {
+        ScopedHighlightIterator {
+            ranged_iterator: RangedHighlightIterator::new(state, changes, text, highlighter)
+        }
+    }
+
+    /// Like [`new`], but with the changes for the line in the form of [`CompactOps`].
+    ///
+    /// [`new`]: #method.new
+    /// [`CompactOps`]: ../parsing/struct.CompactOps.html
+    pub fn from_compact(state: &'a mut HighlightState,
+                        changes: &'a CompactOps,
+                        text: &'b str,
+                        highlighter: &'a Highlighter<'_>)
+                        -> ScopedHighlightIterator<'a, 'b> {
+        ScopedHighlightIterator {
+            ranged_iterator: RangedHighlightIterator::from_compact(state, changes, text, highlighter)
+        }
+    }
+}
+
+impl<'a, 'b> Iterator for ScopedHighlightIterator<'a, 'b> {
+    type Item = (Style, ScopeStack, &'b str);
+
+    /// Yields the next token of text with its `Style` and the scope stack it has.
+    fn next(&mut self) -> Option<(Style, ScopeStack, &'b str)> {
+        self.ranged_iterator.next_token(true).map(|(style, stack, text, _)| (style, stack.unwrap(), text))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ScoredStyle {
     pub foreground: (MatchPower, Color),
@@ -264,9 +577,44 @@ impl ScoredStyle {
 
 impl<'a> Highlighter<'a> {
     pub fn new(theme: &'a Theme) -> Highlighter<'a> {
+        Highlighter::with_themes(&[theme])
+    }
+
+    /// Creates a highlighter for themes layered on top of each other, like a theme with the
+    /// overrides of a user on top of the theme they're based on.
+    ///
+    /// The rules of all themes apply, the more specific selector winning like within one theme.
+    /// When rules of different themes are equally specific, the rule of the later theme wins, and
+    /// the default foreground and background are the ones of the last theme that sets them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{Color, Highlighter, StyleModifier, ThemeBuilder, ThemeSet};
+    /// use syntect::parsing::ScopeStack;
+    /// use std::str::FromStr;
+    ///
+    /// let ts = ThemeSet::load_defaults();
+    /// let base = &ts.themes["base16-ocean.dark"];
+    /// let red = Color { r: 0xff, g: 0, b: 0, a: 0xff };
+    /// let overrides = ThemeBuilder::new()
+    ///     .scope("comment", StyleModifier::default().with_foreground(red))
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// let highlighter = Highlighter::with_themes(&[base, &overrides]);
+    /// let style = |stack| highlighter.style_for_stack(ScopeStack::from_str(stack).unwrap().as_slice());
+    /// assert_eq!(style("source.rust comment.line").foreground, red);
+    /// assert_eq!(style("source.rust string"), Highlighter::new(base).style_for_stack(
+    ///     ScopeStack::from_str("source.rust string").unwrap().as_slice()));
+    /// assert_eq!(highlighter.get_default(), Highlighter::new(base).get_default());
+    /// ```
+    pub fn with_themes(themes: &[&'a Theme]) -> Highlighter<'a> {
         let mut single_selectors = Vec::new();
         let mut multi_selectors = Vec::new();
-        for item in &theme.scopes {
+        // an earlier rule wins over a later one with the same score, so the rules of later themes
+        // go first
+        for item in themes.iter().rev().flat_map(|theme| &theme.scopes) {
             for sel in &item.scope.selectors {
                 if let Some(scope) = sel.extract_single_scope() {
                     single_selectors.push((scope, item.style));
@@ -279,22 +627,37 @@ impl<'a> Highlighter<'a> {
         single_selectors.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
 
         Highlighter {
-            theme,
+            themes: themes.to_vec(),
             single_selectors,
             multi_selectors,
+            style_cache: StyleCache::default(),
         }
     }
 
     /// The default style in the absence of any matched rules.
     /// Basically what plain text gets highlighted as.
     pub fn get_default(&self) -> Style {
+        let setting = |get: fn(&Theme) -> Option<Color>| self.themes.iter().rev().find_map(|theme| get(theme));
         Style {
-            foreground: self.theme.settings.foreground.unwrap_or(Color::BLACK),
-            background: self.theme.settings.background.unwrap_or(Color::WHITE),
+            foreground: setting(|theme| theme.settings.foreground).unwrap_or(Color::BLACK),
+            background: setting(|theme| theme.settings.background).unwrap_or(Color::WHITE),
             font_style: FontStyle::empty(),
         }
     }
 
+    /// The scores and the style for the stack `path` that the last scope was pushed onto, with
+    /// `cur` being the scores for the stack before. They only depend on the stack, so they're
+    /// cached.
+    fn push_style(&self, cur: &ScoredStyle, path: &[Scope]) -> (ScoredStyle, Style) {
+        if let Some(cached) = self.style_cache.get(path) {
+            return cached;
+        }
+        let new_cache = self.update_single_cache_for_push(cur, path);
+        let style = self.finalize_style_with_multis(&new_cache, path);
+        self.style_cache.insert(path, (new_cache.clone(), style));
+        (new_cache, style)
+    }
+
     fn update_single_cache_for_push(&self, cur: &ScoredStyle, path: &[Scope]) -> ScoredStyle {
         let mut new_style = cur.clone();
 
@@ -327,10 +690,13 @@ impl<'a> Highlighter<'a> {
     /// the caller should be caching results.
     pub fn style_for_stack(&self, stack: &[Scope]) -> Style {
         let mut single_cache = ScoredStyle::from_style(self.get_default());
+        let mut style = self.get_default();
         for i in 0..stack.len() {
-            single_cache = self.update_single_cache_for_push(&single_cache, &stack[0..i+1]);
+            let (new_cache, new_style) = self.push_style(&single_cache, &stack[0..i+1]);
+            single_cache = new_cache;
+            style = new_style;
         }
-        self.finalize_style_with_multis(&single_cache, stack)
+        style
     }
 
     /// Returns a [`StyleModifier`] which, if applied to the default style,
@@ -345,9 +711,9 @@ impl<'a> Highlighter<'a> {
     /// [`StyleModifier`]: struct.StyleModifier.html
     /// [`style_for_stack`]: #method.style_for_stack
     pub fn style_mod_for_stack(&self, path: &[Scope]) -> StyleModifier {
-        let mut matching_items : Vec<(MatchPower, &ThemeItem)> = self.theme
-            .scopes
+        let mut matching_items : Vec<(MatchPower, &ThemeItem)> = self.themes
             .iter()
+            .flat_map(|theme| &theme.scopes)
             .filter_map(|item| {
                 item.scope
                     .does_match(path)
@@ -368,6 +734,150 @@ impl<'a> Highlighter<'a> {
         }
         modifier
     }
+
+    /// Explains how the style for a stack comes about: which rules of the themes match it, with
+    /// which selector and score, and which of them decided each part of the final style.
+    ///
+    /// This is meant for debugging themes, like finding out why a token is green, and the
+    /// explanation can be printed as a report for that. It's much slower than
+    /// [`style_for_stack`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{Highlighter, ThemeSet};
+    /// use syntect::parsing::ScopeStack;
+    /// use std::str::FromStr;
+    ///
+    /// let ts = ThemeSet::load_defaults();
+    /// let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+    /// let stack = ScopeStack::from_str("source.rust string.quoted.double.rust").unwrap();
+    /// let explanation = highlighter.explain_style(stack.as_slice());
+    /// let rule = &explanation.matches[explanation.foreground_from.unwrap()];
+    /// println!("foreground from rule {} with selector {:?}", rule.index, rule.selector);
+    /// assert_eq!(explanation.style, highlighter.style_for_stack(stack.as_slice()));
+    /// println!("{}", explanation);
+    /// ```
+    ///
+    /// [`style_for_stack`]: #method.style_for_stack
+    pub fn explain_style(&self, stack: &[Scope]) -> StyleExplanation<'a> {
+        let mut matches: Vec<RuleMatch<'a>> = self.themes.iter().enumerate()
+            .flat_map(|(theme, &layer)| layer.scopes.iter().enumerate().map(move |(index, item)| (theme, index, item)))
+            .filter_map(|(theme, index, item)| {
+                // the selector with the best score decides, the first one if there's a tie
+                let mut best: Option<(&ScopeSelector, MatchPower)> = None;
+                for selector in &item.scope.selectors {
+                    if let Some(score) = selector.does_match(stack) {
+                        if best.map(|(_, best_score)| score > best_score).unwrap_or(true) {
+                            best = Some((selector, score));
+                        }
+                    }
+                }
+                best.map(|(selector, score)| RuleMatch { theme, index, item, selector, score })
+            })
+            .collect();
+        // in order of application: the highest score wins, and on ties the later theme and then
+        // the first rule of the theme
+        matches.sort_by(|a, b| {
+            a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.theme.cmp(&b.theme))
+                .then(b.index.cmp(&a.index))
+        });
+
+        let decided_by = |set: &dyn Fn(&StyleModifier) -> bool| matches.iter().rposition(|m| set(&m.item.style));
+        StyleExplanation {
+            foreground_from: decided_by(&|style| style.foreground.is_some()),
+            background_from: decided_by(&|style| style.background.is_some()),
+            font_style_from: decided_by(&|style| style.font_style.is_some()),
+            style: self.style_for_stack(stack),
+            matches,
+        }
+    }
+}
+
+/// How the style for a scope stack comes about, see [`Highlighter::explain_style`].
+///
+/// [`Highlighter::explain_style`]: struct.Highlighter.html#method.explain_style
+#[derive(Debug, Clone)]
+pub struct StyleExplanation<'a> {
+    /// The rules of the theme that match the stack, from the lowest to the highest priority
+    pub matches: Vec<RuleMatch<'a>>,
+    /// The index in `matches` of the rule that decided the foreground, or `None` if it's the
+    /// default of the theme
+    pub foreground_from: Option<usize>,
+    /// Like `foreground_from`, for the background
+    pub background_from: Option<usize>,
+    /// Like `foreground_from`, for the font style
+    pub font_style_from: Option<usize>,
+    /// The final style, the same as [`Highlighter::style_for_stack`] returns
+    ///
+    /// [`Highlighter::style_for_stack`]: struct.Highlighter.html#method.style_for_stack
+    pub style: Style,
+}
+
+/// A rule of a theme that matched a scope stack
+#[derive(Debug, Clone)]
+pub struct RuleMatch<'a> {
+    /// The index of the theme the rule is from, for a highlighter with several themes
+    pub theme: usize,
+    /// The index of the rule in [`Theme::scopes`]
+    ///
+    /// [`Theme::scopes`]: struct.Theme.html#structfield.scopes
+    pub index: usize,
+    pub item: &'a ThemeItem,
+    /// The selector of the rule that matched
+    pub selector: &'a ScopeSelector,
+    /// How specific the match is, a higher score takes priority
+    pub score: MatchPower,
+}
+
+impl<'a> fmt::Display for RuleMatch<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rule {}", self.index)?;
+        if self.theme > 0 {
+            write!(f, " of theme {}", self.theme)?;
+        }
+        write!(f, " `{}` (score {})", self.selector, self.score.0)
+    }
+}
+
+/// Writes a report for people debugging a theme: the parts of the style with the rules that
+/// decided them, and all the rules that match from the most to the least important one.
+///
+/// ```text
+/// foreground #A3BE8C from rule 12 `string` (score 8)
+/// background #2B303B from the default
+/// font style (empty) from the default
+/// matching rules:
+///   rule 12 `string` (score 8): foreground
+///   rule 0 `source` (score 1)
+/// ```
+impl<'a> fmt::Display for StyleExplanation<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let from = |index: Option<usize>| match index {
+            Some(index) => format!("from {}", self.matches[index]),
+            None => "from the default".to_owned(),
+        };
+        writeln!(f, "foreground {} {}", color_string(self.style.foreground), from(self.foreground_from))?;
+        writeln!(f, "background {} {}", color_string(self.style.background), from(self.background_from))?;
+        writeln!(f, "font style {:?} {}", self.style.font_style, from(self.font_style_from))?;
+        write!(f, "matching rules:")?;
+        for (index, rule) in self.matches.iter().enumerate().rev() {
+            write!(f, "\n  {}", rule)?;
+            let decided: Vec<&str> = [
+                (self.foreground_from, "foreground"),
+                (self.background_from, "background"),
+                (self.font_style_from, "font style"),
+            ].iter()
+                .filter(|&&(from, _)| from == Some(index))
+                .map(|&(_, part)| part)
+                .collect();
+            if !decided.is_empty() {
+                write!(f, ": {}", decided.join(", "))?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(all(feature = "assets", feature = "parsing", any(feature = "dump-load", feature = "dump-load-rs")))]
@@ -536,6 +1046,78 @@ mod tests {
         assert_eq!(full_mod, StyleModifier { foreground: Some(c1), background: None, font_style: Some(FontStyle::ITALIC) });
     }
 
+    #[test]
+    fn can_explain_style() {
+        use std::str::FromStr;
+        use crate::highlighting::{ScopeSelectors, ThemeItem};
+
+        let theme = Theme {
+            scopes: vec![
+                ThemeItem {
+                    scope: ScopeSelectors::from_str("string").unwrap(),
+                    style: StyleModifier { foreground: Some(Color::BLACK), background: None, font_style: None },
+                },
+                ThemeItem {
+                    scope: ScopeSelectors::from_str("comment, source string.quoted").unwrap(),
+                    style: StyleModifier { foreground: Some(Color::WHITE), background: None, font_style: Some(FontStyle::ITALIC) },
+                },
+                ThemeItem {
+                    scope: ScopeSelectors::from_str("string.quoted").unwrap(),
+                    style: StyleModifier { foreground: None, background: None, font_style: Some(FontStyle::BOLD) },
+                },
+                ThemeItem {
+                    scope: ScopeSelectors::from_str("keyword").unwrap(),
+                    style: StyleModifier { foreground: Some(Color::BLACK), background: None, font_style: None },
+                },
+            ],
+            ..Theme::default()
+        };
+        let highlighter = Highlighter::new(&theme);
+        let stack = ScopeStack::from_str("source.x string.quoted.x").unwrap();
+        let explanation = highlighter.explain_style(stack.as_slice());
+
+        let indices: Vec<usize> = explanation.matches.iter().map(|m| m.index).collect();
+        assert_eq!(indices, vec![0, 2, 1]);
+        assert_eq!(explanation.matches[2].selector, &ScopeSelector::from_str("source string.quoted").unwrap());
+        assert_eq!(explanation.foreground_from, Some(2));
+        assert_eq!(explanation.font_style_from, Some(2));
+        assert_eq!(explanation.background_from, None);
+        assert_eq!(explanation.style, highlighter.style_for_stack(stack.as_slice()));
+        assert_eq!(explanation.style.foreground, Color::WHITE);
+        assert_eq!(explanation.style.font_style, FontStyle::ITALIC);
+        assert_eq!(explanation.to_string(), "\
+            foreground #FFFFFF from rule 1 `source string.quoted` (score 17)\n\
+            background #FFFFFF from the default\n\
+            font style ITALIC from rule 1 `source string.quoted` (score 17)\n\
+            matching rules:\n  \
+            rule 1 `source string.quoted` (score 17): foreground, font style\n  \
+            rule 2 `string.quoted` (score 16)\n  \
+            rule 0 `string` (score 8)");
+    }
+
+    #[test]
+    fn can_highlight_compact_ops() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+        let syntax = ss.find_syntax_by_extension("html").unwrap();
+        let text = "<div class=\"a\">\n<script>var x = 1;</script>\n<!-- hi -->\n</div>\n";
+
+        let mut parse_state = ParseState::new(syntax);
+        let mut compact_parse_state = ParseState::new(syntax);
+        let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+        let mut compact_highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+        for line in crate::util::LinesWithEndings::from(text) {
+            let ops = parse_state.parse_line(line, &ss);
+            let compact = compact_parse_state.parse_line_compact(line, &ss);
+            assert!(compact.len() <= ops.len());
+            let expected: Vec<_> = RangedHighlightIterator::new(&mut highlight_state, &ops, line, &highlighter).collect();
+            let regions: Vec<_> = RangedHighlightIterator::from_compact(&mut compact_highlight_state, &compact, line, &highlighter).collect();
+            assert_eq!(regions, expected);
+            assert_eq!(compact_highlight_state.path, highlight_state.path);
+        }
+    }
+
     #[test]
     fn test_ranges() {
         let ps = SyntaxSet::load_from_folder("testdata/Packages").unwrap();
@@ -570,4 +1152,165 @@ mod tests {
                    },
                     "5", Range { start: 30, end: 31 }));
     }
+
+    #[test]
+    fn can_highlight_with_scopes() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+        let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+        let mut plain_state = HighlightState::new(&highlighter, ScopeStack::new());
+        let mut scoped_state = plain_state.clone();
+
+        for line in &["def f(x):\n", "    return 'a' # b\n"] {
+            let ops = state.parse_line(line, &ss);
+            let plain: Vec<(Style, &str)> = HighlightIterator::new(&mut plain_state, &ops, line, &highlighter).collect();
+            let scoped: Vec<(Style, ScopeStack, &str)> = ScopedHighlightIterator::new(&mut scoped_state, &ops, line, &highlighter).collect();
+            assert_eq!(plain, scoped.iter().map(|(style, _, text)| (*style, *text)).collect::<Vec<_>>());
+            for (style, stack, _) in &scoped {
+                assert_eq!(*style, highlighter.style_for_stack(stack.as_slice()));
+            }
+            assert_eq!(plain_state, scoped_state);
+        }
+        let comment = Scope::new("comment.line").unwrap();
+        assert!(ScopedHighlightIterator::new(&mut scoped_state, &state.parse_line("# c\n", &ss), "# c\n", &highlighter)
+            .all(|(_, stack, _)| stack.as_slice().iter().any(|s| comment.is_prefix_of(*s))));
+    }
+
+    #[test]
+    fn can_fingerprint_highlight_states() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let highlighter = Highlighter::new(&ts.themes["base16-ocean.dark"]);
+        let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+        let mut highlight_state = HighlightState::new(&highlighter, ScopeStack::new());
+        let start = highlight_state.fingerprint();
+
+        let line = "\"\"\"doc\n";
+        let ops = state.parse_line(line, &ss);
+        HighlightIterator::new(&mut highlight_state, &ops, line, &highlighter).for_each(drop);
+        assert_ne!(highlight_state.fingerprint(), start);
+        // a state recreated from the scopes is the same
+        let recreated = HighlightState::new(&highlighter, highlight_state.path.clone());
+        assert_eq!(recreated.fingerprint(), highlight_state.fingerprint());
+        assert_ne!(HighlightState::new(&Highlighter::new(&ts.themes["InspiredGitHub"]), recreated.path).fingerprint(),
+                   highlight_state.fingerprint());
+    }
+
+    #[test]
+    fn can_merge_tokens_with_the_same_style() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let highlighter = Highlighter::new(&ts.themes["InspiredGitHub"]);
+        let mut state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+        let mut plain_state = HighlightState::new(&highlighter, ScopeStack::new());
+        let mut merged_state = plain_state.clone();
+        let (mut plain_count, mut merged_count) = (0, 0);
+        for line in &["fn main() {\n", "    let (a, b) = (1, \"two\");\n", "    /* c\n", "    d */ }\n"] {
+            let ops = state.parse_line(line, &ss);
+            let plain: Vec<_> = RangedHighlightIterator::new(&mut plain_state, &ops, line, &highlighter).collect();
+            let merged: Vec<_> = RangedHighlightIterator::new(&mut merged_state, &ops, line, &highlighter)
+                .merge_styles()
+                .collect();
+            assert!(merged.len() <= plain.len());
+            assert!(merged.windows(2).all(|pair| pair[0].0 != pair[1].0 && pair[0].2.end == pair[1].2.start));
+            assert!(merged.iter().all(|(_, text, range)| *text == &line[range.clone()]));
+            assert_eq!(merged.iter().map(|(_, text, _)| *text).collect::<String>(), *line);
+            assert_eq!(plain_state, merged_state);
+            plain_count += plain.len();
+            merged_count += merged.len();
+            for (style, _, range) in plain {
+                assert!(merged.iter().any(|(s, _, r)| *s == style && r.start <= range.start && range.end <= r.end));
+            }
+        }
+        assert!(merged_count < plain_count);
+    }
+
+    #[test]
+    fn can_layer_themes() {
+        use crate::highlighting::ThemeBuilder;
+        use std::str::FromStr;
+        let color = |r, g, b| Color { r, g, b, a: 0xFF };
+        let (red, green, blue) = (color(0xFF, 0, 0), color(0, 0xFF, 0), color(0, 0, 0xFF));
+        let base = ThemeBuilder::new()
+            .foreground(Color::BLACK)
+            .background(Color::WHITE)
+            .scope("string", StyleModifier::default().with_foreground(red))
+            .scope("string.quoted", StyleModifier::default().with_foreground(green))
+            .scope("comment", StyleModifier::default().with_foreground(green).italic())
+            .build()
+            .unwrap();
+        let overrides = ThemeBuilder::new()
+            .background(blue)
+            .scope("string", StyleModifier::default().with_foreground(blue))
+            .scope("comment", StyleModifier::default().with_foreground(red))
+            .build()
+            .unwrap();
+        let highlighter = Highlighter::with_themes(&[&base, &overrides]);
+        let stack = |s| ScopeStack::from_str(s).unwrap();
+        let style = |s| highlighter.style_for_stack(stack(s).as_slice());
+
+        assert_eq!(highlighter.get_default(), Style { foreground: Color::BLACK, background: blue, font_style: FontStyle::empty() });
+        assert_eq!(style("source string.unquoted").foreground, blue);
+        // the more specific selector of the base theme still wins
+        assert_eq!(style("source string.quoted").foreground, green);
+        assert_eq!(style("source comment").foreground, red);
+        assert_eq!(style("source comment").font_style, FontStyle::ITALIC);
+        assert_eq!(highlighter.style_mod_for_stack(stack("source comment").as_slice()).foreground, Some(red));
+
+        let explanation = highlighter.explain_style(stack("source comment").as_slice());
+        let foreground_rule = &explanation.matches[explanation.foreground_from.unwrap()];
+        assert_eq!((foreground_rule.theme, foreground_rule.index), (1, 1));
+        let font_style_rule = &explanation.matches[explanation.font_style_from.unwrap()];
+        assert_eq!((font_style_rule.theme, font_style_rule.index), (0, 2));
+
+        let reversed = Highlighter::with_themes(&[&overrides, &base]);
+        assert_eq!(reversed.style_for_stack(stack("source comment").as_slice()).foreground, green);
+    }
+
+    #[test]
+    fn evicts_styles_when_the_cache_is_full() {
+        let cache = StyleCache::default();
+        let scopes: Vec<Scope> = ["a", "b", "c", "d"].iter().map(|s| Scope::new(s).unwrap()).collect();
+        let entry = (ScoredStyle::from_style(Style::default()), Style::default());
+        for i in 0..2 * STYLE_CACHE_CAPACITY {
+            let path: Vec<Scope> = (0..8).map(|digit| scopes[(i >> (2 * digit)) & 3]).collect();
+            cache.insert(&path, entry.clone());
+            assert!(cache.get(&path).is_some());
+        }
+        assert!(cache.len() <= STYLE_CACHE_CAPACITY);
+        assert!(cache.len() > STYLE_CACHE_CAPACITY / 2);
+    }
+
+    #[test]
+    fn caches_styles_of_stacks() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let theme = &ts.themes["base16-ocean.dark"];
+        let highlighter = Highlighter::new(theme);
+        let code = "fn main() {\n    let s = \"a\";\n    // b\n}\n";
+        let highlight = |highlighter: &Highlighter<'_>| {
+            let mut state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+            let mut highlight_state = HighlightState::new(highlighter, ScopeStack::new());
+            let mut regions = Vec::new();
+            let mut stacks = Vec::new();
+            for line in code.lines().map(|line| format!("{}\n", line)) {
+                let ops = state.parse_line(&line, &ss);
+                for (style, stack, text) in ScopedHighlightIterator::new(&mut highlight_state, &ops, &line, highlighter) {
+                    regions.push((style, text.to_owned()));
+                    stacks.push(stack);
+                }
+            }
+            (regions, stacks)
+        };
+
+        let (first, stacks) = highlight(&highlighter);
+        let cached = highlighter.style_cache.len();
+        assert!(cached > 0);
+        assert_eq!(highlight(&highlighter).0, first);
+        assert_eq!(highlighter.style_cache.len(), cached);
+        for stack in &stacks {
+            assert_eq!(highlighter.style_for_stack(stack.as_slice()), Highlighter::new(theme).style_for_stack(stack.as_slice()));
+        }
+    }
 }
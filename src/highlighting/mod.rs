@@ -6,16 +6,23 @@
 //!
 //! [`Theme`]: struct.Theme.html
 //! [`ThemeSet`]: struct.ThemeSet.html
+mod base16;
+mod css_color;
 mod highlighter;
 mod selector;
+mod semantic;
 pub(crate) mod settings;
 mod style;
 mod theme;
+mod theme_builder;
 mod theme_set;
 
+pub use self::base16::*;
 pub use self::selector::*;
+pub use self::semantic::*;
 pub use self::settings::SettingsError;
 pub use self::style::*;
 pub use self::theme::*;
+pub use self::theme_builder::*;
 pub use self::highlighter::*;
 pub use self::theme_set::*;
@@ -1,18 +1,30 @@
 /// Code based on <https://github.com/defuz/sublimate/blob/master/src/core/syntax/scope.rs>
 /// released under the MIT license by @defuz
 use crate::parsing::{Scope, ScopeStack, MatchPower, ParseScopeError};
+use std::fmt;
 use std::str::FromStr;
 
 /// A single selector consisting of a stack to match and a possible stack to
 /// exclude from being matched.
 ///
+/// Selectors with conjunctions (`&`) or parentheses, like `source & (string | comment)`, keep
+/// the additional parts in [`requires`] and [`excluded_groups`]. All parts have to match for the
+/// selector to match, and the score is the best score of its parts.
+///
+/// These parts are serialized too, so themes dumped before they were added can't be loaded
+/// anymore and have to be dumped again.
+///
 /// You probably want [`ScopeSelectors`] which is this but with union support.
 ///
 /// [`ScopeSelectors`]: struct.ScopeSelectors.html
+/// [`requires`]: #method.requires
+/// [`excluded_groups`]: #method.excluded_groups
 #[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub struct ScopeSelector {
     pub path: ScopeStack,
     pub excludes: Vec<ScopeStack>,
+    requires: Vec<ScopeSelectors>,
+    excluded_groups: Vec<ScopeSelectors>,
 }
 
 /// A selector set that matches anything matched by any of its component selectors.
@@ -35,22 +47,45 @@ impl ScopeSelector {
         if self.excludes.iter().any(|sel| sel.is_empty() || sel.does_match(stack).is_some()) {
             return None;
         }
-        if self.path.is_empty() {
+        if self.excluded_groups.iter().any(|sels| sels.does_match(stack).is_some()) {
+            return None;
+        }
+        let mut power = if self.path.is_empty() {
             // an empty scope selector always matches with a score of 1
-            Some(MatchPower(0o1u64 as f64))
+            MatchPower(0o1u64 as f64)
         } else {
-            self.path.does_match(stack)
+            self.path.does_match(stack)?
+        };
+        for sels in &self.requires {
+            power = power.max(sels.does_match(stack)?);
         }
+        Some(power)
+    }
+
+    /// Further selectors that have to match, from `&` and parentheses
+    pub fn requires(&self) -> &[ScopeSelectors] {
+        &self.requires
+    }
+
+    /// Selectors in parentheses that must not match, like `- (string | comment)`
+    pub fn excluded_groups(&self) -> &[ScopeSelectors] {
+        &self.excluded_groups
     }
 
     /// If this selector is really just a single scope, return it
     pub fn extract_single_scope(&self) -> Option<Scope> {
-        if self.path.len() > 1 || !self.excludes.is_empty() || self.path.is_empty() {
+        if self.path.len() > 1 || !self.excludes.is_empty() || self.path.is_empty() ||
+           !self.requires.is_empty() || !self.excluded_groups.is_empty() {
             return None;
         }
         Some(self.path.as_slice()[0])
     }
 
+    /// True if this selector only has a path, without any other parts
+    fn is_path(&self) -> bool {
+        !self.path.is_empty() && self.excludes.is_empty() && self.requires.is_empty() && self.excluded_groups.is_empty()
+    }
+
     /// Extract all selectors for generating CSS
     pub fn extract_scopes(&self) -> Vec<Scope> {
         self.path.scopes.clone()
@@ -60,24 +95,85 @@ impl ScopeSelector {
 impl FromStr for ScopeSelector {
     type Err = ParseScopeError;
 
-    /// Parses a scope stack followed optionally by (one or more) " -" and then a scope stack to exclude
+    /// Parses a scope stack followed optionally by (one or more) " -" and then a scope stack to
+    /// exclude, or " &" and a scope stack that also has to match. Instead of a scope stack, any
+    /// of these can be a group of selectors in parentheses.
     fn from_str(s: &str) -> Result<ScopeSelector, ParseScopeError> {
-        let mut excludes = Vec::new();
-        let mut path_str: &str = "";
-        for (i, selector) in s.split(" -").enumerate() {
-            if i == 0 {
-                path_str = selector;
+        let mut parser = SelectorParser { s, pos: 0 };
+        let selector = parser.parse_selector()?;
+        if parser.pos < s.len() {
+            // a `,`, `|` or `)` that doesn't belong to a group
+            return Err(ParseScopeError::UnbalancedParentheses);
+        }
+        Ok(selector)
+    }
+}
+
+impl fmt::Display for ScopeSelector {
+    /// Formats the selector in the syntax `from_str` parses, like `source & (string | comment) - string.regexp`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut separator = |f: &mut fmt::Formatter<'_>, operator: &str| {
+            let result = if first { Ok(()) } else { write!(f, " {} ", operator) };
+            first = false;
+            result
+        };
+        if !self.path.is_empty() {
+            separator(f, "&")?;
+            write_path(f, &self.path)?;
+        }
+        for sels in &self.requires {
+            separator(f, "&")?;
+            match sels.selectors[..] {
+                [ref sel] if sel.is_path() && !self.path.is_empty() => write!(f, "{}", sel)?,
+                _ => write!(f, "({})", sels)?,
+            }
+        }
+        for path in &self.excludes {
+            if first {
+                // an exclusion without a path is written as an exclusion of a group
+                write!(f, "-")?;
+                first = false;
             } else {
-                excludes.push(ScopeStack::from_str(selector)?);
+                write!(f, " -")?;
+            }
+            if !path.is_empty() {
+                write!(f, " ")?;
+                write_path(f, path)?;
             }
         }
-        Ok(ScopeSelector {
-            path: ScopeStack::from_str(path_str)?,
-            excludes,
-        })
+        for sels in &self.excluded_groups {
+            write!(f, "{}({})", if first { "- " } else { " - " }, sels)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ScopeSelectors {
+    /// Formats the selectors separated by commas, so they can be parsed back with `from_str`
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, sel) in self.selectors.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", sel)?;
+        }
+        Ok(())
     }
 }
 
+/// Writes the scopes of a stack separated by single spaces
+fn write_path(f: &mut fmt::Formatter<'_>, path: &ScopeStack) -> fmt::Result {
+    for (i, scope) in path.as_slice().iter().enumerate() {
+        if i > 0 {
+            write!(f, " ")?;
+        }
+        write!(f, "{}", scope)?;
+    }
+    Ok(())
+}
+
 impl ScopeSelectors {
     /// Checks if any of the given selectors match the given scope stack
     ///
@@ -93,6 +189,12 @@ impl ScopeSelectors {
     /// assert_eq!(ScopeSelectors::from_str("a.b, a e.f - c k, e.f - a.b").unwrap()
     ///     .does_match(ScopeStack::from_str("a.b c.d j e.f").unwrap().as_slice()),
     ///     Some(MatchPower(0o2001u64 as f64)));
+    ///
+    /// let selectors = ScopeSelectors::from_str("source & (string | comment) - string.regexp").unwrap();
+    /// let comment = ScopeStack::from_str("source.js comment.line").unwrap();
+    /// let regexp = ScopeStack::from_str("source.js string.regexp").unwrap();
+    /// assert_eq!(selectors.does_match(comment.as_slice()), Some(MatchPower(0o10u64 as f64)));
+    /// assert_eq!(selectors.does_match(regexp.as_slice()), None);
     /// ```
     pub fn does_match(&self, stack: &[Scope]) -> Option<MatchPower> {
         self.selectors.iter().filter_map(|sel| sel.does_match(stack)).max()
@@ -104,12 +206,213 @@ impl FromStr for ScopeSelectors {
 
     /// Parses a series of selectors separated by commas or pipes
     fn from_str(s: &str) -> Result<ScopeSelectors, ParseScopeError> {
-        let mut selectors = Vec::new();
-        for selector in s.split(&[',', '|'][..]) {
-            selectors.push(ScopeSelector::from_str(selector)?)
+        let mut parser = SelectorParser { s, pos: 0 };
+        let selectors = parser.parse_selectors()?;
+        if parser.pos < s.len() {
+            return Err(ParseScopeError::UnbalancedParentheses);
+        }
+        Ok(selectors)
+    }
+}
+
+/// A recursive descent parser for the scope selector grammar
+struct SelectorParser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> SelectorParser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.s[self.pos..].chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Parses selectors separated by `,` or `|`, up to the end or an unmatched `)`
+    fn parse_selectors(&mut self) -> Result<ScopeSelectors, ParseScopeError> {
+        let mut selectors = vec![self.parse_selector()?];
+        while let Some(',') | Some('|') = self.peek() {
+            self.pos += 1;
+            selectors.push(self.parse_selector()?);
         }
         Ok(ScopeSelectors { selectors })
     }
+
+    /// Parses operands joined by `&` or `-` into a single selector
+    fn parse_selector(&mut self) -> Result<ScopeSelector, ParseScopeError> {
+        let mut selector = ScopeSelector::default();
+        let mut exclude = false;
+        // whether the last `-` or `&` still needs an operand
+        let mut pending = false;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(',') | Some('|') | Some(')') => break,
+                Some('&') => {
+                    self.pos += 1;
+                    exclude = false;
+                    pending = true;
+                }
+                Some('-') => {
+                    self.pos += 1;
+                    exclude = true;
+                    pending = true;
+                }
+                Some('(') => {
+                    self.pos += 1;
+                    let group = self.parse_selectors()?;
+                    if self.peek() != Some(')') {
+                        return Err(ParseScopeError::UnbalancedParentheses);
+                    }
+                    self.pos += 1;
+                    if exclude {
+                        selector.excluded_groups.push(group);
+                    } else {
+                        selector.requires.push(group);
+                    }
+                    exclude = false;
+                    pending = false;
+                }
+                Some(_) => {
+                    let path = self.parse_path()?;
+                    if exclude {
+                        selector.excludes.push(path);
+                    } else if selector.path.is_empty() {
+                        selector.path = path;
+                    } else {
+                        selector.requires.push(ScopeSelectors {
+                            selectors: vec![ScopeSelector { path, ..ScopeSelector::default() }],
+                        });
+                    }
+                    exclude = false;
+                    pending = false;
+                }
+            }
+        }
+        if pending && exclude {
+            // a trailing `-` excludes everything, like an empty exclusion always did
+            selector.excludes.push(ScopeStack::new());
+        }
+        Ok(selector)
+    }
+
+    /// Parses whitespace separated scopes up to the next operator
+    fn parse_path(&mut self) -> Result<ScopeStack, ParseScopeError> {
+        let start = self.pos;
+        let mut end = self.pos;
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if !is_selector_operator(c) => {
+                    // a `-` inside a scope name isn't an operator
+                    let rest = &self.s[self.pos..];
+                    let len = rest.find(|c: char| c.is_whitespace() || (c != '-' && is_selector_operator(c)))
+                        .unwrap_or(rest.len());
+                    self.pos += len;
+                    end = self.pos;
+                }
+                _ => break,
+            }
+        }
+        ScopeStack::from_str(&self.s[start..end])
+    }
+}
+
+fn is_selector_operator(c: char) -> bool {
+    matches!(c, ',' | '|' | '&' | '-' | '(' | ')')
+}
+
+/// The comparison done by a [`ContextSelector`], like the `operator` of a Sublime Text
+/// keybinding context.
+///
+/// [`ContextSelector`]: struct.ContextSelector.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextOperator {
+    /// `==`, the selectors have to match
+    Equal,
+    /// `!=`, the selectors must not match
+    NotEqual,
+}
+
+/// A `selector` context check as used by Sublime Text keybindings and snippets, for example
+/// `selector == source.python - string, source.ruby`.
+///
+/// This allows editor integrations to decide if a context-sensitive keybinding or snippet
+/// applies at a position, using the scope stack syntect produced for that position.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::ScopeStack;
+/// use syntect::highlighting::ContextSelector;
+/// use std::str::FromStr;
+///
+/// let context = ContextSelector::from_str("selector == source.python - string").unwrap();
+/// let code = ScopeStack::from_str("source.python meta.function").unwrap();
+/// let string = ScopeStack::from_str("source.python string.quoted").unwrap();
+/// assert!(context.matches(code.as_slice()));
+/// assert!(!context.matches(string.as_slice()));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContextSelector {
+    pub operator: ContextOperator,
+    pub selectors: ScopeSelectors,
+}
+
+impl ContextSelector {
+    /// Checks if the context applies to a position with the given scope stack.
+    pub fn matches(&self, stack: &[Scope]) -> bool {
+        let matched = self.selectors.does_match(stack).is_some();
+        match self.operator {
+            ContextOperator::Equal => matched,
+            ContextOperator::NotEqual => !matched,
+        }
+    }
+
+    /// Checks the context against the scope stacks of several selections.
+    ///
+    /// Like the `match_all` key of a Sublime Text context, if `match_all` is true every
+    /// selection has to match, otherwise it is enough if one does.
+    pub fn matches_selections<'a, I>(&self, stacks: I, match_all: bool) -> bool
+        where I: IntoIterator<Item = &'a [Scope]>
+    {
+        let mut stacks = stacks.into_iter();
+        if match_all {
+            stacks.all(|stack| self.matches(stack))
+        } else {
+            stacks.any(|stack| self.matches(stack))
+        }
+    }
+}
+
+impl FromStr for ContextSelector {
+    type Err = ParseScopeError;
+
+    /// Parses an optional `selector` key and `==` or `!=` operator followed by a series of
+    /// selectors. Without an operator the selectors have to match.
+    fn from_str(s: &str) -> Result<ContextSelector, ParseScopeError> {
+        let mut rest = s.trim_start();
+        if let Some(after_key) = rest.strip_prefix("selector") {
+            let after_key = after_key.trim_start();
+            if after_key.starts_with("==") || after_key.starts_with("!=") {
+                rest = after_key;
+            }
+        }
+        let (operator, selectors) = if let Some(sels) = rest.strip_prefix("==") {
+            (ContextOperator::Equal, sels)
+        } else if let Some(sels) = rest.strip_prefix("!=") {
+            (ContextOperator::NotEqual, sels)
+        } else {
+            (ContextOperator::Equal, rest)
+        };
+        Ok(ContextSelector {
+            operator,
+            selectors: ScopeSelectors::from_str(selectors.trim())?,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -124,7 +427,7 @@ mod tests {
         assert_eq!(sels.selectors.len(), 2);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source.php>, <meta.preprocessor>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<string.quoted>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source.php>, <meta.preprocessor>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<string.quoted>] }], requires: [], excluded_groups: [] }");
 
         let sels = ScopeSelectors::from_str("source.php meta.preprocessor -string.quoted|\
                                              source string")
@@ -132,41 +435,58 @@ mod tests {
         assert_eq!(sels.selectors.len(), 2);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source.php>, <meta.preprocessor>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<string.quoted>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source.php>, <meta.preprocessor>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<string.quoted>] }], requires: [], excluded_groups: [] }");
 
         let sels = ScopeSelectors::from_str("text.xml meta.tag.preprocessor.xml punctuation.separator.key-value.xml")
             .unwrap();
         assert_eq!(sels.selectors.len(), 1);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [], requires: [], excluded_groups: [] }");
 
         let sels = ScopeSelectors::from_str("text.xml meta.tag.preprocessor.xml punctuation.separator.key-value.xml - text.html - string")
             .unwrap();
         assert_eq!(sels.selectors.len(), 1);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<text.html>] }, ScopeStack { clear_stack: [], scopes: [<string>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<text.html>] }, ScopeStack { clear_stack: [], scopes: [<string>] }], requires: [], excluded_groups: [] }");
 
         let sels = ScopeSelectors::from_str("text.xml meta.tag.preprocessor.xml punctuation.separator.key-value.xml - text.html - string, source - comment")
             .unwrap();
         assert_eq!(sels.selectors.len(), 2);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<text.html>] }, ScopeStack { clear_stack: [], scopes: [<string>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<text.xml>, <meta.tag.preprocessor.xml>, <punctuation.separator.key-value.xml>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<text.html>] }, ScopeStack { clear_stack: [], scopes: [<string>] }], requires: [], excluded_groups: [] }");
         let second_sel = &sels.selectors[1];
         assert_eq!(format!("{:?}", second_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<comment>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<source>] }, excludes: [ScopeStack { clear_stack: [], scopes: [<comment>] }], requires: [], excluded_groups: [] }");
 
         let sels = ScopeSelectors::from_str(" -a.b|j.g")
             .unwrap();
         assert_eq!(sels.selectors.len(), 2);
         let first_sel = &sels.selectors[0];
         assert_eq!(format!("{:?}", first_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [] }, excludes: [ScopeStack { clear_stack: [], scopes: [<a.b>] }] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [] }, excludes: [ScopeStack { clear_stack: [], scopes: [<a.b>] }], requires: [], excluded_groups: [] }");
         let second_sel = &sels.selectors[1];
         assert_eq!(format!("{:?}", second_sel),
-                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<j.g>] }, excludes: [] }");
+                   "ScopeSelector { path: ScopeStack { clear_stack: [], scopes: [<j.g>] }, excludes: [], requires: [], excluded_groups: [] }");
+    }
+    #[test]
+    fn can_format_selectors() {
+        use std::str::FromStr;
+        for s in &["source.php meta.preprocessor - string.quoted, source string",
+                   "source & (string | comment) - string.regexp",
+                   "a & b c - (d, e - f) - g",
+                   "(a, b)",
+                   "- a.b, j.g",
+                   "a -",
+                   "-"] {
+            let sels = ScopeSelectors::from_str(s).unwrap();
+            let formatted = sels.to_string();
+            assert_eq!(ScopeSelectors::from_str(&formatted).unwrap(), sels, "{}", formatted);
+        }
+        let sels = ScopeSelectors::from_str("source  &(string|comment)- string.regexp|a -b").unwrap();
+        assert_eq!(sels.to_string(), "source & (string, comment) - string.regexp, a - b");
     }
     #[test]
     fn matching_works() {
@@ -330,4 +650,67 @@ mod tests {
                        .does_match(ScopeStack::from_str("a.b c.d j e.f").unwrap().as_slice()),
                    Some(MatchPower(0o01u64 as f64)));
     }
+
+    #[test]
+    fn full_selector_grammar_works() {
+        use crate::parsing::{ScopeStack, MatchPower};
+        use std::str::FromStr;
+        let matches = |selector: &str, stack: &str| {
+            ScopeSelectors::from_str(selector)
+                .unwrap()
+                .does_match(ScopeStack::from_str(stack).unwrap().as_slice())
+        };
+
+        assert_eq!(matches("source & string", "source.js string.quoted"), Some(MatchPower(0o10u64 as f64)));
+        assert_eq!(matches("source & string", "source.js comment"), None);
+        assert_eq!(matches("source&string", "source.js string.quoted"), Some(MatchPower(0o10u64 as f64)));
+        assert_eq!(matches("(string | comment) - string.regexp", "source.js comment.line"), Some(MatchPower(0o10u64 as f64)));
+        assert_eq!(matches("(string | comment) - string.regexp", "source.js string.regexp"), None);
+        assert_eq!(matches("source - (string, comment)", "source.js string.quoted"), None);
+        assert_eq!(matches("source - (string, comment)", "source.js keyword"), Some(MatchPower(0o1u64 as f64)));
+        assert_eq!(matches("source -(comment - comment.block)", "source.js comment.line"), None);
+        assert_eq!(matches("source -(comment - comment.block)", "source.js comment.block"), Some(MatchPower(0o1u64 as f64)));
+        assert_eq!(matches("text, source.js & (meta string)", "source.js meta.block string"), Some(MatchPower(0o110u64 as f64)));
+        assert_eq!(matches("-comment & -string", "source.js keyword"), Some(MatchPower(0o1u64 as f64)));
+        assert_eq!(matches("-comment & -string", "source.js string"), None);
+        // dashes inside of scope names aren't exclusions
+        assert_eq!(matches("meta.tag-name", "text.html meta.tag-name"), Some(MatchPower(0o20u64 as f64)));
+        assert_eq!(matches("meta.tag -name", "text.html meta.tag"), Some(MatchPower(0o20u64 as f64)));
+        assert_eq!(matches("meta.tag -name", "text.html meta.tag name"), None);
+
+        let sel = ScopeSelector::from_str("a & b (c | d)").unwrap();
+        assert_eq!(sel.path, ScopeStack::from_str("a").unwrap());
+        assert_eq!(sel.requires.len(), 2);
+        assert_eq!(sel.extract_single_scope(), None);
+
+        assert!(ScopeSelectors::from_str("(a | b").is_err());
+        assert!(ScopeSelectors::from_str("a) | b").is_err());
+        assert!(ScopeSelector::from_str("a, b").is_err());
+    }
+
+    #[test]
+    fn context_selectors_work() {
+        use crate::parsing::ScopeStack;
+        use std::str::FromStr;
+        let comment = ScopeStack::from_str("source.rust comment.line").unwrap();
+        let string = ScopeStack::from_str("source.rust string.quoted").unwrap();
+        let html = ScopeStack::from_str("text.html.basic").unwrap();
+
+        let context = ContextSelector::from_str("source.rust - comment, text.html").unwrap();
+        assert_eq!(context.operator, ContextOperator::Equal);
+        assert!(!context.matches(comment.as_slice()));
+        assert!(context.matches(string.as_slice()));
+        assert!(context.matches(html.as_slice()));
+
+        let context = ContextSelector::from_str("selector != string").unwrap();
+        assert_eq!(context.operator, ContextOperator::NotEqual);
+        assert!(context.matches(comment.as_slice()));
+        assert!(!context.matches(string.as_slice()));
+
+        let context = ContextSelector::from_str("== source.rust").unwrap();
+        let stacks = [comment.as_slice(), string.as_slice(), html.as_slice()];
+        assert!(context.matches_selections(stacks.iter().cloned(), false));
+        assert!(!context.matches_selections(stacks.iter().cloned(), true));
+        assert!(context.matches_selections(stacks[..2].iter().cloned(), true));
+    }
 }
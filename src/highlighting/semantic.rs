@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use super::highlighter::Highlighter;
+use super::style::{Style, StyleModifier};
+use crate::parsing::Scope;
+
+/// A token from an external source like the semantic tokens of a language server.
+///
+/// The range is in bytes of the line it is applied to. Language servers send positions in UTF-16
+/// code units, so they have to be converted first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SemanticToken {
+    pub range: Range<usize>,
+    /// The type of the token, like `function` or `parameter`
+    pub token_type: String,
+    /// Modifiers of the type, like `readonly` or `deprecated`
+    pub modifiers: Vec<String>,
+}
+
+impl SemanticToken {
+    pub fn new(range: Range<usize>, token_type: &str) -> SemanticToken {
+        SemanticToken { range, token_type: token_type.to_owned(), modifiers: Vec::new() }
+    }
+}
+
+/// How the style of a semantic token is combined with the style from the scopes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SemanticPrecedence {
+    /// The semantic style replaces the style from the scopes, starting from the default style of
+    /// the theme
+    Semantic,
+    /// The colors and font style the theme has for the semantic token replace the ones from the
+    /// scopes, the others are kept
+    Merge,
+    /// The semantic style is only used for text that the scopes don't style, that is text with
+    /// the default style of the theme
+    Syntax,
+}
+
+/// Merges semantic tokens, like the ones language servers send, over the styles syntect
+/// highlighted a line with.
+///
+/// The tokens are styled by the theme: every token type is mapped to a scope, like the type
+/// `function` to `entity.name.function`, and the style the theme has for that scope, together
+/// with the scopes of the modifiers of the token, is the style of the token. The standard token
+/// types and the `deprecated` modifier of the Language Server Protocol are mapped by default.
+/// Tokens with types that aren't mapped, or that the theme doesn't style, don't change anything.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::easy::HighlightLines;
+/// use syntect::highlighting::{Highlighter, SemanticOverlay, SemanticToken, ThemeSet};
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let theme = &ts.themes["base16-ocean.dark"];
+/// let mut h = HighlightLines::new(ss.find_syntax_by_extension("rs").unwrap(), theme);
+///
+/// let line = "let total = sum(values);\n";
+/// let regions = h.highlight(line, &ss);
+/// // a language server knows that `sum` is a function and `values` a parameter
+/// let tokens = vec![SemanticToken::new(12..15, "function"), SemanticToken::new(16..22, "parameter")];
+///
+/// let highlighter = Highlighter::new(theme);
+/// let merged = SemanticOverlay::new(&highlighter).apply(&regions, &tokens);
+/// let function_style = merged.iter().find(|(_, text)| *text == "sum").unwrap().0;
+/// assert_eq!(function_style.foreground, highlighter.style_for_stack(&[
+///     syntect::parsing::Scope::new("entity.name.function").unwrap()
+/// ]).foreground);
+/// assert_eq!(merged.iter().map(|(_, text)| *text).collect::<String>(), line);
+/// ```
+#[derive(Debug)]
+pub struct SemanticOverlay<'a> {
+    highlighter: &'a Highlighter<'a>,
+    token_types: HashMap<String, Scope>,
+    modifiers: HashMap<String, Scope>,
+    precedence: SemanticPrecedence,
+}
+
+/// The scopes of the standard token types of the Language Server Protocol, like VS Code maps them
+const TOKEN_TYPE_SCOPES: &[(&str, &str)] = &[
+    ("namespace", "entity.name.namespace"),
+    ("type", "entity.name.type"),
+    ("class", "entity.name.class"),
+    ("enum", "entity.name.enum"),
+    ("interface", "entity.name.interface"),
+    ("struct", "entity.name.struct"),
+    ("typeParameter", "variable.parameter.type"),
+    ("parameter", "variable.parameter"),
+    ("variable", "variable.other.readwrite"),
+    ("property", "variable.other.property"),
+    ("enumMember", "variable.other.enummember"),
+    ("event", "variable.other.event"),
+    ("function", "entity.name.function"),
+    ("method", "entity.name.function.member"),
+    ("macro", "entity.name.function.preprocessor"),
+    ("keyword", "keyword.control"),
+    ("modifier", "storage.modifier"),
+    ("comment", "comment"),
+    ("string", "string"),
+    ("number", "constant.numeric"),
+    ("regexp", "string.regexp"),
+    ("operator", "keyword.operator"),
+    ("decorator", "entity.name.function.decorator"),
+];
+
+impl<'a> SemanticOverlay<'a> {
+    /// Creates an overlay with the standard mappings and [`SemanticPrecedence::Merge`]
+    ///
+    /// [`SemanticPrecedence::Merge`]: enum.SemanticPrecedence.html#variant.Merge
+    pub fn new(highlighter: &'a Highlighter<'a>) -> SemanticOverlay<'a> {
+        let scope = |name| Scope::new(name).unwrap();
+        SemanticOverlay {
+            highlighter,
+            token_types: TOKEN_TYPE_SCOPES.iter().map(|&(token_type, name)| (token_type.to_owned(), scope(name))).collect(),
+            modifiers: vec![("deprecated".to_owned(), scope("invalid.deprecated"))].into_iter().collect(),
+            precedence: SemanticPrecedence::Merge,
+        }
+    }
+
+    pub fn set_precedence(&mut self, precedence: SemanticPrecedence) {
+        self.precedence = precedence;
+    }
+
+    /// Styles tokens of a type like the scope, replacing the standard mapping if there is one
+    pub fn map_token_type(&mut self, token_type: &str, scope: Scope) {
+        self.token_types.insert(token_type.to_owned(), scope);
+    }
+
+    /// Adds the scope on top of the scope of the type for tokens with the modifier
+    pub fn map_modifier(&mut self, modifier: &str, scope: Scope) {
+        self.modifiers.insert(modifier.to_owned(), scope);
+    }
+
+    /// The style the theme has for the token, `None` if its type isn't mapped or the theme doesn't
+    /// style it.
+    pub fn style_for_token(&self, token: &SemanticToken) -> Option<StyleModifier> {
+        let mut stack = vec![*self.token_types.get(&token.token_type)?];
+        stack.extend(token.modifiers.iter().filter_map(|modifier| self.modifiers.get(modifier)));
+        let style = self.highlighter.style_mod_for_stack(&stack);
+        if style == StyleModifier::default() {
+            None
+        } else {
+            Some(style)
+        }
+    }
+
+    /// Merges the tokens over the regions of a line, as returned by `HighlightLines::highlight`.
+    ///
+    /// Regions are split where tokens start or end, and get the style of the last token in
+    /// `tokens` that covers them. The text of the regions is the same as before.
+    pub fn apply<'b>(&self, regions: &[(Style, &'b str)], tokens: &[SemanticToken]) -> Vec<(Style, &'b str)> {
+        let styled: Vec<(&Range<usize>, StyleModifier)> = tokens.iter()
+            .filter_map(|token| self.style_for_token(token).map(|style| (&token.range, style)))
+            .collect();
+        let default = self.highlighter.get_default();
+
+        let mut merged = Vec::with_capacity(regions.len());
+        let mut start = 0;
+        for &(style, text) in regions {
+            let end = start + text.len();
+            let mut cuts: Vec<usize> = styled.iter()
+                .flat_map(|(range, _)| vec![range.start, range.end])
+                .filter(|&cut| cut > start && cut < end && text.is_char_boundary(cut - start))
+                .collect();
+            cuts.push(end);
+            cuts.sort_unstable();
+            cuts.dedup();
+
+            // the pieces of the region with the same style are kept together
+            let mut piece: Option<(Style, usize)> = None;
+            let mut from = start;
+            for cut in cuts {
+                let token = styled.iter().rev().find(|(range, _)| range.start <= from && from < range.end);
+                let new_style = match (token, self.precedence) {
+                    (None, _) => style,
+                    (Some(&(_, modifier)), SemanticPrecedence::Semantic) => default.apply(modifier),
+                    (Some(&(_, modifier)), SemanticPrecedence::Merge) => style.apply(modifier),
+                    (Some(&(_, modifier)), SemanticPrecedence::Syntax) if style == default => style.apply(modifier),
+                    (Some(_), SemanticPrecedence::Syntax) => style,
+                };
+                match piece {
+                    Some((piece_style, _)) if piece_style == new_style => {}
+                    Some((piece_style, piece_start)) => {
+                        merged.push((piece_style, &text[piece_start - start..from - start]));
+                        piece = Some((new_style, from));
+                    }
+                    None => piece = Some((new_style, from)),
+                }
+                from = cut;
+            }
+            if let Some((piece_style, piece_start)) = piece {
+                merged.push((piece_style, &text[piece_start - start..]));
+            }
+            start = end;
+        }
+        merged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlighting::{Color, FontStyle, ThemeBuilder};
+
+    #[test]
+    fn can_merge_semantic_tokens() {
+        let color = |r, g, b| Color { r, g, b, a: 0xFF };
+        let (red, green, blue) = (color(0xFF, 0, 0), color(0, 0xFF, 0), color(0, 0, 0xFF));
+        let theme = ThemeBuilder::new()
+            .foreground(Color::BLACK)
+            .background(Color::WHITE)
+            .scope("entity.name.function", StyleModifier::default().with_foreground(red))
+            .scope("variable.parameter", StyleModifier::default().bold())
+            .scope("string", StyleModifier::default().with_foreground(blue))
+            .scope("constant.other.custom", StyleModifier::default().with_foreground(green))
+            .build()
+            .unwrap();
+        let highlighter = Highlighter::new(&theme);
+        let default = highlighter.get_default();
+        let string = Style { foreground: blue, ..default };
+        let regions = vec![(default, "f(x, "), (string, "\"ab\""), (default, ")")];
+
+        let mut overlay = SemanticOverlay::new(&highlighter);
+        let tokens = vec![
+            SemanticToken::new(0..1, "function"),
+            SemanticToken::new(2..3, "parameter"),
+            // unknown types and types the theme doesn't style are ignored
+            SemanticToken::new(3..4, "unknown"),
+            SemanticToken::new(4..5, "variable"),
+            SemanticToken::new(5..8, "parameter"),
+        ];
+        let bold = |style: Style| Style { font_style: FontStyle::BOLD, ..style };
+        assert_eq!(overlay.apply(&regions, &tokens), vec![
+            (Style { foreground: red, ..default }, "f"),
+            (default, "("),
+            (bold(default), "x"),
+            (default, ", "),
+            (bold(string), "\"ab"),
+            (string, "\""),
+            (default, ")"),
+        ]);
+
+        overlay.set_precedence(SemanticPrecedence::Syntax);
+        assert_eq!(&overlay.apply(&regions, &tokens)[3..], &[(default, ", "), (string, "\"ab\""), (default, ")")][..]);
+        overlay.set_precedence(SemanticPrecedence::Semantic);
+        assert_eq!(overlay.apply(&regions, &tokens)[4], (bold(default), "\"ab"));
+
+        overlay.map_token_type("custom", Scope::new("constant.other.custom").unwrap());
+        let mut token = SemanticToken::new(1..7, "custom");
+        token.modifiers.push("deprecated".to_owned());
+        let merged = overlay.apply(&regions, &[token]);
+        assert_eq!(merged.iter().map(|(style, text)| (style.foreground, *text)).collect::<Vec<_>>(), vec![
+            (Color::BLACK, "f"),
+            (green, "(x, "),
+            (green, "\"a"),
+            (blue, "b\""),
+            (Color::BLACK, ")"),
+        ]);
+    }
+}
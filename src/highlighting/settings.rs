@@ -1,7 +1,7 @@
 /// Code based on <https://github.com/defuz/sublimate/blob/master/src/core/settings.rs>
 /// released under the MIT license by @defuz
 use plist::Error as PlistError;
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
 
 pub use serde_json::Value as Settings;
 pub use serde_json::Value::Array as SettingsArray;
@@ -21,6 +21,8 @@ pub trait ParseSettings: Sized {
 pub enum SettingsError {
     /// Incorrect Plist syntax
     Plist(PlistError),
+    /// Incorrect JSON syntax in a `.sublime-settings` file
+    Json(serde_json::Error),
 }
 
 impl From<PlistError> for SettingsError {
@@ -29,7 +31,88 @@ impl From<PlistError> for SettingsError {
     }
 }
 
+impl From<serde_json::Error> for SettingsError {
+    fn from(error: serde_json::Error) -> SettingsError {
+        SettingsError::Json(error)
+    }
+}
+
 pub fn read_plist<R: Read + Seek>(reader: R) -> Result<Settings, SettingsError> {
     let settings = plist::from_reader(reader)?;
     Ok(settings)
 }
+
+/// Writes settings as an XML plist, the format of `.tmTheme` files
+pub fn write_plist<W: Write>(settings: &Settings, writer: W) -> Result<(), SettingsError> {
+    plist::to_writer_xml(writer, settings)?;
+    Ok(())
+}
+
+/// Parses the contents of a Sublime Text `.sublime-settings` file, which is JSON that may also
+/// contain comments and trailing commas
+pub fn parse_sublime_settings(s: &str) -> Result<Settings, SettingsError> {
+    let mut json = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while chars.peek().map(|&c| c != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut last = ' ';
+                for c in &mut chars {
+                    if last == '*' && c == '/' {
+                        break;
+                    }
+                    last = c;
+                }
+            }
+            ('}', _) | (']', _) => {
+                let end = json.trim_end().len();
+                if json[..end].ends_with(',') {
+                    json.remove(end - 1);
+                }
+                json.push(c);
+            }
+            _ => {
+                in_string = c == '"';
+                json.push(c);
+            }
+        }
+    }
+    Ok(serde_json::from_str(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_parse_sublime_settings() {
+        let settings = parse_sublime_settings(r#"
+        // Settings in here override those in "Default/Preferences.sublime-settings"
+        {
+            "extensions": [
+                "rsx", /* generated */
+                "a//b",
+            ],
+            "path": "C:\\dir\\",
+        }
+        "#).unwrap();
+        assert_eq!(settings, serde_json::json!({ "extensions": ["rsx", "a//b"], "path": "C:\\dir\\" }));
+        assert!(parse_sublime_settings("{ extensions: [] }").is_err());
+    }
+}
@@ -3,7 +3,7 @@
 use bitflags::bitflags;
 
 /// Foreground and background colors, with font style
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Style {
     /// Foreground color
     pub foreground: Color,
@@ -34,7 +34,7 @@ pub struct StyleModifier {
 /// conversion if you're outputting a different color space from the theme. This can be a problem
 /// because some Sublime themes use sRGB and some don't. This is specified in an attribute syntect
 /// doesn't parse yet.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Color {
     /// Red component
     pub r: u8,
@@ -76,6 +76,97 @@ impl Color {
         b: 0xFF,
         a: 0xFF,
     };
+
+    /// Composites this color over a background, giving the opaque color a renderer should use.
+    ///
+    /// The alpha of the background is ignored, it is treated as opaque.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::Color;
+    ///
+    /// let half_white = Color { r: 0xFF, g: 0xFF, b: 0xFF, a: 0x80 };
+    /// assert_eq!(half_white.blend_over(Color::BLACK), Color { r: 0x80, g: 0x80, b: 0x80, a: 0xFF });
+    /// ```
+    pub fn blend_over(self, background: Color) -> Color {
+        let alpha = u32::from(self.a);
+        let blend = |fg: u8, bg: u8| ((u32::from(fg) * alpha + u32::from(bg) * (255 - alpha) + 127) / 255) as u8;
+        Color {
+            r: blend(self.r, background.r),
+            g: blend(self.g, background.g),
+            b: blend(self.b, background.b),
+            a: 0xFF,
+        }
+    }
+
+    /// The relative luminance of the color as defined by WCAG, from 0 for black to 1 for white.
+    ///
+    /// The alpha is ignored, use [`blend_over`] first for translucent colors.
+    ///
+    /// [`blend_over`]: #method.blend_over
+    pub fn luminance(self) -> f64 {
+        let linear = |channel: u8| {
+            let c = f64::from(channel) / 255.0;
+            if c <= 0.03928 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        0.2126 * linear(self.r) + 0.7152 * linear(self.g) + 0.0722 * linear(self.b)
+    }
+
+    /// The contrast ratio between two colors as defined by WCAG, from 1 for the same colors to 21
+    /// for black and white. WCAG asks for at least 4.5 for normal text.
+    pub fn contrast_ratio(self, other: Color) -> f64 {
+        let (a, b) = (self.luminance(), other.luminance());
+        (a.max(b) + 0.05) / (a.min(b) + 0.05)
+    }
+
+    /// Returns the color with at least the given contrast ratio against the background.
+    ///
+    /// If the contrast is too low, the color is mixed with black or white, whichever contrasts
+    /// more with the background, just as much as needed, so it keeps its hue as far as possible.
+    /// If even black or white don't reach the ratio, that color is returned. The alpha is kept.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::Color;
+    ///
+    /// let gray = Color { r: 0x80, g: 0x80, b: 0x80, a: 0xFF };
+    /// let background = Color { r: 0x60, g: 0x60, b: 0x60, a: 0xFF };
+    /// let adjusted = gray.with_contrast(background, 4.5);
+    /// assert!(adjusted.contrast_ratio(background) >= 4.5);
+    /// assert!(adjusted.r > gray.r);
+    /// ```
+    pub fn with_contrast(self, background: Color, min_ratio: f64) -> Color {
+        if self.contrast_ratio(background) >= min_ratio {
+            return self;
+        }
+        let target = if Color::WHITE.contrast_ratio(background) > Color::BLACK.contrast_ratio(background) {
+            Color::WHITE
+        } else {
+            Color::BLACK
+        };
+        let mix = |amount: f64| {
+            let channel = |from: u8, to: u8| (f64::from(from) + (f64::from(to) - f64::from(from)) * amount).round() as u8;
+            Color {
+                r: channel(self.r, target.r),
+                g: channel(self.g, target.g),
+                b: channel(self.b, target.b),
+                a: self.a,
+            }
+        };
+        // the contrast grows with the amount of the target mixed in
+        let (mut low, mut high) = (0.0, 1.0);
+        for _ in 0..16 {
+            let middle = (low + high) / 2.0;
+            if mix(middle).contrast_ratio(background) >= min_ratio {
+                high = middle;
+            } else {
+                low = middle;
+            }
+        }
+        mix(high)
+    }
 }
 
 impl Style {
@@ -87,6 +178,32 @@ impl Style {
             font_style: modifier.font_style.unwrap_or(self.font_style),
         }
     }
+
+    /// Returns the style with the foreground adjusted to have at least the given contrast ratio
+    /// against the background of the style, see [`Color::with_contrast`].
+    ///
+    /// The colors should be opaque, use [`resolve_alpha`] first for the background the text will
+    /// be drawn on.
+    ///
+    /// [`Color::with_contrast`]: struct.Color.html#method.with_contrast
+    /// [`resolve_alpha`]: #method.resolve_alpha
+    pub fn with_contrast(self, min_ratio: f64) -> Style {
+        Style {
+            foreground: self.foreground.with_contrast(self.background, min_ratio),
+            ..self
+        }
+    }
+
+    /// Resolves the transparency of the colors for drawing on the given background: the
+    /// background of the style is composited over it, and the foreground over the result.
+    pub fn resolve_alpha(self, background: Color) -> Style {
+        let background = self.background.blend_over(background);
+        Style {
+            foreground: self.foreground.blend_over(background),
+            background,
+            font_style: self.font_style,
+        }
+    }
 }
 
 impl Default for Style {
@@ -110,4 +227,93 @@ impl StyleModifier {
             font_style: other.font_style.or(self.font_style),
         }
     }
+
+    /// Sets the foreground color, useful for building modifiers in code like
+    /// `StyleModifier::default().with_foreground(color).bold()`
+    pub fn with_foreground(mut self, color: Color) -> StyleModifier {
+        self.foreground = Some(color);
+        self
+    }
+
+    /// Sets the background color
+    pub fn with_background(mut self, color: Color) -> StyleModifier {
+        self.background = Some(color);
+        self
+    }
+
+    /// Adds bold to the font style
+    pub fn bold(self) -> StyleModifier {
+        self.with_font_style(FontStyle::BOLD)
+    }
+
+    /// Adds italic to the font style
+    pub fn italic(self) -> StyleModifier {
+        self.with_font_style(FontStyle::ITALIC)
+    }
+
+    /// Adds underline to the font style
+    pub fn underline(self) -> StyleModifier {
+        self.with_font_style(FontStyle::UNDERLINE)
+    }
+
+    fn with_font_style(mut self, style: FontStyle) -> StyleModifier {
+        self.font_style = Some(self.font_style.unwrap_or_else(FontStyle::empty) | style);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_blend_colors() {
+        let color = |r, g, b, a| Color { r, g, b, a };
+        let red = color(0xFF, 0, 0, 0xFF);
+        assert_eq!(red.blend_over(Color::WHITE), red);
+        assert_eq!(color(0xFF, 0, 0, 0).blend_over(color(1, 2, 3, 0)), color(1, 2, 3, 0xFF));
+        assert_eq!(color(0xFF, 0, 0x40, 0x40).blend_over(color(0, 0xFF, 0x40, 0xFF)), color(0x40, 0xBF, 0x40, 0xFF));
+
+        let style = Style {
+            foreground: color(0, 0, 0, 0x80),
+            background: color(0xFF, 0xFF, 0xFF, 0x80),
+            font_style: FontStyle::BOLD,
+        };
+        let resolved = style.resolve_alpha(Color::BLACK);
+        assert_eq!(resolved.background, color(0x80, 0x80, 0x80, 0xFF));
+        assert_eq!(resolved.foreground, color(0x40, 0x40, 0x40, 0xFF));
+        assert_eq!(resolved.font_style, FontStyle::BOLD);
+    }
+
+    #[test]
+    fn can_compute_luminance() {
+        let color = |r, g, b| Color { r, g, b, a: 0xFF };
+        assert_eq!(Color::BLACK.luminance(), 0.0);
+        assert!((Color::WHITE.luminance() - 1.0).abs() < 1e-9);
+        assert!((color(0xFF, 0, 0).luminance() - 0.2126).abs() < 1e-9);
+        assert!((color(0x80, 0x80, 0x80).luminance() - 0.2159).abs() < 1e-4);
+        assert!(color(0, 0xFF, 0).luminance() > color(0, 0, 0xFF).luminance());
+    }
+
+    #[test]
+    fn can_ensure_contrast() {
+        let color = |r, g, b| Color { r, g, b, a: 0xFF };
+        assert!((Color::BLACK.contrast_ratio(Color::WHITE) - 21.0).abs() < 1e-9);
+        assert_eq!(Color::WHITE.contrast_ratio(Color::WHITE), 1.0);
+
+        let yellow = color(0xFF, 0xEE, 0x80);
+        assert_eq!(yellow.with_contrast(Color::BLACK, 4.5), yellow);
+        let on_white = yellow.with_contrast(Color::WHITE, 4.5);
+        assert!(on_white.contrast_ratio(Color::WHITE) >= 4.5);
+        assert!(on_white.contrast_ratio(Color::WHITE) < 4.7);
+        assert!(on_white.r > on_white.g && on_white.g > on_white.b);
+
+        let mid_gray = color(0x77, 0x77, 0x77);
+        assert_eq!(mid_gray.with_contrast(mid_gray, 21.0), Color::BLACK);
+
+        let style = Style { foreground: color(0x30, 0x30, 0x40), background: color(0x20, 0x20, 0x20), font_style: FontStyle::BOLD };
+        let adjusted = style.with_contrast(7.0);
+        assert!(adjusted.foreground.contrast_ratio(adjusted.background) >= 7.0);
+        assert_eq!((adjusted.background, adjusted.font_style), (style.background, FontStyle::BOLD));
+    }
 }
@@ -3,7 +3,8 @@
 
 use std::str::FromStr;
 
-use super::settings::{ParseSettings, Settings};
+use super::css_color::parse_css_color;
+use super::settings::{write_plist, ParseSettings, Settings};
 use super::style::*;
 use super::selector::*;
 use crate::parsing::ParseScopeError;
@@ -13,6 +14,10 @@ use self::ParseThemeError::*;
 /// A theme parsed from a `.tmTheme` file.
 ///
 /// This contains additional fields useful for a theme list as well as `settings` for styling your editor.
+/// The main colors of the editor are also available from getters like [`selection`], which fall
+/// back on sensible colors when the theme doesn't set them.
+///
+/// [`selection`]: #method.selection
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Theme {
     pub name: Option<String>,
@@ -181,6 +186,316 @@ impl Default for FontStyle {
     }
 }
 
+impl Theme {
+    /// The default color for text, black if the theme doesn't set one
+    pub fn foreground(&self) -> Color {
+        self.settings.foreground.unwrap_or(Color::BLACK)
+    }
+
+    /// The default background color, white if the theme doesn't set one
+    pub fn background(&self) -> Color {
+        self.settings.background.unwrap_or(Color::WHITE)
+    }
+
+    /// The color of the caret, the [`foreground`] if the theme doesn't set one
+    ///
+    /// [`foreground`]: #method.foreground
+    pub fn caret(&self) -> Color {
+        self.settings.caret.unwrap_or_else(|| self.foreground())
+    }
+
+    /// The background of the line the caret is in, a faint tint of the [`foreground`] over the
+    /// [`background`] if the theme doesn't set one
+    ///
+    /// [`foreground`]: #method.foreground
+    /// [`background`]: #method.background
+    pub fn line_highlight(&self) -> Color {
+        self.settings.line_highlight.unwrap_or_else(|| self.tint(0x18))
+    }
+
+    /// The background of selected text, a tint of the [`foreground`] over the [`background`] if
+    /// the theme doesn't set one
+    ///
+    /// [`foreground`]: #method.foreground
+    /// [`background`]: #method.background
+    pub fn selection(&self) -> Color {
+        self.settings.selection.unwrap_or_else(|| self.tint(0x40))
+    }
+
+    /// The background of the gutter with the line numbers, the [`background`] if the theme
+    /// doesn't set one
+    ///
+    /// [`background`]: #method.background
+    pub fn gutter(&self) -> Color {
+        self.settings.gutter.unwrap_or_else(|| self.background())
+    }
+
+    /// The color of the line numbers in the gutter, the [`foreground`] faded into the
+    /// [`gutter`] if the theme doesn't set one
+    ///
+    /// [`foreground`]: #method.foreground
+    /// [`gutter`]: #method.gutter
+    pub fn gutter_foreground(&self) -> Color {
+        self.settings.gutter_foreground
+            .unwrap_or_else(|| Color { a: 0x80, ..self.foreground() }.blend_over(self.gutter()))
+    }
+
+    /// The background of matches of the current search, the [`selection`] if the theme doesn't
+    /// set one
+    ///
+    /// [`selection`]: #method.selection
+    pub fn find_highlight(&self) -> Color {
+        self.settings.find_highlight.unwrap_or_else(|| self.selection())
+    }
+
+    /// The color of the text of matches of the current search, the [`foreground`] if the theme
+    /// doesn't set one
+    ///
+    /// [`foreground`]: #method.foreground
+    pub fn find_highlight_foreground(&self) -> Color {
+        self.settings.find_highlight_foreground.unwrap_or_else(|| self.foreground())
+    }
+
+    /// Whether the theme is dark, that is whether light text stands out more on its
+    /// [`background`] than dark text, for picking a theme that fits the appearance of the system.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::ThemeSet;
+    ///
+    /// let ts = ThemeSet::load_defaults();
+    /// assert!(ts.themes["base16-ocean.dark"].is_dark());
+    /// assert!(!ts.themes["InspiredGitHub"].is_dark());
+    /// ```
+    ///
+    /// [`background`]: #method.background
+    pub fn is_dark(&self) -> bool {
+        // the contrast with white, 1.05 / (l + 0.05), is higher than the one with black,
+        // (l + 0.05) / 0.05
+        let luminance = self.background().luminance();
+        (luminance + 0.05) * (luminance + 0.05) < 1.05 * 0.05
+    }
+
+    /// The foreground with the given alpha, composited over the background
+    fn tint(&self, alpha: u8) -> Color {
+        Color { a: alpha, ..self.foreground() }.blend_over(self.background())
+    }
+
+    /// Converts the theme back to the settings of a `.tmTheme` file.
+    ///
+    /// Parsing the result with `parse_settings` gives the same theme, but settings syntect doesn't
+    /// use, like `invisibles`, are lost when a theme is parsed.
+    pub fn to_settings(&self) -> Settings {
+        let mut obj = serde_json::Map::new();
+        if let Some(ref name) = self.name {
+            obj.insert("name".to_owned(), Settings::String(name.clone()));
+        }
+        if let Some(ref author) = self.author {
+            obj.insert("author".to_owned(), Settings::String(author.clone()));
+        }
+        let mut items = vec![serde_json::json!({ "settings": self.settings.to_settings() })];
+        for item in &self.scopes {
+            items.push(serde_json::json!({
+                "scope": item.scope.to_string(),
+                "settings": item.style.to_settings(),
+            }));
+        }
+        obj.insert("settings".to_owned(), Settings::Array(items));
+        Settings::Object(obj)
+    }
+
+    /// Writes the theme as the XML plist of a `.tmTheme` file, which other editors can load.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{Color, Theme, ThemeSet};
+    /// use std::io::Cursor;
+    ///
+    /// let mut theme = Theme::default();
+    /// theme.name = Some("Generated".to_owned());
+    /// theme.settings.background = Some(Color { r: 0x2b, g: 0x30, b: 0x3b, a: 0xff });
+    ///
+    /// let plist = theme.to_tm_theme();
+    /// assert!(plist.contains("<string>#2B303B</string>"));
+    /// let loaded = ThemeSet::load_from_reader(&mut Cursor::new(plist)).unwrap();
+    /// assert_eq!(loaded.name.unwrap(), "Generated");
+    /// assert_eq!(loaded.settings.background, theme.settings.background);
+    /// ```
+    pub fn to_tm_theme(&self) -> String {
+        let mut plist = Vec::new();
+        write_plist(&self.to_settings(), &mut plist).expect("writing a theme to memory can't fail");
+        String::from_utf8(plist).expect("plists are written as UTF-8")
+    }
+
+    /// Returns a copy of the theme without transparent colors, for renderers that can only draw
+    /// opaque colors, like terminals.
+    ///
+    /// The background of the theme is composited over the given background, or replaced by it if
+    /// the theme doesn't have one. All other colors are composited over the result, except the
+    /// foreground of a rule with its own background, which is composited over that. Rules are
+    /// resolved one by one, so if a token gets its background from one rule and its foreground
+    /// from another, use [`Style::resolve_alpha`] on the highlighted styles of the original theme
+    /// instead.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::{Color, StyleModifier, ThemeBuilder};
+    ///
+    /// let theme = ThemeBuilder::new()
+    ///     .scope("comment", StyleModifier::default().with_foreground(Color { r: 0xFF, g: 0xFF, b: 0xFF, a: 0x80 }))
+    ///     .build()
+    ///     .unwrap();
+    /// let resolved = theme.resolve_alpha(Color::BLACK);
+    /// assert_eq!(resolved.settings.background, Some(Color::BLACK));
+    /// assert_eq!(resolved.scopes[0].style.foreground, Some(Color { r: 0x80, g: 0x80, b: 0x80, a: 0xFF }));
+    /// ```
+    ///
+    /// [`Style::resolve_alpha`]: struct.Style.html#method.resolve_alpha
+    pub fn resolve_alpha(&self, background: Color) -> Theme {
+        let mut theme = self.clone();
+        let canvas = self.settings.background.map_or(background, |color| color.blend_over(background));
+        let settings = &mut theme.settings;
+        settings.background = Some(canvas);
+        let mut colors = [
+            &mut settings.foreground,
+            &mut settings.caret,
+            &mut settings.line_highlight,
+            &mut settings.misspelling,
+            &mut settings.minimap_border,
+            &mut settings.accent,
+            &mut settings.bracket_contents_foreground,
+            &mut settings.brackets_foreground,
+            &mut settings.brackets_background,
+            &mut settings.tags_foreground,
+            &mut settings.highlight,
+            &mut settings.find_highlight,
+            &mut settings.find_highlight_foreground,
+            &mut settings.gutter,
+            &mut settings.gutter_foreground,
+            &mut settings.selection,
+            &mut settings.selection_foreground,
+            &mut settings.selection_border,
+            &mut settings.inactive_selection,
+            &mut settings.inactive_selection_foreground,
+            &mut settings.guide,
+            &mut settings.active_guide,
+            &mut settings.stack_guide,
+            &mut settings.shadow,
+        ];
+        for color in colors.iter_mut() {
+            **color = color.map(|color| color.blend_over(canvas));
+        }
+        for item in &mut theme.scopes {
+            let style = &mut item.style;
+            style.background = style.background.map(|color| color.blend_over(canvas));
+            let under = style.background.unwrap_or(canvas);
+            style.foreground = style.foreground.map(|color| color.blend_over(under));
+        }
+        theme
+    }
+}
+
+impl ThemeSettings {
+    fn to_settings(&self) -> Settings {
+        let colors = [
+            ("foreground", self.foreground),
+            ("background", self.background),
+            ("caret", self.caret),
+            ("lineHighlight", self.line_highlight),
+            ("misspelling", self.misspelling),
+            ("minimapBorder", self.minimap_border),
+            ("accent", self.accent),
+            ("bracketContentsForeground", self.bracket_contents_foreground),
+            ("bracketsForeground", self.brackets_foreground),
+            ("bracketsBackground", self.brackets_background),
+            ("tagsForeground", self.tags_foreground),
+            ("highlight", self.highlight),
+            ("findHighlight", self.find_highlight),
+            ("findHighlightForeground", self.find_highlight_foreground),
+            ("gutter", self.gutter),
+            ("gutterForeground", self.gutter_foreground),
+            ("selection", self.selection),
+            ("selectionForeground", self.selection_foreground),
+            ("selectionBorder", self.selection_border),
+            ("inactiveSelection", self.inactive_selection),
+            ("inactiveSelectionForeground", self.inactive_selection_foreground),
+            ("guide", self.guide),
+            ("activeGuide", self.active_guide),
+            ("stackGuide", self.stack_guide),
+            ("shadow", self.shadow),
+        ];
+        let options = [
+            ("bracketContentsOptions", &self.bracket_contents_options),
+            ("bracketsOptions", &self.brackets_options),
+            ("tagsOptions", &self.tags_options),
+        ];
+        let css = [("popupCss", &self.popup_css), ("phantomCss", &self.phantom_css)];
+
+        let mut obj = serde_json::Map::new();
+        for &(key, color) in &colors {
+            if let Some(color) = color {
+                obj.insert(key.to_owned(), Settings::String(color_string(color)));
+            }
+        }
+        for &(key, option) in &options {
+            if let Some(option) = option.as_ref().and_then(UnderlineOption::as_str) {
+                obj.insert(key.to_owned(), Settings::String(option.to_owned()));
+            }
+        }
+        for &(key, css) in &css {
+            if let Some(ref css) = *css {
+                obj.insert(key.to_owned(), Settings::String(css.clone()));
+            }
+        }
+        Settings::Object(obj)
+    }
+}
+
+impl StyleModifier {
+    fn to_settings(self) -> Settings {
+        let mut obj = serde_json::Map::new();
+        if let Some(foreground) = self.foreground {
+            obj.insert("foreground".to_owned(), Settings::String(color_string(foreground)));
+        }
+        if let Some(background) = self.background {
+            obj.insert("background".to_owned(), Settings::String(color_string(background)));
+        }
+        if let Some(font_style) = self.font_style {
+            let words: Vec<&str> = [(FontStyle::BOLD, "bold"), (FontStyle::UNDERLINE, "underline"), (FontStyle::ITALIC, "italic")]
+                .iter()
+                .filter(|&&(style, _)| font_style.contains(style))
+                .map(|&(_, word)| word)
+                .collect();
+            obj.insert("fontStyle".to_owned(), Settings::String(words.join(" ")));
+        }
+        Settings::Object(obj)
+    }
+}
+
+/// Formats a color as `#RRGGBB`, or `#RRGGBBAA` if it isn't opaque
+pub(crate) fn color_string(color: Color) -> String {
+    if color.a == 0xFF {
+        format!("#{:02X}{:02X}{:02X}", color.r, color.g, color.b)
+    } else {
+        format!("#{:02X}{:02X}{:02X}{:02X}", color.r, color.g, color.b, color.a)
+    }
+}
+
+impl UnderlineOption {
+    /// The name of the option in a theme, `None` for `UnderlineOption::None`
+    fn as_str(&self) -> Option<&'static str> {
+        match *self {
+            UnderlineOption::None => None,
+            UnderlineOption::Underline => Some("underline"),
+            UnderlineOption::StippledUnderline => Some("stippled_underline"),
+            UnderlineOption::SquigglyUnderline => Some("squiggly_underline"),
+        }
+    }
+}
+
 impl FromStr for UnderlineOption {
     type Err = ParseThemeError;
 
@@ -238,10 +553,12 @@ impl ParseSettings for FontStyle {
 impl FromStr for Color {
     type Err = ParseThemeError;
 
+    /// Parses a color as `#RGB`, `#RRGGBB` or `#RRGGBBAA`, or like CSS does, as a name like
+    /// `red` or with `rgb()`, `rgba()`, `hsl()` or `hsla()`.
     fn from_str(s: &str) -> Result<Color, Self::Err> {
         let mut chars = s.chars();
         if chars.next() != Some('#') {
-            return Err(IncorrectColor);
+            return parse_css_color(s).ok_or(IncorrectColor);
         }
         let mut d = Vec::new();
         for char in chars {
@@ -0,0 +1,155 @@
+use std::str::FromStr;
+
+use super::selector::ScopeSelectors;
+use super::style::{Color, StyleModifier};
+use super::theme::{ParseThemeError, Theme, ThemeItem, ThemeSettings};
+
+/// Builds a [`Theme`] in code, for tools that generate themes instead of loading `.tmTheme` files.
+///
+/// The rules are added in order, and like in a `.tmTheme` file, later rules win over earlier
+/// ones with an equally good selector. Mistakes in the rules are reported by [`build`].
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{Color, Highlighter, StyleModifier, ThemeBuilder};
+/// use syntect::parsing::ScopeStack;
+/// use std::str::FromStr;
+///
+/// let red = Color { r: 0xff, g: 0, b: 0, a: 0xff };
+/// let theme = ThemeBuilder::new()
+///     .name("Generated")
+///     .foreground(Color::BLACK)
+///     .background(Color::WHITE)
+///     .scope("keyword", StyleModifier::default().with_foreground(red).bold())
+///     .scope("comment", StyleModifier::default().italic())
+///     .build()
+///     .unwrap();
+///
+/// let highlighter = Highlighter::new(&theme);
+/// let keyword = ScopeStack::from_str("source.rust keyword.control").unwrap();
+/// assert_eq!(highlighter.style_for_stack(keyword.as_slice()).foreground, red);
+///
+/// assert!(ThemeBuilder::new().scope("string (", StyleModifier::default().bold()).build().is_err());
+/// ```
+///
+/// [`Theme`]: struct.Theme.html
+/// [`build`]: #method.build
+#[derive(Debug, Default)]
+pub struct ThemeBuilder {
+    theme: Theme,
+    /// The selectors of the rules as they were given, to find duplicates
+    selectors: Vec<String>,
+    /// The first mistake in the rules
+    error: Option<ParseThemeError>,
+}
+
+impl ThemeBuilder {
+    /// Creates a builder for an empty theme without a name
+    pub fn new() -> ThemeBuilder {
+        ThemeBuilder::default()
+    }
+
+    pub fn name(mut self, name: &str) -> ThemeBuilder {
+        self.theme.name = Some(name.to_owned());
+        self
+    }
+
+    pub fn author(mut self, author: &str) -> ThemeBuilder {
+        self.theme.author = Some(author.to_owned());
+        self
+    }
+
+    /// Sets the default color for text
+    pub fn foreground(mut self, color: Color) -> ThemeBuilder {
+        self.theme.settings.foreground = Some(color);
+        self
+    }
+
+    /// Sets the default background color
+    pub fn background(mut self, color: Color) -> ThemeBuilder {
+        self.theme.settings.background = Some(color);
+        self
+    }
+
+    /// Replaces all the editor settings, keeping nothing set by [`foreground`] or
+    /// [`background`] before
+    ///
+    /// [`foreground`]: #method.foreground
+    /// [`background`]: #method.background
+    pub fn settings(mut self, settings: ThemeSettings) -> ThemeBuilder {
+        self.theme.settings = settings;
+        self
+    }
+
+    /// Adds a rule styling the text matched by a scope selector, like `"string - string.regexp"`
+    pub fn scope(mut self, selector: &str, style: StyleModifier) -> ThemeBuilder {
+        if self.error.is_some() {
+            return self;
+        }
+        let selector = selector.trim();
+        match self.check_rule(selector, style) {
+            Ok(scope) => {
+                self.selectors.push(selector.to_owned());
+                self.theme.scopes.push(ThemeItem { scope, style });
+            }
+            Err(error) => self.error = Some(error),
+        }
+        self
+    }
+
+    /// Returns the theme, or the first mistake in its rules: a selector that can't be parsed
+    /// (`ScopeParse`), a rule that changes nothing (`UndefinedScopeSettings`) or a selector used
+    /// by two rules (`DuplicateSettings`).
+    pub fn build(self) -> Result<Theme, ParseThemeError> {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.theme),
+        }
+    }
+
+    fn check_rule(&self, selector: &str, style: StyleModifier) -> Result<ScopeSelectors, ParseThemeError> {
+        let scope = ScopeSelectors::from_str(selector)?;
+        if style == StyleModifier::default() {
+            return Err(ParseThemeError::UndefinedScopeSettings(selector.to_owned()));
+        }
+        if self.selectors.iter().any(|s| s == selector) {
+            return Err(ParseThemeError::DuplicateSettings);
+        }
+        Ok(scope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlighting::FontStyle;
+
+    #[test]
+    fn can_build_themes() {
+        let blue = Color { r: 0, g: 0, b: 0xff, a: 0xff };
+        let theme = ThemeBuilder::new()
+            .name("Test")
+            .author("syntect")
+            .settings(ThemeSettings { caret: Some(blue), ..ThemeSettings::default() })
+            .background(Color::WHITE)
+            .scope("string", StyleModifier::default().with_foreground(blue))
+            .scope(" comment - comment.block ", StyleModifier::default().bold().italic())
+            .build()
+            .unwrap();
+        assert_eq!(theme.name, Some("Test".to_owned()));
+        assert_eq!(theme.settings.caret, Some(blue));
+        assert_eq!(theme.settings.background, Some(Color::WHITE));
+        assert_eq!(theme.scopes.len(), 2);
+        assert_eq!(theme.scopes[1].scope, ScopeSelectors::from_str("comment - comment.block").unwrap());
+        assert_eq!(theme.scopes[1].style.font_style, Some(FontStyle::BOLD | FontStyle::ITALIC));
+
+        let error = |builder: ThemeBuilder| builder.build().unwrap_err();
+        let bold = StyleModifier::default().bold();
+        assert!(matches!(error(ThemeBuilder::new().scope("a)", bold)), ParseThemeError::ScopeParse(_)));
+        assert!(matches!(error(ThemeBuilder::new().scope("a", StyleModifier::default())),
+                         ParseThemeError::UndefinedScopeSettings(ref s) if s == "a"));
+        assert!(matches!(error(ThemeBuilder::new().scope("a", bold).scope("b", bold).scope("a ", bold)),
+                         ParseThemeError::DuplicateSettings));
+    }
+}
@@ -3,7 +3,7 @@ use super::settings::*;
 use super::super::LoadingError;
 use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
-use std::io::{BufReader, BufRead, Seek};
+use std::io::{BufReader, BufRead, BufWriter, Seek, Write};
 use walkdir::WalkDir;
 use std::fs::File;
 
@@ -46,6 +46,42 @@ impl ThemeSet {
         Ok(Theme::parse_settings(read_plist(r)?)?)
     }
 
+    /// Writes a theme to a .tmTheme file, see [`Theme::to_tm_theme`]
+    ///
+    /// [`Theme::to_tm_theme`]: struct.Theme.html#method.to_tm_theme
+    pub fn save_theme<P: AsRef<Path>>(theme: &Theme, path: P) -> Result<(), LoadingError> {
+        let mut file = BufWriter::new(File::create(path)?);
+        write_plist(&theme.to_settings(), &mut file)?;
+        file.flush()?;
+        Ok(())
+    }
+
+    /// Picks the theme for a dark or light appearance of the system out of a preferred dark and
+    /// light theme.
+    ///
+    /// If the set doesn't have the preferred theme, the first theme by name that is dark or light
+    /// (see [`Theme::is_dark`]) is used instead, and `None` is only returned if there is none.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::highlighting::ThemeSet;
+    ///
+    /// let ts = ThemeSet::load_defaults();
+    /// let dark_mode = true; // e.g. from the settings of the system
+    /// let theme = ts.theme_for_appearance("base16-ocean.dark", "InspiredGitHub", dark_mode).unwrap();
+    /// assert_eq!(theme.name.as_ref().unwrap(), "Base16 Ocean Dark");
+    /// let light = ts.theme_for_appearance("missing", "missing too", false).unwrap();
+    /// assert!(!light.is_dark());
+    /// ```
+    ///
+    /// [`Theme::is_dark`]: struct.Theme.html#method.is_dark
+    pub fn theme_for_appearance(&self, dark: &str, light: &str, dark_mode: bool) -> Option<&Theme> {
+        let preferred = if dark_mode { dark } else { light };
+        self.themes.get(preferred)
+            .or_else(|| self.themes.values().find(|theme| theme.is_dark() == dark_mode))
+    }
+
     /// Generate a `ThemeSet` from all themes in a folder
     pub fn load_from_folder<P: AsRef<Path>>(folder: P) -> Result<ThemeSet, LoadingError> {
         let mut theme_set = Self::new();
@@ -71,6 +107,7 @@ impl ThemeSet {
 #[cfg(test)]
 mod tests {
     use crate::highlighting::{ThemeSet, Color};
+    use crate::util::TestDir;
     #[test]
     fn can_parse_common_themes() {
         let themes = ThemeSet::load_from_folder("testdata").unwrap();
@@ -97,4 +134,41 @@ mod tests {
                    });
         // assert!(false);
     }
+
+    #[test]
+    fn can_save_themes() {
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        let dir = TestDir::new("can_save_themes");
+        let path = dir.path().join("Saved.tmTheme");
+        ThemeSet::save_theme(&theme, &path).unwrap();
+
+        let saved = ThemeSet::get_theme(&path).unwrap();
+        assert_eq!(serde_json::to_value(&saved).unwrap(), serde_json::to_value(&theme).unwrap());
+        assert_eq!(saved.to_tm_theme(), theme.to_tm_theme());
+    }
+
+    #[test]
+    fn can_get_settings_with_fallbacks() {
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        assert_eq!(theme.background(), theme.settings.background.unwrap());
+        assert_eq!(theme.selection(), theme.settings.selection.unwrap());
+        assert_eq!(theme.caret(), theme.settings.caret.unwrap());
+
+        let mut theme = crate::highlighting::Theme::default();
+        assert_eq!((theme.foreground(), theme.background()), (Color::BLACK, Color::WHITE));
+        assert_eq!(theme.caret(), Color::BLACK);
+        assert_eq!(theme.gutter(), Color::WHITE);
+        assert_eq!(theme.gutter_foreground(), Color { r: 0x7f, g: 0x7f, b: 0x7f, a: 0xff });
+        assert_eq!(theme.find_highlight(), theme.selection());
+        let gray = |color: Color| color.r == color.g && color.g == color.b && color.a == 0xff;
+        assert!(gray(theme.line_highlight()) && gray(theme.selection()));
+        assert!(theme.selection().r < theme.line_highlight().r && theme.line_highlight().r < 0xff);
+
+        theme.settings.foreground = Some(Color::WHITE);
+        theme.settings.background = Some(Color::BLACK);
+        theme.settings.gutter = Some(Color::WHITE);
+        assert_eq!(theme.caret(), Color::WHITE);
+        assert_eq!(theme.find_highlight_foreground(), Color::WHITE);
+        assert_eq!(theme.gutter_foreground(), Color::WHITE);
+    }
 }
@@ -1,8 +1,8 @@
 //! Rendering highlighted code as HTML+CSS
 use std::fmt::Write;
 use crate::parsing::{ScopeStackOp, BasicScopeStackOp, Scope, ScopeStack, SyntaxReference, ParseState, SyntaxSet, SCOPE_REPO};
-use crate::easy::{HighlightLines, HighlightFile};
-use crate::highlighting::{Color, FontStyle, Style, Theme};
+use crate::easy::{HighlightLines, HighlightFile, ScopeTokenIterator};
+use crate::highlighting::{Color, FontStyle, Highlighter, Style, Theme};
 use crate::util::LinesWithEndings;
 use crate::escape::Escape;
 
@@ -97,6 +97,12 @@ pub fn css_for_theme(theme: &Theme) -> String {
 }
 
 /// Create a complete CSS for a given theme. Can be used inline, or written to a CSS file.
+///
+/// There is a rule for the `code` class with the default colors of the theme and one for each
+/// scope a rule of the theme selects, using the same classes as [`ClassedHTMLGenerator`]. Font
+/// styles set by a rule replace the inherited ones, like they do in the inline styles.
+///
+/// [`ClassedHTMLGenerator`]: struct.ClassedHTMLGenerator.html
 pub fn css_for_theme_with_class_style(theme: &Theme, style: ClassStyle) -> String {
     let mut css = String::new();
 
@@ -109,15 +115,19 @@ pub fn css_for_theme_with_class_style(theme: &Theme, style: ClassStyle) -> Strin
         ClassStyle::Spaced => {
             css.push_str(".code {\n");
         },
-        ClassStyle::SpacedPrefixed { prefix } => {
+        ClassStyle::SpacedPrefixed { prefix } | ClassStyle::SpacedEscaped { prefix } => {
             css.push_str(&format!(".{}code {{\n", prefix));
         },
     };
     if let Some(fgc) = theme.settings.foreground {
-        css.push_str(&format!(" color: #{:02x}{:02x}{:02x};\n", fgc.r, fgc.g, fgc.b));
+        css.push_str(" color: ");
+        write_css_color(&mut css, fgc);
+        css.push_str(";\n");
     }
     if let Some(bgc) = theme.settings.background {
-        css.push_str(&format!(" background-color: #{:02x}{:02x}{:02x};\n", bgc.r, bgc.g, bgc.b));
+        css.push_str(" background-color: ");
+        write_css_color(&mut css, bgc);
+        css.push_str(";\n");
     }
     css.push_str("}\n\n");
 
@@ -130,23 +140,25 @@ pub fn css_for_theme_with_class_style(theme: &Theme, style: ClassStyle) -> Strin
                 css.push_str(" {\n");
 
                 if let Some(fg) =  i.style.foreground {
-                    css.push_str(&format!(" color: #{:02x}{:02x}{:02x};\n", fg.r, fg.g, fg.b));
+                    css.push_str(" color: ");
+                    write_css_color(&mut css, fg);
+                    css.push_str(";\n");
                 }
 
                 if let Some(bg) = i.style.background {
-                    css.push_str(&format!(" background-color: #{:02x}{:02x}{:02x};\n", bg.r, bg.g, bg.b));
+                    css.push_str(" background-color: ");
+                    write_css_color(&mut css, bg);
+                    css.push_str(";\n");
                 }
 
+                // a font style in a rule replaces the inherited one, like it does for inline styles
                 if let Some(fs) = i.style.font_style {
-                    if fs.contains(FontStyle::UNDERLINE) {
-                        css.push_str(&format!("font-style: underline;\n"));
-                    }
-                    if fs.contains(FontStyle::BOLD) {
-                        css.push_str(&format!("font-weight: bold;\n"));
-                    }
-                    if fs.contains(FontStyle::ITALIC) {
-                        css.push_str(&format!("font-style: italic;\n"));
-                    }
+                    let decoration = if fs.contains(FontStyle::UNDERLINE) { "underline" } else { "none" };
+                    let weight = if fs.contains(FontStyle::BOLD) { "bold" } else { "normal" };
+                    let font_style = if fs.contains(FontStyle::ITALIC) { "italic" } else { "normal" };
+                    css.push_str(&format!(" text-decoration: {};\n", decoration));
+                    css.push_str(&format!(" font-weight: {};\n", weight));
+                    css.push_str(&format!(" font-style: {};\n", font_style));
                 }
                 css.push_str("}\n");
             }
@@ -178,6 +190,15 @@ pub enum ClassStyle {
     SpacedPrefixed {
         prefix: &'static str,
     },
+    /// Like `SpacedPrefixed`, but atoms that aren't valid CSS class names are
+    /// escaped, so that every atom gets its own class and the classes can be
+    /// mapped back to the atoms. See [`atom_to_class`] for the escaping. The
+    /// prefix can be empty.
+    ///
+    /// [`atom_to_class`]: fn.atom_to_class.html
+    SpacedEscaped {
+        prefix: &'static str,
+    },
 }
 
 fn scope_to_classes(s: &mut String, scope: Scope, style: ClassStyle) {
@@ -188,14 +209,7 @@ fn scope_to_classes(s: &mut String, scope: Scope, style: ClassStyle) {
         if i != 0 {
             s.push_str(" ")
         }
-        match style {
-            ClassStyle::Spaced => {
-            },
-            ClassStyle::SpacedPrefixed { prefix } => {
-                s.push_str(&prefix);
-            },
-        }
-        s.push_str(atom_s);
+        s.push_str(&atom_to_class(atom_s, style));
     }
 }
 
@@ -205,14 +219,105 @@ fn scope_to_selector(s: &mut String, scope: Scope, style: ClassStyle) {
         let atom = scope.atom_at(i as usize);
         let atom_s = repo.atom_str(atom);
         s.push_str(".");
-        match style {
-            ClassStyle::Spaced => {
-            },
-            ClassStyle::SpacedPrefixed { prefix } => {
-                s.push_str(&prefix);
-            },
+        s.push_str(&atom_to_class(atom_s, style));
+    }
+}
+
+/// The CSS class name for an atom of a scope, like `string` for the second atom of
+/// `source.string`, as [`ClassedHTMLGenerator`] and [`css_for_theme_with_class_style`] use it.
+///
+/// With `Spaced` and `SpacedPrefixed` the atom is used as it is. With `SpacedEscaped` the
+/// mapping is the same in every release and different atoms always get different classes.
+/// Atoms are kept as they are if they are plain names, which are made of ASCII letters, digits
+/// and `-` and start with a letter. Other characters, which aren't allowed in CSS class names
+/// or would need escaping in selectors, are written as `_` followed by the hex digits of each
+/// of their UTF-8 bytes, so `c++` becomes `c_2b_2b`, and `_` itself is doubled. [`class_to_atom`]
+/// reverses the mapping.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::html::{atom_to_class, class_to_atom, ClassStyle};
+///
+/// assert_eq!(atom_to_class("c++", ClassStyle::Spaced), "c++");
+/// assert_eq!(atom_to_class("c++", ClassStyle::SpacedEscaped { prefix: "sy-" }), "sy-c_2b_2b");
+/// assert_eq!(class_to_atom("sy-c_2b_2b", ClassStyle::SpacedEscaped { prefix: "sy-" }).unwrap(), "c++");
+/// ```
+///
+/// [`ClassedHTMLGenerator`]: struct.ClassedHTMLGenerator.html
+/// [`css_for_theme_with_class_style`]: fn.css_for_theme_with_class_style.html
+/// [`class_to_atom`]: fn.class_to_atom.html
+pub fn atom_to_class(atom: &str, style: ClassStyle) -> String {
+    let mut class = String::with_capacity(atom.len());
+    match style {
+        ClassStyle::Spaced => {
+            class.push_str(atom);
+        },
+        ClassStyle::SpacedPrefixed { prefix } => {
+            class.push_str(prefix);
+            class.push_str(atom);
+        },
+        ClassStyle::SpacedEscaped { prefix } => {
+            class.push_str(prefix);
+            escape_atom(&mut class, atom);
+        },
+    }
+    class
+}
+
+fn escape_atom(class: &mut String, atom: &str) {
+    for (i, byte) in atom.bytes().enumerate() {
+        let plain = byte.is_ascii_alphabetic() || (i > 0 && (byte.is_ascii_digit() || byte == b'-'));
+        if plain {
+            class.push(char::from(byte));
+        } else if byte == b'_' {
+            class.push_str("__");
+        } else {
+            write!(class, "_{:02x}", byte).unwrap();
+        }
+    }
+}
+
+/// The atom of a scope that [`atom_to_class`] maps to a class name, or `None` if no atom is
+/// mapped to it.
+///
+/// Only `SpacedEscaped` maps different atoms to different classes, with the other styles the
+/// class is just returned without its prefix.
+///
+/// [`atom_to_class`]: fn.atom_to_class.html
+pub fn class_to_atom(class: &str, style: ClassStyle) -> Option<String> {
+    let prefix = match style {
+        ClassStyle::Spaced => "",
+        ClassStyle::SpacedPrefixed { prefix } | ClassStyle::SpacedEscaped { prefix } => prefix,
+    };
+    if !class.starts_with(prefix) {
+        return None;
+    }
+    let encoded = &class[prefix.len()..];
+    if let ClassStyle::Spaced | ClassStyle::SpacedPrefixed { .. } = style {
+        return Some(encoded.to_owned());
+    }
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte != b'_' {
+            bytes.push(byte);
+            rest = tail;
+        } else if tail.first() == Some(&b'_') {
+            bytes.push(b'_');
+            rest = &tail[1..];
+        } else {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
         }
-        s.push_str(atom_s);
+    }
+    let atom = String::from_utf8(bytes).ok()?;
+    // only accept the one class name the atom is mapped to
+    if atom_to_class(&atom, style) == class {
+        Some(atom)
+    } else {
+        None
     }
 }
 
@@ -259,6 +364,236 @@ pub fn highlighted_html_for_file<P: AsRef<Path>>(path: P,
     Ok(output)
 }
 
+/// A debugging version of [`highlighted_html_for_string`] that shows which rules of the theme
+/// styled each token, for finding out why some text gets the wrong color.
+///
+/// Every token gets its own `<span>`, with the scope stack in a `data-scopes` attribute and the
+/// rules that decided its colors and font style in `data-foreground`, `data-background` and
+/// `data-font-style`, written like `rule 12 `string` (score 8)` or `default` for parts that
+/// come from the theme's defaults. See [`Highlighter::explain_style`] for the details.
+///
+/// ```
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::html::highlighted_html_with_provenance;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+/// let html = highlighted_html_with_provenance("let x = 1;\n", &ss, syntax, &ts.themes["base16-ocean.dark"]);
+/// assert!(html.contains("data-scopes=\"source.rust constant.numeric.integer.decimal.rust\""));
+/// ```
+///
+/// [`highlighted_html_for_string`]: fn.highlighted_html_for_string.html
+/// [`Highlighter::explain_style`]: ../highlighting/struct.Highlighter.html#method.explain_style
+pub fn highlighted_html_with_provenance(s: &str, ss: &SyntaxSet, syntax: &SyntaxReference, theme: &Theme) -> String {
+    let highlighter = Highlighter::new(theme);
+    highlighted_html_for_string_with_attributes(s, ss, syntax, theme, |scopes, _| {
+        let explanation = highlighter.explain_style(scopes.as_slice());
+        let rule = |from: Option<usize>| match from {
+            Some(index) => explanation.matches[index].to_string(),
+            None => "default".to_owned(),
+        };
+        vec![
+            ("data-scopes".to_owned(), scopes.to_string().trim_end().to_owned()),
+            ("data-foreground".to_owned(), rule(explanation.foreground_from)),
+            ("data-background".to_owned(), rule(explanation.background_from)),
+            ("data-font-style".to_owned(), rule(explanation.font_style_from)),
+        ]
+    })
+}
+
+/// Like [`highlighted_html_for_string`], but with extra attributes on the `<span>` of every
+/// token, for adding things like `data-` attributes, ARIA attributes or tooltips.
+///
+/// Every token gets its own `<span>`, and `attributes` is called with its scope stack and text
+/// to get the attributes to add after the `style`, as pairs of names and values. The values are
+/// escaped, but the names are written as they are and must be valid attribute names.
+///
+/// ```
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::html::highlighted_html_for_string_with_attributes;
+/// use syntect::parsing::{Scope, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+/// let comment = Scope::new("comment").unwrap();
+/// let html = highlighted_html_for_string_with_attributes("// hi\n", &ss, syntax, &ts.themes["base16-ocean.dark"], |scopes, _| {
+///     if scopes.as_slice().iter().any(|scope| comment.is_prefix_of(*scope)) {
+///         vec![("title".to_owned(), "a comment".to_owned())]
+///     } else {
+///         Vec::new()
+///     }
+/// });
+/// assert!(html.contains("title=\"a comment\">"));
+/// ```
+///
+/// [`highlighted_html_for_string`]: fn.highlighted_html_for_string.html
+pub fn highlighted_html_for_string_with_attributes<F>(s: &str,
+                                                      ss: &SyntaxSet,
+                                                      syntax: &SyntaxReference,
+                                                      theme: &Theme,
+                                                      mut attributes: F)
+                                                      -> String
+    where F: FnMut(&ScopeStack, &str) -> Vec<(String, String)>
+{
+    let highlighter = Highlighter::new(theme);
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let (mut output, bg) = start_highlighted_html_snippet(theme);
+
+    for line in LinesWithEndings::from(s) {
+        let ops = state.parse_line(line, ss);
+        for (range, scopes) in ScopeTokenIterator::new(&ops, line, &mut stack) {
+            let text = &line[range];
+            let style = highlighter.style_for_stack(scopes.as_slice());
+            output.push_str("<span style=\"");
+            write_css_style(&mut output, &style, style.background != bg);
+            output.push('"');
+            for (name, value) in attributes(&scopes, text) {
+                write!(output, " {}=\"{}\"", name, Escape(&value)).unwrap();
+            }
+            write!(output, ">{}</span>", Escape(text)).unwrap();
+        }
+    }
+    output.push_str("</pre>\n");
+    output
+}
+
+/// Like [`highlighted_html_for_string`], but with a number in front of every line and an anchor
+/// for linking to each line.
+///
+/// Every line is wrapped in a `<span id="L1">`, counting from 1, so pages can link to lines with
+/// URLs like `code.html#L12` like GitHub does. The line numbers are links to their line, padded to
+/// the same width and shown in the gutter colors of the theme, see
+/// [`append_highlighted_html_for_numbered_line`].
+///
+/// ```
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::html::highlighted_html_for_string_with_line_numbers;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_plain_text();
+/// let html = highlighted_html_for_string_with_line_numbers("a\nb\n", &ss, syntax, &ts.themes["base16-ocean.dark"]);
+/// assert!(html.contains("<span id=\"L2\"><a href=\"#L2\""));
+/// ```
+///
+/// [`highlighted_html_for_string`]: fn.highlighted_html_for_string.html
+/// [`append_highlighted_html_for_numbered_line`]: fn.append_highlighted_html_for_numbered_line.html
+pub fn highlighted_html_for_string_with_line_numbers(s: &str, ss: &SyntaxSet, syntax: &SyntaxReference, theme: &Theme) -> String {
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let (mut output, bg) = start_highlighted_html_snippet(theme);
+    let width = LinesWithEndings::from(s).count().to_string().len();
+
+    for (index, line) in LinesWithEndings::from(s).enumerate() {
+        let regions = highlighter.highlight(line, ss);
+        append_highlighted_html_for_numbered_line(&regions[..], IncludeBackground::IfDifferent(bg), index + 1, width, theme, &mut output);
+    }
+    output.push_str("</pre>\n");
+    output
+}
+
+/// Like [`append_highlighted_html_for_styled_line`], but wraps the line in a `<span>` with the id
+/// `L` followed by the line number and starts it with the number.
+///
+/// The number is a link to the line, padded with spaces to `width` characters and followed by a
+/// space. It has the [`gutter_foreground`] and [`gutter`] colors of the theme and can't be
+/// selected, so copying the code doesn't copy the numbers with it.
+///
+/// [`append_highlighted_html_for_styled_line`]: fn.append_highlighted_html_for_styled_line.html
+/// [`gutter_foreground`]: ../highlighting/struct.Theme.html#method.gutter_foreground
+/// [`gutter`]: ../highlighting/struct.Theme.html#method.gutter
+pub fn append_highlighted_html_for_numbered_line(v: &[(Style, &str)],
+                                                 bg: IncludeBackground,
+                                                 line_number: usize,
+                                                 width: usize,
+                                                 theme: &Theme,
+                                                 s: &mut String) {
+    write!(s, "<span id=\"L{0}\"><a href=\"#L{0}\" style=\"color:", line_number).unwrap();
+    write_css_color(s, theme.gutter_foreground());
+    s.push_str(";background-color:");
+    write_css_color(s, theme.gutter());
+    write!(s, ";text-decoration:none;user-select:none;\">{:>1$} </a>", line_number, width).unwrap();
+    append_highlighted_html_for_styled_line(v, bg, s);
+    s.push_str("</span>");
+}
+
+/// How to number the lines in [`highlighted_html_table_for_string`].
+///
+/// By default lines are numbered from 1 in decimal.
+///
+/// [`highlighted_html_table_for_string`]: fn.highlighted_html_table_for_string.html
+#[derive(Debug, Clone, Copy)]
+pub struct LineNumbers {
+    /// The number of the first line, e.g. for showing an excerpt of a file
+    pub start: usize,
+    /// Turns a line number into the text shown for it, which is escaped
+    pub format: fn(usize) -> String,
+}
+
+impl Default for LineNumbers {
+    fn default() -> LineNumbers {
+        LineNumbers {
+            start: 1,
+            format: |number| number.to_string(),
+        }
+    }
+}
+
+/// Creates highlighted HTML for a string as a table with the line numbers in one column and the
+/// code in the other, so that selecting and copying the code doesn't include the numbers.
+///
+/// The code is highlighted like [`highlighted_html_for_string`] does. The numbers are in the
+/// gutter colors of the theme and are links to themselves, with ids like `L12` for the line
+/// numbered 12, so pages can link to lines.
+///
+/// ```
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::html::{highlighted_html_table_for_string, LineNumbers};
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let syntax = ss.find_syntax_plain_text();
+/// let numbers = LineNumbers { start: 41, format: |number| format!("{:04}", number) };
+/// let html = highlighted_html_table_for_string("a\nb\n", &ss, syntax, &ts.themes["base16-ocean.dark"], numbers);
+/// assert!(html.contains("<a id=\"L42\" href=\"#L42\""));
+/// assert!(html.contains(">0042</a>"));
+/// ```
+///
+/// [`highlighted_html_for_string`]: fn.highlighted_html_for_string.html
+pub fn highlighted_html_table_for_string(s: &str,
+                                         ss: &SyntaxSet,
+                                         syntax: &SyntaxReference,
+                                         theme: &Theme,
+                                         numbers: LineNumbers)
+                                         -> String {
+    let mut highlighter = HighlightLines::new(syntax, theme);
+    let bg = theme.background();
+    let mut number_color = String::new();
+    write_css_color(&mut number_color, theme.gutter_foreground());
+    let mut gutter = String::new();
+    let mut code = String::new();
+
+    for (index, line) in LinesWithEndings::from(s).enumerate() {
+        let number = numbers.start + index;
+        writeln!(gutter, "<a id=\"L{0}\" href=\"#L{0}\" style=\"color:{1};text-decoration:none;\">{2}</a>",
+               number, number_color, Escape(&(numbers.format)(number))).unwrap();
+        let regions = highlighter.highlight(line, ss);
+        append_highlighted_html_for_styled_line(&regions[..], IncludeBackground::IfDifferent(bg), &mut code);
+    }
+
+    let mut output = String::from("<table style=\"border-collapse:collapse;background-color:");
+    write_css_color(&mut output, bg);
+    output.push_str(";\"><tr><td style=\"padding:0;text-align:right;vertical-align:top;user-select:none;background-color:");
+    write_css_color(&mut output, theme.gutter());
+    writeln!(output, ";\"><pre style=\"margin:0;\">{}</pre></td><td style=\"padding:0 0 0 1em;vertical-align:top;\"><pre style=\"margin:0;\">{}</pre></td></tr></table>", gutter, code).unwrap();
+    output
+}
+
 /// Output HTML for a line of code with `<span>` elements
 /// specifying classes for each token. The span elements are nested
 /// like the scope stack and the scopes are mapped to classes based
@@ -379,7 +714,7 @@ pub fn styled_line_to_highlighted_html(v: &[(Style, &str)], bg: IncludeBackgroun
 
 /// Like `styled_line_to_highlighted_html` but appends to a `String` for increased efficiency.
 /// In fact `styled_line_to_highlighted_html` is just a wrapper around this function.
-pub fn append_highlighted_html_for_styled_line(v: &[(Style, &str)], bg: IncludeBackground, mut s: &mut String) {
+pub fn append_highlighted_html_for_styled_line(v: &[(Style, &str)], bg: IncludeBackground, s: &mut String) {
     let mut prev_style: Option<&Style> = None;
     for &(ref style, text) in v.iter() {
         let unify_style = if let Some(ps) = prev_style {
@@ -401,23 +736,8 @@ pub fn append_highlighted_html_for_styled_line(v: &[(Style, &str)], bg: IncludeB
                 IncludeBackground::No => false,
                 IncludeBackground::IfDifferent(c) => (style.background != c),
             };
-            if include_bg {
-                write!(s, "background-color:").unwrap();
-                write_css_color(&mut s, style.background);
-                write!(s, ";").unwrap();
-            }
-            if style.font_style.contains(FontStyle::UNDERLINE) {
-                write!(s, "text-decoration:underline;").unwrap();
-            }
-            if style.font_style.contains(FontStyle::BOLD) {
-                write!(s, "font-weight:bold;").unwrap();
-            }
-            if style.font_style.contains(FontStyle::ITALIC) {
-                write!(s, "font-style:italic;").unwrap();
-            }
-            write!(s, "color:").unwrap();
-            write_css_color(&mut s, style.foreground);
-            write!(s, ";\">{}", Escape(text)).unwrap();
+            write_css_style(s, style, include_bg);
+            write!(s, "\">{}", Escape(text)).unwrap();
         }
     }
     if prev_style.is_some() {
@@ -425,6 +745,135 @@ pub fn append_highlighted_html_for_styled_line(v: &[(Style, &str)], bg: IncludeB
     }
 }
 
+/// Writes the inline CSS for a style, without the quotes of the `style` attribute
+fn write_css_style(s: &mut String, style: &Style, include_bg: bool) {
+    if include_bg {
+        write!(s, "background-color:").unwrap();
+        write_css_color(s, style.background);
+        write!(s, ";").unwrap();
+    }
+    if style.font_style.contains(FontStyle::UNDERLINE) {
+        write!(s, "text-decoration:underline;").unwrap();
+    }
+    if style.font_style.contains(FontStyle::BOLD) {
+        write!(s, "font-weight:bold;").unwrap();
+    }
+    if style.font_style.contains(FontStyle::ITALIC) {
+        write!(s, "font-style:italic;").unwrap();
+    }
+    write!(s, "color:").unwrap();
+    write_css_color(s, style.foreground);
+    write!(s, ";").unwrap();
+}
+
+/// A change to the lines of HTML of a document, see [`html_line_patches`].
+///
+/// Line numbers are zero-based and refer to the lines as they are after applying the patches
+/// before this one.
+///
+/// [`html_line_patches`]: fn.html_line_patches.html
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum LinePatch {
+    /// Replaces the HTML of a line
+    Replace { line: usize, html: String },
+    /// Inserts lines before the given line, which may be one past the last line
+    Insert { line: usize, html: Vec<String> },
+    /// Removes `count` lines starting at the given line
+    Remove { line: usize, count: usize },
+}
+
+/// Compares two highlighted versions of a document and returns the HTML for the lines that
+/// changed, for live previews that send small updates instead of whole documents.
+///
+/// Each line is rendered like [`styled_line_to_highlighted_html`] does. Unchanged lines at the
+/// start and end of the document are skipped, and lines in between are only sent if their
+/// highlighting changed, so typing on a line usually only results in a single `Replace`, even
+/// if it is the first of several inserted lines.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::Style;
+/// use syntect::html::{apply_line_patches, html_line_patches, IncludeBackground, LinePatch};
+///
+/// let plain = Style::default();
+/// let before = vec![vec![(plain, "a\n")], vec![(plain, "b\n")], vec![(plain, "c\n")]];
+/// let after = vec![vec![(plain, "a\n")], vec![(plain, "B\n")], vec![(plain, "x\n")], vec![(plain, "c\n")]];
+///
+/// let patches = html_line_patches(&before, &after, IncludeBackground::No);
+/// assert_eq!(patches.len(), 2);
+/// assert!(matches!(patches[0], LinePatch::Replace { line: 1, .. }));
+/// assert!(matches!(patches[1], LinePatch::Insert { line: 2, .. }));
+///
+/// // the client applies them to its copy of the lines
+/// let mut lines: Vec<String> = before.iter()
+///     .map(|line| syntect::html::styled_line_to_highlighted_html(line, IncludeBackground::No))
+///     .collect();
+/// apply_line_patches(&mut lines, &patches);
+/// assert_eq!(lines.len(), 4);
+/// ```
+///
+/// [`styled_line_to_highlighted_html`]: fn.styled_line_to_highlighted_html.html
+pub fn html_line_patches(previous: &[Vec<(Style, &str)>],
+                         current: &[Vec<(Style, &str)>],
+                         bg: IncludeBackground)
+                         -> Vec<LinePatch> {
+    let prefix = previous.iter().zip(current).take_while(|(a, b)| a == b).count();
+    let suffix = previous[prefix..].iter().rev()
+        .zip(current[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let old_lines = &previous[prefix..previous.len() - suffix];
+    let new_lines = &current[prefix..current.len() - suffix];
+
+    let mut patches = Vec::new();
+    for (i, (old, new)) in old_lines.iter().zip(new_lines).enumerate() {
+        if old != new {
+            patches.push(LinePatch::Replace {
+                line: prefix + i,
+                html: styled_line_to_highlighted_html(new, bg),
+            });
+        }
+    }
+    let common = old_lines.len().min(new_lines.len());
+    if new_lines.len() > common {
+        patches.push(LinePatch::Insert {
+            line: prefix + common,
+            html: new_lines[common..].iter().map(|line| styled_line_to_highlighted_html(line, bg)).collect(),
+        });
+    } else if old_lines.len() > common {
+        patches.push(LinePatch::Remove { line: prefix + common, count: old_lines.len() - common });
+    }
+    patches
+}
+
+/// Applies patches from [`html_line_patches`] to the HTML of each line of a document.
+///
+/// Patches that refer to lines that don't exist are ignored.
+///
+/// [`html_line_patches`]: fn.html_line_patches.html
+pub fn apply_line_patches(lines: &mut Vec<String>, patches: &[LinePatch]) {
+    for patch in patches {
+        match *patch {
+            LinePatch::Replace { line, ref html } => {
+                if let Some(old) = lines.get_mut(line) {
+                    old.clone_from(html);
+                }
+            }
+            LinePatch::Insert { line, ref html } => {
+                let line = line.min(lines.len());
+                lines.splice(line..line, html.iter().cloned());
+            }
+            LinePatch::Remove { line, count } => {
+                let start = line.min(lines.len());
+                let end = line.saturating_add(count).min(lines.len());
+                lines.drain(start..end);
+            }
+        }
+    }
+}
+
 /// Returns a `<pre style="...">\n` tag with the correct background color for the given theme.
 /// This is for if you want to roll your own HTML output, you probably just want to use
 /// `highlighted_html_for_string`.
@@ -438,13 +887,94 @@ pub fn append_highlighted_html_for_styled_line(v: &[(Style, &str)], bg: IncludeB
 /// You're responsible for creating the string `</pre>` to close this, I'm not gonna provide a
 /// helper for that :-)
 pub fn start_highlighted_html_snippet(t: &Theme) -> (String, Color) {
-    let c = t.settings.background.unwrap_or(Color::WHITE);
+    let c = t.background();
     (format!("<pre style=\"background-color:#{:02x}{:02x}{:02x};\">\n",
             c.r,
             c.g,
             c.b), c)
 }
 
+/// The snippets [`theme_preview_html`] shows, as pairs of a token to find the syntax with
+/// [`SyntaxSet::find_syntax_by_token`] and the code.
+///
+/// [`theme_preview_html`]: fn.theme_preview_html.html
+/// [`SyntaxSet::find_syntax_by_token`]: ../parsing/struct.SyntaxSet.html#method.find_syntax_by_token
+pub const THEME_PREVIEW_SAMPLES: &[(&str, &str)] = &[
+    ("rs", "// Sums the even numbers\nfn sum(xs: &[i32]) -> i32 {\n    xs.iter().filter(|&x| x % 2 == 0).sum()\n}\n"),
+    ("py", "@cache\ndef greet(name: str) -> str:\n    return f\"Hello, {name}!\"  # TODO\n"),
+    ("js", "const answer = { value: 42, ok: true };\nconsole.log(`answer: ${answer.value}`);\n"),
+    ("html", "<a href=\"/docs\" class=\"link\">Docs &amp; more</a>\n"),
+];
+
+/// Renders a compact HTML card that previews a theme, for example to show in a theme picker.
+///
+/// The card has the name of the theme, a palette of its background, foreground and the colors
+/// used in the samples, and the [`THEME_PREVIEW_SAMPLES`] highlighted with the theme. Samples
+/// for languages the syntax set doesn't have are left out. All styles are inline, the card is
+/// a `div` with the `syntect-theme-preview` class.
+///
+/// As with [`highlighted_html_for_string`], the syntax set has to be one for lines with
+/// newlines.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::html::theme_preview_html;
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::parsing::SyntaxSet;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let ts = ThemeSet::load_defaults();
+/// let cards: Vec<String> = ts.themes.values().map(|theme| theme_preview_html(theme, &ss)).collect();
+/// assert_eq!(cards.len(), ts.themes.len());
+/// ```
+///
+/// [`THEME_PREVIEW_SAMPLES`]: constant.THEME_PREVIEW_SAMPLES.html
+/// [`highlighted_html_for_string`]: fn.highlighted_html_for_string.html
+pub fn theme_preview_html(theme: &Theme, ss: &SyntaxSet) -> String {
+    let bg = theme.background();
+    let fg = theme.foreground();
+    let mut palette = vec![bg, fg];
+    let mut snippets = String::new();
+
+    for &(token, code) in THEME_PREVIEW_SAMPLES {
+        let syntax = match ss.find_syntax_by_token(token) {
+            Some(syntax) => syntax,
+            None => continue,
+        };
+        let mut highlighter = HighlightLines::new(syntax, theme);
+        write!(snippets, "<pre style=\"margin:0.5em 0 0 0;\" title=\"{}\">", Escape(&syntax.name)).unwrap();
+        for line in LinesWithEndings::from(code) {
+            let regions = highlighter.highlight(line, ss);
+            for &(style, _) in &regions {
+                if !palette.contains(&style.foreground) {
+                    palette.push(style.foreground);
+                }
+            }
+            append_highlighted_html_for_styled_line(&regions[..], IncludeBackground::IfDifferent(bg), &mut snippets);
+        }
+        snippets.push_str("</pre>\n");
+    }
+
+    let mut s = String::new();
+    s.push_str("<div class=\"syntect-theme-preview\" style=\"padding:0.5em;border-radius:4px;background-color:");
+    write_css_color(&mut s, bg);
+    s.push_str(";color:");
+    write_css_color(&mut s, fg);
+    writeln!(s, ";\">\n<div style=\"font-weight:bold;\">{}</div>", Escape(theme.name.as_deref().unwrap_or("Unnamed theme"))).unwrap();
+    s.push_str("<div>");
+    for color in palette {
+        let mut hex = String::new();
+        write_css_color(&mut hex, color);
+        write!(s, "<span title=\"{0}\" style=\"display:inline-block;width:1em;height:1em;margin-right:2px;\
+                   border:1px solid #8888;background-color:{0};\"></span>", hex).unwrap();
+    }
+    s.push_str("</div>\n");
+    s.push_str(&snippets);
+    s.push_str("</div>\n");
+    s
+}
+
 #[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
 #[cfg(test)]
 mod tests {
@@ -477,6 +1007,65 @@ mod tests {
         assert_eq!(html2, include_str!("../testdata/test1.html").trim_end());
     }
 
+    #[test]
+    fn theme_preview() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let theme = &ts.themes["InspiredGitHub"];
+        let html = theme_preview_html(theme, &ss);
+        assert!(html.starts_with("<div class=\"syntect-theme-preview\" style=\"padding:0.5em;border-radius:4px;background-color:#ffffff;color:#323232;\">\n"));
+        assert!(html.contains("<div style=\"font-weight:bold;\">GitHub</div>"));
+        assert!(html.contains("<span title=\"#ffffff\""));
+        assert!(html.contains("title=\"Python\""));
+        assert!(html.contains("&amp;amp;"));
+        assert!(html.ends_with("</pre>\n</div>\n"));
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_plain_text_syntax();
+        let html = theme_preview_html(theme, &builder.build());
+        assert!(!html.contains("<pre"));
+    }
+
+    #[test]
+    fn line_patches() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let theme = &ts.themes["InspiredGitHub"];
+        let highlight = |text| {
+            let mut highlighter = HighlightLines::new(syntax, theme);
+            LinesWithEndings::from(text).map(|line| highlighter.highlight(line, &ss)).collect::<Vec<_>>()
+        };
+        let render = |lines: &[Vec<(Style, &str)>]| -> Vec<String> {
+            lines.iter().map(|line| styled_line_to_highlighted_html(line, IncludeBackground::No)).collect()
+        };
+
+        let v1 = highlight("x = 1\ny = 2\nz = 3\n");
+        assert!(html_line_patches(&v1, &v1, IncludeBackground::No).is_empty());
+
+        // opening a string changes the highlighting of all following lines
+        let v2 = highlight("x = 1\ny = \"\"\"2\nz = 3\n");
+        let patches = html_line_patches(&v1, &v2, IncludeBackground::No);
+        assert_eq!(patches.len(), 2);
+        let mut lines = render(&v1);
+        apply_line_patches(&mut lines, &patches);
+        assert_eq!(lines, render(&v2));
+
+        let v3 = highlight("x = 1\n");
+        let patches = html_line_patches(&v2, &v3, IncludeBackground::No);
+        assert_eq!(patches, vec![LinePatch::Remove { line: 1, count: 2 }]);
+        apply_line_patches(&mut lines, &patches);
+        assert_eq!(lines, render(&v3));
+
+        let patches = html_line_patches(&v3, &v1, IncludeBackground::No);
+        assert_eq!(patches.len(), 1);
+        apply_line_patches(&mut lines, &patches);
+        assert_eq!(lines, render(&v1));
+
+        let json = serde_json::to_string(&patches[0]).unwrap();
+        assert!(json.starts_with("{\"op\":\"insert\",\"line\":1,\"html\":["), "{}", json);
+    }
+
     #[test]
     fn strings() {
         let ss = SyntaxSet::load_defaults_newlines();
@@ -558,4 +1147,106 @@ fn main() {
         let html = html_generator.finalize();
         assert_eq!(html, "<span class=\"source rust\"><span class=\"comment line double-slash rust\"><span class=\"punctuation definition comment rust\">//</span> Rust source</span>\n<span class=\"meta function rust\"><span class=\"meta function rust\"><span class=\"storage type function rust\">fn</span> </span><span class=\"entity name function rust\">main</span></span><span class=\"meta function rust\"><span class=\"meta function parameters rust\"><span class=\"punctuation section parameters begin rust\">(</span></span><span class=\"meta function rust\"><span class=\"meta function parameters rust\"><span class=\"punctuation section parameters end rust\">)</span></span></span></span><span class=\"meta function rust\"> </span><span class=\"meta function rust\"><span class=\"meta block rust\"><span class=\"punctuation section block begin rust\">{</span>\n    <span class=\"support macro rust\">println!</span><span class=\"meta group rust\"><span class=\"punctuation section group begin rust\">(</span></span><span class=\"meta group rust\"><span class=\"string quoted double rust\"><span class=\"punctuation definition string begin rust\">&quot;</span>Hello World!<span class=\"punctuation definition string end rust\">&quot;</span></span></span><span class=\"meta group rust\"><span class=\"punctuation section group end rust\">)</span></span><span class=\"punctuation terminator rust\">;</span>\n</span><span class=\"meta block rust\"><span class=\"punctuation section block end rust\">}</span></span></span>\n</span>");
     }
+
+    #[test]
+    fn can_map_atoms_to_classes_and_back() {
+        let escaped = ClassStyle::SpacedEscaped { prefix: "" };
+        let prefixed = ClassStyle::SpacedEscaped { prefix: "sy-" };
+        let cases = [
+            ("rust", "rust"),
+            ("double-slash", "double-slash"),
+            ("c++", "c_2b_2b"),
+            ("1c", "_31c"),
+            ("-x", "_2dx"),
+            ("a_b", "a__b"),
+            ("a_5fb", "a__5fb"),
+            ("a.b", "a_2eb"),
+            ("é", "_c3_a9"),
+            ("", ""),
+        ];
+        let mut classes = Vec::new();
+        for &(atom, class) in &cases {
+            assert_eq!(atom_to_class(atom, ClassStyle::Spaced), atom);
+            assert_eq!(atom_to_class(atom, ClassStyle::SpacedPrefixed { prefix: "sy-" }), format!("sy-{}", atom));
+            assert_eq!(atom_to_class(atom, escaped), class);
+            assert_eq!(class_to_atom(class, escaped).as_deref(), Some(atom));
+            let prefixed_class = atom_to_class(atom, prefixed);
+            assert_eq!(prefixed_class, format!("sy-{}", class));
+            assert_eq!(class_to_atom(&prefixed_class, prefixed).as_deref(), Some(atom));
+            classes.push(class);
+        }
+        classes.sort_unstable();
+        classes.dedup();
+        assert_eq!(classes.len(), cases.len());
+
+        for invalid in &["sy-_", "sy-_2", "sy-_zz", "sy-_c3", "sy-_61", "sy-a_", "rust"] {
+            assert_eq!(class_to_atom(invalid, prefixed), None, "{}", invalid);
+        }
+        assert_eq!(class_to_atom("_61", escaped), None);
+        assert_eq!(class_to_atom("sy-c++", ClassStyle::SpacedPrefixed { prefix: "sy-" }).as_deref(), Some("c++"));
+        assert_eq!(class_to_atom("c++", ClassStyle::SpacedPrefixed { prefix: "sy-" }), None);
+    }
+
+    #[test]
+    fn can_annotate_html_with_provenance() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let ts = ThemeSet::load_defaults();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let html = highlighted_html_with_provenance("x = 'a'\n", &ss, syntax, &ts.themes["base16-ocean.dark"]);
+        assert!(html.starts_with("<pre style=\"background-color:#2b303b;\">\n<span style=\"color:#c0c5ce;\" data-scopes=\"source.python meta.qualified-name.python meta.generic-name.python\" data-foreground=\"default\" data-background=\"default\" data-font-style=\"default\">x</span>"));
+        assert!(html.contains("<span style=\"color:#a3be8c;\" data-scopes=\"source.python meta.string.python string.quoted.single.python\" data-foreground=\"rule 13 `string` (score 64)\" data-background=\"default\" data-font-style=\"default\">a</span>"));
+        assert!(html.ends_with("\n</span></pre>\n"));
+    }
+
+    #[test]
+    fn can_generate_css_for_theme() {
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        let css = css_for_theme_with_class_style(&theme, ClassStyle::SpacedPrefixed { prefix: "sy-" });
+        assert!(css.starts_with("/*\n * theme \"Monokai\" generated by syntect\n */\n\n.sy-code {\n color: #f8f8f2;\n background-color: #272822;\n}\n\n"));
+        assert!(css.contains("\n.sy-string {\n color: #e6db74;\n}\n"));
+        assert!(css.contains("\n.sy-entity.sy-name.sy-class {\n color: #a6e22e;\n text-decoration: underline;\n font-weight: normal;\n font-style: normal;\n}\n"));
+        assert!(css.contains("\n.sy-storage.sy-type {\n color: #66d9ef;\n text-decoration: none;\n font-weight: normal;\n font-style: italic;\n}\n"));
+    }
+
+    #[test]
+    fn can_number_lines() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        let text: String = (1..=10).map(|i| format!("{}\n", i)).collect();
+        let html = highlighted_html_for_string_with_line_numbers(&text, &ss, ss.find_syntax_plain_text(), &theme);
+        let number_style = "color:#90908a;background-color:#272822;text-decoration:none;user-select:none;";
+        assert!(html.starts_with(&format!("<pre style=\"background-color:#272822;\">\n<span id=\"L1\"><a href=\"#L1\" style=\"{}\"> 1 </a><span style=\"color:#f8f8f2;\">1\n</span></span><span id=\"L2\">", number_style)));
+        assert!(html.ends_with(&format!("<span id=\"L10\"><a href=\"#L10\" style=\"{}\">10 </a><span style=\"color:#f8f8f2;\">10\n</span></span></pre>\n", number_style)));
+    }
+
+    #[test]
+    fn can_add_attributes_to_spans() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        let syntax = ss.find_syntax_by_extension("py").unwrap();
+        let mut tokens = Vec::new();
+        let html = highlighted_html_for_string_with_attributes("x = \"<\"\n", &ss, syntax, &theme, |scopes, text| {
+            tokens.push(text.to_owned());
+            vec![("data-depth".to_owned(), scopes.len().to_string()), ("title".to_owned(), text.to_owned())]
+        });
+        assert_eq!(&tokens[..], &["x", " ", "=", " ", "\"", "<", "\"", "\n"][..]);
+        assert!(html.contains("<span style=\"color:#e6db74;\" data-depth=\"3\" title=\"&lt;\">&lt;</span>"));
+    }
+
+    #[test]
+    fn can_highlight_as_table() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let theme = ThemeSet::get_theme("testdata/Monokai/Monokai.tmTheme").unwrap();
+        let numbers = LineNumbers { start: 9, ..LineNumbers::default() };
+        let html = highlighted_html_table_for_string("a\n<b\n", &ss, ss.find_syntax_plain_text(), &theme, numbers);
+        assert_eq!(html, concat!(
+            "<table style=\"border-collapse:collapse;background-color:#272822;\"><tr>",
+            "<td style=\"padding:0;text-align:right;vertical-align:top;user-select:none;background-color:#272822;\"><pre style=\"margin:0;\">",
+            "<a id=\"L9\" href=\"#L9\" style=\"color:#90908a;text-decoration:none;\">9</a>\n",
+            "<a id=\"L10\" href=\"#L10\" style=\"color:#90908a;text-decoration:none;\">10</a>\n",
+            "</pre></td><td style=\"padding:0 0 0 1em;vertical-align:top;\"><pre style=\"margin:0;\">",
+            "<span style=\"color:#f8f8f2;\">a\n</span><span style=\"color:#f8f8f2;\">&lt;b\n</span>",
+            "</pre></td></tr></table>\n",
+        ));
+    }
 }
@@ -33,13 +33,25 @@ extern crate pretty_assertions;
 pub mod dumps;
 #[cfg(feature = "parsing")]
 pub mod easy;
+#[cfg(feature = "encoding")]
+pub mod encoding;
 #[cfg(feature = "html")]
 mod escape;
 pub mod highlighting;
 #[cfg(feature = "html")]
 pub mod html;
 pub mod parsing;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "parsing")]
+pub mod stress;
+#[cfg(feature = "parsing")]
+pub mod symbols;
+#[cfg(feature = "parsing")]
+pub mod syntax_tests;
 pub mod util;
+#[cfg(feature = "watch")]
+pub mod watch;
 
 use std::io::Error as IoError;
 use std::error::Error;
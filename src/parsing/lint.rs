@@ -0,0 +1,249 @@
+//! Checks for mistakes in syntax definitions that loading them doesn't catch.
+use super::regex::Regex;
+use super::scope::Scope;
+use super::syntax_definition::*;
+use std::collections::HashSet;
+use std::fmt;
+
+/// A likely mistake in a syntax definition, see [`SyntaxDefinition::lint`].
+///
+/// Displays as a message with the context and pattern it is about.
+///
+/// [`SyntaxDefinition::lint`]: struct.SyntaxDefinition.html#method.lint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lint {
+    /// The name of the context with the mistake
+    pub context: String,
+    /// The index of the pattern in the patterns of the context, `None` if the mistake is in the
+    /// context itself
+    pub pattern: Option<usize>,
+    pub kind: LintKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LintKind {
+    /// The context isn't used by `main`, the `prototype` or any context they use, directly or
+    /// through other contexts. Contexts that are only used by other syntaxes are reported as well.
+    UnusedContext,
+    /// A scope with an empty atom or characters that scope selectors can't match
+    InvalidScope(String),
+    /// The pattern can match the empty string but has no operation, and the parser ignores such
+    /// matches
+    EmptyMatchWithoutOperation,
+    /// A capture refers to a group that the regex doesn't have
+    MissingCaptureGroup {
+        group: usize,
+        /// The number of groups in the regex
+        groups: usize,
+    },
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.context)?;
+        if let Some(pattern) = self.pattern {
+            write!(f, ", pattern {}", pattern)?;
+        }
+        match self.kind {
+            LintKind::UnusedContext => write!(f, ": unused context"),
+            LintKind::InvalidScope(ref scope) => write!(f, ": invalid scope {:?}", scope),
+            LintKind::EmptyMatchWithoutOperation =>
+                write!(f, ": pattern can match the empty string but has no operation"),
+            LintKind::MissingCaptureGroup { group, groups } =>
+                write!(f, ": capture {} refers to a missing group, the regex has {}", group, groups),
+        }
+    }
+}
+
+impl SyntaxDefinition {
+    /// Looks for likely mistakes in the definition: unused contexts, invalid scope names,
+    /// patterns that can match the empty string but do nothing, and captures of groups that the
+    /// regex doesn't have.
+    ///
+    /// This compiles the regexes, and is meant to be used on definitions before they are added to
+    /// a syntax set, like by a tool that checks a package. The lints are sorted by context name.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxDefinition;
+    ///
+    /// let syntax = SyntaxDefinition::load_from_str(r#"
+    /// name: Example
+    /// scope: source.example
+    /// contexts:
+    ///   main:
+    ///     - match: '(\w+) ='
+    ///       captures:
+    ///         2: variable.other
+    ///   forgotten:
+    ///     - match: x
+    /// "#, true, None).unwrap();
+    /// let lints: Vec<String> = syntax.lint().iter().map(|lint| lint.to_string()).collect();
+    /// assert_eq!(lints, vec![
+    ///     "forgotten: unused context",
+    ///     "main, pattern 0: capture 2 refers to a missing group, the regex has 1",
+    /// ]);
+    /// ```
+    pub fn lint(&self) -> Vec<Lint> {
+        let used = self.used_contexts();
+        let mut contexts: Vec<_> = self.contexts.iter().collect();
+        contexts.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut lints = Vec::new();
+        for (name, context) in contexts {
+            let mut lint = |pattern, kind| lints.push(Lint { context: name.clone(), pattern, kind });
+            if !used.contains(name.as_str()) {
+                lint(None, LintKind::UnusedContext);
+            }
+            for scope in context.meta_scope.iter().chain(&context.meta_content_scope) {
+                if let Some(scope) = invalid_scope(*scope) {
+                    lint(None, LintKind::InvalidScope(scope));
+                }
+            }
+            for (index, pattern) in context.patterns.iter().enumerate() {
+                let match_pat = match *pattern {
+                    Pattern::Match(ref match_pat) => match_pat,
+                    Pattern::Include(_) => continue,
+                };
+                let capture_scopes = match_pat.captures.iter().flatten().flat_map(|(_, scopes)| scopes);
+                for scope in match_pat.scope.iter().chain(capture_scopes) {
+                    if let Some(scope) = invalid_scope(*scope) {
+                        lint(Some(index), LintKind::InvalidScope(scope));
+                    }
+                }
+                if let MatchOperation::None = match_pat.operation {
+                    // patterns with backreferences only get their regex when they are used
+                    let regex = &match_pat.regex;
+                    if !match_pat.has_captures && Regex::try_compile(regex.regex_str()).is_none() && regex.is_match("") {
+                        lint(Some(index), LintKind::EmptyMatchWithoutOperation);
+                    }
+                }
+                // the loader drops captures of missing groups, but definitions built in code
+                // can still have them
+                let groups = match_pat.regex.capture_groups();
+                let dropped = context.dropped_captures().iter()
+                    .filter(|&&(pattern, _)| pattern == index)
+                    .map(|&(_, group)| group);
+                let captured = match_pat.captures.iter().flatten().map(|&(group, _)| group);
+                for group in captured.chain(dropped) {
+                    if group > groups {
+                        lint(Some(index), LintKind::MissingCaptureGroup { group, groups });
+                    }
+                }
+            }
+        }
+        lints
+    }
+
+    /// Finds the contexts that can be reached from the entry points of the syntax
+    fn used_contexts(&self) -> HashSet<&str> {
+        let mut used = HashSet::new();
+        let mut queue: Vec<&str> = ["__start", "__main", "main", "prototype"].iter()
+            .filter(|name| self.contexts.contains_key(**name))
+            .cloned()
+            .collect();
+        while let Some(name) = queue.pop() {
+            if !used.insert(name) {
+                continue;
+            }
+            for pattern in &self.contexts[name].patterns {
+                let references: Vec<&ContextReference> = match *pattern {
+                    Pattern::Include(ref reference) => vec![reference],
+                    Pattern::Match(ref match_pat) => {
+                        let operation_refs = match match_pat.operation {
+                            MatchOperation::Push(ref refs) |
+                            MatchOperation::Set(ref refs) |
+                            MatchOperation::Branch { alternatives: ref refs, .. } => &refs[..],
+                            MatchOperation::Pop | MatchOperation::None | MatchOperation::Fail(_) => &[],
+                        };
+                        operation_refs.iter().chain(&match_pat.with_prototype).collect()
+                    }
+                };
+                for reference in references {
+                    if let Some((name, _)) = self.local_context(reference) {
+                        queue.push(name);
+                    }
+                }
+            }
+        }
+        used
+    }
+
+    /// Finds the context of this syntax that a reference points to
+    fn local_context(&self, reference: &ContextReference) -> Option<(&str, &Context)> {
+        let name = match *reference {
+            ContextReference::Named(ref name) | ContextReference::Inline(ref name) => {
+                if name == "$top_level_main" { "main" } else { name }
+            }
+            ContextReference::ByScope { scope, ref sub_context } if scope == self.scope =>
+                sub_context.as_ref().map_or("main", |s| s),
+            ContextReference::File { ref name, ref sub_context } if *name == self.name =>
+                sub_context.as_ref().map_or("main", |s| s),
+            _ => return None,
+        };
+        self.contexts.get_key_value(name).map(|(name, context)| (name.as_str(), context))
+    }
+}
+
+/// Returns the scope as a string if it is invalid
+fn invalid_scope(scope: Scope) -> Option<String> {
+    let scope = scope.build_string();
+    let invalid_atom = |atom: &str| atom.is_empty() || atom.contains(|c: char| c.is_whitespace() || ",|&()".contains(c));
+    if scope.split('.').any(invalid_atom) {
+        Some(scope)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "yaml-load")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_lint_syntaxes() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+name: Lints
+scope: source.lints
+contexts:
+  prototype:
+    - include: comments
+  main:
+    - match: a
+      push: [string, scope:source.lints#by_scope]
+    - match: \s*
+      scope: whitespace
+    - match: '(?=x)'
+      pop: true
+    - match: '(a)(?:b)(c)'
+      captures:
+        1: a
+        2: c
+        3: missing
+    - match: 'x*'
+      scope: source..x
+  comments:
+    - meta_scope: comment.(
+  string:
+    - match: '"'
+      set:
+        - match: y
+  by_scope: []
+  unused:
+    - include: also_unused
+  also_unused: []
+"#, true, None).unwrap();
+        let lints: Vec<String> = syntax.lint().iter().map(|lint| lint.to_string()).collect();
+        assert_eq!(lints, vec![
+            "also_unused: unused context",
+            "comments: invalid scope \"comment.(\"",
+            "main, pattern 1: pattern can match the empty string but has no operation",
+            "main, pattern 3: capture 3 refers to a missing group, the regex has 2",
+            "main, pattern 4: invalid scope \"source..x\"",
+            "main, pattern 4: pattern can match the empty string but has no operation",
+            "unused: unused context",
+        ]);
+    }
+}
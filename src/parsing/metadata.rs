@@ -2,9 +2,11 @@
 //! rules, comment markers, and other syntax-specific things.
 
 use std::collections::BTreeMap;
+use std::ptr;
 use std::path::PathBuf;
 use std::fs::File;
 use std::io::BufReader;
+use std::ops::Range;
 use std::str::FromStr;
 
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -55,6 +57,18 @@ pub struct MetadataItems {
     pub disable_indent_next_line_pattern: Option<Regex>,
     pub unindented_line_pattern: Option<Regex>,
     pub indent_parens: Option<bool>,
+    /// Characters, in addition to alphanumerics and `_`, that are considered part of a word
+    /// for things like double-click selection and word motion (e.g. `-` in CSS).
+    pub word_characters: Option<String>,
+    /// Characters that separate words, like Sublime Text's `word_separators` setting. When this
+    /// is set, every other character that isn't whitespace is part of a word.
+    pub word_separators: Option<String>,
+    /// Pairs of opening and closing strings (brackets, quotes) that an editor should insert
+    /// together when the opening one is typed.
+    pub smart_typing_pairs: Option<Vec<(String, String)>>,
+    /// Whether text with this scope is a symbol, like a function or class name, that should be
+    /// listed in a symbol index like Sublime Text's Goto Symbol.
+    pub show_in_symbol_list: Option<bool>,
     #[serde(default)]
     pub shell_variables: BTreeMap<String, String>,
     /// For convenience; this is the first value in `shell_variables`
@@ -93,6 +107,10 @@ const KEYS_WE_USE: &[&str] = &[
     "disableIndentNextLinePattern",
     "unIndentedLinePattern",
     "indentParens",
+    "wordCharacters",
+    "wordSeparators",
+    "smartTypingPairs",
+    "showInSymbolList",
     "shellVariables",
 ];
 
@@ -179,7 +197,49 @@ fn append_vars(obj: &mut Dict, vars: Settings, scope: &str) {
     }
 }
 
+lazy_static! {
+    static ref PAIRING_SUPPRESSED_SCOPES: [Scope; 2] = [
+        Scope::new("string").unwrap(),
+        Scope::new("comment").unwrap(),
+    ];
+}
+
 impl Metadata {
+    /// Returns the closing string to insert when `opening` is typed at a position with the
+    /// given scope stack, or `None` if no auto-pairing should happen there.
+    ///
+    /// Pairing is suppressed inside `string` and `comment` scopes, unless there is metadata
+    /// that specifically targets the string or comment and provides its own pairs.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use] extern crate serde_json;
+    /// # use syntect::parsing::*;
+    /// # use std::str::FromStr;
+    /// let items = json!({ "smartTypingPairs": [["(", ")"], ["\"", "\""]] });
+    /// # let items = items.as_object().cloned().unwrap();
+    /// let rust = MetadataSet::from_raw(("source.rust".into(), items)).unwrap();
+    /// let metadata = Metadata { scoped_metadata: vec![rust] };
+    ///
+    /// let code = ScopeStack::from_str("source.rust").unwrap();
+    /// assert_eq!(metadata.auto_pair(code.as_slice(), "("), Some(")"));
+    ///
+    /// let string = ScopeStack::from_str("source.rust string.quoted.double").unwrap();
+    /// assert_eq!(metadata.auto_pair(string.as_slice(), "("), None);
+    /// ```
+    pub fn auto_pair(&self, scope: &[Scope], opening: &str) -> Option<&str> {
+        let mut scoped = self.metadata_for_scope(scope);
+        let suppressed_at = scope.iter()
+            .rposition(|s| PAIRING_SUPPRESSED_SCOPES.iter().any(|p| p.is_prefix_of(*s)));
+        if let Some(idx) = suppressed_at {
+            // only keep metadata which doesn't also apply outside the string or comment
+            let outer = self.metadata_for_scope(&scope[..idx]);
+            scoped.items.retain(|(_, set)| !outer.items.iter().any(|(_, o)| ptr::eq(*o, *set)));
+        }
+        scoped.closing_pair(opening)
+    }
+
     /// For a given stack of scopes, returns a [`ScopedMetadata`] object which provides convenient
     /// access to metadata items which match the stack.
     ///
@@ -238,6 +298,12 @@ impl MetadataSet {
            .and_then(MetadataSet::get_line_comment_marker);
        let block_comment = settings.get("shellVariables").and_then(|v| v.as_object())
            .and_then(MetadataSet::get_block_comment_markers);
+       // `.tmPreferences` files usually write this flag as the integer 1 or 0
+       if let Some(flag) = settings.get_mut("showInSymbolList") {
+           if let Some(i) = flag.as_i64() {
+               *flag = (i != 0).into();
+           }
+       }
 
 
         let mut items: MetadataItems = serde_json::from_value(settings.into())
@@ -354,8 +420,171 @@ impl<'a> ScopedMetadata<'a> {
         self.items[idx].1.items.block_comment.as_ref().map(|(a, b)| (a.as_str(), b.as_str()))
     }
 
+    /// Returns the edits that comment out the lines, or uncomment them if they are all commented
+    /// already, like Sublime Text's Toggle Comment.
+    ///
+    /// The [`line_comment`] marker is used if there is one, it's inserted at the smallest
+    /// indentation of the lines that aren't blank. Otherwise the lines are wrapped in the
+    /// [`block_comment`] markers. Trailing newlines of the lines are left alone. The edits are
+    /// in the order of the text and don't overlap, so they can be applied from last to first.
+    /// There are none if there are no comment markers or the lines are blank.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use] extern crate serde_json;
+    /// # use syntect::parsing::*;
+    /// let items = json!({ "shellVariables": { "TM_COMMENT_START": "// " } });
+    /// # let items = items.as_object().cloned().unwrap();
+    /// let js = MetadataSet::from_raw(("source.js".into(), items)).unwrap();
+    /// let metadata = Metadata { scoped_metadata: vec![js] };
+    /// let scoped = metadata.metadata_for_scope(&[Scope::new("source.js").unwrap()]);
+    ///
+    /// let mut lines = vec!["  a();".to_owned(), "".to_owned(), "    b();".to_owned()];
+    /// let apply = |lines: &mut Vec<String>, edits: Vec<LineEdit>| {
+    ///     for edit in edits.into_iter().rev() {
+    ///         lines[edit.line].replace_range(edit.range, &edit.text);
+    ///     }
+    /// };
+    /// let edits = scoped.toggle_comment(&lines);
+    /// apply(&mut lines, edits);
+    /// assert_eq!(lines, vec!["  // a();", "", "  //   b();"]);
+    ///
+    /// let edits = scoped.toggle_comment(&lines);
+    /// apply(&mut lines, edits);
+    /// assert_eq!(lines, vec!["  a();", "", "    b();"]);
+    /// ```
+    ///
+    /// [`line_comment`]: #method.line_comment
+    /// [`block_comment`]: #method.block_comment
+    pub fn toggle_comment<S: AsRef<str>>(&self, lines: &[S]) -> Vec<LineEdit> {
+        let lines: Vec<&str> = lines.iter()
+            .map(|line| line.as_ref().trim_end_matches(&['\r', '\n'][..]))
+            .collect();
+        if let Some(marker) = self.line_comment() {
+            toggle_line_comments(&lines, marker)
+        } else if let Some((start, end)) = self.block_comment() {
+            toggle_block_comment(&lines, start, end)
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The extra word characters for this scope, if any metadata provides them, either as
+    /// `wordCharacters` or as a `TM_WORD_CHARACTERS` shell variable.
+    pub fn word_characters(&self) -> Option<&str> {
+        self.best_match(|items| {
+            items.word_characters.as_deref()
+                .or_else(|| items.shell_variables.get("TM_WORD_CHARACTERS").map(|s| s.as_str()))
+        })
+    }
+
+    /// The characters that separate words in this scope, if any metadata provides them.
+    pub fn word_separators(&self) -> Option<&str> {
+        self.best_match(|items| items.word_separators.as_deref())
+    }
+
+    /// Returns true if `c` is part of a word in this scope.
+    ///
+    /// Anything listed in [`word_characters`] is a word character. If there are
+    /// [`word_separators`], all other characters except them and whitespace are as well,
+    /// otherwise only alphanumerics and `_` are.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use] extern crate serde_json;
+    /// # use syntect::parsing::*;
+    /// let items = json!({ "wordSeparators": "./\\()\"':,;<>~!@#$%^&*|+=[]{}`?" });
+    /// # let items = items.as_object().cloned().unwrap();
+    /// let css = MetadataSet::from_raw(("source.css".into(), items)).unwrap();
+    /// let metadata = Metadata { scoped_metadata: vec![css] };
+    ///
+    /// let css = metadata.metadata_for_scope(&[Scope::new("source.css").unwrap()]);
+    /// assert!(css.is_word_char('-'));
+    /// assert!(!css.is_word_char('.'));
+    /// let rust = metadata.metadata_for_scope(&[Scope::new("source.rust").unwrap()]);
+    /// assert!(!rust.is_word_char('-'));
+    /// ```
+    ///
+    /// [`word_characters`]: #method.word_characters
+    /// [`word_separators`]: #method.word_separators
+    pub fn is_word_char(&self, c: char) -> bool {
+        if matches!(self.word_characters(), Some(chars) if chars.contains(c)) {
+            return true;
+        }
+        match self.word_separators() {
+            Some(separators) => !c.is_whitespace() && !separators.contains(c),
+            None => c.is_alphanumeric() || c == '_',
+        }
+    }
+
+    /// Splits a line into the byte ranges of the words it contains, according to the word
+    /// characters of this scope.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # #[macro_use] extern crate serde_json;
+    /// # use syntect::parsing::*;
+    /// let items = json!({ "wordCharacters": "-" });
+    /// # let items = items.as_object().cloned().unwrap();
+    /// let css = MetadataSet::from_raw(("source.css".into(), items)).unwrap();
+    /// let metadata = Metadata { scoped_metadata: vec![css] };
+    /// let scoped = metadata.metadata_for_scope(&[Scope::new("source.css").unwrap()]);
+    ///
+    /// let line = "a.foo-bar { }";
+    /// let words: Vec<&str> = scoped.word_ranges(line).into_iter().map(|r| &line[r]).collect();
+    /// assert_eq!(words, vec!["a", "foo-bar"]);
+    /// ```
+    pub fn word_ranges(&self, line: &str) -> Vec<Range<usize>> {
+        let mut words = Vec::new();
+        let mut start = None;
+        for (i, c) in line.char_indices() {
+            match (self.is_word_char(c), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    words.push(s..i);
+                    start = None;
+                }
+                _ => (),
+            }
+        }
+        if let Some(s) = start {
+            words.push(s..line.len());
+        }
+        words
+    }
+
+    /// Returns the byte range of the word containing (or starting at) the byte index `index`,
+    /// which is what double-click selection would select.
+    pub fn word_at(&self, line: &str, index: usize) -> Option<Range<usize>> {
+        self.word_ranges(line).into_iter().find(|r| r.start <= index && index < r.end)
+    }
+
+    /// Whether text with this scope should be listed as a symbol, if any metadata says so.
+    pub fn show_in_symbol_list(&self) -> Option<bool> {
+        self.best_match(|items| items.show_in_symbol_list)
+    }
+
+    /// The auto-pairing brackets and quotes for this scope, if any metadata provides them.
+    pub fn smart_typing_pairs(&self) -> Option<&'a [(String, String)]> {
+        self.best_match(|items| items.smart_typing_pairs.as_deref())
+    }
+
+    /// Returns the string that should be inserted to close `opening`, if `opening` is
+    /// one of the [`smart_typing_pairs`] for this scope.
+    ///
+    /// [`smart_typing_pairs`]: #method.smart_typing_pairs
+    pub fn closing_pair(&self, opening: &str) -> Option<&'a str> {
+        self.smart_typing_pairs()?
+            .iter()
+            .find(|(open, _)| open == opening)
+            .map(|(_, close)| close.as_str())
+    }
+
     fn best_match<T, F>(&self, f: F) -> Option<T>
-        where F: FnMut(&MetadataItems) -> Option<T>
+        where F: FnMut(&'a MetadataItems) -> Option<T>
     {
         self.items.iter()
             .map(|(_, meta_set)| &meta_set.items)
@@ -368,6 +597,81 @@ impl<'a> ScopedMetadata<'a> {
     }
 }
 
+/// A replacement of a part of a line, see [`ScopedMetadata::toggle_comment`]
+///
+/// [`ScopedMetadata::toggle_comment`]: struct.ScopedMetadata.html#method.toggle_comment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEdit {
+    /// The index of the line
+    pub line: usize,
+    /// The byte range of the line to replace, which is empty for insertions
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+fn indentation(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+fn toggle_line_comments(lines: &[&str], marker: &str) -> Vec<LineEdit> {
+    let non_blank: Vec<usize> = (0..lines.len()).filter(|&i| !lines[i].trim().is_empty()).collect();
+    // markers usually end with a space, which lines that were commented by hand might lack
+    let trimmed = marker.trim_end();
+    let commented = non_blank.iter().all(|&i| lines[i].trim_start().starts_with(trimmed));
+    if commented {
+        non_blank.into_iter().map(|i| {
+            let start = indentation(lines[i]);
+            let mut end = start + trimmed.len();
+            if lines[i][end..].starts_with(&marker[trimmed.len()..]) {
+                end += marker.len() - trimmed.len();
+            }
+            LineEdit { line: i, range: start..end, text: String::new() }
+        }).collect()
+    } else {
+        let indent = non_blank.iter().map(|&i| indentation(lines[i])).min().unwrap_or(0);
+        non_blank.into_iter()
+            .map(|i| LineEdit { line: i, range: indent..indent, text: marker.to_owned() })
+            .collect()
+    }
+}
+
+fn toggle_block_comment(lines: &[&str], start: &str, end: &str) -> Vec<LineEdit> {
+    let first = match lines.iter().position(|line| !line.trim().is_empty()) {
+        Some(first) => first,
+        None => return Vec::new(),
+    };
+    let last = lines.iter().rposition(|line| !line.trim().is_empty()).unwrap();
+    let (trimmed_start, trimmed_end) = (start.trim_end(), end.trim_start());
+    let (first_line, last_line) = (lines[first].trim_start(), lines[last].trim_end());
+    let commented = first_line.starts_with(trimmed_start) && last_line.ends_with(trimmed_end)
+        && (first != last || first_line.trim_end().len() >= trimmed_start.len() + trimmed_end.len());
+
+    let indent = indentation(lines[first]);
+    let line_end = last_line.len();
+    if !commented {
+        return vec![
+            LineEdit { line: first, range: indent..indent, text: start.to_owned() },
+            LineEdit { line: last, range: line_end..line_end, text: end.to_owned() },
+        ];
+    }
+    let mut start_end = indent + trimmed_start.len();
+    if lines[first][start_end..].starts_with(&start[trimmed_start.len()..]) {
+        start_end += start.len() - trimmed_start.len();
+    }
+    let mut end_start = line_end - trimmed_end.len();
+    if lines[last][..end_start].ends_with(&end[..end.len() - trimmed_end.len()]) {
+        end_start -= end.len() - trimmed_end.len();
+    }
+    // the space after the start marker of an empty comment is also the one before the end marker
+    if first == last && end_start < start_end {
+        end_start = start_end;
+    }
+    vec![
+        LineEdit { line: first, range: indent..start_end, text: String::new() },
+        LineEdit { line: last, range: end_start..line_end, text: String::new() },
+    ]
+}
+
 impl RawMetadataEntry {
     pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, LoadingError> {
         let path: PathBuf = path.into();
@@ -414,7 +718,7 @@ impl<'de> Deserialize<'de> for MetadataSet {
 mod tests {
     use std::path::Path;
     use super::*;
-    use crate::parsing::SyntaxSet;
+    use crate::parsing::{ScopeStack, SyntaxSet};
 
     #[test]
     fn load_raw() {
@@ -496,4 +800,93 @@ mod tests {
         assert_eq!(indent_ctx.increase_indent("struct This {}"), false);
 
     }
+
+    #[test]
+    fn word_characters() {
+        let css_items = serde_json::json!({ "wordCharacters": "-" });
+        let css = MetadataSet::from_raw(("source.css".into(), css_items.as_object().cloned().unwrap())).unwrap();
+        let metadata = Metadata { scoped_metadata: vec![css] };
+
+        let css_scope = [Scope::new("source.css").unwrap()];
+        let scoped = metadata.metadata_for_scope(&css_scope);
+        assert_eq!(scoped.word_characters(), Some("-"));
+        assert!(scoped.is_word_char('-'));
+        assert_eq!(scoped.word_at("a.foo-bar { }", 4), Some(2..9));
+        assert_eq!(scoped.word_at("a.foo-bar { }", 10), None);
+
+        let rust_scope = [Scope::new("source.rust").unwrap()];
+        let scoped = metadata.metadata_for_scope(&rust_scope);
+        assert!(!scoped.is_word_char('-'));
+        assert_eq!(scoped.word_ranges("föo-bar_1"), vec![0..4, 5..10]);
+
+        let separators = serde_json::json!({ "wordSeparators": ".-", "wordCharacters": "." });
+        let shell = serde_json::json!({ "shellVariables": { "TM_WORD_CHARACTERS": "$" } });
+        let metadata = Metadata {
+            scoped_metadata: vec![
+                MetadataSet::from_raw(("text.x".into(), separators.as_object().cloned().unwrap())).unwrap(),
+                MetadataSet::from_raw(("source.php".into(), shell.as_object().cloned().unwrap())).unwrap(),
+            ],
+        };
+        let scoped = metadata.metadata_for_scope(&[Scope::new("text.x").unwrap()]);
+        assert_eq!(scoped.word_separators(), Some(".-"));
+        assert_eq!(scoped.word_ranges("a.b-c (d)\te"), vec![0..3, 4..5, 6..9, 10..11]);
+        let scoped = metadata.metadata_for_scope(&[Scope::new("source.php").unwrap()]);
+        assert_eq!(scoped.word_characters(), Some("$"));
+        assert_eq!(scoped.word_at("echo $name;", 6), Some(5..10));
+    }
+
+    #[test]
+    fn auto_pairs() {
+        let source_items = serde_json::json!({ "smartTypingPairs": [["(", ")"], ["[", "]"]] });
+        let string_items = serde_json::json!({ "smartTypingPairs": [["{", "}"]] });
+        let source = MetadataSet::from_raw(("source.py".into(), source_items.as_object().cloned().unwrap())).unwrap();
+        let string = MetadataSet::from_raw(("source.py string.quoted.format".into(), string_items.as_object().cloned().unwrap())).unwrap();
+        let metadata = Metadata { scoped_metadata: vec![source, string] };
+
+        let code = ScopeStack::from_str("source.py meta.function-call").unwrap();
+        assert_eq!(metadata.auto_pair(code.as_slice(), "["), Some("]"));
+        assert_eq!(metadata.auto_pair(code.as_slice(), "{"), None);
+
+        let comment = ScopeStack::from_str("source.py comment.line").unwrap();
+        assert_eq!(metadata.auto_pair(comment.as_slice(), "("), None);
+
+        let format_string = ScopeStack::from_str("source.py string.quoted.format").unwrap();
+        assert_eq!(metadata.auto_pair(format_string.as_slice(), "{"), Some("}"));
+        assert_eq!(metadata.auto_pair(format_string.as_slice(), "("), None);
+    }
+
+    #[test]
+    fn toggle_comments() {
+        let items = serde_json::json!({ "shellVariables": {
+            "TM_COMMENT_START": "# ",
+            "TM_COMMENT_START_2": "<!-- ",
+            "TM_COMMENT_END_2": " -->",
+        } });
+        let line = MetadataSet::from_raw(("source.py".into(), items.as_object().cloned().unwrap())).unwrap();
+        let items = serde_json::json!({ "shellVariables": {
+            "TM_COMMENT_START": "<!-- ",
+            "TM_COMMENT_END": " -->",
+        } });
+        let block = MetadataSet::from_raw(("text.html".into(), items.as_object().cloned().unwrap())).unwrap();
+        let metadata = Metadata { scoped_metadata: vec![line, block] };
+        let toggle = |scope: &str, lines: &[&str]| {
+            let scoped = metadata.metadata_for_scope(&[Scope::new(scope).unwrap()]);
+            let mut lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+            for edit in scoped.toggle_comment(&lines).into_iter().rev() {
+                lines[edit.line].replace_range(edit.range, &edit.text);
+            }
+            lines
+        };
+
+        assert_eq!(toggle("source.py", &["if a:\n", "    b\n"]), vec!["# if a:\n", "#     b\n"]);
+        assert_eq!(toggle("source.py", &["  # a\n", "  #b\n", "\n"]), vec!["  a\n", "  b\n", "\n"]);
+        assert_eq!(toggle("source.py", &["# a\n", "b"]), vec!["# # a\n", "# b"]);
+        assert_eq!(toggle("text.html", &["\n", "  <p>\n", "  x</p>\r\n"]), vec!["\n", "  <!-- <p>\n", "  x</p> -->\r\n"]);
+        assert_eq!(toggle("text.html", &["  <!-- <p>\n", "  x</p> -->\n"]), vec!["  <p>\n", "  x</p>\n"]);
+        assert_eq!(toggle("text.html", &["<!--x-->"]), vec!["x"]);
+        assert_eq!(toggle("text.html", &["<!-- -->"]), vec![""]);
+        assert_eq!(toggle("text.html", &["<!-->"]), vec!["<!-- <!--> -->"]);
+        assert_eq!(toggle("text.plain", &["a"]), vec!["a"]);
+        assert_eq!(toggle("source.py", &[" ", ""]), vec![" ", ""]);
+    }
 }
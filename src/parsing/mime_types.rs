@@ -0,0 +1,54 @@
+//! The built-in table used by `SyntaxSet::find_syntax_by_mime_type`
+
+/// MIME types with the scopes of the syntaxes for them, in order of preference
+pub(crate) static MIME_TYPE_SCOPES: &[(&str, &[&str])] = &[
+    ("application/ecmascript", &["source.js", "source.js.default"]),
+    ("application/graphql", &["source.graphql"]),
+    ("application/javascript", &["source.js", "source.js.default"]),
+    ("application/json", &["source.json"]),
+    ("application/ld+json", &["source.json"]),
+    ("application/sql", &["source.sql"]),
+    ("application/toml", &["source.toml"]),
+    ("application/typescript", &["source.ts"]),
+    ("application/x-httpd-php", &["embedding.php"]),
+    ("application/x-latex", &["text.tex.latex"]),
+    ("application/x-perl", &["source.perl"]),
+    ("application/x-python", &["source.python"]),
+    ("application/x-ruby", &["source.ruby"]),
+    ("application/x-sh", &["source.shell.bash"]),
+    ("application/x-tex", &["text.tex"]),
+    ("application/x-yaml", &["source.yaml"]),
+    ("application/xhtml+xml", &["text.html.basic"]),
+    ("application/xml", &["text.xml"]),
+    ("application/yaml", &["source.yaml"]),
+    ("text/css", &["source.css"]),
+    ("text/csv", &["text.plain"]),
+    ("text/html", &["text.html.basic"]),
+    ("text/javascript", &["source.js", "source.js.default"]),
+    ("text/jsx", &["source.js.react", "source.js"]),
+    ("text/markdown", &["text.html.markdown"]),
+    ("text/plain", &["text.plain"]),
+    ("text/rust", &["source.rust"]),
+    ("text/tsx", &["source.tsx"]),
+    ("text/x-c", &["source.c"]),
+    ("text/x-c++src", &["source.c++"]),
+    ("text/x-csharp", &["source.cs"]),
+    ("text/x-diff", &["source.diff"]),
+    ("text/x-go", &["source.go"]),
+    ("text/x-haskell", &["source.haskell"]),
+    ("text/x-java", &["source.java"]),
+    ("text/x-java-source", &["source.java"]),
+    ("text/x-kotlin", &["source.Kotlin"]),
+    ("text/x-lua", &["source.lua"]),
+    ("text/x-markdown", &["text.html.markdown"]),
+    ("text/x-objcsrc", &["source.objc"]),
+    ("text/x-patch", &["source.diff"]),
+    ("text/x-rst", &["text.restructuredtext"]),
+    ("text/x-rust", &["source.rust"]),
+    ("text/x-scala", &["source.scala"]),
+    ("text/x-shellscript", &["source.shell.bash"]),
+    ("text/x-swift", &["source.swift"]),
+    ("text/x-toml", &["source.toml"]),
+    ("text/xml", &["text.xml"]),
+    ("text/yaml", &["source.yaml"]),
+];
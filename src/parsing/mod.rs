@@ -4,14 +4,20 @@
 //!
 //! [`SyntaxSet`]: struct.SyntaxSet.html
 
+#[cfg(feature = "parsing")]
+mod lint;
 #[cfg(feature = "metadata")]
 pub mod metadata;
 #[cfg(feature = "parsing")]
+mod mime_types;
+#[cfg(feature = "parsing")]
 mod parser;
 #[cfg(feature = "parsing")]
 pub mod syntax_definition;
 #[cfg(feature = "parsing")]
 mod syntax_set;
+#[cfg(feature = "tmlanguage-load")]
+pub mod tm_language;
 #[cfg(all(feature = "parsing", feature = "yaml-load"))]
 mod yaml_load;
 
@@ -27,6 +33,8 @@ pub use self::yaml_load::*;
 pub use self::syntax_set::*;
 #[cfg(feature = "parsing")]
 pub use self::parser::*;
+#[cfg(feature = "parsing")]
+pub use self::lint::*;
 #[cfg(feature = "metadata")]
 pub use self::metadata::*;
 
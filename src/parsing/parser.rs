@@ -1,10 +1,17 @@
 use super::syntax_definition::*;
 use super::scope::*;
-use super::regex::Region;
+use super::regex::{MatchLimits, Region};
+use std::borrow::Cow;
 use std::usize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::ops::Range;
+use std::panic::{self, AssertUnwindSafe};
 use std::i32;
-use std::hash::BuildHasherDefault;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{BuildHasherDefault, Hash, Hasher};
+use std::time::{Duration, Instant};
 use fnv::FnvHasher;
 use crate::parsing::syntax_set::{SyntaxSet, SyntaxReference};
 
@@ -27,23 +34,106 @@ use crate::parsing::syntax_set::{SyntaxSet, SyntaxReference};
 /// It is not recommended that you try caching the first time you implement highlighting.
 ///
 /// [`HighlightState`]: ../highlighting/struct.HighlightState.html
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct ParseState {
     stack: Vec<StateLevel>,
     first_line: bool,
     // See issue #101. Contains indices of frames pushed by `with_prototype`s.
     // Doesn't look at `with_prototype`s below top of stack.
     proto_starts: Vec<usize>,
+    match_limits: MatchLimits,
+    newline_policy: NewlinePolicy,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+/// How [`ParseState`] treats the line endings of the lines it parses, see
+/// [`ParseState::set_newline_policy`].
+///
+/// Syntaxes loaded with newlines expect every line to end with exactly `\n`, while syntaxes
+/// loaded without them expect no line ending at all. Anything else makes patterns like `$\n?`
+/// or `\n` match differently, so it is easiest to normalize the lines in one place.
+///
+/// The operations returned by the parser always refer to the line as it was passed in.
+///
+/// [`ParseState`]: struct.ParseState.html
+/// [`ParseState::set_newline_policy`]: struct.ParseState.html#method.set_newline_policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NewlinePolicy {
+    /// Parse lines exactly as they are passed in, which new states do
+    Keep,
+    /// Turn a trailing `\r\n` or `\r` into `\n`, for syntax sets loaded with newlines. The
+    /// whole line ending gets the scopes of the `\n`.
+    Newline,
+    /// Remove a trailing `\n`, `\r\n` or `\r`, for syntax sets loaded without newlines. The line
+    /// ending keeps the scopes active at the end of the line.
+    Strip,
+}
+
+impl NewlinePolicy {
+    /// Returns the line the way the parser sees it with this policy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::NewlinePolicy;
+    ///
+    /// assert_eq!(NewlinePolicy::Newline.normalize("a\r\n"), "a\n");
+    /// assert_eq!(NewlinePolicy::Strip.normalize("a\r\n"), "a");
+    /// assert_eq!(NewlinePolicy::Keep.normalize("a\r\n"), "a\r\n");
+    /// ```
+    pub fn normalize(self, line: &str) -> Cow<'_, str> {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        let content = content.strip_suffix('\r').unwrap_or(content);
+        match self {
+            NewlinePolicy::Keep => Cow::Borrowed(line),
+            NewlinePolicy::Newline if !line[content.len()..].starts_with('\r') => Cow::Borrowed(line),
+            NewlinePolicy::Newline => Cow::Owned(format!("{}\n", content)),
+            NewlinePolicy::Strip => Cow::Borrowed(content),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
 struct StateLevel {
     context: ContextId,
     prototypes: Vec<ContextId>,
     captures: Option<(Region, String)>,
 }
 
-#[derive(Debug)]
+/// A read-only view of one level of the context stack of a [`ParseState`], see
+/// [`ParseState::context_stack`].
+///
+/// [`ParseState`]: struct.ParseState.html
+/// [`ParseState::context_stack`]: struct.ParseState.html#method.context_stack
+#[derive(Debug, Clone)]
+pub struct ContextLevel<'a> {
+    /// The syntax the context belongs to, `None` if the context isn't part of the syntax set
+    pub syntax: Option<&'a SyntaxReference>,
+    /// The name of the context in its syntax, like `main`, or `#anon_main_0` for an anonymous
+    /// context defined in `main`
+    pub name: Option<&'a str>,
+    pub meta_scope: &'a [Scope],
+    pub meta_content_scope: &'a [Scope],
+    /// The contexts of the `with_prototype` of the match that pushed this level, as syntax and
+    /// context name. These apply to this level and the levels above it.
+    pub with_prototypes: Vec<Option<(&'a str, &'a str)>>,
+    /// True if this level was pushed by a pattern from a `with_prototype`, in which case the
+    /// `with_prototype`s of the levels below it don't apply anymore
+    pub from_with_prototype: bool,
+    /// True if the level holds on to the text and captures of the match that pushed it, which
+    /// happens when its patterns refer to them with backreferences like `\1`
+    pub has_captures: bool,
+}
+
+impl<'a> fmt::Display for ContextLevel<'a> {
+    /// Formats the level as `Syntax#context`, with `?` for unknown parts
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}",
+               self.syntax.map(|s| s.name.as_str()).unwrap_or("?"),
+               self.name.unwrap_or("?"))
+    }
+}
+
+#[derive(Debug, Clone)]
 struct RegexMatch<'a> {
     regions: Region,
     context: &'a Context,
@@ -52,8 +142,447 @@ struct RegexMatch<'a> {
     would_loop: bool,
 }
 
-/// Maps the pattern to the start index, which is -1 if not found.
-type SearchCache = HashMap<*const MatchPattern, Option<Region>, BuildHasherDefault<FnvHasher>>;
+/// An error returned by [`ParseState::parse_line_checked`] instead of panicking.
+///
+/// [`ParseState::parse_line_checked`]: struct.ParseState.html#method.parse_line_checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The context stack of the state is empty, so the state is corrupt
+    EmptyStack,
+    /// The state refers to a context that isn't in the syntax set. This happens when the state
+    /// was created for a syntax from a different [`SyntaxSet`].
+    ///
+    /// [`SyntaxSet`]: struct.SyntaxSet.html
+    MissingContext,
+    /// Parsing panicked, for example because of an invalid regex in the syntax definition.
+    /// Contains the panic message.
+    Panicked(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use crate::parsing::ParseError::*;
+
+        match *self {
+            EmptyStack => write!(f, "Parse state has an empty context stack"),
+            MissingContext => write!(f, "Parse state refers to a context that isn't in the syntax set"),
+            Panicked(ref msg) => write!(f, "Parsing panicked: {}", msg),
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Limits on how much work [`ParseState::parse_line_with_budget`] may do for a single line.
+///
+/// Some syntax definitions contain regexes that can backtrack catastrophically on certain
+/// lines, which makes parsing them take a very long time. Interactive tools can use a budget to
+/// give up on such lines instead. Limits that are `None` are not checked.
+///
+/// [`ParseState::parse_line_with_budget`]: struct.ParseState.html#method.parse_line_with_budget
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ParseBudget {
+    /// The maximum number of regex searches to run for the line
+    pub max_searches: Option<usize>,
+    /// The maximum time to spend on the line.
+    ///
+    /// This is checked between regex searches, so a single very slow search can still exceed it.
+    /// Note that `std::time::Instant` is not supported on `wasm32-unknown-unknown`.
+    pub max_duration: Option<Duration>,
+    /// Lines longer than this many bytes are skipped entirely, like Sublime Text does.
+    ///
+    /// A skipped line keeps the scopes that were active at its start and doesn't change the
+    /// state, so the following lines are parsed as if it wasn't there. Only the scope of the
+    /// syntax is still pushed if the first line is skipped.
+    pub max_line_length: Option<usize>,
+    /// Only search this many bytes ahead of the current position for the next match.
+    ///
+    /// If nothing matches in the window, its text is left with the current scopes and the search
+    /// continues after it. Because regexes only see the window, tokens crossing its end can be
+    /// split or missed, but on very long lines with few matches, like minified code, this avoids
+    /// searching the rest of the line over and over. This doesn't mark the line as degraded.
+    pub search_window: Option<usize>,
+}
+
+/// The result of [`ParseState::parse_line_with_budget`].
+///
+/// [`ParseState::parse_line_with_budget`]: struct.ParseState.html#method.parse_line_with_budget
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BudgetedLine {
+    /// The scope stack operations for the part of the line that was parsed, like the result of
+    /// `parse_line`.
+    pub ops: Vec<(usize, ScopeStackOp)>,
+    /// True if the budget ran out and the rest of the line wasn't parsed
+    pub degraded: bool,
+}
+
+/// A scope stack operation along with the part of the line its scope applies to, see
+/// [`ParseState::parse_line_with_text`].
+///
+/// [`ParseState::parse_line_with_text`]: struct.ParseState.html#method.parse_line_with_text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextOp {
+    pub index: usize,
+    pub op: ScopeStackOp,
+    /// For a push of the scope of a pattern or one of its captures, the range of the line that
+    /// was matched. `None` for other operations and for the meta scopes of contexts, which can
+    /// continue on later lines.
+    pub range: Option<Range<usize>>,
+}
+
+impl TextOp {
+    /// The text the scope of a push applies to
+    pub fn text<'a>(&self, line: &'a str) -> Option<&'a str> {
+        self.range.clone().map(|range| &line[range])
+    }
+}
+
+/// A pattern that was tried while parsing a line, see [`ParseTracer`].
+///
+/// [`ParseTracer`]: trait.ParseTracer.html
+#[derive(Debug, Clone, Copy)]
+pub struct TracedMatch<'a> {
+    /// The context the pattern is in, which can be a context included by the current one
+    pub context: &'a Context,
+    pub pattern: &'a MatchPattern,
+    /// The position in the line the search started at
+    pub search_start: usize,
+    /// The start and end of the match, `None` if the pattern didn't match
+    pub matched: Option<(usize, usize)>,
+    /// True if the pattern is from a `with_prototype`
+    pub from_with_prototype: bool,
+}
+
+/// A match that would have made the parser loop forever, see [`ParseTracer::loop_prevented`].
+///
+/// Displays as a message that points at the pattern, for logging.
+///
+/// [`ParseTracer::loop_prevented`]: trait.ParseTracer.html#method.loop_prevented
+#[derive(Clone, Copy)]
+pub struct LoopDiagnostic<'a> {
+    syntax_set: &'a SyntaxSet,
+    /// The context with the pattern, which can be a context included by the current one
+    pub context: &'a Context,
+    /// The index of the pattern in the patterns of the context
+    pub pattern_index: usize,
+    /// The position in the line where the pattern matched without consuming anything
+    pub position: usize,
+    /// The character that was skipped instead, `None` at the end of the line
+    pub skipped: Option<char>,
+}
+
+impl<'a> LoopDiagnostic<'a> {
+    /// The pattern that matched without consuming anything
+    pub fn pattern(&self) -> &'a MatchPattern {
+        self.context.match_at(self.pattern_index)
+    }
+
+    /// The syntax and name of the context with the pattern, `None` if it isn't a named context.
+    ///
+    /// This looks through all contexts of the syntax set, so it is only done when asked for.
+    pub fn context_name(&self) -> Option<(&'a SyntaxReference, &'a str)> {
+        self.syntax_set.context_name_of(self.context)
+    }
+}
+
+impl<'a> fmt::Debug for LoopDiagnostic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LoopDiagnostic")
+            .field("context", &self.context_name().map(|(syntax, name)| (&syntax.name, name)))
+            .field("pattern", &self.pattern().regex.regex_str())
+            .field("position", &self.position)
+            .field("skipped", &self.skipped)
+            .finish()
+    }
+}
+
+impl<'a> fmt::Display for LoopDiagnostic<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.context_name() {
+            Some((syntax, name)) => write!(f, "{}#{}", syntax.name, name)?,
+            None => write!(f, "?")?,
+        }
+        write!(f, ": pattern {:?} would loop at {}", self.pattern().regex.regex_str(), self.position)?;
+        match self.skipped {
+            Some(c) => write!(f, ", skipped {:?}", c),
+            None => write!(f, ", skipped the rest of the line"),
+        }
+    }
+}
+
+/// Callbacks for following what the parser does, used with [`ParseState::parse_line_traced`].
+///
+/// This is meant for tools that help debugging syntax definitions, all methods do nothing by
+/// default.
+///
+/// [`ParseState::parse_line_traced`]: struct.ParseState.html#method.parse_line_traced
+pub trait ParseTracer {
+    /// Called for every pattern that is searched for, whether it matched or not.
+    ///
+    /// The patterns are tried in order of priority, and the search stops early if a pattern
+    /// matches right at the search position.
+    fn candidate(&mut self, _candidate: &TracedMatch<'_>) {}
+
+    /// Called with the match that was chosen at a position, before it is applied to the state
+    fn winner(&mut self, _winner: &TracedMatch<'_>, _state: &ParseState) {}
+
+    /// Called after a match pushed, set or popped contexts, with the new state.
+    ///
+    /// Use [`ParseState::context_stack`] to look at the contexts.
+    ///
+    /// [`ParseState::context_stack`]: struct.ParseState.html#method.context_stack
+    fn stack_changed(&mut self, _state: &ParseState) {}
+
+    /// Called when the chosen match is a pop that doesn't consume anything right after a push
+    /// that didn't either, which would loop forever. Like Sublime Text, the parser skips a
+    /// character and tries again then, so the diagnostic helps finding out why a character
+    /// didn't get the expected scope.
+    fn loop_prevented(&mut self, _diagnostic: &LoopDiagnostic<'_>) {}
+
+    /// Called when a `fail` matches but no `branch_point` with its name matched before it on the
+    /// same line, in which case the match is applied like one without an operation.
+    ///
+    /// Backtracking only works within a line, because the operations of the earlier lines are
+    /// already returned, so a syntax that expects to backtrack across lines is parsed with the
+    /// alternative that was tried first.
+    fn unmatched_fail(&mut self, _fail: &TracedMatch<'_>) {}
+}
+
+/// The tracer used when nothing is traced
+struct NoTracer;
+
+impl ParseTracer for NoTracer {}
+
+/// A [`ParseTracer`] that counts how often each pattern of a syntax set is chosen, to find the
+/// rules of a syntax that never fire.
+///
+/// Parse a corpus with it using [`ParseState::parse_line_traced`], then call [`report`] with the
+/// same syntax set. Counts from different syntax sets can't be mixed.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{Coverage, ParseState, SyntaxSet};
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("json").unwrap();
+/// let mut coverage = Coverage::default();
+/// let mut state = ParseState::new(syntax);
+/// state.parse_line_traced("{\"a\": [1, true]}\n", &ss, &mut coverage);
+///
+/// for context in coverage.report(&ss).iter().filter(|c| c.syntax.name == "JSON") {
+///     for pattern in context.patterns.iter().filter(|p| p.hits == 0) {
+///         println!("{}: pattern {} of {} never matched", context.syntax.name, pattern.index, context.name);
+///     }
+/// }
+/// ```
+///
+/// [`ParseTracer`]: trait.ParseTracer.html
+/// [`ParseState::parse_line_traced`]: struct.ParseState.html#method.parse_line_traced
+/// [`report`]: #method.report
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    /// Addresses of the patterns in the syntax set with how often they were chosen
+    hits: HashMap<usize, usize>,
+    /// Addresses of the contexts whose patterns were searched for
+    searched: HashSet<usize>,
+}
+
+/// How often the patterns of a context were chosen, see [`Coverage::report`].
+///
+/// [`Coverage::report`]: struct.Coverage.html#method.report
+#[derive(Debug, Clone)]
+pub struct ContextCoverage<'a> {
+    pub syntax: &'a SyntaxReference,
+    pub name: &'a str,
+    /// Whether the parser ever searched for the patterns of the context, which happens when it is
+    /// on the stack or included by a context on the stack
+    pub searched: bool,
+    /// The match patterns of the context, includes aren't counted
+    pub patterns: Vec<PatternCoverage<'a>>,
+}
+
+/// How often a match pattern was chosen, see [`Coverage::report`].
+///
+/// [`Coverage::report`]: struct.Coverage.html#method.report
+#[derive(Debug, Clone)]
+pub struct PatternCoverage<'a> {
+    /// The index of the pattern in the patterns of its context
+    pub index: usize,
+    pub pattern: &'a MatchPattern,
+    pub hits: usize,
+}
+
+impl Coverage {
+    /// The number of times the pattern was chosen
+    pub fn hits(&self, pattern: &MatchPattern) -> usize {
+        self.hits.get(&(pattern as *const MatchPattern as usize)).cloned().unwrap_or(0)
+    }
+
+    /// Lists the named contexts of all syntaxes in the set, sorted by name within each syntax,
+    /// with the counts for their patterns.
+    pub fn report<'a>(&self, syntax_set: &'a SyntaxSet) -> Vec<ContextCoverage<'a>> {
+        let mut report = Vec::new();
+        for syntax in syntax_set.syntaxes() {
+            let mut contexts: Vec<_> = syntax.contexts.iter().collect();
+            contexts.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, id) in contexts {
+                let context = syntax_set.get_context(id);
+                let patterns = context.patterns.iter().enumerate().filter_map(|(index, pattern)| {
+                    match *pattern {
+                        Pattern::Match(ref pattern) => Some(PatternCoverage { index, pattern, hits: self.hits(pattern) }),
+                        Pattern::Include(_) => None,
+                    }
+                }).collect();
+                report.push(ContextCoverage {
+                    syntax,
+                    name,
+                    searched: self.searched.contains(&(context as *const Context as usize)),
+                    patterns,
+                });
+            }
+        }
+        report
+    }
+}
+
+impl ParseTracer for Coverage {
+    fn candidate(&mut self, candidate: &TracedMatch<'_>) {
+        self.searched.insert(candidate.context as *const Context as usize);
+    }
+
+    fn winner(&mut self, winner: &TracedMatch<'_>, _state: &ParseState) {
+        *self.hits.entry(winner.pattern as *const MatchPattern as usize).or_insert(0) += 1;
+    }
+}
+
+/// Keeps track of the remaining [`ParseBudget`] while parsing a line
+#[derive(Debug)]
+struct BudgetTracker {
+    searches_left: Option<usize>,
+    deadline: Option<Instant>,
+    search_window: Option<usize>,
+    exhausted: bool,
+}
+
+impl BudgetTracker {
+    fn new(budget: &ParseBudget) -> BudgetTracker {
+        BudgetTracker {
+            searches_left: budget.max_searches,
+            deadline: budget.max_duration.map(|d| Instant::now() + d),
+            search_window: budget.search_window,
+            exhausted: false,
+        }
+    }
+
+    fn unlimited() -> BudgetTracker {
+        BudgetTracker { searches_left: None, deadline: None, search_window: None, exhausted: false }
+    }
+
+    /// The end of the part of the line to search from `start`
+    fn search_end(&self, line: &str, start: usize) -> usize {
+        match self.search_window {
+            Some(window) if start + window < line.len() => {
+                let mut end = start + window.max(1);
+                while !line.is_char_boundary(end) {
+                    end += 1;
+                }
+                end
+            }
+            _ => line.len(),
+        }
+    }
+
+    /// Accounts for one regex search, returns false if there is no budget left for it
+    fn spend_search(&mut self) -> bool {
+        if let Some(ref mut left) = self.searches_left {
+            if *left == 0 {
+                self.exhausted = true;
+            } else {
+                *left -= 1;
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                self.exhausted = true;
+            }
+        }
+        !self.exhausted
+    }
+}
+
+/// A `branch_point` match on the current line, kept to be able to backtrack to it.
+#[derive(Debug)]
+struct BranchPoint<'a> {
+    name: &'a str,
+    /// The match of the `branch_point` pattern
+    reg_match: RegexMatch<'a>,
+    /// Index of the alternative that is currently being tried
+    alternative: usize,
+    /// The number of stack changes in the journal, the `proto_starts`, start position, and
+    /// number of ops before the match, to rewind the state to it
+    journal_len: usize,
+    proto_starts: Vec<usize>,
+    start: usize,
+    non_consuming_push_at: (usize, usize),
+    ops_len: usize,
+}
+
+/// A change to the context stack of a [`ParseState`], kept to be able to undo it
+#[derive(Debug)]
+enum StackChange {
+    Pushed,
+    Popped(StateLevel),
+}
+
+/// What is kept while parsing a line, passed down to the methods that parse it
+struct LineParse<'a, 'b, T: ParseTracer + ?Sized> {
+    /// The position in the line to continue parsing at
+    start: usize,
+    /// The last position after a newline in the line that isn't its end, 0 if there is none.
+    /// Patterns anchored to the start of a line can't match after it.
+    last_line_start: usize,
+    /// Used for detecting loops with push/pop, see long comment above.
+    non_consuming_push_at: (usize, usize),
+    ops: Vec<(usize, ScopeStackOp)>,
+    search_cache: SearchCache,
+    regions: Region,
+    /// Backtracking only works within a line, because ops of earlier lines are already returned
+    branch_points: Vec<BranchPoint<'a>>,
+    budget: &'b mut BudgetTracker,
+    tracer: &'b mut T,
+    /// The changes to the context stack that may have to be undone
+    journal: &'b mut Vec<StackChange>,
+    /// Whether to record all changes, otherwise they are only recorded while there are branch
+    /// points to go back to
+    record_all: bool,
+    /// The ranges of the pushes of pattern and capture scopes by the index of their op, if they
+    /// are asked for
+    text_ranges: Option<&'b mut Vec<(usize, Range<usize>)>>,
+}
+
+impl<'a, 'b, T: ParseTracer + ?Sized> LineParse<'a, 'b, T> {
+    /// The journal, if changes to the stack have to be recorded right now
+    fn recording_journal(&mut self) -> Option<&mut Vec<StackChange>> {
+        if self.record_all || !self.branch_points.is_empty() {
+            Some(&mut *self.journal)
+        } else {
+            None
+        }
+    }
+}
+
+/// What is known about where a pattern matches in the rest of the line
+enum CachedSearch {
+    /// The match that a search found, which is valid until the position passes its start
+    Match(Region),
+    /// The pattern doesn't match anywhere in the rest of the line
+    NoMatch,
+    /// The pattern can't match before this position, according to its prefilter
+    NotBefore(usize),
+}
+
+type SearchCache = HashMap<*const MatchPattern, CachedSearch, BuildHasherDefault<FnvHasher>>;
 
 // To understand the implementation of this, here's an introduction to how
 // Sublime Text syntax definitions work.
@@ -160,9 +689,147 @@ impl ParseState {
             stack: vec![start_state],
             first_line: true,
             proto_starts: Vec::new(),
+            match_limits: MatchLimits::default(),
+            newline_policy: NewlinePolicy::Keep,
         }
     }
 
+    /// Sets the limits for the regex engine for the searches of this state, which take
+    /// precedence over the ones set with [`set_default_match_limits`]. Clones of the state keep
+    /// them.
+    ///
+    /// A server can use this to bound the time a pathological line takes, at the cost of
+    /// patterns that need a lot of backtracking not matching. Searches that exceed a limit are
+    /// treated as not matching.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{MatchLimits, ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// state.set_match_limits(MatchLimits { retry_limit: Some(10_000), stack_limit: Some(1_000) });
+    /// let ops = state.parse_line("x = 1\n", &ss);
+    /// assert!(!ops.is_empty());
+    /// ```
+    ///
+    /// [`set_default_match_limits`]: fn.set_default_match_limits.html
+    pub fn set_match_limits(&mut self, limits: MatchLimits) {
+        self.match_limits = limits;
+    }
+
+    /// The limits set with [`set_match_limits`]
+    ///
+    /// [`set_match_limits`]: #method.set_match_limits
+    pub fn match_limits(&self) -> MatchLimits {
+        self.match_limits
+    }
+
+    /// Sets how the line endings of the lines passed to the parse methods are treated. Clones of
+    /// the state keep the policy.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{NewlinePolicy, ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("py").unwrap();
+    /// let mut state = ParseState::new(syntax);
+    /// state.set_newline_policy(NewlinePolicy::Newline);
+    ///
+    /// // a file with Windows line endings parses like one with `\n`
+    /// let crlf = state.parse_line("# hi\r\n", &ss);
+    /// let lf = ParseState::new(syntax).parse_line("# hi\n", &ss);
+    /// assert_eq!(crlf.len(), lf.len());
+    /// // the comment ends after the `\r\n`
+    /// assert_eq!(crlf.last().unwrap().0, 6);
+    /// ```
+    pub fn set_newline_policy(&mut self, policy: NewlinePolicy) {
+        self.newline_policy = policy;
+    }
+
+    /// The policy set with [`set_newline_policy`]
+    ///
+    /// [`set_newline_policy`]: #method.set_newline_policy
+    pub fn newline_policy(&self) -> NewlinePolicy {
+        self.newline_policy
+    }
+
+    /// A hash of the state, for keys of caches and for quickly checking if the parser reached a
+    /// state it was in before, like when re-parsing after an edit.
+    ///
+    /// Equal states have the same fingerprint, and different states almost certainly don't. The
+    /// fingerprint of a state is the same every time it is computed by the same program, but
+    /// it refers to contexts by their index in the syntax set, so it is only meaningful together
+    /// with the same syntax set, and it can change with the versions of syntect and Rust.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("rs").unwrap();
+    /// let mut a = ParseState::new(syntax);
+    /// let mut b = ParseState::new(syntax);
+    /// a.parse_line("/* a\n", &ss);
+    /// b.parse_line("/* b\n", &ss);
+    /// assert_eq!(a.fingerprint(), b.fingerprint());
+    /// b.parse_line("*/\n", &ss);
+    /// assert_ne!(a.fingerprint(), b.fingerprint());
+    /// ```
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Returns the context stack of the parser, from the bottom to the top, for debugging syntax
+    /// definitions.
+    ///
+    /// The syntax set has to be the one used for parsing, otherwise the names are wrong or
+    /// missing.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// state.parse_line("x = '''\n", &ss);
+    /// let stack = state.context_stack(&ss);
+    /// assert_eq!(stack[0].to_string(), "Python#__start");
+    /// assert!(stack.len() > 2);
+    /// for level in &stack {
+    ///     println!("{} {:?}", level, level.meta_scope);
+    /// }
+    /// ```
+    pub fn context_stack<'a>(&self, syntax_set: &'a SyntaxSet) -> Vec<ContextLevel<'a>> {
+        let name_of = |id: &ContextId| syntax_set.context_name(id);
+        self.stack.iter().enumerate().map(|(i, level)| {
+            let context = if syntax_set.has_context(&level.context) {
+                Some(syntax_set.get_context(&level.context))
+            } else {
+                None
+            };
+            let name = name_of(&level.context);
+            ContextLevel {
+                syntax: name.map(|(syntax, _)| syntax),
+                name: name.map(|(_, name)| name),
+                meta_scope: context.map(|c| &c.meta_scope[..]).unwrap_or(&[]),
+                meta_content_scope: context.map(|c| &c.meta_content_scope[..]).unwrap_or(&[]),
+                with_prototypes: level.prototypes.iter()
+                    .map(|id| name_of(id).map(|(syntax, name)| (syntax.name.as_str(), name)))
+                    .collect(),
+                from_with_prototype: self.proto_starts.contains(&i),
+                has_captures: level.captures.is_some(),
+            }
+        }).collect()
+    }
+
     /// Parses a single line of the file. Because of the way regex engines work you unfortunately
     /// have to pass in a single line contiguous in memory. This can be bad for really long lines.
     /// Sublime Text avoids this by just not highlighting lines that are too long (thousands of characters).
@@ -183,52 +850,361 @@ impl ParseState {
     /// [`SyntaxSet`]: struct.SyntaxSet.html
     /// [`ParseState`]: struct.ParseState.html
     pub fn parse_line(&mut self, line: &str, syntax_set: &SyntaxSet) -> Vec<(usize, ScopeStackOp)> {
+        self.parse_line_inner(line, syntax_set, &mut BudgetTracker::unlimited(), &mut NoTracer, None, None)
+    }
+
+    /// Like [`parse_line`], but takes the line as bytes that should be UTF-8, like lines read
+    /// from a log, so they don't have to be converted to a `String` first.
+    ///
+    /// Valid UTF-8 is parsed without copying it. Otherwise each invalid sequence is replaced by
+    /// U+FFFD like `String::from_utf8_lossy` does, and the offsets of the returned operations are
+    /// mapped back to offsets in `line`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// let ops = state.parse_line_bytes(b"x = '\xFF\xFE' # a\n", &ss);
+    /// // the comment starts at the `#` in the bytes, not in the fixed line
+    /// assert!(ops.iter().any(|&(i, _)| i == 10));
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    pub fn parse_line_bytes(&mut self, line: &[u8], syntax_set: &SyntaxSet) -> Vec<(usize, ScopeStackOp)> {
+        if let Ok(valid) = std::str::from_utf8(line) {
+            return self.parse_line(valid, syntax_set);
+        }
+
+        let mut fixed = String::with_capacity(line.len() + 8);
+        // offsets in `fixed` right after a replacement character, and the offsets in `line` they
+        // correspond to
+        let mut anchors = Vec::new();
+        let mut rest = line;
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    fixed.push_str(valid);
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    fixed.push_str(std::str::from_utf8(valid).unwrap());
+                    fixed.push('\u{FFFD}');
+                    rest = &invalid[e.error_len().unwrap_or(invalid.len())..];
+                    anchors.push((fixed.len(), line.len() - rest.len()));
+                }
+            }
+        }
+
+        let mut ops = self.parse_line(&fixed, syntax_set);
+        for op in &mut ops {
+            // operations are at character boundaries, so never inside a replacement character
+            let anchor = anchors.iter().rev().find(|&&(fixed_offset, _)| fixed_offset <= op.0);
+            if let Some(&(fixed_offset, line_offset)) = anchor {
+                op.0 = line_offset + (op.0 - fixed_offset);
+            }
+        }
+        ops
+    }
+
+    /// Like [`parse_line`], but also returns the range of the line each pushed scope applies to,
+    /// so the text of matches and captures doesn't have to be found again from the operations.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, ScopeStackOp, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// let line = "import os\n";
+    /// let names: Vec<String> = state.parse_line_with_text(line, &ss).iter()
+    ///     .filter_map(|op| match op.op {
+    ///         ScopeStackOp::Push(scope) => Some(format!("{}: {:?}", scope, op.text(line)?)),
+    ///         _ => None,
+    ///     })
+    ///     .collect();
+    /// assert!(names.contains(&"keyword.control.import.python: \"import\"".to_string()));
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    pub fn parse_line_with_text(&mut self, line: &str, syntax_set: &SyntaxSet) -> Vec<TextOp> {
+        let mut ranges = Vec::new();
+        let ops = self.parse_line_inner(line, syntax_set, &mut BudgetTracker::unlimited(), &mut NoTracer, None, Some(&mut ranges));
+        let mut ranges = ranges.into_iter().peekable();
+        ops.into_iter().enumerate().map(|(i, (index, op))| {
+            let range = match ranges.peek() {
+                Some(&(op_index, _)) if op_index == i => ranges.next().map(|(_, range)| range),
+                _ => None,
+            };
+            TextOp { index, op, range }
+        }).collect()
+    }
+
+    /// Like [`parse_line`], but calls the methods of the tracer for the patterns that are tried,
+    /// the matches that are chosen and the changes of the context stack.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, ParseTracer, SyntaxSet, TracedMatch};
+    ///
+    /// #[derive(Default)]
+    /// struct Counter { tried: usize, matched: usize }
+    ///
+    /// impl ParseTracer for Counter {
+    ///     fn candidate(&mut self, _candidate: &TracedMatch<'_>) {
+    ///         self.tried += 1;
+    ///     }
+    ///     fn winner(&mut self, winner: &TracedMatch<'_>, _state: &ParseState) {
+    ///         println!("{:?} matched at {:?}", winner.pattern.regex.regex_str(), winner.matched);
+    ///         self.matched += 1;
+    ///     }
+    /// }
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+    /// let mut counter = Counter::default();
+    /// state.parse_line_traced("x = 1\n", &ss, &mut counter);
+    /// assert!(counter.matched > 0);
+    /// assert!(counter.tried > counter.matched);
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    pub fn parse_line_traced(&mut self,
+                             line: &str,
+                             syntax_set: &SyntaxSet,
+                             tracer: &mut dyn ParseTracer)
+                             -> Vec<(usize, ScopeStackOp)> {
+        self.parse_line_inner(line, syntax_set, &mut BudgetTracker::unlimited(), tracer, None, None)
+    }
+
+    /// Like [`parse_line`], but returns the operations in the more compact form of
+    /// [`CompactOps`], which is worth it when keeping the result for many lines around.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_extension("py").unwrap();
+    /// let ops = ParseState::new(syntax).parse_line("print('hi')\n", &ss);
+    /// let compact = ParseState::new(syntax).parse_line_compact("print('hi')\n", &ss);
+    /// assert!(compact.runs() < ops.len());
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    /// [`CompactOps`]: struct.CompactOps.html
+    pub fn parse_line_compact(&mut self, line: &str, syntax_set: &SyntaxSet) -> CompactOps {
+        CompactOps::from_ops(&self.parse_line(line, syntax_set))
+    }
+
+    /// Like [`parse_line`], but returns an error instead of panicking.
+    ///
+    /// This first checks that the state can be used with the syntax set, then parses the line and
+    /// catches any panic caused by e.g. a broken syntax definition. If an error is returned, the
+    /// state is left as it was before the call, which is done by keeping the contexts popped while
+    /// parsing the line rather than a copy of the state. This is intended for long-running services that
+    /// want to recover from bad input instead of crashing a worker.
+    ///
+    /// Note that a caught panic is still reported by the panic hook, and that nothing can be
+    /// caught when compiling with `panic = "abort"`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseError, ParseState, SyntaxSet};
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+    /// assert!(state.parse_line_checked("fn main() {}\n", &ss).is_ok());
+    ///
+    /// // a state used with the wrong syntax set
+    /// let empty = SyntaxSet::new();
+    /// assert_eq!(state.parse_line_checked("fn main() {}\n", &empty), Err(ParseError::MissingContext));
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    pub fn parse_line_checked(&mut self,
+                              line: &str,
+                              syntax_set: &SyntaxSet)
+                              -> Result<Vec<(usize, ScopeStackOp)>, ParseError> {
+        if self.stack.is_empty() {
+            return Err(ParseError::EmptyStack);
+        }
+        let contexts_missing = self.stack.iter().any(|level| {
+            !syntax_set.has_context(&level.context) ||
+                level.prototypes.iter().any(|p| !syntax_set.has_context(p))
+        });
+        if contexts_missing {
+            return Err(ParseError::MissingContext);
+        }
+
+        // instead of a copy of the whole state, only the changes to the stack are kept to undo them
+        let first_line = self.first_line;
+        let proto_starts = self.proto_starts.clone();
+        let mut journal = Vec::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            self.parse_line_inner(line, syntax_set, &mut BudgetTracker::unlimited(), &mut NoTracer, Some(&mut journal), None)
+        }));
+        result.map_err(|payload| {
+            self.undo_stack_changes(&mut journal, 0);
+            self.first_line = first_line;
+            self.proto_starts = proto_starts;
+            let msg = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            ParseError::Panicked(msg)
+        })
+    }
+
+    /// Like [`parse_line`], but gives up on the line once the given budget is used up, or skips
+    /// it if it is too long.
+    ///
+    /// If that happens, the returned [`BudgetedLine`] is marked as `degraded` and only has the
+    /// operations for the part of the line before the point where parsing stopped, the rest of
+    /// the line keeps the scopes that were active at that point. The state stays consistent
+    /// with the returned operations, so following lines can be parsed as usual.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{ParseBudget, ParseState, SyntaxSet};
+    /// use std::time::Duration;
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let mut state = ParseState::new(ss.find_syntax_by_extension("rs").unwrap());
+    /// let budget = ParseBudget { max_duration: Some(Duration::from_millis(50)), ..Default::default() };
+    /// let line = state.parse_line_with_budget("fn main() {}\n", &ss, budget);
+    /// assert!(!line.degraded);
+    /// ```
+    ///
+    /// [`parse_line`]: #method.parse_line
+    /// [`BudgetedLine`]: struct.BudgetedLine.html
+    pub fn parse_line_with_budget(&mut self,
+                                  line: &str,
+                                  syntax_set: &SyntaxSet,
+                                  budget: ParseBudget)
+                                  -> BudgetedLine {
+        if budget.max_line_length.map(|max| line.len() > max).unwrap_or(false) {
+            // the scope of the syntax still has to be pushed for the following lines
+            let mut ops = Vec::new();
+            self.push_first_line_scope(syntax_set, &mut ops);
+            return BudgetedLine { ops, degraded: true };
+        }
+        let mut tracker = BudgetTracker::new(&budget);
+        let ops = self.parse_line_inner(line, syntax_set, &mut tracker, &mut NoTracer, None, None);
+        BudgetedLine { ops, degraded: tracker.exhausted }
+    }
+
+    /// Parses a line, recording all changes to the context stack in `journal` and the ranges of
+    /// the pushes of patterns in `text_ranges` if they are given
+    fn parse_line_inner<T: ParseTracer + ?Sized>(&mut self,
+                                                 line: &str,
+                                                 syntax_set: &SyntaxSet,
+                                                 budget: &mut BudgetTracker,
+                                                 tracer: &mut T,
+                                                 journal: Option<&mut Vec<StackChange>>,
+                                                 mut text_ranges: Option<&mut Vec<(usize, Range<usize>)>>)
+                                                 -> Vec<(usize, ScopeStackOp)> {
+        match self.newline_policy.normalize(line) {
+            Cow::Borrowed(normalized) => self.parse_normalized_line(normalized, syntax_set, budget, tracer, journal, text_ranges),
+            Cow::Owned(normalized) => {
+                let mut ops = self.parse_normalized_line(&normalized, syntax_set, budget, tracer, journal, text_ranges.as_deref_mut());
+                // only the line ending changed, which ends where the original line does
+                for op in &mut ops {
+                    if op.0 == normalized.len() {
+                        op.0 = line.len();
+                    }
+                }
+                for (_, range) in text_ranges.into_iter().flatten() {
+                    if range.end == normalized.len() {
+                        range.end = line.len();
+                    }
+                }
+                ops
+            }
+        }
+    }
+
+    fn parse_normalized_line<T: ParseTracer + ?Sized>(&mut self,
+                                                      line: &str,
+                                                      syntax_set: &SyntaxSet,
+                                                      budget: &mut BudgetTracker,
+                                                      tracer: &mut T,
+                                                      journal: Option<&mut Vec<StackChange>>,
+                                                      text_ranges: Option<&mut Vec<(usize, Range<usize>)>>)
+                                                      -> Vec<(usize, ScopeStackOp)> {
         assert!(!self.stack.is_empty(),
                 "Somehow main context was popped from the stack");
-        let mut match_start = 0;
         let mut res = Vec::new();
+        self.push_first_line_scope(syntax_set, &mut res);
+
+        let fnv = BuildHasherDefault::<FnvHasher>::default();
+        let record_all = journal.is_some();
+        let mut own_journal = Vec::new();
+        let last_line_start = match line.len().checked_sub(1) {
+            Some(last) => line.as_bytes()[..last].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1),
+            None => 0,
+        };
+        let mut parse = LineParse {
+            start: 0,
+            last_line_start,
+            non_consuming_push_at: (0, 0),
+            ops: res,
+            search_cache: HashMap::with_capacity_and_hasher(128, fnv),
+            regions: Region::new(),
+            branch_points: Vec::new(),
+            budget,
+            tracer,
+            journal: journal.unwrap_or(&mut own_journal),
+            record_all,
+            text_ranges,
+        };
+
+        while self.parse_next_token(line, syntax_set, &mut parse) {}
 
+        parse.ops
+    }
+
+    /// Adds the push of the scope of the syntax to the operations of the first line
+    fn push_first_line_scope(&mut self, syntax_set: &SyntaxSet, ops: &mut Vec<(usize, ScopeStackOp)>) {
         if self.first_line {
             let cur_level = &self.stack[self.stack.len() - 1];
             let context = syntax_set.get_context(&cur_level.context);
             if !context.meta_content_scope.is_empty() {
-                res.push((0, ScopeStackOp::Push(context.meta_content_scope[0])));
+                ops.push((0, ScopeStackOp::Push(context.meta_content_scope[0])));
             }
             self.first_line = false;
         }
+    }
 
-        let mut regions = Region::new();
-        let fnv = BuildHasherDefault::<FnvHasher>::default();
-        let mut search_cache: SearchCache = HashMap::with_capacity_and_hasher(128, fnv);
-        // Used for detecting loops with push/pop, see long comment above.
-        let mut non_consuming_push_at = (0, 0);
-
-        while self.parse_next_token(
-            line,
-            syntax_set,
-            &mut match_start,
-            &mut search_cache,
-            &mut regions,
-            &mut non_consuming_push_at,
-            &mut res
-        ) {}
-
-        res
+    /// Undoes the changes to the context stack recorded in `journal` after the first `keep`
+    fn undo_stack_changes(&mut self, journal: &mut Vec<StackChange>, keep: usize) {
+        while journal.len() > keep {
+            match journal.pop() {
+                Some(StackChange::Pushed) => {
+                    self.stack.pop();
+                }
+                Some(StackChange::Popped(level)) => self.stack.push(level),
+                None => {}
+            }
+        }
     }
 
-    fn parse_next_token(
+    fn parse_next_token<'a, T: ParseTracer + ?Sized>(
         &mut self,
         line: &str,
-        syntax_set: &SyntaxSet,
-        start: &mut usize,
-        search_cache: &mut SearchCache,
-        regions: &mut Region,
-        non_consuming_push_at: &mut (usize, usize),
-        ops: &mut Vec<(usize, ScopeStackOp)>,
+        syntax_set: &'a SyntaxSet,
+        parse: &mut LineParse<'a, '_, T>,
     ) -> bool {
         let check_pop_loop = {
-            let (pos, stack_depth) = *non_consuming_push_at;
-            pos == *start && stack_depth == self.stack.len()
+            let (pos, stack_depth) = parse.non_consuming_push_at;
+            pos == parse.start && stack_depth == self.stack.len()
         };
 
         // Trim proto_starts that are no longer valid
@@ -236,7 +1212,19 @@ impl ParseState {
             self.proto_starts.pop();
         }
 
-        let best_match = self.find_best_match(line, *start, syntax_set, search_cache, regions, check_pop_loop);
+        let best_match = self.find_best_match(line, syntax_set, check_pop_loop, parse);
+        if parse.budget.exhausted {
+            // a better match might not have been found yet, so just stop here
+            return false;
+        }
+        if best_match.is_none() {
+            // nothing in the search window, continue after it
+            let end = parse.budget.search_end(line, parse.start);
+            if end < line.len() {
+                parse.start = end;
+                return true;
+            }
+        }
 
         if let Some(reg_match) = best_match {
             if reg_match.would_loop {
@@ -248,13 +1236,22 @@ impl ParseState {
                 // advances one character and tries again, thus preventing the
                 // loop.
 
-                // println!("pop_would_loop for match {:?}, start {}", reg_match, *start);
+                // println!("pop_would_loop for match {:?}, start {}", reg_match, parse.start);
 
                 // nth(1) gets the next character if there is one. Need to do
                 // this instead of just += 1 because we have byte indices and
                 // unicode characters can be more than 1 byte.
-                if let Some((i, _)) = line[*start..].char_indices().nth(1) {
-                    *start += i;
+                let start = parse.start;
+                let next = line[start..].char_indices().nth(1);
+                parse.tracer.loop_prevented(&LoopDiagnostic {
+                    syntax_set,
+                    context: reg_match.context,
+                    pattern_index: reg_match.pat_index,
+                    position: start,
+                    skipped: next.and(line[start..].chars().next()),
+                });
+                if let Some((i, _)) = next {
+                    parse.start += i;
                     return true;
                 } else {
                     // End of line, no character to advance and no point trying
@@ -263,50 +1260,133 @@ impl ParseState {
                 }
             }
 
-            let match_end = reg_match.regions.pos(0).unwrap().1;
-
-            let consuming = match_end > *start;
-            if !consuming {
-                // The match doesn't consume any characters. If this is a
-                // "push", remember the position and stack size so that we can
-                // check the next "pop" for loops. Otherwise leave the state,
-                // e.g. non-consuming "set" could also result in a loop.
-                let context = reg_match.context;
-                let match_pattern = context.match_at(reg_match.pat_index);
-                if let MatchOperation::Push(_) = match_pattern.operation {
-                    *non_consuming_push_at = (match_end, self.stack.len() + 1);
+            parse.tracer.winner(&TracedMatch {
+                context: reg_match.context,
+                pattern: reg_match.context.match_at(reg_match.pat_index),
+                search_start: parse.start,
+                matched: reg_match.regions.pos(0),
+                from_with_prototype: reg_match.from_with_prototype,
+            }, self);
+
+            match reg_match.context.match_at(reg_match.pat_index).operation {
+                MatchOperation::Branch { ref name, .. } => {
+                    parse.branch_points.push(BranchPoint {
+                        name,
+                        reg_match: reg_match.clone(),
+                        alternative: 0,
+                        journal_len: parse.journal.len(),
+                        proto_starts: self.proto_starts.clone(),
+                        start: parse.start,
+                        non_consuming_push_at: parse.non_consuming_push_at,
+                        ops_len: parse.ops.len(),
+                    });
+                }
+                MatchOperation::Fail(ref name) => {
+                    if let Some(i) = parse.branch_points.iter().rposition(|point| point.name == name) {
+                        parse.branch_points.truncate(i + 1);
+                        let point = &mut parse.branch_points[i];
+                        if let MatchOperation::Branch { ref alternatives, .. } =
+                            point.reg_match.context.match_at(point.reg_match.pat_index).operation {
+                            if point.alternative + 1 < alternatives.len() {
+                                // Go back to the branch point and try the next alternative. The
+                                // search cache is only valid for positions after the current one.
+                                point.alternative += 1;
+                                self.undo_stack_changes(parse.journal, point.journal_len);
+                                self.proto_starts.clone_from(&point.proto_starts);
+                                parse.start = point.start;
+                                parse.non_consuming_push_at = point.non_consuming_push_at;
+                                parse.ops.truncate(point.ops_len);
+                                if let Some(ranges) = parse.text_ranges.as_mut() {
+                                    while ranges.last().map(|&(i, _)| i >= point.ops_len).unwrap_or(false) {
+                                        ranges.pop();
+                                    }
+                                }
+                                parse.search_cache.clear();
+                                let reg_match = point.reg_match.clone();
+                                let alternative = point.alternative;
+                                self.apply_match(line, syntax_set, &reg_match, alternative, parse);
+                                parse.tracer.stack_changed(self);
+                                return true;
+                            }
+                        }
+                        // All alternatives failed, so the last one is kept
+                        parse.branch_points.pop();
+                        if parse.branch_points.is_empty() && !parse.record_all {
+                            parse.journal.clear();
+                        }
+                    } else {
+                        parse.tracer.unmatched_fail(&TracedMatch {
+                            context: reg_match.context,
+                            pattern: reg_match.context.match_at(reg_match.pat_index),
+                            search_start: parse.start,
+                            matched: reg_match.regions.pos(0),
+                            from_with_prototype: reg_match.from_with_prototype,
+                        });
+                    }
                 }
+                _ => {}
             }
 
-            *start = match_end;
-
-            // ignore `with_prototype`s below this if a context is pushed
-            if reg_match.from_with_prototype {
-                // use current height, since we're before the actual push
-                self.proto_starts.push(self.stack.len());
+            self.apply_match(line, syntax_set, &reg_match, 0, parse);
+            match reg_match.context.match_at(reg_match.pat_index).operation {
+                MatchOperation::None | MatchOperation::Fail(_) => {}
+                _ => parse.tracer.stack_changed(self),
             }
-
-            let level_context = {
-                let id = &self.stack[self.stack.len() - 1].context;
-                syntax_set.get_context(id)
-            };
-            self.exec_pattern(line, &reg_match, level_context, syntax_set, ops);
-
             true
         } else {
             false
         }
     }
 
-    fn find_best_match<'a>(
+    fn apply_match<'a, T: ParseTracer + ?Sized>(
+        &mut self,
+        line: &str,
+        syntax_set: &'a SyntaxSet,
+        reg_match: &RegexMatch<'a>,
+        alternative: usize,
+        parse: &mut LineParse<'a, '_, T>,
+    ) {
+        let match_end = reg_match.regions.pos(0).unwrap().1;
+
+        let consuming = match_end > parse.start;
+        if !consuming {
+            // The match doesn't consume any characters. If this is a
+            // "push", remember the position and stack size so that we can
+            // check the next "pop" for loops. Otherwise leave the state,
+            // e.g. non-consuming "set" could also result in a loop.
+            let context = reg_match.context;
+            let match_pattern = context.match_at(reg_match.pat_index);
+            match match_pattern.operation {
+                MatchOperation::Push(_) | MatchOperation::Branch { .. } => {
+                    parse.non_consuming_push_at = (match_end, self.stack.len() + 1);
+                }
+                _ => {}
+            }
+        }
+
+        parse.start = match_end;
+
+        // ignore `with_prototype`s below this if a context is pushed
+        if reg_match.from_with_prototype {
+            // use current height, since we're before the actual push
+            self.proto_starts.push(self.stack.len());
+        }
+
+        let level_context = {
+            let id = &self.stack[self.stack.len() - 1].context;
+            syntax_set.get_context(id)
+        };
+        self.exec_pattern(line, reg_match, alternative, level_context, syntax_set, parse);
+    }
+
+    fn find_best_match<'a, T: ParseTracer + ?Sized>(
         &self,
         line: &str,
-        start: usize,
         syntax_set: &'a SyntaxSet,
-        search_cache: &mut SearchCache,
-        regions: &mut Region,
         check_pop_loop: bool,
+        parse: &mut LineParse<'a, '_, T>,
     ) -> Option<RegexMatch<'a>> {
+        let start = parse.start;
         let cur_level = &self.stack[self.stack.len() - 1];
         let context = syntax_set.get_context(&cur_level.context);
         let prototype = if let Some(ref p) = context.prototype {
@@ -336,9 +1416,18 @@ impl ParseState {
             for (pat_context, pat_index) in context_iter(syntax_set, syntax_set.get_context(ctx)) {
                 let match_pat = pat_context.match_at(pat_index);
 
-                if let Some(match_region) = self.search(
-                    line, start, match_pat, captures, search_cache, regions
-                ) {
+                let searched = self.search(line, min_start, pat_context, pat_index, captures, parse);
+                if parse.budget.exhausted {
+                    return None;
+                }
+                parse.tracer.candidate(&TracedMatch {
+                    context: pat_context,
+                    pattern: match_pat,
+                    search_start: start,
+                    matched: searched.as_ref().and_then(|region| region.pos(0)),
+                    from_with_prototype: from_with_proto,
+                });
+                if let Some(match_region) = searched {
                     let (match_start, match_end) = match_region.pos(0).unwrap();
 
                     // println!("matched pattern {:?} at start {} end {}", match_pat.regex_str, match_start, match_end);
@@ -378,40 +1467,88 @@ impl ParseState {
         best_match
     }
 
-    fn search(&self,
-              line: &str,
-              start: usize,
-              match_pat: &MatchPattern,
-              captures: Option<&(Region, String)>,
-              search_cache: &mut SearchCache,
-              regions: &mut Region,
+    fn search<T: ParseTracer + ?Sized>(&self,
+                                       line: &str,
+                                       best_start: usize,
+                                       context: &Context,
+                                       pat_index: usize,
+                                       captures: Option<&(Region, String)>,
+                                       parse: &mut LineParse<'_, '_, T>,
     ) -> Option<Region> {
+        let match_pat = context.match_at(pat_index);
+        let start = parse.start;
+        let search_cache = &mut parse.search_cache;
+        let regions = &mut parse.regions;
+        let budget = &mut *parse.budget;
         // println!("{} - {:?} - {:?}", match_pat.regex_str, match_pat.has_captures, cur_level.captures.is_some());
         let match_ptr = match_pat as *const MatchPattern;
 
-        if let Some(maybe_region) = search_cache.get(&match_ptr) {
-            if let Some(ref region) = *maybe_region {
-                let match_start = region.pos(0).unwrap().0;
-                if match_start >= start {
+        // Anchored patterns can only match at the start of the line or after a newline in it,
+        // which the last character can't be followed by
+        if start > parse.last_line_start && match_pat.regex.is_anchored_start() {
+            return None;
+        }
+
+        // results for a window of the line aren't valid for later positions
+        let end = budget.search_end(line, start);
+        let uses_captures = match_pat.has_captures && captures.is_some();
+
+        let mut filtered = uses_captures;
+        match search_cache.get(&match_ptr) {
+            Some(CachedSearch::Match(region)) => {
+                let (match_start, match_end) = region.pos(0).unwrap();
+                // A match found with a search that reached further than the current window
+                // can't be used, the search below only sees the window
+                if match_start >= start && match_end <= end {
                     // Cached match is valid, return it. Otherwise do another
                     // search below.
                     return Some(region.clone());
                 }
-            } else {
-                // Didn't find a match earlier, so no point trying to match it again
-                return None;
             }
+            // Didn't find a match earlier, so no point trying to match it again
+            Some(CachedSearch::NoMatch) => return None,
+            Some(&CachedSearch::NotBefore(earliest)) if earliest >= start => {
+                if earliest > best_start || earliest > end {
+                    return None;
+                }
+                filtered = true;
+            }
+            _ => {}
+        }
+
+        // Skip patterns whose literal prefixes or first bytes don't occur in the rest of the line,
+        // or only after the best match so far, which rules out most patterns of big contexts
+        // without running the regex engine. The position is kept until it's passed.
+        if !filtered {
+            match context.prefilter().earliest_start(pat_index, line, start) {
+                None => {
+                    search_cache.insert(match_pat, CachedSearch::NoMatch);
+                    return None;
+                }
+                // a match starting before the end of a limited search range can extend past it
+                Some(earliest) if earliest > start => {
+                    search_cache.insert(match_pat, CachedSearch::NotBefore(earliest));
+                    if earliest > best_start || earliest > end {
+                        return None;
+                    }
+                }
+                Some(_) => {}
+            }
+        }
+
+        if !budget.spend_search() {
+            return None;
         }
 
-        let (matched, can_cache) = if match_pat.has_captures && captures.is_some() {
+        let (matched, can_cache) = if uses_captures {
             let &(ref region, ref s) = captures.unwrap();
             let regex = match_pat.regex_with_refs(region, s);
-            let matched = regex.search(line, start, line.len(), Some(regions));
+            let matched = regex.search_with_limits(line, start, end, Some(regions), self.match_limits);
             (matched, false)
         } else {
             let regex = match_pat.regex();
-            let matched = regex.search(line, start, line.len(), Some(regions));
-            (matched, true)
+            let matched = regex.search_with_limits(line, start, end, Some(regions), self.match_limits);
+            (matched, end == line.len())
         };
 
         if matched {
@@ -422,42 +1559,58 @@ impl ParseState {
                 _ => true,
             };
             if can_cache && does_something {
-                search_cache.insert(match_pat, Some(regions.clone()));
+                search_cache.insert(match_pat, CachedSearch::Match(regions.clone()));
             }
             if does_something {
                 // print!("catch {} at {} on {}", match_pat.regex_str, match_start, line);
                 return Some(regions.clone());
             }
         } else if can_cache {
-            search_cache.insert(match_pat, None);
+            search_cache.insert(match_pat, CachedSearch::NoMatch);
         }
         None
     }
 
     /// Returns true if the stack was changed
-    fn exec_pattern<'a>(
+    fn exec_pattern<'a, T: ParseTracer + ?Sized>(
         &mut self,
         line: &str,
         reg_match: &RegexMatch<'a>,
+        alternative: usize,
         level_context: &'a Context,
         syntax_set: &'a SyntaxSet,
-        ops: &mut Vec<(usize, ScopeStackOp)>,
+        parse: &mut LineParse<'a, '_, T>,
     ) -> bool {
+        let ops = &mut parse.ops;
+        let text_ranges = &mut parse.text_ranges;
         let (match_start, match_end) = reg_match.regions.pos(0).unwrap();
         let context = reg_match.context;
         let pat = context.match_at(reg_match.pat_index);
         // println!("running pattern {:?} on '{}' at {}, operation {:?}", pat.regex_str, line, match_start, pat.operation);
 
-        self.push_meta_ops(true, match_start, level_context, &pat.operation, syntax_set, ops);
+        // a branch behaves like a push of the alternative that is being tried
+        let branch_op;
+        let operation = match pat.operation {
+            MatchOperation::Branch { ref alternatives, .. } => {
+                branch_op = MatchOperation::Push(vec![alternatives[alternative].clone()]);
+                &branch_op
+            }
+            ref operation => operation,
+        };
+
+        self.push_meta_ops(true, match_start, level_context, operation, syntax_set, ops);
         for s in &pat.scope {
             // println!("pushing {:?} at {}", s, match_start);
+            if let Some(ranges) = text_ranges.as_mut() {
+                ranges.push((ops.len(), match_start..match_end));
+            }
             ops.push((match_start, ScopeStackOp::Push(*s)));
         }
         if let Some(ref capture_map) = pat.captures {
             // captures could appear in an arbitrary order, have to produce ops in right order
             // ex: ((bob)|(hi))* could match hibob in wrong order, and outer has to push first
             // we don't have to handle a capture matching multiple times, Sublime doesn't
-            let mut map: Vec<((usize, i32), ScopeStackOp)> = Vec::new();
+            let mut map = Vec::new();
             for &(cap_index, ref scopes) in capture_map.iter() {
                 if let Some((cap_start, cap_end)) = reg_match.regions.pos(cap_index) {
                     // marking up empty captures causes pops to be sorted wrong
@@ -467,13 +1620,17 @@ impl ParseState {
                     // println!("capture {:?} at {:?}-{:?}", scopes[0], cap_start, cap_end);
                     for scope in scopes.iter() {
                         map.push(((cap_start, -((cap_end - cap_start) as i32)),
-                                  ScopeStackOp::Push(*scope)));
+                                  ScopeStackOp::Push(*scope),
+                                  Some(cap_start..cap_end)));
                     }
-                    map.push(((cap_end, i32::MIN), ScopeStackOp::Pop(scopes.len())));
+                    map.push(((cap_end, i32::MIN), ScopeStackOp::Pop(scopes.len()), None));
                 }
             }
             map.sort_by(|a, b| a.0.cmp(&b.0));
-            for ((index, _), op) in map.into_iter() {
+            for ((index, _), op, range) in map.into_iter() {
+                if let (Some(ranges), Some(range)) = (text_ranges.as_mut(), range) {
+                    ranges.push((ops.len(), range));
+                }
                 ops.push((index, op));
             }
         }
@@ -481,9 +1638,9 @@ impl ParseState {
             // println!("popping at {}", match_end);
             ops.push((match_end, ScopeStackOp::Pop(pat.scope.len())));
         }
-        self.push_meta_ops(false, match_end, &*level_context, &pat.operation, syntax_set, ops);
+        self.push_meta_ops(false, match_end, level_context, operation, syntax_set, ops);
 
-        self.perform_op(line, &reg_match.regions, pat, syntax_set)
+        self.perform_op(line, &reg_match.regions, pat, operation, syntax_set, parse.recording_journal())
     }
 
     fn push_meta_ops<'a>(
@@ -589,7 +1746,7 @@ impl ParseState {
                     }
                 }
             },
-            MatchOperation::None => (),
+            MatchOperation::None | MatchOperation::Branch { .. } | MatchOperation::Fail(_) => (),
         }
     }
 
@@ -599,21 +1756,34 @@ impl ParseState {
         line: &str,
         regions: &Region,
         pat: &MatchPattern,
-        syntax_set: &SyntaxSet
+        operation: &MatchOperation,
+        syntax_set: &SyntaxSet,
+        mut journal: Option<&mut Vec<StackChange>>,
     ) -> bool {
-        let (ctx_refs, old_proto_ids) = match pat.operation {
+        let (ctx_refs, old_proto_ids) = match *operation {
             MatchOperation::Push(ref ctx_refs) => (ctx_refs, None),
             MatchOperation::Set(ref ctx_refs) => {
                 // a `with_prototype` stays active when the context is `set`
                 // until the context layer in the stack (where the `with_prototype`
                 // was initially applied) is popped off.
-                (ctx_refs, self.stack.pop().map(|s| s.prototypes))
+                let old_proto_ids = self.stack.pop().map(|s| match journal {
+                    Some(ref mut journal) => {
+                        let prototypes = s.prototypes.clone();
+                        journal.push(StackChange::Popped(s));
+                        prototypes
+                    }
+                    None => s.prototypes,
+                });
+                (ctx_refs, old_proto_ids)
             }
             MatchOperation::Pop => {
-                self.stack.pop();
+                let popped = self.stack.pop();
+                if let (Some(journal), Some(popped)) = (journal, popped) {
+                    journal.push(StackChange::Popped(popped));
+                }
                 return true;
             }
-            MatchOperation::None => return false,
+            MatchOperation::None | MatchOperation::Branch { .. } | MatchOperation::Fail(_) => return false,
         };
         for (i, r) in ctx_refs.iter().enumerate() {
             let mut proto_ids = if i == 0 {
@@ -651,11 +1821,124 @@ impl ParseState {
                 prototypes: proto_ids,
                 captures,
             });
+            if let Some(ref mut journal) = journal {
+                journal.push(StackChange::Pushed);
+            }
         }
         true
     }
 }
 
+/// Parses all lines of a file from the start, returning the ops of each line like
+/// [`ParseState::parse_line`] would.
+///
+/// With the `rayon` feature, chunks of lines are parsed in parallel, each starting from a guessed
+/// state: the state at the start of the syntax. Chunks are then stitched together in order: when
+/// the actual state at the start of a chunk differs from the guess, its lines are parsed again from
+/// the actual state until the two parses reach the same state, after which the speculative results
+/// are used. Most files return to the main context often, so little has to be parsed twice. Without
+/// the feature, the lines are simply parsed one after the other.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{parse_lines_parallel, SyntaxSet};
+/// use syntect::util::LinesWithEndings;
+///
+/// let ss = SyntaxSet::load_defaults_newlines();
+/// let syntax = ss.find_syntax_by_extension("py").unwrap();
+/// let lines: Vec<&str> = LinesWithEndings::from("def f():\n    return 1\n").collect();
+/// let ops = parse_lines_parallel(syntax, &lines, &ss);
+/// assert_eq!(ops.len(), 2);
+/// ```
+///
+/// [`ParseState::parse_line`]: struct.ParseState.html#method.parse_line
+pub fn parse_lines_parallel(syntax: &SyntaxReference,
+                            lines: &[&str],
+                            syntax_set: &SyntaxSet)
+                            -> Vec<Vec<(usize, ScopeStackOp)>> {
+    #[cfg(feature = "rayon")]
+    {
+        let chunk_len = (lines.len() / (rayon::current_num_threads() * 4)).max(PARALLEL_CHUNK_MIN_LINES);
+        parse_chunks(syntax, lines, syntax_set, chunk_len)
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        let mut state = ParseState::new(syntax);
+        lines.iter().map(|line| state.parse_line(line, syntax_set)).collect()
+    }
+}
+
+/// Chunks smaller than this aren't worth parsing speculatively
+#[cfg(feature = "rayon")]
+const PARALLEL_CHUNK_MIN_LINES: usize = 500;
+
+/// A chunk of lines parsed from a guessed state
+struct SpeculativeChunk {
+    start: ParseState,
+    ops: Vec<Vec<(usize, ScopeStackOp)>>,
+    /// The state after each line
+    states: Vec<ParseState>,
+}
+
+#[cfg_attr(not(any(feature = "rayon", test)), allow(dead_code))]
+fn parse_chunks(syntax: &SyntaxReference,
+                lines: &[&str],
+                syntax_set: &SyntaxSet,
+                chunk_len: usize)
+                -> Vec<Vec<(usize, ScopeStackOp)>> {
+    let parse_chunk = |(i, chunk): (usize, &[&str])| {
+        let mut state = ParseState::new(syntax);
+        // only the first chunk starts at the first line
+        state.first_line = i == 0;
+        let start = state.clone();
+        let mut ops = Vec::with_capacity(chunk.len());
+        let mut states = Vec::with_capacity(chunk.len());
+        for line in chunk {
+            ops.push(state.parse_line(line, syntax_set));
+            states.push(state.clone());
+        }
+        SpeculativeChunk { start, ops, states }
+    };
+    #[cfg(feature = "rayon")]
+    let chunks: Vec<SpeculativeChunk> = {
+        use rayon::prelude::*;
+        lines.par_chunks(chunk_len.max(1)).enumerate().map(parse_chunk).collect()
+    };
+    #[cfg(not(feature = "rayon"))]
+    let chunks: Vec<SpeculativeChunk> = lines.chunks(chunk_len.max(1)).enumerate().map(parse_chunk).collect();
+
+    let mut result = Vec::with_capacity(lines.len());
+    let mut state: Option<ParseState> = None;
+    for (chunk_lines, chunk) in lines.chunks(chunk_len.max(1)).zip(chunks) {
+        let mut actual = match state {
+            Some(actual) if actual != chunk.start => actual,
+            // the guess was right, or this is the first chunk
+            _ => {
+                result.extend(chunk.ops);
+                state = chunk.states.into_iter().last();
+                continue;
+            }
+        };
+        let mut converged_at = None;
+        for (i, line) in chunk_lines.iter().enumerate() {
+            if i > 0 && actual == chunk.states[i - 1] {
+                converged_at = Some(i);
+                break;
+            }
+            result.push(actual.parse_line(line, syntax_set));
+        }
+        match converged_at {
+            Some(i) => {
+                result.extend(chunk.ops.into_iter().skip(i));
+                state = chunk.states.into_iter().last();
+            }
+            None => state = Some(actual),
+        }
+    }
+    result
+}
+
 #[cfg(feature = "yaml-load")]
 #[cfg(test)]
 mod tests {
@@ -994,6 +2277,60 @@ contexts:
         expect_scope_stacks_with_syntax(&line2, &expect2, syntax.clone());
     }
 
+    #[test]
+    fn can_escape_embedded_syntax_from_nested_contexts() {
+        let outer = SyntaxDefinition::load_from_str(r#"
+        name: Outer
+        scope: source.outer
+        contexts:
+          main:
+            - match: '\{\{'
+              scope: punctuation.begin
+              embed: scope:source.inner
+              embed_scope: meta.embedded
+              escape: '\}\}'
+              escape_captures:
+                0: punctuation.end
+            - match: x
+              scope: outer.x
+        "#, true, None).unwrap();
+        let inner = SyntaxDefinition::load_from_str(r#"
+        name: Inner
+        scope: source.inner
+        contexts:
+          prototype:
+            - match: '#'
+              scope: inner.comment
+          main:
+            - match: '\('
+              set: group
+          group:
+            - match: '\['
+              push:
+                - match: x
+                  scope: inner.x
+        "#, true, None).unwrap();
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(outer);
+        builder.add(inner);
+        let ss = builder.build();
+        let mut state = ParseState::new(ss.find_syntax_by_scope(Scope::new("source.outer").unwrap()).unwrap());
+
+        let ops = ops(&mut state, "x{{#([x}}x", &ss);
+        expect_scope_stacks_for_ops(ops.clone(), &[
+            "<source.outer>, <meta.embedded>, <source.inner>, <inner.comment>",
+            "<source.outer>, <meta.embedded>, <inner.x>",
+            "<source.outer>, <punctuation.end>",
+            "<source.outer>, <outer.x>",
+        ]);
+        // the escape pops every context the embedded syntax pushed or set
+        let mut stack = ScopeStack::new();
+        for &(_, ref op) in &ops {
+            stack.apply(op);
+        }
+        assert_eq!(stack.as_slice(), &[Scope::new("source.outer").unwrap()]);
+    }
+
     #[test]
     fn can_parse_non_consuming_pop_that_would_loop() {
         // See https://github.com/trishume/syntect/issues/127
@@ -1402,6 +2739,220 @@ contexts:
         );
     }
 
+    #[test]
+    fn can_inspect_context_stack() {
+        let syntax = r#"%YAML 1.2
+---
+name: Stack
+scope: source.stack
+contexts:
+  main:
+    - match: '(\w+)<<'
+      push: heredoc
+      with_prototype:
+        - match: '\$'
+          push: interpolation
+  heredoc:
+    - meta_scope: string.heredoc
+    - match: ^\1$
+      pop: true
+  interpolation:
+    - match: '{'
+      push: [block, block]
+    - match: '\$'
+      pop: true
+  block:
+    - match: '}'
+      pop: true
+"#;
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let mut state = ParseState::new(&syntax_set.syntaxes()[0]);
+        state.parse_line("EOF<< $ {\n", &syntax_set);
+
+        let stack = state.context_stack(&syntax_set);
+        let names: Vec<String> = stack.iter().map(|level| level.to_string()).collect();
+        assert_eq!(names, vec!["Stack#__start", "Stack#__main", "Stack#heredoc",
+                               "Stack#interpolation", "Stack#block", "Stack#block"]);
+
+        let heredoc = &stack[2];
+        assert_eq!(heredoc.meta_scope, &[Scope::new("string.heredoc").unwrap()][..]);
+        assert_eq!(heredoc.with_prototypes.len(), 1);
+        assert_eq!(heredoc.with_prototypes[0].map(|(syntax, _)| syntax), Some("Stack"));
+        assert!(heredoc.has_captures);
+        assert!(!heredoc.from_with_prototype);
+        assert!(stack[3].from_with_prototype);
+        assert!(!stack[3].has_captures);
+        assert!(stack[3].with_prototypes.is_empty());
+
+        // contexts that aren't part of the syntax set
+        let empty = SyntaxSet::new();
+        let stack = state.context_stack(&empty);
+        assert_eq!(stack.len(), 6);
+        assert_eq!(stack[2].to_string(), "?#?");
+        assert!(stack[2].meta_scope.is_empty());
+    }
+
+    #[test]
+    fn can_trace_parsing() {
+        #[derive(Default)]
+        struct Recorder {
+            events: Vec<String>,
+        }
+
+        impl ParseTracer for Recorder {
+            fn candidate(&mut self, candidate: &TracedMatch<'_>) {
+                self.events.push(format!("try {} at {} -> {:?}",
+                                         candidate.pattern.regex.regex_str(),
+                                         candidate.search_start,
+                                         candidate.matched));
+            }
+            fn winner(&mut self, winner: &TracedMatch<'_>, _state: &ParseState) {
+                self.events.push(format!("win {}", winner.pattern.regex.regex_str()));
+            }
+            fn stack_changed(&mut self, state: &ParseState) {
+                self.events.push(format!("stack {}", state.stack.len()));
+            }
+        }
+
+        let syntax = r#"%YAML 1.2
+---
+scope: source.trace
+contexts:
+  main:
+    - match: a
+      scope: a
+    - match: '\('
+      push: group
+  group:
+    - match: '\)'
+      pop: true
+"#;
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let mut state = ParseState::new(&syntax_set.syntaxes()[0]);
+        let mut recorder = Recorder::default();
+        let ops = state.parse_line_traced("(a)", &syntax_set, &mut recorder);
+        assert_eq!(ops, ParseState::new(&syntax_set.syntaxes()[0]).parse_line("(a)", &syntax_set));
+        assert_eq!(recorder.events, vec![
+            // the initial push of the main context
+            "try  at 0 -> Some((0, 0))",
+            "win ",
+            "stack 2",
+            "try a at 0 -> Some((1, 2))",
+            "try \\( at 0 -> Some((0, 1))",
+            "win \\(",
+            "stack 3",
+            "try \\) at 1 -> Some((2, 3))",
+            "win \\)",
+            "stack 2",
+            "try a at 3 -> None",
+            "try \\( at 3 -> None",
+        ]);
+    }
+
+    #[test]
+    fn can_parse_chunks_from_guessed_states() {
+        let syntax = r#"%YAML 1.2
+---
+scope: source.chunks
+contexts:
+  main:
+    - match: '"'
+      push: string
+    - match: \w+
+      scope: word
+  string:
+    - meta_scope: string
+    - match: '"'
+      pop: true
+"#;
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let syntax = &syntax_set.syntaxes()[0];
+        let lines = ["a \"b\n", "c\n", "d\" e\n", "f\n", "g \"h\n", "i\n", "j\n", "k\"\n", "l\n"];
+        let mut state = ParseState::new(syntax);
+        let expected: Vec<_> = lines.iter().map(|line| state.parse_line(line, &syntax_set)).collect();
+        for chunk_len in 1..=lines.len() {
+            assert_eq!(parse_chunks(syntax, &lines, &syntax_set, chunk_len), expected, "chunks of {}", chunk_len);
+        }
+        assert_eq!(parse_lines_parallel(syntax, &lines, &syntax_set), expected);
+    }
+
+    #[test]
+    fn can_measure_coverage() {
+        let syntax = r#"%YAML 1.2
+---
+scope: source.coverage
+contexts:
+  main:
+    - match: a
+      scope: a
+    - match: b
+      scope: b
+    - include: parens
+  parens:
+    - match: '\('
+      push: group
+  group:
+    - match: '\)'
+      pop: true
+  unused:
+    - match: c
+"#;
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let mut state = ParseState::new(&syntax_set.syntaxes()[0]);
+        let mut coverage = Coverage::default();
+        for line in &["a (a)\n", "(a\n", "a\n"] {
+            state.parse_line_traced(line, &syntax_set, &mut coverage);
+        }
+        let report: Vec<_> = coverage.report(&syntax_set).iter()
+            .filter(|context| !context.name.starts_with("__"))
+            .map(|context| {
+                let hits: Vec<_> = context.patterns.iter().map(|p| (p.index, p.hits)).collect();
+                (context.name, context.searched, hits)
+            })
+            .collect();
+        assert_eq!(report, vec![
+            ("group", true, vec![(0, 1)]),
+            ("main", true, vec![(0, 1), (1, 0)]),
+            ("parens", true, vec![(0, 2)]),
+            ("unused", false, vec![(0, 0)]),
+        ]);
+    }
+
+    #[test]
+    fn can_report_prevented_loops() {
+        #[derive(Default)]
+        struct Recorder {
+            diagnostics: Vec<String>,
+        }
+
+        impl ParseTracer for Recorder {
+            fn loop_prevented(&mut self, diagnostic: &LoopDiagnostic<'_>) {
+                assert_eq!(diagnostic.pattern().regex.regex_str(), "(?=hello)");
+                self.diagnostics.push(diagnostic.to_string());
+            }
+        }
+
+        let syntax = r#"%YAML 1.2
+---
+name: Loops
+scope: source.loops
+contexts:
+  main:
+    - match: (?=hello)
+      push: test
+  test:
+    - match: (?=hello)
+      pop: true
+"#;
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let mut state = ParseState::new(&syntax_set.syntaxes()[0]);
+        let mut recorder = Recorder::default();
+        state.parse_line_traced("a hello", &syntax_set, &mut recorder);
+        assert_eq!(recorder.diagnostics, vec![
+            r#"Loops#test: pattern "(?=hello)" would loop at 2, skipped 'h'"#,
+        ]);
+    }
+
     #[test]
     fn can_parse_issue176() {
         let syntax = r#"
@@ -1752,6 +3303,461 @@ contexts:
         expect_scope_stacks_with_syntax(&line_without_newline, expect, syntax_nonewlines);
     }
 
+    #[test]
+    fn can_give_up_on_line_when_budget_runs_out() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: a
+              scope: a
+            - match: b
+              scope: b
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+
+        let budget = ParseBudget { max_searches: Some(2), ..Default::default() };
+        let line = state.parse_line_with_budget("ab ab", &ss, budget);
+        assert!(line.degraded);
+        assert_eq!(line.ops, vec![
+            (0, Push(Scope::new("source.test").unwrap())),
+            (0, Push(Scope::new("a").unwrap())),
+            (1, Pop(1)),
+        ]);
+
+        // the next line is parsed normally
+        let line = state.parse_line_with_budget("b", &ss, ParseBudget::default());
+        assert!(!line.degraded);
+        assert_eq!(line.ops, vec![(0, Push(Scope::new("b").unwrap())), (1, Pop(1))]);
+
+        let budget = ParseBudget { max_duration: Some(Duration::from_secs(0)), ..Default::default() };
+        let line = state.parse_line_with_budget("ab", &ss, budget);
+        assert!(line.degraded);
+        assert!(line.ops.is_empty());
+    }
+
+    #[test]
+    fn can_include_with_prototype_of_other_syntax() {
+        let inner = SyntaxDefinition::load_from_str(r#"
+        name: Inner
+        scope: source.inner
+        contexts:
+          prototype:
+            - match: '#'
+              scope: comment
+          main:
+            - match: x
+              scope: x
+        "#, true, None).unwrap();
+        let outer = |apply: bool| SyntaxDefinition::load_from_str(&format!(r#"
+        name: Outer
+        scope: source.outer
+        contexts:
+          main:
+            - include: scope:source.inner
+              apply_prototype: {}
+        "#, apply), true, None).unwrap();
+        let comment = (1, Push(Scope::new("comment").unwrap()));
+
+        for &apply in &[true, false] {
+            let mut builder = SyntaxSetBuilder::new();
+            builder.add(outer(apply));
+            builder.add(inner.clone());
+            let ss = builder.build();
+            let mut state = ParseState::new(ss.find_syntax_by_name("Outer").unwrap());
+            let ops = ops(&mut state, "x#\n", &ss);
+            assert!(ops.contains(&(0, Push(Scope::new("x").unwrap()))));
+            assert_eq!(ops.contains(&comment), apply);
+        }
+    }
+
+    #[test]
+    fn can_limit_search_window() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '\bkey\b'
+              scope: keyword
+            - match: '[0-9]+'
+              scope: number
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let budget = ParseBudget { search_window: Some(4), ..Default::default() };
+        let line = "a b c d e f key 1234567\n";
+
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let windowed = state.parse_line_with_budget(line, &ss, budget);
+        assert!(!windowed.degraded);
+        let keyword = Scope::new("keyword").unwrap();
+        let number = Scope::new("number").unwrap();
+        assert!(windowed.ops.contains(&(12, Push(keyword))));
+        // the number crosses window boundaries so it is split up
+        let numbers: Vec<usize> = windowed.ops.iter()
+            .filter(|op| op.1 == Push(number))
+            .map(|op| op.0)
+            .collect();
+        assert_eq!(numbers, vec![16, 19]);
+
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let full = state.parse_line(line, &ss);
+        assert_eq!(full.iter().filter(|op| op.1 == Push(number)).count(), 1);
+    }
+
+    #[test]
+    fn can_backtrack_to_branch_point() {
+        let syntax = r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: (?=\()
+              branch_point: paren
+              branch:
+                - group
+                - tuple
+            - match: \w+
+              scope: word
+          group:
+            - meta_scope: meta.group
+            - match: \(
+            - match: ','
+              fail: paren
+            - match: \)
+              pop: true
+          tuple:
+            - meta_scope: meta.tuple
+            - match: \(
+            - match: ','
+              scope: punctuation.separator
+            - match: \)
+              pop: true
+        "#;
+
+        let group = stack_states(parse("(a) (b)\n", syntax)).join("\n");
+        assert!(group.contains("<meta.group>"));
+        assert!(!group.contains("<meta.tuple>"));
+
+        let ops = parse("(a) (b, c) d\n", syntax);
+        let stacks = stack_states(ops.clone()).join("\n");
+        assert!(stacks.contains("<source.test>, <meta.group>"));
+        assert!(stacks.contains("<source.test>, <meta.tuple>, <punctuation.separator>"));
+        // the ops of the failed branch are discarded
+        let tuple_start = ops.iter().position(|op| *op == (4, Push(Scope::new("meta.tuple").unwrap()))).unwrap();
+        assert!(ops[tuple_start..].iter().all(|op| op.1 != Push(Scope::new("meta.group").unwrap())));
+        assert!(ops.windows(2).all(|w| w[0].0 <= w[1].0));
+
+        // every alternative failing keeps the last one
+        let stacks = stack_states(parse("(a, b,\n", syntax.replace("scope: punctuation.separator", "fail: paren").as_str())).join("\n");
+        assert!(stacks.contains("<meta.tuple>"));
+
+        // a fail on a later line than its branch point is reported and otherwise ignored
+        #[derive(Default)]
+        struct Recorder {
+            unmatched: Vec<(String, usize)>,
+        }
+        impl ParseTracer for Recorder {
+            fn unmatched_fail(&mut self, fail: &TracedMatch<'_>) {
+                self.unmatched.push((fail.pattern.regex.regex_str().to_owned(), fail.matched.unwrap().0));
+            }
+        }
+        let syntax_set = link(SyntaxDefinition::load_from_str(syntax, true, None).unwrap());
+        let mut state = ParseState::new(&syntax_set.syntaxes()[0]);
+        let mut recorder = Recorder::default();
+        state.parse_line_traced("(a\n", &syntax_set, &mut recorder);
+        let ops = state.parse_line_traced("b, c)\n", &syntax_set, &mut recorder);
+        assert_eq!(recorder.unmatched, vec![(",".to_owned(), 1)]);
+        assert!(stack_states(ops).iter().all(|stack| !stack.contains("<meta.tuple>")));
+
+        // backtracking restores the contexts popped since the branch point
+        let bang = syntax.replace("- match: \\w+\n", "- match: '!'\n              fail: paren\n            - match: \\w+\n");
+        assert_ne!(bang, syntax);
+        let ops = parse("(a) !\n", &bang);
+        let stacks = stack_states(ops).join("\n");
+        assert!(stacks.contains("<source.test>, <meta.tuple>"));
+        assert!(!stacks.contains("<meta.group>"));
+    }
+
+    #[test]
+    fn anchored_patterns_only_match_at_line_start() {
+        let syntax = r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '^#'
+              scope: comment
+            - match: '\A!'
+              scope: bang
+        "#;
+        assert_eq!(parse("# a # !\n", syntax), vec![
+            (0, Push(Scope::new("source.test").unwrap())),
+            (0, Push(Scope::new("comment").unwrap())),
+            (1, Pop(1)),
+        ]);
+        assert_eq!(parse("! #\n", syntax)[1], (0, Push(Scope::new("bang").unwrap())));
+        // a newline inside the line starts another line, only Oniguruma's `^` matches after it
+        #[cfg(feature = "regex-onig")]
+        assert_eq!(parse("a\n# b\n", syntax)[1], (2, Push(Scope::new("comment").unwrap())));
+    }
+
+    #[test]
+    fn prefilter_keeps_matches_of_patterns_with_combined_flags() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '(?mi)foo'
+              scope: keyword
+            - match: '(?mx) a b  # spaced out'
+              scope: constant
+            - match: 'z(?im)z|bar|baz'
+              scope: storage
+            - match: 'z(?mx)z| c d'
+              scope: string
+            - match: 'x'
+              scope: variable
+        "#, true, None).unwrap();
+        let syntax_set = link(syntax);
+        let mut unfiltered = syntax_set.clone();
+        unfiltered.disable_prefilters();
+
+        let line = "FOO ab BAZ x Foo cd\n";
+        let filtered_ops = ParseState::new(&syntax_set.syntaxes()[0]).parse_line(line, &syntax_set);
+        let unfiltered_ops = ParseState::new(&unfiltered.syntaxes()[0]).parse_line(line, &unfiltered);
+        assert_eq!(filtered_ops, unfiltered_ops);
+        assert!(filtered_ops.contains(&(0, Push(Scope::new("keyword").unwrap()))));
+        assert!(filtered_ops.contains(&(4, Push(Scope::new("constant").unwrap()))));
+        assert!(filtered_ops.contains(&(13, Push(Scope::new("keyword").unwrap()))));
+    }
+
+    #[test]
+    fn can_recover_from_invalid_regex() {
+        let mut syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: a
+              scope: a
+        "#, true, None).unwrap();
+        syntax.contexts.get_mut("main").unwrap().patterns.push(Pattern::Match(MatchPattern::new(
+            false, "(".to_string(), vec![], None, MatchOperation::None, None,
+        )));
+        let ss = link(syntax);
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let before = state.clone();
+
+        match state.parse_line_checked("ab", &ss) {
+            Err(ParseError::Panicked(msg)) => assert!(msg.contains("regex")),
+            other => panic!("expected a panic to be caught, got {:?}", other),
+        }
+        assert_eq!(state, before);
+        assert_eq!(state.parse_line_checked("ab", &SyntaxSet::new()), Err(ParseError::MissingContext));
+
+        // the stack is restored when the panic happens after it was changed on the line
+        let mut syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: a
+              push: inner
+          inner:
+            - match: b
+              set: broken
+            - match: c
+              pop: true
+          broken:
+            - match: d
+              pop: true
+        "#, true, None).unwrap();
+        syntax.contexts.get_mut("broken").unwrap().patterns.push(Pattern::Match(MatchPattern::new(
+            false, "(".to_string(), vec![], None, MatchOperation::None, None,
+        )));
+        let ss = link(syntax);
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        state.parse_line("a", &ss);
+        let before = state.clone();
+        assert!(state.parse_line_checked("c a b", &ss).is_err());
+        assert_eq!(state, before);
+        assert!(state.parse_line_checked("c a", &ss).is_ok());
+        assert_eq!(state, before);
+    }
+
+    #[test]
+    fn can_skip_long_lines() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '"'
+              push: string
+          string:
+            - meta_scope: string
+            - match: '"'
+              pop: true
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let budget = ParseBudget { max_line_length: Some(5), ..Default::default() };
+
+        let line = state.parse_line_with_budget("a \"b", &ss, budget);
+        assert!(!line.degraded);
+        let before = state.clone();
+        let line = state.parse_line_with_budget("\" a very long line", &ss, budget);
+        assert!(line.degraded);
+        assert!(line.ops.is_empty());
+        assert_eq!(state, before);
+
+        // still in the string
+        let line = state.parse_line_with_budget("c\"", &ss, budget);
+        assert_eq!(line.ops, vec![(2, Pop(1))]);
+
+        // a skipped first line still pushes the scope of the syntax
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let line = state.parse_line_with_budget("a very long first line", &ss, budget);
+        assert!(line.degraded);
+        assert_eq!(line.ops, vec![(0, Push(Scope::new("source.test").unwrap()))]);
+        let line = state.parse_line_with_budget("\"b\"", &ss, budget);
+        assert_eq!(line.ops[0], (0, Push(Scope::new("string").unwrap())));
+    }
+
+    #[test]
+    fn can_find_text_of_ops() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '(\w+)(=)'
+              scope: assignment
+              captures:
+                1: name
+                2: operator
+            - match: '"'
+              push:
+                - clear_scopes: 1
+                - meta_scope: string
+                - match: '"'
+                  pop: true
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let mut state = ParseState::new(&ss.syntaxes()[0]);
+        let pushes = |ops: Vec<TextOp>, line| -> Vec<(String, Option<&str>)> {
+            ops.iter().filter_map(|op| match op.op {
+                Push(scope) => Some((scope.build_string(), op.text(line))),
+                _ => None,
+            }).collect()
+        };
+
+        let line = "ab= \"x";
+        assert_eq!(pushes(state.parse_line_with_text(line, &ss), line), vec![
+            ("source.test".to_string(), None),
+            ("assignment".to_string(), Some("ab=")),
+            ("name".to_string(), Some("ab")),
+            ("operator".to_string(), Some("=")),
+            ("string".to_string(), None),
+        ]);
+        let line = "y\" c=";
+        assert_eq!(pushes(state.parse_line_with_text(line, &ss), line), vec![
+            ("assignment".to_string(), Some("c=")),
+            ("name".to_string(), Some("c")),
+            ("operator".to_string(), Some("=")),
+        ]);
+        let line = "\"z\"";
+        assert_eq!(pushes(state.parse_line_with_text(line, &ss), line), vec![
+            ("string".to_string(), None),
+        ]);
+    }
+
+    #[test]
+    fn can_parse_bytes() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '\w+'
+              scope: word
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let parse = |line: &[u8]| ParseState::new(&ss.syntaxes()[0]).parse_line_bytes(line, &ss);
+        let word = Push(Scope::new("word").unwrap());
+        let source = Push(Scope::new("source.test").unwrap());
+
+        assert_eq!(parse("é a".as_bytes()), vec![(0, source.clone()), (0, word.clone()), (2, Pop(1)), (3, word.clone()), (4, Pop(1))]);
+        assert_eq!(parse(b"\xE9 a\xF0\x9F b"), vec![
+            (0, source), (2, word.clone()), (3, Pop(1)), (6, word), (7, Pop(1)),
+        ]);
+    }
+
+    #[test]
+    fn can_normalize_newlines() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: 'a$\n?'
+              scope: a
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let parse = |policy, line| {
+            let mut state = ParseState::new(&ss.syntaxes()[0]);
+            state.set_newline_policy(policy);
+            state.parse_line(line, &ss)
+        };
+        let a = Scope::new("a").unwrap();
+        let source = Push(Scope::new("source.test").unwrap());
+
+        assert_eq!(parse(NewlinePolicy::Keep, "a\r\n"), vec![(0, source.clone())]);
+        assert_eq!(parse(NewlinePolicy::Newline, "a\r\n"), vec![(0, source.clone()), (0, Push(a)), (3, Pop(1))]);
+        assert_eq!(parse(NewlinePolicy::Newline, "a\r"), vec![(0, source.clone()), (0, Push(a)), (2, Pop(1))]);
+        assert_eq!(parse(NewlinePolicy::Newline, "a\n"), vec![(0, source.clone()), (0, Push(a)), (2, Pop(1))]);
+        assert_eq!(parse(NewlinePolicy::Strip, "a\r\n"), vec![(0, source.clone()), (0, Push(a)), (1, Pop(1))]);
+        assert_eq!(parse(NewlinePolicy::Strip, "a"), vec![(0, source), (0, Push(a)), (1, Pop(1))]);
+    }
+
+    #[test]
+    fn can_fingerprint_states() {
+        let syntax = SyntaxDefinition::load_from_str(r#"
+        name: test
+        scope: source.test
+        contexts:
+          main:
+            - match: '<<(\w+)'
+              push: heredoc
+          heredoc:
+            - match: '^\1$'
+              pop: true
+        "#, true, None).unwrap();
+        let ss = link(syntax);
+        let state_after = |lines: &[&str]| {
+            let mut state = ParseState::new(&ss.syntaxes()[0]);
+            for line in lines {
+                state.parse_line(line, &ss);
+            }
+            state
+        };
+
+        let start = state_after(&["a\n"]);
+        assert_eq!(start.fingerprint(), state_after(&["b\n", "c\n"]).fingerprint());
+        let heredoc = state_after(&["<<END\n"]);
+        assert_ne!(start.fingerprint(), heredoc.fingerprint());
+        assert_eq!(heredoc.fingerprint(), state_after(&["<<END\n", "x\n"]).fingerprint());
+        assert_ne!(heredoc.fingerprint(), state_after(&["<<EOF\n"]).fingerprint());
+        assert_eq!(start.fingerprint(), state_after(&["<<END\n", "END\n"]).fingerprint());
+
+        let states: std::collections::HashSet<ParseState> = vec![start.clone(), heredoc, start].into_iter().collect();
+        assert_eq!(states.len(), 2);
+    }
+
     fn expect_scope_stacks_with_syntax(line: &str, expect: &[&str], syntax: SyntaxDefinition) {
         // check that each expected scope stack appears at least once while parsing the given test line
 
@@ -1,6 +1,174 @@
+#[cfg(not(any(feature = "regex-onig", feature = "regex-fancy")))]
+compile_error!("the `parsing` feature requires a regex engine, enable either `regex-onig` or `regex-fancy`");
+
 use lazycell::AtomicLazyCell;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
 use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// Compiled regexes by pattern, `None` while the cache is disabled
+    static ref SHARED_REGEXES: Mutex<Option<HashMap<String, Arc<regex_impl::Regex>>>> = Mutex::new(None);
+}
+
+/// Enables or disables a process-wide cache of compiled regexes.
+///
+/// Every [`SyntaxSet`] normally compiles its own regexes, so a program that loads the same
+/// syntaxes into several syntax sets, like one per worker, compiles every regex several times.
+/// With the cache enabled, regexes are looked up by their pattern before compiling them, and
+/// all sets share the compiled regex. All patterns are compiled with the same options, so the
+/// pattern is enough to identify a regex.
+///
+/// The cache is disabled by default, because it keeps every regex that was compiled while it
+/// is enabled, even after the syntax sets are dropped. Disabling it drops the cache, regexes
+/// that are in use stay compiled.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{set_shared_regex_cache, shared_regex_cache_len, SyntaxSet};
+///
+/// set_shared_regex_cache(true);
+/// let first = SyntaxSet::load_defaults_newlines();
+/// let second = SyntaxSet::load_defaults_newlines();
+/// first.precompile_syntax(first.find_syntax_by_extension("py").unwrap());
+/// let compiled = shared_regex_cache_len();
+/// assert!(compiled > 0);
+///
+/// // the second set uses the regexes compiled for the first
+/// second.precompile_syntax(second.find_syntax_by_extension("py").unwrap());
+/// assert_eq!(shared_regex_cache_len(), compiled);
+/// ```
+///
+/// [`SyntaxSet`]: struct.SyntaxSet.html
+pub fn set_shared_regex_cache(enabled: bool) {
+    let mut cache = SHARED_REGEXES.lock().unwrap();
+    match (enabled, cache.is_some()) {
+        (true, false) => *cache = Some(HashMap::new()),
+        (false, true) => *cache = None,
+        _ => {}
+    }
+}
+
+/// Removes all regexes from the process-wide cache, see [`set_shared_regex_cache`]
+///
+/// [`set_shared_regex_cache`]: fn.set_shared_regex_cache.html
+pub fn clear_shared_regex_cache() {
+    if let Some(ref mut regexes) = *SHARED_REGEXES.lock().unwrap() {
+        regexes.clear();
+    }
+}
+
+/// The number of regexes in the process-wide cache, see [`set_shared_regex_cache`]
+///
+/// [`set_shared_regex_cache`]: fn.set_shared_regex_cache.html
+pub fn shared_regex_cache_len() -> usize {
+    SHARED_REGEXES.lock().unwrap().as_ref().map(|regexes| regexes.len()).unwrap_or(0)
+}
+
+/// Limits for the regex engine that bound how much work a single search can do, see
+/// [`set_default_match_limits`] and [`ParseState::set_match_limits`].
+///
+/// A search that exceeds a limit is treated as not matching. Lower limits make the worst case of
+/// regexes that backtrack catastrophically faster, at the cost of patterns failing to match on
+/// some long lines. Limits that are `None` fall back to the process-wide defaults, and then to
+/// the defaults of the engine.
+///
+/// The limits are only supported by the Oniguruma engine (`regex-onig`), with `regex-fancy` they
+/// have no effect.
+///
+/// [`set_default_match_limits`]: fn.set_default_match_limits.html
+/// [`ParseState::set_match_limits`]: struct.ParseState.html#method.set_match_limits
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct MatchLimits {
+    /// The maximum number of backtracking steps of a search, Oniguruma's "retry limit in match".
+    /// Oniguruma's default is 10 000 000.
+    pub retry_limit: Option<u32>,
+    /// The maximum number of entries on the backtracking stack of a search. Oniguruma doesn't
+    /// limit it by default.
+    pub stack_limit: Option<u32>,
+}
+
+impl MatchLimits {
+    /// Uses the limits of `other` for the limits that aren't set
+    pub fn or(self, other: MatchLimits) -> MatchLimits {
+        MatchLimits {
+            retry_limit: self.retry_limit.or(other.retry_limit),
+            stack_limit: self.stack_limit.or(other.stack_limit),
+        }
+    }
+}
+
+/// Marks a limit that isn't set in the atomics below
+const NO_LIMIT: u64 = u64::MAX;
+
+static DEFAULT_RETRY_LIMIT: AtomicU64 = AtomicU64::new(NO_LIMIT);
+static DEFAULT_STACK_LIMIT: AtomicU64 = AtomicU64::new(NO_LIMIT);
+
+/// Sets the regex engine limits for all searches of the process that don't set their own, see
+/// [`MatchLimits`].
+///
+/// This is meant to be called once at startup, searches that are running at the same time might
+/// not see the new limits.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{default_match_limits, set_default_match_limits, MatchLimits};
+///
+/// set_default_match_limits(MatchLimits { retry_limit: Some(100_000), stack_limit: None });
+/// assert_eq!(default_match_limits().retry_limit, Some(100_000));
+/// ```
+///
+/// [`MatchLimits`]: struct.MatchLimits.html
+pub fn set_default_match_limits(limits: MatchLimits) {
+    let store = |atomic: &AtomicU64, limit: Option<u32>| {
+        atomic.store(limit.map(u64::from).unwrap_or(NO_LIMIT), Ordering::Relaxed);
+    };
+    store(&DEFAULT_RETRY_LIMIT, limits.retry_limit);
+    store(&DEFAULT_STACK_LIMIT, limits.stack_limit);
+}
+
+/// The limits set with [`set_default_match_limits`]
+///
+/// [`set_default_match_limits`]: fn.set_default_match_limits.html
+pub fn default_match_limits() -> MatchLimits {
+    let load = |atomic: &AtomicU64| match atomic.load(Ordering::Relaxed) {
+        NO_LIMIT => None,
+        limit => Some(limit as u32),
+    };
+    MatchLimits {
+        retry_limit: load(&DEFAULT_RETRY_LIMIT),
+        stack_limit: load(&DEFAULT_STACK_LIMIT),
+    }
+}
+
+/// Compiles a regex, or gets it from the shared cache if that is enabled
+fn compile_shared(regex_str: &str) -> Arc<regex_impl::Regex> {
+    let enabled = match *SHARED_REGEXES.lock().unwrap() {
+        Some(ref regexes) => {
+            if let Some(regex) = regexes.get(regex_str) {
+                return Arc::clone(regex);
+            }
+            true
+        }
+        None => false,
+    };
+    // compile without holding the lock, so neither other threads nor a panic for an invalid
+    // pattern affect the cache
+    let regex = Arc::new(regex_impl::Regex::new(regex_str).expect("regex string should be pre-tested"));
+    if !enabled {
+        return regex;
+    }
+    // another thread might have compiled the same pattern in the meantime
+    match *SHARED_REGEXES.lock().unwrap() {
+        Some(ref mut regexes) => Arc::clone(regexes.entry(regex_str.to_owned()).or_insert(regex)),
+        None => regex,
+    }
+}
 
 /// An abstraction for regex patterns.
 ///
@@ -10,7 +178,8 @@ use std::error::Error;
 #[derive(Debug)]
 pub struct Regex {
     regex_str: String,
-    regex: AtomicLazyCell<regex_impl::Regex>,
+    regex: AtomicLazyCell<Arc<regex_impl::Regex>>,
+    anchored_start: bool,
 }
 
 /// A region contains text positions for capture groups in a match result.
@@ -19,6 +188,15 @@ pub struct Region {
     region: regex_impl::Region,
 }
 
+// SAFETY: an onig region is a plain struct with raw pointers to the `beg` and `end` arrays of its
+// positions, which onig allocates with `malloc` for this region alone and frees with `free` when
+// it's dropped. Nothing in it refers to thread-local or shared state: its capture history tree is
+// only filled by searches with `ONIG_OPTION_CAPTURE_GROUP`, which syntect never uses, and the
+// regex it was filled by isn't referenced. So it can be moved to another thread like a `Vec`,
+// which makes parse states `Send`. It isn't `Sync`, which isn't needed.
+#[cfg(feature = "regex-onig")]
+unsafe impl Send for Region {}
+
 impl Regex {
     /// Create a new regex from the pattern string.
     ///
@@ -26,6 +204,7 @@ impl Regex {
     /// return a result.
     pub fn new(regex_str: String) -> Self {
         Self {
+            anchored_start: starts_with_anchor(&regex_str),
             regex_str,
             regex: AtomicLazyCell::new(),
         }
@@ -41,6 +220,21 @@ impl Regex {
         &self.regex_str
     }
 
+    /// Counts the capture groups in the pattern, not counting the whole match, without
+    /// compiling it.
+    pub(crate) fn capture_groups(&self) -> usize {
+        count_capture_groups(&self.regex_str)
+    }
+
+    /// Whether the pattern can only match at the start of a line, because it starts with `^` or
+    /// `\A` that isn't part of an alternation.
+    ///
+    /// This is determined from the pattern string without compiling it, and errs on the side of
+    /// returning false.
+    pub fn is_anchored_start(&self) -> bool {
+        self.anchored_start
+    }
+
     /// Check if the regex matches the given text.
     pub fn is_match(&self, text: &str) -> bool {
         self.regex().is_match(text)
@@ -59,18 +253,41 @@ impl Regex {
         begin: usize,
         end: usize,
         region: Option<&mut Region>,
+    ) -> bool {
+        self.search_with_limits(text, begin, end, region, MatchLimits::default())
+    }
+
+    /// Like [`search`], but with limits for the regex engine that take precedence over the
+    /// process-wide defaults. A search that exceeds them doesn't match.
+    ///
+    /// [`search`]: #method.search
+    pub fn search_with_limits(
+        &self,
+        text: &str,
+        begin: usize,
+        end: usize,
+        region: Option<&mut Region>,
+        limits: MatchLimits,
     ) -> bool {
         self.regex()
-            .search(text, begin, end, region.map(|r| &mut r.region))
+            .search(text, begin, end, region.map(|r| &mut r.region), limits.or(default_match_limits()))
+    }
+
+    /// Compiles the regex now if it hasn't been compiled yet
+    pub(crate) fn compile(&self) {
+        self.regex();
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_compiled(&self) -> bool {
+        self.regex.filled()
     }
 
     fn regex(&self) -> &regex_impl::Regex {
         if let Some(regex) = self.regex.borrow() {
             regex
         } else {
-            let regex =
-                regex_impl::Regex::new(&self.regex_str).expect("regex string should be pre-tested");
-            self.regex.fill(regex).ok();
+            self.regex.fill(compile_shared(&self.regex_str)).ok();
             self.regex.borrow().unwrap()
         }
     }
@@ -81,6 +298,7 @@ impl Clone for Regex {
         Regex {
             regex_str: self.regex_str.clone(),
             regex: AtomicLazyCell::new(),
+            anchored_start: self.anchored_start,
         }
     }
 }
@@ -112,6 +330,515 @@ impl<'de> Deserialize<'de> for Regex {
     }
 }
 
+pub(crate) fn count_capture_groups(regex_str: &str) -> usize {
+    let chars: Vec<char> = regex_str.chars().collect();
+    let mut count = 0;
+    let mut class_depth = 0usize;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 1,
+            '[' => {
+                class_depth += 1;
+                // a `]` right at the start of a class is a literal
+                if chars.get(i + 1) == Some(&'^') {
+                    i += 1;
+                }
+                if chars.get(i + 1) == Some(&']') {
+                    i += 1;
+                }
+            }
+            ']' if class_depth > 0 => class_depth -= 1,
+            '(' if class_depth == 0 => {
+                let captures = match (chars.get(i + 1), chars.get(i + 2), chars.get(i + 3)) {
+                    // named groups, unlike lookbehinds
+                    (Some('?'), Some('<'), Some(c)) => *c != '=' && *c != '!',
+                    (Some('?'), Some('P'), Some('<')) | (Some('?'), Some('\''), _) => true,
+                    (Some('?'), _, _) => false,
+                    _ => true,
+                };
+                if captures {
+                    count += 1;
+                }
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+    count
+}
+
+fn starts_with_anchor(regex_str: &str) -> bool {
+    let rest = match regex_str.strip_prefix("\\A").or_else(|| regex_str.strip_prefix('^')) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    // comments in extended mode could hide the structure from the scan below
+    if enables_flag(rest, &['x']) {
+        return false;
+    }
+
+    // a top-level alternation would make the anchor optional
+    let mut group_depth = 0usize;
+    let mut class_depth = 0usize;
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '[' => {
+                class_depth += 1;
+                // a `]` right at the start of a class is a literal
+                if chars.peek() == Some(&'^') {
+                    chars.next();
+                }
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                }
+            }
+            ']' if class_depth > 0 => class_depth -= 1,
+            '(' if class_depth == 0 => group_depth += 1,
+            ')' if class_depth == 0 => group_depth = group_depth.saturating_sub(1),
+            '|' if class_depth == 0 && group_depth == 0 => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// A set of bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+    fn contains(&self, b: u8) -> bool {
+        self.0[(b / 64) as usize] & (1 << (b % 64)) != 0
+    }
+
+    fn insert(&mut self, b: u8) {
+        self.0[(b / 64) as usize] |= 1 << (b % 64);
+    }
+
+    fn insert_range(&mut self, from: u8, to: u8) {
+        for b in from..=to {
+            self.insert(b);
+        }
+    }
+
+    /// Inserts the first byte of the UTF-8 encoding of the char
+    fn insert_char(&mut self, c: char) {
+        let mut buf = [0; 4];
+        self.insert(c.encode_utf8(&mut buf).as_bytes()[0]);
+    }
+
+    fn union(&mut self, other: &ByteSet) {
+        for (a, b) in self.0.iter_mut().zip(&other.0) {
+            *a |= *b;
+        }
+    }
+}
+
+/// What is known about where the match patterns of a context can start, so that patterns that
+/// can't match in the rest of a line are skipped without running the regex engine.
+///
+/// It's built once for all the patterns of a context when the syntax set is linked. Patterns
+/// are filtered either by literals that every match starts with one of, or by the bytes every
+/// match starts with one of.
+#[derive(Debug, Default)]
+pub(crate) struct ContextPrefilter {
+    /// How the pattern at each index is filtered
+    filters: Box<[PatternFilter]>,
+}
+
+#[derive(Debug)]
+enum PatternFilter {
+    /// The pattern is too complicated to tell, or can match the empty string
+    None,
+    Literals(Vec<String>),
+    FirstBytes(ByteSet),
+}
+
+impl ContextPrefilter {
+    /// Builds the prefilter for the patterns of a context, which are `None` for includes
+    pub(crate) fn new<'a, I: IntoIterator<Item = Option<&'a Regex>>>(patterns: I) -> ContextPrefilter {
+        let filters = patterns.into_iter().map(|regex| {
+            let regex_str = match regex {
+                Some(regex) => regex.regex_str(),
+                None => return PatternFilter::None,
+            };
+            // case-insensitive or extended mode changes what the literals match anywhere in the
+            // pattern
+            if enables_flag(regex_str, &['i', 'x']) {
+                return PatternFilter::None;
+            }
+            let chars: Vec<char> = regex_str.chars().collect();
+            if let Some(prefixes) = literal_prefixes(&chars) {
+                PatternFilter::Literals(prefixes)
+            } else if let Some(set) = first_bytes(&chars) {
+                PatternFilter::FirstBytes(set)
+            } else {
+                PatternFilter::None
+            }
+        }).collect();
+        ContextPrefilter { filters }
+    }
+
+    /// Quickly finds the earliest position from `begin` on where a match of at least one byte of
+    /// the pattern at `index` could start, or `None` if there is none.
+    ///
+    /// If the pattern is too complicated to tell, or can match the empty string, this returns
+    /// `begin`.
+    pub(crate) fn earliest_start(&self, index: usize, text: &str, begin: usize) -> Option<usize> {
+        let rest = &text[begin..];
+        let found = match self.filters.get(index) {
+            Some(PatternFilter::Literals(prefixes)) => {
+                prefixes.iter().filter_map(|prefix| rest.find(prefix.as_str())).min()
+            }
+            Some(PatternFilter::FirstBytes(set)) => {
+                rest.as_bytes().iter().position(|&b| set.contains(b))
+            }
+            Some(PatternFilter::None) | None => Some(0),
+        };
+        found.map(|i| begin + i)
+    }
+}
+
+/// Whether a flag group like `(?mi)` or `(?sx:...)` anywhere in the pattern turns on one of
+/// `flags`. This can give false positives for escaped parentheses, which is fine for deciding
+/// whether the pattern is too complicated to scan.
+fn enables_flag(regex_str: &str, flags: &[char]) -> bool {
+    let mut rest = regex_str;
+    while let Some(i) = rest.find("(?") {
+        rest = &rest[i + 2..];
+        let len = rest.find(|c: char| !c.is_ascii_alphabetic() && c != '-').unwrap_or(rest.len());
+        let (group_flags, after) = rest.split_at(len);
+        let is_flag_group = after.starts_with(')') || after.starts_with(':');
+        let enabled = group_flags.split('-').next().unwrap_or("");
+        if is_flag_group && enabled.chars().any(|c| flags.contains(&c)) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Finds literals that a match of the pattern has to start with one of.
+///
+/// Like `first_bytes`, this only understands literals at the start of the pattern or of
+/// alternatives in a group at its start.
+fn literal_prefixes(chars: &[char]) -> Option<Vec<String>> {
+    let mut pos = 0;
+    let prefixes = literal_prefixes_of_alternation(chars, &mut pos)?;
+    if pos == chars.len() {
+        Some(prefixes)
+    } else {
+        None
+    }
+}
+
+fn literal_prefixes_of_alternation(chars: &[char], pos: &mut usize) -> Option<Vec<String>> {
+    let mut prefixes = Vec::new();
+    loop {
+        prefixes.extend(literal_prefixes_of_sequence(chars, pos)?);
+        match chars.get(*pos) {
+            Some('|') => *pos += 1,
+            _ => return Some(prefixes),
+        }
+    }
+}
+
+fn literal_prefixes_of_sequence(chars: &[char], pos: &mut usize) -> Option<Vec<String>> {
+    skip_anchors(chars, pos);
+    let prefixes = if chars.get(*pos) == Some(&'(') {
+        *pos += 1;
+        enter_group(chars, pos)?;
+        let prefixes = literal_prefixes_of_alternation(chars, pos)?;
+        if chars.get(*pos) != Some(&')') {
+            return None;
+        }
+        *pos += 1;
+        if allows_no_repetitions(chars, *pos) {
+            return None;
+        }
+        prefixes
+    } else {
+        let mut literal = String::new();
+        loop {
+            let (c, len) = match chars.get(*pos) {
+                Some('\\') => match chars.get(*pos + 1).and_then(|&c| escaped_literal(c)) {
+                    Some(c) => (c, 2),
+                    None => break,
+                },
+                Some(&c) if !"()[]{}|.?*+^$".contains(c) => (c, 1),
+                _ => break,
+            };
+            if allows_no_repetitions(chars, *pos + len) {
+                break;
+            }
+            literal.push(c);
+            *pos += len;
+            if matches!(chars.get(*pos), Some('+') | Some('{')) {
+                break;
+            }
+        }
+        if literal.is_empty() {
+            return None;
+        }
+        vec![literal]
+    };
+    skip_to_alternation_end(chars, pos)?;
+    Some(prefixes)
+}
+
+/// Returns the character that an escape stands for, if it's a single character
+fn escaped_literal(escaped: char) -> Option<char> {
+    match escaped {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        'f' => Some('\u{0C}'),
+        'v' => Some('\u{0B}'),
+        'e' => Some('\u{1B}'),
+        'a' => Some('\u{07}'),
+        c if c.is_ascii_alphanumeric() => None,
+        c => Some(c),
+    }
+}
+
+/// Skips the zero-width assertions that patterns often start with
+fn skip_anchors(chars: &[char], pos: &mut usize) {
+    loop {
+        match chars.get(*pos) {
+            Some('^') => *pos += 1,
+            Some('\\') if matches!(chars.get(*pos + 1), Some('b') | Some('B') | Some('A') | Some('G')) => *pos += 2,
+            _ => return,
+        }
+    }
+}
+
+/// Skips the `?:` or name of a group after its `(`, returning `None` for lookarounds, flags and
+/// comments
+fn enter_group(chars: &[char], pos: &mut usize) -> Option<()> {
+    if chars.get(*pos) == Some(&'?') {
+        match (chars.get(*pos + 1), chars.get(*pos + 2)) {
+            (Some(':'), _) | (Some('>'), _) => *pos += 2,
+            (Some('<'), Some(c)) | (Some('P'), Some(c)) if c.is_alphabetic() || *c == '<' => {
+                while chars.get(*pos)? != &'>' {
+                    *pos += 1;
+                }
+                *pos += 1;
+            }
+            _ => return None,
+        }
+    }
+    Some(())
+}
+
+/// Whether a quantifier at the position allows the item before it to not be there at all
+fn allows_no_repetitions(chars: &[char], pos: usize) -> bool {
+    match chars.get(pos) {
+        Some('?') | Some('*') => true,
+        Some('{') => matches!(chars.get(pos + 1), Some('0') | Some(',')),
+        _ => false,
+    }
+}
+
+/// Finds the bytes that a match of the pattern can start with.
+///
+/// This only understands the common ways patterns start, like literals, classes, groups with
+/// alternations and anchors. It returns `None` for anything else, and for patterns that could
+/// match the empty string, so it never rules out a possible match.
+fn first_bytes(chars: &[char]) -> Option<ByteSet> {
+    let mut pos = 0;
+    let set = first_bytes_of_alternation(chars, &mut pos)?;
+    if pos == chars.len() {
+        Some(set)
+    } else {
+        // an unbalanced `)`
+        None
+    }
+}
+
+/// Handles alternatives up to the end of the pattern or the `)` of the current group
+fn first_bytes_of_alternation(chars: &[char], pos: &mut usize) -> Option<ByteSet> {
+    let mut set = ByteSet::default();
+    loop {
+        set.union(&first_bytes_of_sequence(chars, pos)?);
+        match chars.get(*pos) {
+            Some('|') => *pos += 1,
+            _ => return Some(set),
+        }
+    }
+}
+
+/// Handles one alternative, of which only the first item that consumes input matters
+fn first_bytes_of_sequence(chars: &[char], pos: &mut usize) -> Option<ByteSet> {
+    skip_anchors(chars, pos);
+    if *pos == chars.len() {
+        return None;
+    }
+    let set = first_bytes_of_atom(chars, pos)?;
+    // a quantifier that allows no repetitions makes the next item relevant as well
+    if allows_no_repetitions(chars, *pos) {
+        return None;
+    }
+    skip_to_alternation_end(chars, pos)?;
+    Some(set)
+}
+
+fn first_bytes_of_atom(chars: &[char], pos: &mut usize) -> Option<ByteSet> {
+    let mut set = ByteSet::default();
+    match chars[*pos] {
+        '(' => {
+            *pos += 1;
+            enter_group(chars, pos)?;
+            set = first_bytes_of_alternation(chars, pos)?;
+            if chars.get(*pos) != Some(&')') {
+                return None;
+            }
+            *pos += 1;
+        }
+        '[' => {
+            *pos += 1;
+            set = first_bytes_of_class(chars, pos)?;
+        }
+        '\\' => {
+            set = first_bytes_of_escape(*chars.get(*pos + 1)?)?;
+            *pos += 2;
+        }
+        '.' | '$' | ')' | '|' | '?' | '*' | '+' | '{' => return None,
+        c => {
+            set.insert_char(c);
+            *pos += 1;
+        }
+    }
+    Some(set)
+}
+
+/// Handles a character class after its `[`
+fn first_bytes_of_class(chars: &[char], pos: &mut usize) -> Option<ByteSet> {
+    let mut set = ByteSet::default();
+    if chars.get(*pos) == Some(&'^') {
+        return None;
+    }
+    let mut first = true;
+    loop {
+        let c = *chars.get(*pos)?;
+        *pos += 1;
+        let lower = match c {
+            ']' if !first => return Some(set),
+            '[' | '&' => return None,
+            '\\' => {
+                let escaped = *chars.get(*pos)?;
+                *pos += 1;
+                if escaped.is_ascii_alphanumeric() {
+                    set.union(&first_bytes_of_escape(escaped)?);
+                    first = false;
+                    continue;
+                }
+                escaped
+            }
+            c => c,
+        };
+        first = false;
+        if chars.get(*pos) == Some(&'-') && matches!(chars.get(*pos + 1), Some(&c) if c != ']') {
+            let upper = match chars[*pos + 1] {
+                '\\' => *chars.get(*pos + 2).filter(|c| !c.is_ascii_alphanumeric())?,
+                '[' => return None,
+                c => c,
+            };
+            *pos += if chars[*pos + 1] == '\\' { 3 } else { 2 };
+            if upper < lower {
+                return None;
+            }
+            if lower.is_ascii() {
+                set.insert_range(lower as u8, upper.min('\u{7f}') as u8);
+            }
+            if !upper.is_ascii() {
+                set.insert_range(0x80, 0xFF);
+            }
+        } else {
+            set.insert_char(lower);
+        }
+    }
+}
+
+fn first_bytes_of_escape(escaped: char) -> Option<ByteSet> {
+    let mut set = ByteSet::default();
+    match escaped {
+        // these also match non-ASCII characters
+        'd' | 'w' | 's' | 'h' => {
+            match escaped {
+                'd' => set.insert_range(b'0', b'9'),
+                'w' => {
+                    set.insert_range(b'0', b'9');
+                    set.insert_range(b'a', b'z');
+                    set.insert_range(b'A', b'Z');
+                    set.insert(b'_');
+                }
+                'h' => {
+                    set.insert_range(b'0', b'9');
+                    set.insert_range(b'a', b'f');
+                    set.insert_range(b'A', b'F');
+                }
+                _ => {
+                    set.insert_range(b'\t', b'\r');
+                    set.insert(b' ');
+                }
+            }
+            set.insert_range(0x80, 0xFF);
+        }
+        'n' => set.insert(b'\n'),
+        't' => set.insert(b'\t'),
+        'r' => set.insert(b'\r'),
+        'f' => set.insert(0x0C),
+        'v' => set.insert(0x0B),
+        'e' => set.insert(0x1B),
+        'a' => set.insert(0x07),
+        c if c.is_ascii_alphanumeric() => return None,
+        c => set.insert_char(c),
+    }
+    Some(set)
+}
+
+/// Skips the rest of the current alternative, stopping before a `|` or `)` that ends it
+fn skip_to_alternation_end(chars: &[char], pos: &mut usize) -> Option<()> {
+    let mut depth = 0usize;
+    let mut in_class = false;
+    while let Some(&c) = chars.get(*pos) {
+        match c {
+            '\\' => *pos += 1,
+            '[' if !in_class => {
+                in_class = true;
+                // a `]` right at the start of a class is a literal
+                if chars.get(*pos + 1) == Some(&'^') {
+                    *pos += 1;
+                }
+                if chars.get(*pos + 1) == Some(&']') {
+                    *pos += 1;
+                }
+            }
+            '[' => return None,
+            ']' if in_class => in_class = false,
+            '(' if !in_class => depth += 1,
+            ')' if !in_class && depth == 0 => return Some(()),
+            ')' if !in_class => depth -= 1,
+            '|' if !in_class && depth == 0 => return Some(()),
+            _ => {}
+        }
+        *pos += 1;
+    }
+    Some(())
+}
+
+impl Hash for Region {
+    /// Hashes the range of the whole match, which is enough for regions that are equal to have
+    /// the same hash
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.pos(0).hash(state);
+    }
+}
+
 impl Region {
     pub fn new() -> Self {
         Self {
@@ -131,6 +858,7 @@ impl Region {
 #[cfg(feature = "regex-onig")]
 mod regex_impl {
     pub use onig::Region;
+    use super::MatchLimits;
     use onig::{MatchParam, RegexOptions, SearchOptions, Syntax};
     use std::error::Error;
 
@@ -168,14 +896,22 @@ mod regex_impl {
             begin: usize,
             end: usize,
             region: Option<&mut Region>,
+            limits: MatchLimits,
         ) -> bool {
+            let mut param = MatchParam::default();
+            if let Some(limit) = limits.retry_limit {
+                param.set_retry_limit_in_match(limit);
+            }
+            if let Some(limit) = limits.stack_limit {
+                param.set_match_stack_limit(limit);
+            }
             let matched = self.regex.search_with_param(
                 text,
                 begin,
                 end,
                 SearchOptions::SEARCH_OPTION_NONE,
                 region,
-                MatchParam::default(),
+                param,
             );
 
             // If there's an error during search, treat it as non-matching.
@@ -193,6 +929,7 @@ mod regex_impl {
 // If both regex-fancy and regex-onig are requested, this condition makes regex-onig win.
 #[cfg(all(feature = "regex-fancy", not(feature = "regex-onig")))]
 mod regex_impl {
+    use super::MatchLimits;
     use std::error::Error;
 
     #[derive(Debug)]
@@ -231,6 +968,7 @@ mod regex_impl {
             begin: usize,
             end: usize,
             region: Option<&mut Region>,
+            _limits: MatchLimits,
         ) -> bool {
             // If there's an error during search, treat it as non-matching.
             // For example, in case of catastrophic backtracking, fancy-regex should
@@ -278,6 +1016,160 @@ mod tests {
         assert!(regex.regex.filled());
     }
 
+    #[test]
+    fn can_move_regions_between_threads() {
+        let regex = Regex::new(String::from(r"(b+)(c)"));
+        let region = std::thread::spawn(move || {
+            let mut region = Region::new();
+            assert!(regex.search("abbc", 0, 4, Some(&mut region)));
+            region
+        }).join().unwrap();
+        assert_eq!(region.pos(0), Some((1, 4)));
+        assert_eq!(region.pos(1), Some((1, 3)));
+        assert_eq!(region.pos(2), Some((3, 4)));
+    }
+
+    #[test]
+    fn can_share_compiled_regexes() {
+        // a pattern no other test uses, since the cache is global
+        let pattern = r"shared[ ]cache\d+";
+        set_shared_regex_cache(true);
+        let first = Regex::new(pattern.to_string());
+        let second = Regex::new(pattern.to_string());
+        assert!(first.is_match("shared cache1"));
+        assert!(second.is_match("shared cache2"));
+        assert!(Arc::ptr_eq(first.regex.borrow().unwrap(), second.regex.borrow().unwrap()));
+        assert!(shared_regex_cache_len() >= 1);
+
+        set_shared_regex_cache(false);
+        assert_eq!(shared_regex_cache_len(), 0);
+        let third = Regex::new(pattern.to_string());
+        assert!(third.is_match("shared cache3"));
+        assert!(!Arc::ptr_eq(first.regex.borrow().unwrap(), third.regex.borrow().unwrap()));
+    }
+
+    #[test]
+    fn finds_earliest_start() {
+        let prefilter = |pattern: &str| ContextPrefilter::new(Some(Some(&Regex::new(pattern.to_string()))));
+        let earliest_start = |pattern: &str, text: &str| prefilter(pattern).earliest_start(0, text, 0);
+        let cases = [
+            (r"\bfn\b", "let x = 1", None),
+            (r"\bfn\b", "f n", None),
+            (r"\bfn\b", "x fn", Some(2)),
+            (r"\b(if|else|while)\b", "for x to y", None),
+            (r"\b(if|else|while)\b", "x = y\nwhile", Some(6)),
+            (r"(?:\#|//)\s*TODO", "a + b", None),
+            (r"(?:\#|//)\s*TODO", "a // b #", Some(2)),
+            (r"x+y", "y x", Some(2)),
+            (r"ab?c", "c ac", Some(2)),
+            (r"\n", "a\n", Some(1)),
+            (r"[A-Z_][A-Z0-9_]*", "lower case", None),
+            (r"[A-Z_][A-Z0-9_]*", "lower_case", Some(5)),
+            (r"[]x]", "]", Some(0)),
+            (r"\d+\.\d*", "no numbers", None),
+            (r"\d+\.\d*", "ünïcode", Some(0)),
+            (r"<(?<tag>[a-z]+)>", "no tags", None),
+            (r"\G\s+\)", "abc", None),
+            (r"é", "e", None),
+            (r"é", "café", Some(3)),
+            // patterns that aren't understood or can match the empty string could match anywhere
+            (r"(?i)fn", "FN", Some(0)),
+            (r"(?=x)", "abc", Some(0)),
+            (r"a?b", "abc", Some(0)),
+            (r"a*", "", Some(0)),
+            (r"(a|)", "", Some(0)),
+            (r"[^a]", "aaa", Some(0)),
+            (r".", "", Some(0)),
+            (r"\1", "", Some(0)),
+            (r"x{0,2}y", "y", Some(0)),
+            (r"^$", "", Some(0)),
+        ];
+        for &(pattern, text, expected) in &cases {
+            assert_eq!(earliest_start(pattern, text), expected, "{} on {:?}", pattern, text);
+        }
+
+        assert_eq!(prefilter(r"x\b").earliest_start(0, "x..x..", 1), Some(3));
+        assert_eq!(prefilter(r"[xy]").earliest_start(0, "x..x..", 4), None);
+    }
+
+    #[test]
+    fn filters_each_pattern_of_context() {
+        let regexes: Vec<Regex> = [r"\bfn\b", r"[xy]", r"a*"].iter()
+            .map(|pattern| Regex::new(pattern.to_string()))
+            .collect();
+        // the second pattern is an include
+        let prefilter = ContextPrefilter::new(vec![Some(&regexes[0]), None, Some(&regexes[1]), Some(&regexes[2])]);
+        let text = "y fn x fn";
+        assert_eq!(prefilter.earliest_start(0, text, 0), Some(2));
+        assert_eq!(prefilter.earliest_start(0, text, 3), Some(7));
+        assert_eq!(prefilter.earliest_start(1, text, 3), Some(3));
+        assert_eq!(prefilter.earliest_start(2, text, 1), Some(5));
+        assert_eq!(prefilter.earliest_start(2, text, 6), None);
+        assert_eq!(prefilter.earliest_start(3, text, 6), Some(6));
+    }
+
+    #[test]
+    fn finds_literal_prefixes() {
+        let prefixes = |pattern: &str| {
+            literal_prefixes(&pattern.chars().collect::<Vec<_>>())
+        };
+        assert_eq!(prefixes(r"\b(?:foo|ba?r)\.x"), Some(vec!["foo".to_string(), "b".to_string()]));
+        assert_eq!(prefixes(r"^\s*#"), None);
+        assert_eq!(prefixes(r"\{\{"), Some(vec!["{{".to_string()]));
+        assert_eq!(prefixes(r"(foo)?bar"), None);
+    }
+
+    #[test]
+    fn prefilter_ignores_patterns_with_combined_flags() {
+        // flags set in the middle of a pattern can apply to the alternatives after them too
+        let patterns = [r"(?mi)Foo", r"(?mx) a b", r"(?mx:c d)", r"z(?im)z|Bar", r"z(?mx)z| e f"];
+        let texts = ["foo", "ab", "cd", "bar", "ef"];
+        let regexes: Vec<Regex> = patterns.iter().map(|p| Regex::new(p.to_string())).collect();
+        let prefilter = ContextPrefilter::new(regexes.iter().map(Some));
+        for (index, (regex, text)) in regexes.iter().zip(&texts).enumerate() {
+            // Oniguruma applies the flags only to the alternatives after them in the same group
+            if index < 3 || regex.search(text, 0, text.len(), None) {
+                assert_eq!(prefilter.earliest_start(index, text, 0), Some(0), "{}", regex.regex_str());
+            }
+        }
+        assert!(regexes[0].search("foo", 0, 3, None));
+        assert!(regexes[1].search("ab", 0, 2, None));
+        assert!(!enables_flag(r"(?m-i)Baz(?<i>x)(?P<x>y)", &['i', 'x']));
+    }
+
+    #[test]
+    fn counts_capture_groups() {
+        assert_eq!(count_capture_groups(r"abc"), 0);
+        assert_eq!(count_capture_groups(r"(a)(?:b)(c(d))"), 3);
+        assert_eq!(count_capture_groups(r"(?<name>a)(?<=b)(?<!c)(?=d)(?!e)(?>f)"), 1);
+        assert_eq!(count_capture_groups(r"\(a\)[(](b)[]()]"), 1);
+    }
+
+    #[cfg(feature = "regex-onig")]
+    #[test]
+    fn applies_match_limits() {
+        let regex = Regex::new(r"(a|ab)+c".into());
+        let text = "abababababc";
+        assert!(regex.search(text, 0, text.len(), None));
+        let tight = MatchLimits { retry_limit: Some(1), stack_limit: None };
+        assert!(!regex.search_with_limits(text, 0, text.len(), None, tight));
+        let loose = MatchLimits { retry_limit: Some(1_000), stack_limit: Some(1_000) };
+        assert!(regex.search_with_limits(text, 0, text.len(), None, loose));
+        assert_eq!(tight.or(loose), MatchLimits { retry_limit: Some(1), stack_limit: Some(1_000) });
+    }
+
+    #[test]
+    fn detects_anchored_patterns() {
+        let anchored = [r"^\s*#", r"\A(foo|bar)", r"^[|(]+x", r"^\|", r"^[]|]"];
+        for pattern in &anchored {
+            assert!(Regex::new(pattern.to_string()).is_anchored_start(), "{}", pattern);
+        }
+        let unanchored = [r"\s*^", r"^a|b", r"(^a)", r"\^a", r"^(?x) a # (\n | b", r"^(?mx) a # (\n | b", r"\Gfoo"];
+        for pattern in &unanchored {
+            assert!(!Regex::new(pattern.to_string()).is_anchored_start(), "{}", pattern);
+        }
+    }
+
     #[test]
     fn serde_as_string() {
         let pattern: Regex = serde_json::from_str("\"just a string\"").unwrap();
@@ -2,6 +2,7 @@
 use std::collections::HashMap;
 use std::u16;
 use std::sync::Mutex;
+use std::convert::TryFrom;
 use std::fmt;
 use std::str::FromStr;
 use std::u64;
@@ -56,6 +57,8 @@ pub enum ParseScopeError {
     /// The internal representation uses 16 bits per atom, so if all scopes ever
     /// used by the program have more than 2^16-2 atoms, things break
     TooManyAtoms,
+    /// A scope selector has a `(` without a matching `)` or the other way around
+    UnbalancedParentheses,
 }
 
 /// The structure used to keep track of the mapping between scope atom numbers and their string
@@ -86,7 +89,7 @@ pub struct ScopeRepository {
 /// `text.html.ruby text.html.basic source.js.embedded.html string.quoted.double.js`
 ///
 /// [`ScopeSelectors`]: ../highlighting/struct.ScopeSelectors.html
-#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
 pub struct ScopeStack {
     clear_stack: Vec<Vec<Scope>>,
     pub scopes: Vec<Scope>,
@@ -126,6 +129,118 @@ pub enum BasicScopeStackOp {
     Pop,
 }
 
+/// The scope stack operations for a line, coalesced into a compact form
+///
+/// The operations at the same position are stored together without repeating the position,
+/// consecutive pops are merged and a push that is immediately popped again is dropped, since it
+/// doesn't apply to any text. For large files this takes considerably less memory than the
+/// `Vec<(usize, ScopeStackOp)>` returned by [`ParseState::parse_line`], and highlighting it with
+/// [`HighlightIterator::from_compact`] gives the same result.
+///
+/// Positions are stored as `u32`, so lines have to be shorter than 4 GiB.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{CompactOps, Scope, ScopeStackOp};
+///
+/// let s = Scope::new("string").unwrap();
+/// let ops = vec![
+///     (0, ScopeStackOp::Push(s)),
+///     (2, ScopeStackOp::Pop(1)),
+///     (2, ScopeStackOp::Push(s)),
+///     (2, ScopeStackOp::Pop(1)),
+/// ];
+/// let compact = CompactOps::from_ops(&ops);
+/// assert_eq!(compact.len(), 2);
+/// assert_eq!(compact.to_ops(), vec![(0, ScopeStackOp::Push(s)), (2, ScopeStackOp::Pop(1))]);
+/// ```
+///
+/// [`ParseState::parse_line`]: struct.ParseState.html#method.parse_line
+/// [`HighlightIterator::from_compact`]: ../highlighting/struct.HighlightIterator.html#method.from_compact
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompactOps {
+    ops: Box<[ScopeStackOp]>,
+    /// The position of each run of operations and the index after its last operation
+    runs: Box<[(u32, u32)]>,
+}
+
+impl CompactOps {
+    /// Coalesces operations as returned by [`ParseState::parse_line`]
+    ///
+    /// [`ParseState::parse_line`]: struct.ParseState.html#method.parse_line
+    pub fn from_ops(ops: &[(usize, ScopeStackOp)]) -> CompactOps {
+        let mut compact_ops: Vec<ScopeStackOp> = Vec::with_capacity(ops.len());
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        let mut run_start = 0;
+        for (i, &(pos, ref op)) in ops.iter().enumerate() {
+            match *op {
+                ScopeStackOp::Noop => {}
+                ScopeStackOp::Pop(mut n) => {
+                    // only merge with operations at the same position
+                    while n > 0 && compact_ops.len() > run_start {
+                        match compact_ops.last() {
+                            Some(ScopeStackOp::Push(_)) => n -= 1,
+                            Some(&ScopeStackOp::Pop(prev)) => n += prev,
+                            _ => break,
+                        }
+                        compact_ops.pop();
+                    }
+                    if n > 0 {
+                        compact_ops.push(ScopeStackOp::Pop(n));
+                    }
+                }
+                _ => compact_ops.push(op.clone()),
+            }
+            let run_ends = ops.get(i + 1).map(|&(next, _)| next != pos).unwrap_or(true);
+            if run_ends && compact_ops.len() > run_start {
+                let pos = u32::try_from(pos).expect("line too long for CompactOps");
+                runs.push((pos, compact_ops.len() as u32));
+                run_start = compact_ops.len();
+            }
+        }
+        CompactOps {
+            ops: compact_ops.into_boxed_slice(),
+            runs: runs.into_boxed_slice(),
+        }
+    }
+
+    /// The number of operations
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The number of distinct positions that have operations
+    pub fn runs(&self) -> usize {
+        self.runs.len()
+    }
+
+    /// Returns the position and the operations of a run
+    pub fn run(&self, index: usize) -> Option<(usize, &[ScopeStackOp])> {
+        let &(pos, end) = self.runs.get(index)?;
+        let start = if index == 0 { 0 } else { self.runs[index - 1].1 as usize };
+        Some((pos as usize, &self.ops[start..end as usize]))
+    }
+
+    /// Iterates over the positions and the operations to apply at each of them, in order
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[ScopeStackOp])> + '_ {
+        (0..self.runs.len()).filter_map(move |i| self.run(i))
+    }
+
+    /// Expands the operations again, in the form returned by [`ParseState::parse_line`]
+    ///
+    /// [`ParseState::parse_line`]: struct.ParseState.html#method.parse_line
+    pub fn to_ops(&self) -> Vec<(usize, ScopeStackOp)> {
+        self.iter()
+            .flat_map(|(pos, ops)| ops.iter().map(move |op| (pos, op.clone())))
+            .collect()
+    }
+}
+
 fn pack_as_u16s(atoms: &[usize]) -> Result<Scope, ParseScopeError> {
     let mut res = Scope { a: 0, b: 0 };
 
@@ -487,6 +602,44 @@ impl ScopeStack {
         self.len() == 0
     }
 
+    /// Returns the shortest sequence of operations that turns this stack into `other`.
+    ///
+    /// Scopes the two stacks have in common at the bottom are left alone, so a renderer that
+    /// opens and closes a tag per scope doesn't need to close and reopen them. Only the visible
+    /// scopes are compared, applying the result gives a stack with the same scopes as `other`
+    /// but no information about cleared scopes.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{Scope, ScopeStack, ScopeStackOp};
+    /// use std::str::FromStr;
+    /// let mut a = ScopeStack::from_str("source.rust meta.block string.quoted").unwrap();
+    /// let b = ScopeStack::from_str("source.rust meta.block comment.line punctuation").unwrap();
+    /// let ops = a.diff(&b);
+    /// assert_eq!(ops, vec![
+    ///     ScopeStackOp::Pop(1),
+    ///     ScopeStackOp::Push(Scope::new("comment.line").unwrap()),
+    ///     ScopeStackOp::Push(Scope::new("punctuation").unwrap()),
+    /// ]);
+    /// for op in &ops {
+    ///     a.apply(op);
+    /// }
+    /// assert_eq!(a.as_slice(), b.as_slice());
+    /// ```
+    pub fn diff(&self, other: &ScopeStack) -> Vec<ScopeStackOp> {
+        let common = self.scopes.iter()
+            .zip(other.scopes.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let mut ops = Vec::with_capacity(other.len() - common + 1);
+        if self.len() > common {
+            ops.push(ScopeStackOp::Pop(self.len() - common));
+        }
+        ops.extend(other.scopes[common..].iter().map(|&s| ScopeStackOp::Push(s)));
+        ops
+    }
+
     /// Checks if this stack as a selector matches the given stack, returning the match score if so
     ///
     /// Higher match scores indicate stronger matches. Scores are ordered according to the rules
@@ -562,6 +715,43 @@ mod tests {
         // assert_eq!(Scope::new("source.php"), Scope::new("source.php"));
     }
 
+    #[test]
+    fn compact_ops_work() {
+        let a = Scope::new("a").unwrap();
+        let b = Scope::new("b").unwrap();
+        let ops = vec![
+            (0, ScopeStackOp::Push(a)),
+            (0, ScopeStackOp::Noop),
+            (0, ScopeStackOp::Push(b)),
+            (3, ScopeStackOp::Pop(1)),
+            (3, ScopeStackOp::Push(b)),
+            (3, ScopeStackOp::Pop(2)),
+            (3, ScopeStackOp::Clear(ClearAmount::All)),
+            (5, ScopeStackOp::Restore),
+            (5, ScopeStackOp::Push(a)),
+            (7, ScopeStackOp::Push(b)),
+            (7, ScopeStackOp::Pop(1)),
+        ];
+        let compact = CompactOps::from_ops(&ops);
+        assert_eq!(compact.runs(), 3);
+        assert_eq!(compact.run(0), Some((0, &[ScopeStackOp::Push(a), ScopeStackOp::Push(b)][..])));
+        assert_eq!(compact.run(1), Some((3, &[ScopeStackOp::Pop(2), ScopeStackOp::Clear(ClearAmount::All)][..])));
+        assert_eq!(compact.run(2), Some((5, &[ScopeStackOp::Restore, ScopeStackOp::Push(a)][..])));
+        assert_eq!(compact.run(3), None);
+        assert_eq!(compact.len(), 6);
+
+        let mut expected = ScopeStack::new();
+        for (_, op) in &ops {
+            expected.apply(op);
+        }
+        let mut stack = ScopeStack::new();
+        for (_, op) in compact.to_ops() {
+            stack.apply(&op);
+        }
+        assert_eq!(stack, expected);
+        assert!(CompactOps::from_ops(&[]).is_empty());
+    }
+
     #[test]
     fn repo_works() {
         let mut repo = ScopeRepository::new();
@@ -614,6 +804,22 @@ mod tests {
             .is_prefix_of(Scope::new("1.2.3.4.5.6.7.8").unwrap()));
     }
 
+    #[test]
+    fn diff_works() {
+        use std::str::FromStr;
+        let a = ScopeStack::from_str("a.b c.d e.f").unwrap();
+        let b = ScopeStack::from_str("a.b g.h").unwrap();
+        assert_eq!(a.diff(&a), vec![]);
+        assert_eq!(a.diff(&b), vec![ScopeStackOp::Pop(2), ScopeStackOp::Push(Scope::new("g.h").unwrap())]);
+        assert_eq!(b.diff(&ScopeStack::new()), vec![ScopeStackOp::Pop(2)]);
+
+        let mut applied = ScopeStack::new();
+        for op in applied.diff(&a) {
+            applied.apply(&op);
+        }
+        assert_eq!(applied, a);
+    }
+
     #[test]
     fn matching_works() {
         use std::str::FromStr;
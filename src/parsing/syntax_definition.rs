@@ -7,14 +7,15 @@
 use std::collections::{BTreeMap, HashMap};
 use std::hash::Hash;
 use super::scope::*;
-use super::regex::{Regex, Region};
+use super::regex::{ContextPrefilter, Regex, Region};
+use lazycell::AtomicLazyCell;
 use regex_syntax::escape;
 use serde::{Serialize, Serializer};
 use crate::parsing::syntax_set::SyntaxSet;
 
 pub type CaptureMapping = Vec<(usize, Vec<Scope>)>;
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub struct ContextId {
     index: usize,
 }
@@ -38,6 +39,21 @@ pub struct SyntaxDefinition {
     pub variables: HashMap<String, String>,
     #[serde(serialize_with = "ordered_map")]
     pub contexts: HashMap<String, Context>,
+    /// The `version` key of the syntax file, if it has one
+    #[serde(default)]
+    pub version: Option<String>,
+    /// A stable hash of the source the definition was loaded from, which identifies the exact
+    /// revision of the grammar. It is 0 for definitions that weren't loaded from a source.
+    #[serde(default)]
+    pub content_hash: u64,
+    /// Extensions that select this syntax without being advertised in file type lists, from the
+    /// `hidden_file_extensions` key
+    #[serde(default)]
+    pub hidden_file_extensions: Vec<String>,
+    /// MIME types of files in this syntax, from the `mime_types` key, which Sublime Text doesn't
+    /// use. See [`SyntaxSet::find_syntax_by_mime_type`](../struct.SyntaxSet.html#method.find_syntax_by_mime_type).
+    #[serde(default)]
+    pub mime_types: Vec<String>,
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -55,6 +71,14 @@ pub struct Context {
     pub uses_backrefs: bool,
 
     pub patterns: Vec<Pattern>,
+
+    /// Built by the linker, or on first use for contexts that weren't linked in this process
+    #[serde(skip)]
+    prefilter: PrefilterCell,
+    /// Captures the loader dropped because their regex doesn't have the group, only kept for
+    /// `SyntaxDefinition::lint`
+    #[serde(skip)]
+    dropped_captures: DroppedCaptures,
 }
 
 impl Context {
@@ -67,10 +91,74 @@ impl Context {
             uses_backrefs: false,
             patterns: Vec::new(),
             prototype: None,
+            prefilter: PrefilterCell::default(),
+            dropped_captures: DroppedCaptures::default(),
+        }
+    }
+
+    /// What is known about where the match patterns of this context can start
+    pub(crate) fn prefilter(&self) -> &ContextPrefilter {
+        if let Some(prefilter) = self.prefilter.0.borrow() {
+            return prefilter;
         }
+        let regexes = self.patterns.iter().map(|pattern| match *pattern {
+            Pattern::Match(ref match_pat) => Some(&match_pat.regex),
+            Pattern::Include(_) => None,
+        });
+        self.prefilter.0.fill(ContextPrefilter::new(regexes)).ok();
+        self.prefilter.0.borrow().unwrap()
+    }
+
+    /// The pattern indexes and groups of captures that were dropped while loading because the
+    /// regex of the pattern doesn't have the group
+    pub(crate) fn dropped_captures(&self) -> &[(usize, usize)] {
+        &self.dropped_captures.0
+    }
+
+    pub(crate) fn add_dropped_capture(&mut self, pattern: usize, group: usize) {
+        self.dropped_captures.0.push((pattern, group));
+    }
+
+    /// Replaces the prefilter with one that doesn't skip any pattern, to compare results
+    #[cfg(test)]
+    pub(crate) fn disable_prefilter(&mut self) {
+        self.prefilter = PrefilterCell::default();
+        self.prefilter.0.fill(ContextPrefilter::default()).ok();
+    }
+}
+
+/// The prefilter of a context, which depends only on its patterns, so it's left out when
+/// comparing, cloning and serializing contexts like compiled regexes are
+#[derive(Debug, Default)]
+struct PrefilterCell(AtomicLazyCell<ContextPrefilter>);
+
+impl Clone for PrefilterCell {
+    fn clone(&self) -> PrefilterCell {
+        PrefilterCell::default()
     }
 }
 
+impl PartialEq for PrefilterCell {
+    fn eq(&self, _other: &PrefilterCell) -> bool {
+        true
+    }
+}
+
+impl Eq for PrefilterCell {}
+
+/// Lint information about how a context was loaded, which isn't part of what the context
+/// matches, so it's left out when comparing and serializing contexts
+#[derive(Clone, Debug, Default)]
+struct DroppedCaptures(Vec<(usize, usize)>);
+
+impl PartialEq for DroppedCaptures {
+    fn eq(&self, _other: &DroppedCaptures) -> bool {
+        true
+    }
+}
+
+impl Eq for DroppedCaptures {}
+
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum Pattern {
     Match(MatchPattern),
@@ -119,6 +207,15 @@ pub enum MatchOperation {
     Set(Vec<ContextReference>),
     Pop,
     None,
+    /// Pushes the first of the alternatives. If a `Fail` with the same name matches later on the
+    /// same line, parsing backtracks to this match and pushes the next alternative instead.
+    Branch {
+        name: String,
+        alternatives: Vec<ContextReference>,
+    },
+    /// Backtracks to the most recent `Branch` with this name on the same line, does nothing if
+    /// there is none
+    Fail(String),
 }
 
 impl<'a> Iterator for MatchIter<'a> {
@@ -149,6 +246,10 @@ impl<'a> Iterator for MatchIter<'a> {
                             }
                             _ => return self.next(), // skip this and move onto the next one
                         };
+                        // a context that ends up including itself would never finish
+                        if self.ctx_stack.iter().any(|c| std::ptr::eq(*c, ctx_ptr)) {
+                            continue;
+                        }
                         self.ctx_stack.push(ctx_ptr);
                         self.index_stack.push(0);
                     }
@@ -4,22 +4,31 @@ use super::scope::*;
 #[cfg(feature = "metadata")]
 use super::metadata::{LoadMetadata, Metadata, RawMetadataEntry};
 
-#[cfg(feature = "yaml-load")]
 use super::super::LoadingError;
+use crate::highlighting::settings::parse_sublime_settings;
 
 use std::collections::{HashMap, HashSet};
 use std::path::Path;
-#[cfg(feature = "yaml-load")]
 use walkdir::WalkDir;
-#[cfg(feature = "yaml-load")]
 use std::io::Read;
-use std::io::{self, BufRead, BufReader};
+use std::io;
+use std::fmt;
 use std::fs::File;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
 
 use lazycell::AtomicLazyCell;
 use super::regex::Regex;
 use crate::parsing::syntax_definition::ContextId;
+use crate::util::shebang_interpreter;
+use super::mime_types::MIME_TYPE_SCOPES;
+
+/// The number of bytes that [`SyntaxSet::find_syntax_for_file`] reads to find the first line
+///
+/// [`SyntaxSet::find_syntax_for_file`]: struct.SyntaxSet.html#method.find_syntax_for_file
+pub const FIRST_LINE_READ_LIMIT: usize = 1024;
 
 /// A syntax set holds multiple syntaxes that have been linked together.
 ///
@@ -39,6 +48,18 @@ pub struct SyntaxSet {
 
     #[serde(skip_serializing, skip_deserializing, default = "AtomicLazyCell::new")]
     first_line_cache: AtomicLazyCell<FirstLineCache>,
+    /// Name of the syntax returned by `find_syntax_plain_text`, if not the default one.
+    ///
+    /// Like the metadata this is not part of serialized dumps.
+    #[serde(skip, default)]
+    fallback_syntax: Option<String>,
+    /// File name patterns and the names of their syntaxes, see
+    /// `SyntaxSetBuilder::add_file_glob`. Not part of serialized dumps either.
+    #[serde(skip, default)]
+    file_globs: Vec<(String, String)>,
+    /// See `SyntaxSetBuilder::set_case_insensitive_extensions`, also not part of serialized dumps
+    #[serde(skip, default)]
+    case_insensitive_extensions: bool,
     /// Metadata, e.g. indent and commenting information.
     ///
     /// NOTE: if serializing, you should handle metadata manually; that is, you should serialize and
@@ -59,6 +80,25 @@ pub struct SyntaxReference {
     pub variables: HashMap<String, String>,
     #[serde(serialize_with = "ordered_map")]
     pub(crate) contexts: HashMap<String, ContextId>,
+    /// See [`SyntaxDefinition::version`](syntax_definition/struct.SyntaxDefinition.html#structfield.version)
+    #[serde(default)]
+    pub version: Option<String>,
+    /// See [`SyntaxDefinition::content_hash`](syntax_definition/struct.SyntaxDefinition.html#structfield.content_hash)
+    #[serde(default)]
+    pub content_hash: u64,
+    /// See [`SyntaxDefinition::hidden_file_extensions`](syntax_definition/struct.SyntaxDefinition.html#structfield.hidden_file_extensions)
+    #[serde(default)]
+    pub hidden_file_extensions: Vec<String>,
+    /// See [`SyntaxDefinition::mime_types`](syntax_definition/struct.SyntaxDefinition.html#structfield.mime_types)
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+}
+
+impl SyntaxReference {
+    fn has_extension(&self, extension: &str, ignore_case: bool) -> bool {
+        self.file_extensions.iter().chain(&self.hidden_file_extensions)
+            .any(|e| e == extension || (ignore_case && e.eq_ignore_ascii_case(extension)))
+    }
 }
 
 /// A syntax set builder is used for loading syntax definitions from the file
@@ -74,6 +114,9 @@ pub struct SyntaxReference {
 pub struct SyntaxSetBuilder {
     syntaxes: Vec<SyntaxDefinition>,
     path_syntaxes: Vec<(String, usize)>,
+    fallback_syntax: Option<String>,
+    file_globs: Vec<(String, String)>,
+    case_insensitive_extensions: bool,
     #[cfg(feature = "metadata")]
     raw_metadata: LoadMetadata,
 
@@ -84,23 +127,6 @@ pub struct SyntaxSetBuilder {
     existing_metadata: Option<Metadata>,
 }
 
-#[cfg(feature = "yaml-load")]
-fn load_syntax_file(p: &Path,
-                    lines_include_newline: bool)
-                    -> Result<SyntaxDefinition, LoadingError> {
-    let mut f = File::open(p)?;
-    let mut s = String::new();
-    f.read_to_string(&mut s)?;
-
-    Ok(
-        SyntaxDefinition::load_from_str(
-            &s,
-            lines_include_newline,
-            p.file_stem().and_then(|x| x.to_str())
-        ).map_err(|e| LoadingError::ParseSyntax(e, Some(format!("{}", p.display()))))?
-    )
-}
-
 impl Clone for SyntaxSet {
     fn clone(&self) -> SyntaxSet {
         SyntaxSet {
@@ -109,6 +135,9 @@ impl Clone for SyntaxSet {
             path_syntaxes: self.path_syntaxes.clone(),
             // Will need to be re-initialized
             first_line_cache: AtomicLazyCell::new(),
+            fallback_syntax: self.fallback_syntax.clone(),
+            file_globs: self.file_globs.clone(),
+            case_insensitive_extensions: self.case_insensitive_extensions,
             #[cfg(feature = "metadata")]
             metadata: self.metadata.clone(),
         }
@@ -122,6 +151,9 @@ impl Default for SyntaxSet {
             contexts: Vec::new(),
             path_syntaxes: Vec::new(),
             first_line_cache: AtomicLazyCell::new(),
+            fallback_syntax: None,
+            file_globs: Vec::new(),
+            case_insensitive_extensions: false,
             #[cfg(feature = "metadata")]
             metadata: Metadata::default(),
         }
@@ -150,11 +182,32 @@ impl SyntaxSet {
         Ok(builder.build())
     }
 
-    /// The list of syntaxes in the set
+    /// The list of syntaxes in the set, including hidden ones, in the order they were added to the
+    /// builder
     pub fn syntaxes(&self) -> &[SyntaxReference] {
         &self.syntaxes[..]
     }
 
+    /// Makes every context try all of its patterns, to compare results with the prefilters
+    #[cfg(test)]
+    pub(crate) fn disable_prefilters(&mut self) {
+        for context in &mut self.contexts {
+            context.disable_prefilter();
+        }
+    }
+
+    /// The syntaxes in the set that aren't marked `hidden`, which are the ones to show in lists
+    /// of file types
+    pub fn visible_syntaxes(&self) -> impl DoubleEndedIterator<Item = &SyntaxReference> {
+        self.syntaxes.iter().filter(|s| !s.hidden)
+    }
+
+    /// The syntaxes in the set that are marked `hidden`, usually because they are only meant to be
+    /// embedded in other syntaxes
+    pub fn hidden_syntaxes(&self) -> impl DoubleEndedIterator<Item = &SyntaxReference> {
+        self.syntaxes.iter().filter(|s| s.hidden)
+    }
+
     #[cfg(feature = "metadata")]
     pub fn set_metadata(&mut self, metadata: Metadata) {
         self.metadata = metadata;
@@ -179,8 +232,45 @@ impl SyntaxSet {
         self.syntaxes.iter().rev().find(|&s| name == s.name)
     }
 
+    /// Finds a syntax that lists the extension in its `file_extensions` or
+    /// `hidden_file_extensions`.
+    ///
+    /// Syntaxes marked `hidden` are skipped, use [`find_syntax_by_extension_including_hidden`] to
+    /// find those too.
+    ///
+    /// If case-insensitive extensions were enabled with
+    /// [`SyntaxSetBuilder::set_case_insensitive_extensions`], extensions that only differ in case
+    /// are found as well, but exact matches are preferred.
+    ///
+    /// [`find_syntax_by_extension_including_hidden`]: #method.find_syntax_by_extension_including_hidden
+    /// [`SyntaxSetBuilder::set_case_insensitive_extensions`]: struct.SyntaxSetBuilder.html#method.set_case_insensitive_extensions
     pub fn find_syntax_by_extension<'a>(&'a self, extension: &str) -> Option<&'a SyntaxReference> {
-        self.syntaxes.iter().rev().find(|&s| s.file_extensions.iter().any(|e| e == extension))
+        self.find_by_extension(extension, false)
+    }
+
+    /// Same as [`find_syntax_by_extension`] but also finds syntaxes marked `hidden`
+    ///
+    /// [`find_syntax_by_extension`]: #method.find_syntax_by_extension
+    pub fn find_syntax_by_extension_including_hidden<'a>(&'a self, extension: &str) -> Option<&'a SyntaxReference> {
+        self.find_by_extension(extension, true)
+    }
+
+    /// Finds the last of the syntaxes with the extension, looking for one that has it with a
+    /// different case only if there is none with the exact one
+    fn find_by_extension(&self, extension: &str, include_hidden: bool) -> Option<&SyntaxReference> {
+        let find = |ignore_case| self.syntaxes.iter().rev()
+            .find(|&s| (include_hidden || !s.hidden) && s.has_extension(extension, ignore_case));
+        find(false).or_else(|| if self.case_insensitive_extensions { find(true) } else { None })
+    }
+
+    /// Matches a file name against a glob pattern, ignoring case if case-insensitive extensions
+    /// are enabled
+    fn matches_glob(&self, pattern: &str, file_name: &str) -> bool {
+        if self.case_insensitive_extensions {
+            glob_matches(&pattern.to_ascii_lowercase(), &file_name.to_ascii_lowercase())
+        } else {
+            glob_matches(pattern, file_name)
+        }
     }
 
     /// Searches for a syntax first by extension and then by case-insensitive name
@@ -197,18 +287,121 @@ impl SyntaxSet {
         self.syntaxes.iter().rev().find(|&syntax| syntax.name.eq_ignore_ascii_case(s))
     }
 
+    /// Finds a syntax for a MIME type, for example from a `Content-Type` header.
+    ///
+    /// Parameters like `; charset=utf-8` are ignored. Syntaxes that list the type in their
+    /// [`mime_types`] are preferred, then a built-in table of common types is used. Types with a
+    /// structured syntax suffix like `application/vnd.api+json` fall back to the syntax for the
+    /// suffix, and `x-` types like `text/x-elixir` to [`find_syntax_by_token`] with the rest of
+    /// the name. Syntaxes marked `hidden` are skipped.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_mime_type("application/json; charset=utf-8").unwrap();
+    /// assert_eq!(syntax.name, "JSON");
+    /// assert_eq!(ss.find_syntax_by_mime_type("application/problem+json").unwrap().name, "JSON");
+    /// assert_eq!(ss.find_syntax_by_mime_type("text/x-python").unwrap().name, "Python");
+    /// ```
+    ///
+    /// [`mime_types`]: struct.SyntaxReference.html#structfield.mime_types
+    /// [`find_syntax_by_token`]: #method.find_syntax_by_token
+    pub fn find_syntax_by_mime_type<'a>(&'a self, mime_type: &str) -> Option<&'a SyntaxReference> {
+        let mime_type = mime_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        let (kind, subtype) = match mime_type.find('/') {
+            Some(slash) => (&mime_type[..slash], &mime_type[slash + 1..]),
+            None => return None,
+        };
+
+        let mut candidates = vec![mime_type.clone()];
+        if let Some(plus) = subtype.rfind('+') {
+            let suffix = &subtype[plus + 1..];
+            candidates.push(format!("application/{}", suffix));
+            candidates.push(format!("text/{}", suffix));
+        }
+
+        for candidate in &candidates {
+            let found = self.visible_syntaxes().rev().find(|s| s.mime_types.contains(candidate)).or_else(|| {
+                MIME_TYPE_SCOPES.iter()
+                    .filter(|(mime, _)| mime == candidate)
+                    .flat_map(|(_, scopes)| scopes.iter())
+                    .filter_map(|scope| Scope::new(scope).ok())
+                    .find_map(|scope| self.visible_syntaxes().rev().find(|s| s.scope == scope))
+            });
+            if found.is_some() {
+                return found;
+            }
+        }
+
+        let name = subtype.strip_prefix("x-")?;
+        let name = name.split('+').next().unwrap_or(name);
+        if kind == "text" || kind == "application" {
+            self.find_syntax_by_token(name)
+        } else {
+            None
+        }
+    }
+
+    /// Finds a syntax for a file name, without a path.
+    ///
+    /// This tries the patterns added with [`SyntaxSetBuilder::add_file_glob`], then syntaxes that
+    /// list the exact file name, like `CMakeLists.txt`, in their `file_extensions`, then glob
+    /// patterns like `Dockerfile.*` in `file_extensions`, and finally the extension. Syntaxes
+    /// marked `hidden` are only found through the added patterns. With
+    /// [`SyntaxSetBuilder::set_case_insensitive_extensions`], all of these ignore case.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    /// builder.add_file_glob("*.json.tmpl", "JSON");
+    /// let ss = builder.build();
+    /// assert_eq!(ss.find_syntax_by_file_name("settings.json.tmpl").unwrap().name, "JSON");
+    /// assert_eq!(ss.find_syntax_by_file_name("main.py").unwrap().name, "Python");
+    /// ```
+    ///
+    /// [`SyntaxSetBuilder::add_file_glob`]: struct.SyntaxSetBuilder.html#method.add_file_glob
+    /// [`SyntaxSetBuilder::set_case_insensitive_extensions`]: struct.SyntaxSetBuilder.html#method.set_case_insensitive_extensions
+    pub fn find_syntax_by_file_name<'a>(&'a self, file_name: &str) -> Option<&'a SyntaxReference> {
+        let globbed = self.file_globs.iter().rev()
+            .filter(|(pattern, _)| self.matches_glob(pattern, file_name))
+            .find_map(|(_, name)| self.find_syntax_by_name(name));
+        if globbed.is_some() {
+            return globbed;
+        }
+        self.find_syntax_by_extension(file_name)
+            .or_else(|| self.visible_syntaxes().rev().find(|s| {
+                s.file_extensions.iter().chain(&s.hidden_file_extensions)
+                    .any(|e| e.contains(&['*', '?', '['][..]) && self.matches_glob(e, file_name))
+            }))
+            .or_else(|| {
+                let extension = Path::new(file_name).extension().and_then(|x| x.to_str())?;
+                self.find_syntax_by_extension(extension)
+            })
+    }
+
     /// Try to find the syntax for a file based on its first line
     ///
     /// This uses regexes that come with some sublime syntax grammars for matching things like
-    /// shebangs and mode lines like `-*- Mode: C -*-`
+    /// shebangs and mode lines like `-*- Mode: C -*-`. Syntaxes marked `hidden` are skipped.
+    ///
+    /// Shebangs are first tried in a normalized form with just the interpreter, as found by
+    /// [`shebang_interpreter`], because the regexes often don't expect `env` options, version
+    /// numbers or `busybox`.
+    ///
+    /// [`shebang_interpreter`]: ../util/fn.shebang_interpreter.html
     pub fn find_syntax_by_first_line<'a>(&'a self, s: &str) -> Option<&'a SyntaxReference> {
         let cache = self.first_line_cache();
-        for &(ref reg, i) in cache.regexes.iter().rev() {
-            if reg.search(s, 0, s.len(), None) {
+        if let Some(interpreter) = shebang_interpreter(s) {
+            let normalized = format!("#!/usr/bin/{}", interpreter);
+            if let Some(i) = cache.find(&normalized) {
                 return Some(&self.syntaxes[i]);
             }
         }
-        None
+        cache.find(s).map(|i| &self.syntaxes[i])
     }
 
     /// Searches for a syntax by it's original file path when it was first loaded from disk
@@ -224,10 +417,14 @@ impl SyntaxSet {
         self.path_syntaxes.iter().rev().find(|t| t.0.ends_with(&slash_path) || t.0 == path).map(|&(_,i)| &self.syntaxes[i])
     }
 
-    /// Convenience method that tries to find the syntax for a file path, first by extension/name
-    /// and then by first line of the file if that doesn't work.
+    /// Convenience method that tries to find the syntax for a file path, first by its file name
+    /// with [`find_syntax_by_file_name`] and then by first line of the file if that doesn't work.
+    /// Syntaxes marked `hidden` are only returned for patterns added with
+    /// [`SyntaxSetBuilder::add_file_glob`].
     ///
-    /// May IO Error because it sometimes tries to read the first line of the file.
+    /// May IO Error because it sometimes tries to read the first line of the file. At most
+    /// [`FIRST_LINE_READ_LIMIT`] bytes are read, use [`find_syntax_for_file_with_limit`] to change
+    /// that. The line is decoded like in [`find_syntax_by_first_bytes`].
     ///
     /// # Examples
     ///
@@ -242,20 +439,34 @@ impl SyntaxSet {
     ///     .unwrap_or_else(|| ss.find_syntax_plain_text());
     /// assert_eq!(syntax.name, "HTML (Rails)");
     /// ```
+    ///
+    /// [`find_syntax_by_file_name`]: #method.find_syntax_by_file_name
+    /// [`SyntaxSetBuilder::add_file_glob`]: struct.SyntaxSetBuilder.html#method.add_file_glob
+    /// [`FIRST_LINE_READ_LIMIT`]: constant.FIRST_LINE_READ_LIMIT.html
+    /// [`find_syntax_for_file_with_limit`]: #method.find_syntax_for_file_with_limit
+    /// [`find_syntax_by_first_bytes`]: #method.find_syntax_by_first_bytes
     pub fn find_syntax_for_file<P: AsRef<Path>>(&self,
                                                 path_obj: P)
                                                 -> io::Result<Option<&SyntaxReference>> {
+        self.find_syntax_for_file_with_limit(path_obj, FIRST_LINE_READ_LIMIT)
+    }
+
+    /// Like [`find_syntax_for_file`], but reads at most `max_bytes` bytes of the file to find the
+    /// first line. A longer first line is cut off, which usually still leaves enough of it for
+    /// shebangs and mode lines.
+    ///
+    /// [`find_syntax_for_file`]: #method.find_syntax_for_file
+    pub fn find_syntax_for_file_with_limit<P: AsRef<Path>>(&self,
+                                                           path_obj: P,
+                                                           max_bytes: usize)
+                                                           -> io::Result<Option<&SyntaxReference>> {
         let path: &Path = path_obj.as_ref();
         let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
-        let extension = path.extension().and_then(|x| x.to_str()).unwrap_or("");
-        let ext_syntax = self.find_syntax_by_extension(file_name).or_else(
-                            || self.find_syntax_by_extension(extension));
+        let ext_syntax = self.find_syntax_by_file_name(file_name);
         let line_syntax = if ext_syntax.is_none() {
-            let mut line = String::new();
-            let f = File::open(path)?;
-            let mut line_reader = BufReader::new(&f);
-            line_reader.read_line(&mut line)?;
-            self.find_syntax_by_first_line(&line)
+            let mut bytes = Vec::new();
+            File::open(path)?.take(max_bytes as u64).read_to_end(&mut bytes)?;
+            self.find_syntax_by_first_bytes(&bytes)
         } else {
             None
         };
@@ -263,8 +474,33 @@ impl SyntaxSet {
         Ok(syntax)
     }
 
+    /// Like [`find_syntax_by_first_line`], but for the raw start of a file, which doesn't have to
+    /// be valid UTF-8.
+    ///
+    /// A UTF-8 byte order mark is skipped, text with a UTF-16 byte order mark is decoded as
+    /// UTF-16, and invalid UTF-8 is replaced with `U+FFFD`, so files in other encodings or with
+    /// binary data still work as long as the interesting part of the line is ASCII. Only the
+    /// first line is looked at.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = ss.find_syntax_by_first_bytes(b"\xEF\xBB\xBF#!/usr/bin/env python3\n\xFF\xFE");
+    /// assert_eq!(syntax.unwrap().name, "Python");
+    /// ```
+    ///
+    /// [`find_syntax_by_first_line`]: #method.find_syntax_by_first_line
+    pub fn find_syntax_by_first_bytes<'a>(&'a self, bytes: &[u8]) -> Option<&'a SyntaxReference> {
+        self.find_syntax_by_first_line(&decode_first_line(bytes))
+    }
+
     /// Finds a syntax for plain text, which usually has no highlighting rules.
     ///
+    /// If a different fallback syntax was configured with
+    /// [`SyntaxSetBuilder::set_fallback_syntax`], that syntax is returned instead.
+    ///
     /// This is good as a fallback when you can't find another syntax but you still want to use the
     /// same highlighting pipeline code.
     ///
@@ -282,8 +518,11 @@ impl SyntaxSet {
     /// ```
     ///
     /// [`add_plain_text_syntax`]: struct.SyntaxSetBuilder.html#method.add_plain_text_syntax
+    /// [`SyntaxSetBuilder::set_fallback_syntax`]: struct.SyntaxSetBuilder.html#method.set_fallback_syntax
     pub fn find_syntax_plain_text(&self) -> &SyntaxReference {
-        self.find_syntax_by_name("Plain Text")
+        self.fallback_syntax.as_ref()
+            .and_then(|name| self.find_syntax_by_name(name))
+            .or_else(|| self.find_syntax_by_name("Plain Text"))
             .expect("All syntax sets ought to have a plain text syntax")
     }
 
@@ -294,9 +533,11 @@ impl SyntaxSet {
     /// in the set, but not the other way around.
     pub fn into_builder(self) -> SyntaxSetBuilder {
         #[cfg(feature = "metadata")]
-        let SyntaxSet { syntaxes, contexts, path_syntaxes, metadata, .. } = self;
+        let SyntaxSet {
+            syntaxes, contexts, path_syntaxes, fallback_syntax, file_globs, case_insensitive_extensions, metadata, ..
+        } = self;
         #[cfg(not(feature = "metadata"))]
-        let SyntaxSet { syntaxes, contexts, path_syntaxes, .. } = self;
+        let SyntaxSet { syntaxes, contexts, path_syntaxes, fallback_syntax, file_globs, case_insensitive_extensions, .. } = self;
 
         let mut context_map = HashMap::with_capacity(contexts.len());
         for (i, context) in contexts.into_iter().enumerate() {
@@ -314,6 +555,10 @@ impl SyntaxSet {
                 hidden,
                 variables,
                 contexts,
+                version,
+                content_hash,
+                hidden_file_extensions,
+                mime_types,
             } = syntax;
 
             let mut builder_contexts = HashMap::with_capacity(contexts.len());
@@ -331,6 +576,10 @@ impl SyntaxSet {
                 hidden,
                 variables,
                 contexts: builder_contexts,
+                version,
+                content_hash,
+                hidden_file_extensions,
+                mime_types,
             };
             builder_syntaxes.push(syntax_definition);
         }
@@ -338,6 +587,9 @@ impl SyntaxSet {
         SyntaxSetBuilder {
             syntaxes: builder_syntaxes,
             path_syntaxes,
+            fallback_syntax,
+            file_globs,
+            case_insensitive_extensions,
             #[cfg(feature = "metadata")]
             existing_metadata: Some(metadata),
             #[cfg(feature = "metadata")]
@@ -345,11 +597,213 @@ impl SyntaxSet {
         }
     }
 
+    /// Adds syntaxes to this set without building it again.
+    ///
+    /// This is meant for loading syntaxes lazily, like user-provided grammars when they're first
+    /// needed. Unlike going through [`into_builder`], only the new syntaxes are linked and the
+    /// contexts of the existing ones are kept as they are, with their compiled regexes. The only
+    /// exception are references in existing syntaxes that couldn't be resolved before, like an
+    /// embed of a syntax that wasn't loaded yet, which are linked if a new syntax resolves them.
+    ///
+    /// A new syntax with the same name or scope as an existing one takes its place in lookups
+    /// and for linking new references, but existing references keep pointing to the old one.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{SyntaxDefinition, SyntaxSet};
+    /// let mut ss = SyntaxSet::load_defaults_newlines();
+    /// let syntax = SyntaxDefinition::load_from_str(r#"
+    /// name: Todo
+    /// scope: text.todo
+    /// file_extensions: [todo]
+    /// contexts:
+    ///   main:
+    ///     - match: '^- \[x\] '
+    ///       push: scope:text.html.markdown
+    ///       with_prototype:
+    ///         - match: $
+    ///           pop: true
+    /// "#, true, None).unwrap();
+    /// ss.add_syntaxes(vec![syntax]);
+    /// assert_eq!(ss.find_syntax_by_extension("todo").unwrap().name, "Todo");
+    /// ```
+    ///
+    /// [`into_builder`]: #method.into_builder
+    pub fn add_syntaxes<I: IntoIterator<Item = SyntaxDefinition>>(&mut self, syntaxes: I) {
+        let first_new = self.syntaxes.len();
+        SyntaxSetBuilder::add_definitions(syntaxes, &mut self.syntaxes, &mut self.contexts);
+        SyntaxSetBuilder::link_syntaxes(&self.syntaxes, &mut self.contexts, first_new);
+        self.first_line_cache = AtomicLazyCell::new();
+    }
+
+    /// Lists the references to other contexts that linking couldn't resolve, because the context
+    /// or syntax they name isn't in the set.
+    ///
+    /// Such references are ignored when they are included, and make the parser panic when a
+    /// match pushes or sets them, so build tools can use this to reject broken packages early.
+    /// The references are listed by syntax, and by the name of the context they are in.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{SyntaxDefinition, SyntaxSetBuilder};
+    ///
+    /// let mut builder = SyntaxSetBuilder::new();
+    /// builder.add(SyntaxDefinition::load_from_str(r#"
+    /// name: Broken
+    /// scope: source.broken
+    /// contexts:
+    ///   main:
+    ///     - include: scope:source.missing
+    /// "#, true, None).unwrap());
+    /// let ss = builder.build();
+    /// let unresolved = ss.unresolved_references();
+    /// assert_eq!(unresolved[0].to_string(), "Broken#main: scope:source.missing");
+    /// ```
+    pub fn unresolved_references(&self) -> Vec<UnresolvedReference<'_>> {
+        let mut unresolved = Vec::new();
+        for syntax in &self.syntaxes {
+            let mut contexts: Vec<_> = syntax.contexts.iter().collect();
+            contexts.sort_by(|a, b| a.0.cmp(b.0));
+            for (name, id) in contexts {
+                let references = self.get_context(id).patterns.iter().flat_map(|pattern| {
+                    let references: Vec<&ContextReference> = match *pattern {
+                        Pattern::Include(ref reference) => vec![reference],
+                        Pattern::Match(ref match_pat) => {
+                            let operation_refs = match match_pat.operation {
+                                MatchOperation::Push(ref refs) |
+                                MatchOperation::Set(ref refs) |
+                                MatchOperation::Branch { alternatives: ref refs, .. } => &refs[..],
+                                MatchOperation::Pop | MatchOperation::None | MatchOperation::Fail(_) => &[],
+                            };
+                            operation_refs.iter().chain(&match_pat.with_prototype).collect()
+                        }
+                    };
+                    references
+                });
+                for reference in references {
+                    if let ContextReference::Direct(_) = *reference {
+                        continue;
+                    }
+                    unresolved.push(UnresolvedReference { syntax, context: name, reference });
+                }
+            }
+        }
+        unresolved
+    }
+
     #[inline(always)]
     pub(crate) fn get_context(&self, context_id: &ContextId) -> &Context {
         &self.contexts[context_id.index()]
     }
 
+    pub(crate) fn has_context(&self, context_id: &ContextId) -> bool {
+        context_id.index() < self.contexts.len()
+    }
+
+    /// Finds the syntax a context belongs to and the name of the context in it
+    pub(crate) fn context_name(&self, context_id: &ContextId) -> Option<(&SyntaxReference, &str)> {
+        self.syntaxes.iter().find_map(|syntax| {
+            syntax.contexts.iter()
+                .find(|&(_, id)| id == context_id)
+                .map(|(name, _)| (syntax, name.as_str()))
+        })
+    }
+
+    /// Like `context_name`, for a context of the set
+    pub(crate) fn context_name_of(&self, context: &Context) -> Option<(&SyntaxReference, &str)> {
+        self.syntaxes.iter().find_map(|syntax| {
+            syntax.contexts.iter()
+                .find(|&(_, id)| std::ptr::eq(self.get_context(id), context))
+                .map(|(name, _)| (syntax, name.as_str()))
+        })
+    }
+
+    /// Compiles the regexes of a syntax now, instead of the first time they are needed.
+    ///
+    /// Regexes are compiled lazily, which makes the first lines highlighted with a syntax slower.
+    /// Contexts of other syntaxes that are embedded aren't compiled, and neither are patterns
+    /// that refer to captures of an earlier match, because those are compiled for each match.
+    pub fn precompile_syntax(&self, syntax: &SyntaxReference) {
+        for context_id in syntax.contexts.values() {
+            self.precompile_context(context_id);
+        }
+    }
+
+    /// Like [`precompile_syntax`], but for the syntaxes found with [`find_syntax_by_token`] for
+    /// each of the tokens, on the given number of background threads. Unknown tokens are ignored.
+    ///
+    /// This is meant to be called at startup with the languages that are likely to be opened
+    /// first, so they're ready by the time they are needed. Parsing can go on in the meantime.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// use std::sync::Arc;
+    ///
+    /// let ss = Arc::new(SyntaxSet::load_defaults_newlines());
+    /// let handle = SyntaxSet::precompile_in_background(&ss, &["rs", "py", "Markdown"], 2);
+    /// // ... continue starting up ...
+    /// handle.join();
+    /// ```
+    ///
+    /// [`precompile_syntax`]: #method.precompile_syntax
+    /// [`find_syntax_by_token`]: #method.find_syntax_by_token
+    pub fn precompile_in_background(syntax_set: &Arc<SyntaxSet>, tokens: &[&str], threads: usize) -> PrecompileHandle {
+        let mut context_ids: Vec<ContextId> = tokens.iter()
+            .filter_map(|token| syntax_set.find_syntax_by_token(token))
+            .flat_map(|syntax| syntax.contexts.values().cloned())
+            .collect();
+        context_ids.sort_by_key(|id| id.index());
+        context_ids.dedup();
+
+        let context_ids = Arc::new(context_ids);
+        let next = Arc::new(AtomicUsize::new(0));
+        let threads = (0..threads.max(1)).map(|_| {
+            let syntax_set = Arc::clone(syntax_set);
+            let context_ids = Arc::clone(&context_ids);
+            let next = Arc::clone(&next);
+            thread::spawn(move || {
+                while let Some(context_id) = context_ids.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    syntax_set.precompile_context(context_id);
+                }
+            })
+        }).collect();
+        PrecompileHandle { threads }
+    }
+
+    /// Compiles the regexes of all syntaxes now, like [`precompile_syntax`] does for one.
+    ///
+    /// Servers can call this at startup to avoid a latency spike the first time each syntax is
+    /// used, at the cost of compiling regexes that might never be needed. With the `rayon`
+    /// feature the regexes are compiled in parallel on the global rayon thread pool.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    ///
+    /// let ss = SyntaxSet::load_defaults_newlines();
+    /// ss.precompile_all();
+    /// ```
+    ///
+    /// [`precompile_syntax`]: #method.precompile_syntax
+    pub fn precompile_all(&self) {
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            self.contexts.par_iter().for_each(precompile_patterns);
+        }
+        #[cfg(not(feature = "rayon"))]
+        self.contexts.iter().for_each(precompile_patterns);
+    }
+
+    fn precompile_context(&self, context_id: &ContextId) {
+        precompile_patterns(self.get_context(context_id));
+    }
+
     fn first_line_cache(&self) -> &FirstLineCache {
         if let Some(cache) = self.first_line_cache.borrow() {
             cache
@@ -361,6 +815,15 @@ impl SyntaxSet {
     }
 }
 
+fn precompile_patterns(context: &Context) {
+    for pattern in &context.patterns {
+        if let Pattern::Match(ref match_pat) = *pattern {
+            if !match_pat.has_captures {
+                match_pat.regex.compile();
+            }
+        }
+    }
+}
 
 impl SyntaxSetBuilder {
     pub fn new() -> SyntaxSetBuilder {
@@ -384,6 +847,78 @@ impl SyntaxSetBuilder {
         self.syntaxes.push(syn);
     }
 
+    /// Sets the syntax, by name, that [`SyntaxSet::find_syntax_plain_text`] returns, for example
+    /// a plain text syntax that still highlights URLs and `TODO` markers.
+    ///
+    /// If no syntax with that name is in the built set, the regular plain text syntax is used.
+    /// This setting isn't included when dumping a syntax set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::{SyntaxDefinition, SyntaxSetBuilder};
+    /// let mut builder = SyntaxSetBuilder::new();
+    /// builder.add_plain_text_syntax();
+    /// builder.add(SyntaxDefinition::load_from_str(r#"
+    /// name: Plain Text with Links
+    /// scope: text.plain.links
+    /// contexts:
+    ///   main:
+    ///     - match: '\bhttps?://\S+'
+    ///       scope: markup.underline.link
+    ///     - match: '\b(TODO|FIXME)\b'
+    ///       scope: comment.todo
+    /// "#, true, None).unwrap());
+    /// builder.set_fallback_syntax("Plain Text with Links");
+    /// let ss = builder.build();
+    /// assert_eq!(ss.find_syntax_plain_text().name, "Plain Text with Links");
+    /// ```
+    ///
+    /// [`SyntaxSet::find_syntax_plain_text`]: struct.SyntaxSet.html#method.find_syntax_plain_text
+    pub fn set_fallback_syntax<S: Into<String>>(&mut self, name: S) {
+        self.fallback_syntax = Some(name.into());
+    }
+
+    /// Makes file names that match a glob pattern use the syntax with the given name, for names
+    /// that an extension can't describe, like `Dockerfile.*` or `*.conf.j2`.
+    ///
+    /// The pattern is matched against the whole file name, `*` matches any number of
+    /// characters, `?` a single character and `[...]` one of a set of characters like `[a-z]`
+    /// or `[!0-9]`. These patterns take precedence over the `file_extensions` of syntaxes, and
+    /// patterns added later over earlier ones. Like the fallback syntax they aren't included when
+    /// dumping a syntax set.
+    ///
+    /// Syntaxes can also list glob patterns in their `file_extensions`, which are matched
+    /// against the whole file name as well, but only if no exact file name or extension matches.
+    ///
+    /// See [`SyntaxSet::find_syntax_by_file_name`].
+    ///
+    /// [`SyntaxSet::find_syntax_by_file_name`]: struct.SyntaxSet.html#method.find_syntax_by_file_name
+    pub fn add_file_glob<P: Into<String>, S: Into<String>>(&mut self, pattern: P, syntax_name: S) {
+        self.file_globs.push((pattern.into(), syntax_name.into()));
+    }
+
+    /// Makes the syntax set ignore the case of extensions and file names, so that `MAKEFILE` or
+    /// `main.RS` find a syntax, since casing conventions vary across platforms and file systems.
+    ///
+    /// Matches with the exact case are still preferred. Only ASCII letters are compared
+    /// case-insensitively. This is off by default, and like the fallback syntax it isn't included
+    /// when dumping a syntax set.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxSet;
+    /// let mut builder = SyntaxSet::load_defaults_newlines().into_builder();
+    /// builder.set_case_insensitive_extensions(true);
+    /// let ss = builder.build();
+    /// assert_eq!(ss.find_syntax_by_extension("PY").unwrap().name, "Python");
+    /// assert_eq!(ss.find_syntax_by_file_name("MAKEFILE").unwrap().name, "Makefile");
+    /// ```
+    pub fn set_case_insensitive_extensions(&mut self, enabled: bool) {
+        self.case_insensitive_extensions = enabled;
+    }
+
     /// Loads all the `.sublime-syntax` files in a folder into this builder.
     ///
     /// The `lines_include_newline` parameter is used to work around the fact that Sublime Text
@@ -397,24 +932,53 @@ impl SyntaxSetBuilder {
     /// In the future I might include a "slow mode" that copies the lines passed in and appends a
     /// newline if there isn't one, but in the interest of performance currently this hacky fix will
     /// have to do.
+    ///
+    /// Syntaxes that `extends` another syntax are resolved against the other files in the folder,
+    /// so the base syntax has to be loaded in the same call.
+    ///
+    /// With the `tmlanguage-load` feature, `.tmLanguage` and `.tmLanguage.json` files are loaded
+    /// as well, except for the ones with the same scope as a `.sublime-syntax` file in the
+    /// folder, which supersedes them like in Sublime Text.
+    ///
+    /// The order doesn't depend on the order in which the file system lists files: the syntaxes
+    /// are added sorted by their path, comparing the names of the files and folders at each level
+    /// byte by byte, with all `.tmLanguage` files after all `.sublime-syntax` files. Since the
+    /// contexts of each syntax are sorted by name when building, the index of every syntax and
+    /// context in the built set only depends on the files, so dumps of sets loaded from the same
+    /// files on different machines are byte for byte identical. The paths used by
+    /// [`SyntaxSet::find_syntax_by_path`] start with `folder` as given, so pass the same relative
+    /// path for that as well.
+    ///
+    /// [`SyntaxSet::find_syntax_by_path`]: struct.SyntaxSet.html#method.find_syntax_by_path
     #[cfg(feature = "yaml-load")]
     pub fn add_from_folder<P: AsRef<Path>>(
         &mut self,
         folder: P,
         lines_include_newline: bool
     ) -> Result<(), LoadingError> {
+        let mut sources = Vec::new();
+        #[cfg(feature = "tmlanguage-load")]
+        let mut tm_languages = Vec::new();
         for entry in WalkDir::new(folder).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
             let entry = entry.map_err(LoadingError::WalkDir)?;
             if entry.path().extension().map_or(false, |e| e == "sublime-syntax") {
-                let syntax = load_syntax_file(entry.path(), lines_include_newline)?;
-                if let Some(path_str) = entry.path().to_str() {
-                    // Split the path up and rejoin with slashes so that syntaxes loaded on Windows
-                    // can still be loaded the same way.
-                    let path = Path::new(path_str);
-                    let path_parts: Vec<_> = path.iter().map(|c| c.to_str().unwrap()).collect();
-                    self.path_syntaxes.push((path_parts.join("/").to_string(), self.syntaxes.len()));
+                let mut source = String::new();
+                File::open(entry.path())?.read_to_string(&mut source)?;
+                // Split the path up and rejoin with slashes so that syntaxes loaded on Windows
+                // can still be loaded the same way.
+                let path_str = entry.path().to_str().map(|path_str| {
+                    let path_parts: Vec<_> = Path::new(path_str).iter().map(|c| c.to_str().unwrap()).collect();
+                    path_parts.join("/")
+                });
+                sources.push((entry.path().to_owned(), path_str, source));
+            }
+
+            #[cfg(feature = "tmlanguage-load")]
+            {
+                let file_name = entry.file_name().to_str().unwrap_or("");
+                if file_name.ends_with(".tmLanguage") || file_name.ends_with(".tmLanguage.json") {
+                    tm_languages.push(entry.path().to_owned());
                 }
-                self.syntaxes.push(syntax);
             }
 
             #[cfg(feature = "metadata")]
@@ -428,6 +992,99 @@ impl SyntaxSetBuilder {
             }
         }
 
+        #[cfg(feature = "tmlanguage-load")]
+        let first_loaded = self.syntaxes.len();
+        for (path, path_str, source) in &sources {
+            let syntax = SyntaxDefinition::load_from_str_with_bases(
+                source,
+                lines_include_newline,
+                path.file_stem().and_then(|x| x.to_str()),
+                |base| {
+                    // `extends` paths look like `Packages/C++/C.sublime-syntax`
+                    let base = base.strip_prefix("Packages/").unwrap_or(base);
+                    let suffix = format!("/{}", base);
+                    sources.iter()
+                        .find(|(_, other, _)| {
+                            other.as_ref().map(|o| o == base || o.ends_with(&suffix)).unwrap_or(false)
+                        })
+                        .map(|(_, _, source)| source.clone())
+                },
+            ).map_err(|e| LoadingError::ParseSyntax(e, Some(format!("{}", path.display()))))?;
+            if let Some(path_str) = path_str {
+                self.path_syntaxes.push((path_str.clone(), self.syntaxes.len()));
+            }
+            self.syntaxes.push(syntax);
+        }
+
+        #[cfg(feature = "tmlanguage-load")]
+        {
+            let last_loaded = self.syntaxes.len();
+            for path in tm_languages {
+                let mut bytes = Vec::new();
+                File::open(&path)?.read_to_end(&mut bytes)?;
+                let name = path.file_name().and_then(|x| x.to_str()).and_then(|x| x.split('.').next());
+                let syntax = if path.extension() == Some("json".as_ref()) {
+                    let source = String::from_utf8(bytes)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    SyntaxDefinition::load_from_tm_language_json(&source, lines_include_newline, name)
+                } else {
+                    SyntaxDefinition::load_from_tm_language(&bytes, lines_include_newline, name)
+                }.map_err(|e| LoadingError::ParseSyntax(e, Some(format!("{}", path.display()))))?;
+                if !self.syntaxes[first_loaded..last_loaded].iter().any(|s| s.scope == syntax.scope) {
+                    self.syntaxes.push(syntax);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Makes files with the given extensions use the syntax with the given name, the way the
+    /// `extensions` key of a `.sublime-settings` file does in Sublime Text.
+    ///
+    /// The extensions are removed from all other syntaxes so that they can't take precedence.
+    /// Returns `false` if no syntax with that name has been added.
+    pub fn add_extensions<I, S>(&mut self, syntax_name: &str, extensions: I) -> bool
+        where I: IntoIterator<Item = S>,
+              S: Into<String>
+    {
+        let index = match self.syntaxes.iter().rposition(|s| s.name == syntax_name) {
+            Some(index) => index,
+            None => return false,
+        };
+        for extension in extensions {
+            let extension = extension.into();
+            for syntax in &mut self.syntaxes {
+                syntax.file_extensions.retain(|e| *e != extension);
+                syntax.hidden_file_extensions.retain(|e| *e != extension);
+            }
+            self.syntaxes[index].file_extensions.push(extension);
+        }
+        true
+    }
+
+    /// Applies the `extensions` of all the `.sublime-settings` files in a folder, for example a
+    /// Sublime Text `Packages/User` folder, using [`add_extensions`].
+    ///
+    /// Like in Sublime Text, the settings for a syntax are in a file named after it, such as
+    /// `Rust.sublime-settings`. Files for syntaxes that haven't been added yet are ignored, so
+    /// call this after adding all the syntaxes.
+    ///
+    /// [`add_extensions`]: #method.add_extensions
+    pub fn add_settings_from_folder<P: AsRef<Path>>(&mut self, folder: P) -> Result<(), LoadingError> {
+        for entry in WalkDir::new(folder).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = entry.map_err(LoadingError::WalkDir)?;
+            let path = entry.path();
+            if path.extension().map(|e| e == "sublime-settings").unwrap_or(false) {
+                let syntax_name = path.file_stem().and_then(|s| s.to_str()).ok_or(LoadingError::BadPath)?;
+                let mut source = String::new();
+                File::open(path)?.read_to_string(&mut source)?;
+                let settings = parse_sublime_settings(&source)?;
+                if let Some(extensions) = settings.get("extensions").and_then(|e| e.as_array()) {
+                    self.add_extensions(syntax_name, extensions.iter().filter_map(|e| e.as_str()));
+                }
+            }
+        }
         Ok(())
     }
 
@@ -455,18 +1112,52 @@ impl SyntaxSetBuilder {
     pub fn build(self) -> SyntaxSet {
 
         #[cfg(not(feature = "metadata"))]
-        let SyntaxSetBuilder { syntaxes: syntax_definitions, path_syntaxes } = self;
+        let SyntaxSetBuilder {
+            syntaxes: syntax_definitions,
+            path_syntaxes,
+            fallback_syntax,
+            file_globs,
+            case_insensitive_extensions,
+        } = self;
         #[cfg(feature = "metadata")]
         let SyntaxSetBuilder {
             syntaxes: syntax_definitions,
             path_syntaxes,
+            fallback_syntax,
+            file_globs,
+            case_insensitive_extensions,
             raw_metadata,
             existing_metadata,
         } = self;
 
         let mut syntaxes = Vec::with_capacity(syntax_definitions.len());
         let mut all_contexts = Vec::new();
+        Self::add_definitions(syntax_definitions, &mut syntaxes, &mut all_contexts);
+        Self::link_syntaxes(&syntaxes, &mut all_contexts, 0);
 
+        #[cfg(feature = "metadata")]
+        let metadata = match existing_metadata {
+            Some(existing) => existing.merged_with_raw(raw_metadata),
+            None => raw_metadata.into(),
+        };
+
+        SyntaxSet {
+            syntaxes,
+            contexts: all_contexts,
+            path_syntaxes,
+            first_line_cache: AtomicLazyCell::new(),
+            fallback_syntax,
+            file_globs,
+            case_insensitive_extensions,
+            #[cfg(feature = "metadata")]
+            metadata,
+        }
+    }
+
+    /// Turns definitions into syntax references with their contexts appended to `all_contexts`
+    fn add_definitions<I>(syntax_definitions: I, syntaxes: &mut Vec<SyntaxReference>, all_contexts: &mut Vec<Context>)
+        where I: IntoIterator<Item = SyntaxDefinition>
+    {
         for syntax_definition in syntax_definitions {
             let SyntaxDefinition {
                 name,
@@ -476,6 +1167,10 @@ impl SyntaxSetBuilder {
                 hidden,
                 variables,
                 contexts,
+                version,
+                content_hash,
+                hidden_file_extensions,
+                mime_types,
             } = syntax_definition;
 
             let mut map = HashMap::new();
@@ -500,28 +1195,43 @@ impl SyntaxSetBuilder {
                 hidden,
                 variables,
                 contexts: map,
+                version,
+                content_hash,
+                hidden_file_extensions,
+                mime_types,
             };
             syntaxes.push(syntax);
         }
+    }
+
+    /// Links the contexts of the syntaxes from `first_new` on. The contexts of earlier syntaxes are
+    /// already linked, but their references that couldn't be resolved yet are tried again.
+    fn link_syntaxes(syntaxes: &[SyntaxReference], all_contexts: &mut [Context], first_new: usize) {
+        for syntax in &syntaxes[..first_new] {
+            for context_id in syntax.contexts.values() {
+                Self::link_context(&mut all_contexts[context_id.index()], syntax, syntaxes);
+            }
+        }
 
         let mut found_more_backref_includes = true;
-        for syntax in &syntaxes {
+        for syntax in &syntaxes[first_new..] {
             let mut no_prototype = HashSet::new();
             let prototype = syntax.contexts.get("prototype");
             if let Some(prototype_id) = prototype {
                 // TODO: We could do this after parsing YAML, instead of here?
-                Self::recursively_mark_no_prototype(syntax, prototype_id.index(), &all_contexts, &mut no_prototype);
+                Self::recursively_mark_no_prototype(syntax, prototype_id.index(), all_contexts, &mut no_prototype);
             }
 
             for context_id in syntax.contexts.values() {
                 let index = context_id.index();
-                let mut context = &mut all_contexts[index];
+                let context = &mut all_contexts[index];
                 if let Some(prototype_id) = prototype {
                     if context.meta_include_prototype && !no_prototype.contains(&index) {
                         context.prototype = Some(*prototype_id);
                     }
                 }
-                Self::link_context(&mut context, syntax, &syntaxes);
+                Self::link_context(context, syntax, syntaxes);
+                context.prefilter();
                 
                 if context.uses_backrefs {
                     found_more_backref_includes = true;
@@ -549,28 +1259,13 @@ impl SyntaxSetBuilder {
                         _ => false,
                     }
                 }) {
-                    let mut context = &mut all_contexts[context_index];
+                    let context = &mut all_contexts[context_index];
                     context.uses_backrefs = true;
                     // look for contexts including this context
                     found_more_backref_includes = true;
                 }
             }
         }
-
-        #[cfg(feature = "metadata")]
-        let metadata = match existing_metadata {
-            Some(existing) => existing.merged_with_raw(raw_metadata),
-            None => raw_metadata.into(),
-        };
-
-        SyntaxSet {
-            syntaxes,
-            contexts: all_contexts,
-            path_syntaxes,
-            first_line_cache: AtomicLazyCell::new(),
-            #[cfg(feature = "metadata")]
-            metadata,
-        }
     }
 
     /// Anything recursively included by the prototype shouldn't include the prototype.
@@ -593,8 +1288,9 @@ impl SyntaxSetBuilder {
                 Pattern::Match(ref match_pat) => {
                     let maybe_context_refs = match match_pat.operation {
                         MatchOperation::Push(ref context_refs) |
-                        MatchOperation::Set(ref context_refs) => Some(context_refs),
-                        MatchOperation::Pop | MatchOperation::None => None,
+                        MatchOperation::Set(ref context_refs) |
+                        MatchOperation::Branch { alternatives: ref context_refs, .. } => Some(context_refs),
+                        MatchOperation::Pop | MatchOperation::None | MatchOperation::Fail(_) => None,
                     };
                     if let Some(context_refs) = maybe_context_refs {
                         for context_ref in context_refs.iter() {
@@ -679,8 +1375,9 @@ impl SyntaxSetBuilder {
     fn link_match_pat(match_pat: &mut MatchPattern, syntax: &SyntaxReference, syntaxes: &[SyntaxReference]) {
         let maybe_context_refs = match match_pat.operation {
             MatchOperation::Push(ref mut context_refs) |
-            MatchOperation::Set(ref mut context_refs) => Some(context_refs),
-            MatchOperation::Pop | MatchOperation::None => None,
+            MatchOperation::Set(ref mut context_refs) |
+            MatchOperation::Branch { alternatives: ref mut context_refs, .. } => Some(context_refs),
+            MatchOperation::Pop | MatchOperation::None | MatchOperation::Fail(_) => None,
         };
         if let Some(context_refs) = maybe_context_refs {
             for context_ref in context_refs.iter_mut() {
@@ -693,6 +1390,151 @@ impl SyntaxSetBuilder {
     }
 }
 
+/// A reference that linking couldn't resolve, see [`SyntaxSet::unresolved_references`].
+///
+/// Displays as the syntax and context with the reference, followed by the reference in the
+/// notation of syntax files.
+///
+/// [`SyntaxSet::unresolved_references`]: struct.SyntaxSet.html#method.unresolved_references
+#[derive(Debug, Clone, Copy)]
+pub struct UnresolvedReference<'a> {
+    pub syntax: &'a SyntaxReference,
+    /// The name of the context with the reference
+    pub context: &'a str,
+    pub reference: &'a ContextReference,
+}
+
+impl<'a> fmt::Display for UnresolvedReference<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}#{}: ", self.syntax.name, self.context)?;
+        let sub_context = match *self.reference {
+            ContextReference::Named(ref name) | ContextReference::Inline(ref name) => return write!(f, "{}", name),
+            ContextReference::Direct(id) => return write!(f, "{:?}", id),
+            ContextReference::ByScope { scope, ref sub_context } => {
+                write!(f, "scope:{}", scope)?;
+                sub_context
+            }
+            ContextReference::File { ref name, ref sub_context } => {
+                write!(f, "{}.sublime-syntax", name)?;
+                sub_context
+            }
+        };
+        match *sub_context {
+            Some(ref sub_context) => write!(f, "#{}", sub_context),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Regex compilation running on background threads, see [`SyntaxSet::precompile_in_background`].
+///
+/// Dropping the handle lets the threads finish on their own.
+///
+/// [`SyntaxSet::precompile_in_background`]: struct.SyntaxSet.html#method.precompile_in_background
+#[derive(Debug)]
+pub struct PrecompileHandle {
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl PrecompileHandle {
+    /// Waits until all the regexes are compiled
+    pub fn join(self) {
+        for thread in self.threads {
+            // a regex that fails to compile panics again when it's used, so it's reported there
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Matches a whole file name against a pattern with `*`, `?` and `[...]`
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // where to resume after the last `*` if the rest doesn't match
+    let mut backtrack = None;
+    while n < name.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, n));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match_class(&pattern, p, name[n]),
+            Some(&c) if c == name[n] => Some(p + 1),
+            _ => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                n += 1;
+            }
+            (None, Some((star, start))) => {
+                p = star + 1;
+                n = start + 1;
+                backtrack = Some((star, start + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Decodes the first line of the bytes, including its newline, see
+/// `SyntaxSet::find_syntax_by_first_bytes`
+fn decode_first_line(bytes: &[u8]) -> String {
+    let text = if let Some(rest) = bytes.strip_prefix(b"\xEF\xBB\xBF") {
+        String::from_utf8_lossy(rest).into_owned()
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFF\xFE") {
+        decode_utf16(rest, u16::from_le_bytes)
+    } else if let Some(rest) = bytes.strip_prefix(b"\xFE\xFF") {
+        decode_utf16(rest, u16::from_be_bytes)
+    } else {
+        String::from_utf8_lossy(bytes).into_owned()
+    };
+    match text.find('\n') {
+        Some(end) => text[..=end].to_owned(),
+        None => text,
+    }
+}
+
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let units = bytes.chunks_exact(2).map(|pair| from_bytes([pair[0], pair[1]]));
+    std::char::decode_utf16(units)
+        .map(|c| c.unwrap_or(std::char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// Matches `c` against the `[...]` class starting at `start`, returning the index after it
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut i = start + 1;
+    let negated = matches!(pattern.get(i), Some('!') | Some('^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match pattern.get(i) {
+            // a `[` without a closing `]` only matches itself
+            None => return if c == '[' { Some(start + 1) } else { None },
+            Some(']') if !first => break,
+            Some(&lo) => {
+                if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).map(|&hi| hi != ']').unwrap_or(false) {
+                    matched |= lo <= c && c <= pattern[i + 2];
+                    i += 3;
+                } else {
+                    matched |= lo == c;
+                    i += 1;
+                }
+            }
+        }
+        first = false;
+    }
+    if matched != negated { Some(i + 1) } else { None }
+}
+
 #[derive(Debug)]
 struct FirstLineCache {
     /// (first line regex, syntax index) pairs for all syntaxes with a first line regex
@@ -703,6 +1545,9 @@ impl FirstLineCache {
     fn new(syntaxes: &[SyntaxReference]) -> FirstLineCache {
         let mut regexes = Vec::new();
         for (i, syntax) in syntaxes.iter().enumerate() {
+            if syntax.hidden {
+                continue;
+            }
             if let Some(ref reg_str) = syntax.first_line_match {
                 let reg = Regex::new(reg_str.into());
                 regexes.push((reg, i));
@@ -712,6 +1557,10 @@ impl FirstLineCache {
             regexes,
         }
     }
+
+    fn find(&self, line: &str) -> Option<usize> {
+        self.regexes.iter().rev().find(|(reg, _)| reg.search(line, 0, line.len(), None)).map(|&(_, i)| i)
+    }
 }
 
 
@@ -721,6 +1570,40 @@ mod tests {
     use super::*;
     use crate::parsing::{ParseState, Scope, syntax_definition};
     use std::collections::HashMap;
+    use crate::ParseSyntaxError;
+    use crate::util::TestDir;
+
+    #[test]
+    fn can_list_unresolved_references() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(SyntaxDefinition::load_from_str(r#"
+name: A
+scope: source.a
+contexts:
+  main:
+    - include: b
+    - include: nope
+    - match: x
+      push: B.sublime-syntax
+    - match: y
+      push: [scope:source.missing#inner, Missing.sublime-syntax]
+      with_prototype:
+        - include: also_nope
+  b:
+    - match: z
+      set: scope:source.b
+"#, true, None).unwrap());
+        builder.add(SyntaxDefinition::load_from_str("name: B\nscope: source.b\ncontexts: {main: []}", true, None).unwrap());
+        let ss = builder.build();
+        let unresolved: Vec<String> = ss.unresolved_references().iter().map(|r| r.to_string()).collect();
+        assert_eq!(unresolved, vec![
+            // the context made for the with_prototype
+            "A##anon_main_0: also_nope",
+            "A#main: nope",
+            "A#main: scope:source.missing#inner",
+            "A#main: Missing.sublime-syntax",
+        ]);
+    }
 
     #[test]
     fn can_load() {
@@ -735,6 +1618,10 @@ mod tests {
             hidden: false,
             variables: HashMap::new(),
             contexts: HashMap::new(),
+            version: None,
+            content_hash: 0,
+            hidden_file_extensions: Vec::new(),
+            mime_types: Vec::new(),
         };
 
         builder.add(cmake_dummy_syntax);
@@ -829,6 +1716,321 @@ mod tests {
         assert_ops_contain(&ops, &expected);
     }
 
+    #[test]
+    fn skips_hidden_syntaxes_in_lookups() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(SyntaxDefinition::load_from_str(
+            r#"
+            name: Hidden A
+            scope: source.a.hidden
+            file_extensions: [a, ha]
+            hidden: true
+            first_line_match: '^#!hidden'
+            contexts:
+              main: []
+            "#,
+            true,
+            None,
+        ).unwrap());
+        builder.add(SyntaxDefinition::load_from_str(
+            r#"
+            name: B
+            scope: source.b
+            file_extensions: [b]
+            hidden_file_extensions: [bb]
+            contexts:
+              main: []
+            "#,
+            true,
+            None,
+        ).unwrap());
+        let ss = builder.build();
+
+        assert_eq!(ss.find_syntax_by_extension("a").unwrap().name, "A");
+        assert!(ss.find_syntax_by_extension("ha").is_none());
+        assert_eq!(ss.find_syntax_by_extension_including_hidden("a").unwrap().name, "Hidden A");
+        assert_eq!(ss.find_syntax_by_extension_including_hidden("ha").unwrap().name, "Hidden A");
+        assert_eq!(ss.find_syntax_by_extension("bb").unwrap().name, "B");
+        assert_eq!(ss.find_syntax_by_token("bb").unwrap().name, "B");
+        assert!(ss.find_syntax_by_first_line("#!hidden").is_none());
+        assert_eq!(ss.find_syntax_for_file("foo.bb").unwrap().unwrap().name, "B");
+        assert_eq!(ss.find_syntax_by_name("Hidden A").unwrap().scope, Scope::new("source.a.hidden").unwrap());
+
+        let visible: Vec<_> = ss.visible_syntaxes().map(|s| s.name.as_str()).collect();
+        let hidden: Vec<_> = ss.hidden_syntaxes().map(|s| s.name.as_str()).collect();
+        assert_eq!(visible, vec!["A", "B"]);
+        assert_eq!(hidden, vec!["Hidden A"]);
+    }
+
+    #[test]
+    fn can_add_extensions_from_settings() {
+        let dir = TestDir::new("can_add_extensions_from_settings");
+        let folder = dir.path();
+        std::fs::create_dir_all(folder.join("User")).unwrap();
+        std::fs::write(folder.join("User/B.sublime-settings"), r#"
+            // extensions that should be highlighted as B
+            {
+                "extensions": ["a", "bx",],
+                "tab_size": 4,
+            }
+        "#).unwrap();
+        std::fs::write(folder.join("User/Unknown.sublime-settings"), r#"{"extensions": ["u"]}"#).unwrap();
+        std::fs::write(folder.join("User/Preferences.sublime-settings"), r#"{"font_size": 12}"#).unwrap();
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(syntax_b());
+        builder.add_settings_from_folder(&folder).unwrap();
+        let ss = builder.build();
+
+        assert_eq!(ss.find_syntax_by_extension("a").unwrap().name, "B");
+        assert_eq!(ss.find_syntax_by_extension("bx").unwrap().name, "B");
+        assert_eq!(ss.find_syntax_by_extension("b").unwrap().name, "B");
+        assert!(ss.find_syntax_by_extension("u").is_none());
+        assert!(ss.find_syntax_by_name("A").unwrap().file_extensions.is_empty());
+    }
+
+    #[test]
+    fn can_add_syntaxes_to_linked_set() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        let mut ss = builder.build();
+
+        // A pushes B, which isn't there yet
+        assert!(ss.find_syntax_by_first_line("syntax b").is_none());
+        let a_main = ss.find_syntax_by_name("A").unwrap().contexts["main"];
+        fn first_regex<'a>(ss: &'a SyntaxSet, context: &ContextId) -> &'a Regex {
+            match ss.get_context(context).patterns[0] {
+                Pattern::Match(ref pattern) => &pattern.regex,
+                _ => unreachable!(),
+            }
+        }
+        first_regex(&ss, &a_main).compile();
+
+        ss.add_syntaxes(vec![
+            syntax_b(),
+            SyntaxDefinition::load_from_str(r#"
+                name: C
+                scope: source.c
+                first_line_match: syntax\s+b
+                contexts:
+                  main:
+                    - match: 'c'
+                      scope: c
+                    - match: 'go_a'
+                      push: scope:source.a#main
+                "#, true, None).unwrap(),
+        ]);
+
+        assert!(first_regex(&ss, &a_main).is_compiled());
+        assert_eq!(ss.find_syntax_by_extension("b").unwrap().name, "B");
+        assert_eq!(ss.find_syntax_by_first_line("syntax b").unwrap().name, "C");
+        let mut state = ParseState::new(ss.find_syntax_by_name("A").unwrap());
+        let ops = state.parse_line("a go_b b", &ss);
+        assert_ops_contain(&ops, &(7, ScopeStackOp::Push(Scope::new("b").unwrap())));
+        let mut state = ParseState::new(ss.find_syntax_by_name("C").unwrap());
+        let ops = state.parse_line("c go_a a", &ss);
+        assert_ops_contain(&ops, &(7, ScopeStackOp::Push(Scope::new("a").unwrap())));
+
+        // the result is the same as building everything at once
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(syntax_b());
+        let rebuilt = builder.build();
+        let mut incremental = SyntaxSetBuilder::new();
+        incremental.add(syntax_a());
+        let mut incremental = incremental.build();
+        incremental.add_syntaxes(vec![syntax_b()]);
+        assert_eq!(incremental.contexts, rebuilt.contexts);
+    }
+
+    #[test]
+    fn can_match_globs() {
+        assert!(glob_matches("Dockerfile.*", "Dockerfile.dev"));
+        assert!(!glob_matches("Dockerfile.*", "Dockerfile"));
+        assert!(glob_matches("*.conf.j2", "nginx.conf.j2"));
+        assert!(glob_matches("*.conf.j2", ".conf.j2"));
+        assert!(!glob_matches("*.conf.j2", "nginx.conf"));
+        assert!(glob_matches("*a*b", "xaybab"));
+        assert!(glob_matches("file?.txt", "file1.txt"));
+        assert!(!glob_matches("file?.txt", "file.txt"));
+        assert!(glob_matches("v[0-9].log", "v3.log"));
+        assert!(!glob_matches("v[!0-9].log", "v3.log"));
+        assert!(glob_matches("[]]x", "]x"));
+        assert!(glob_matches("a[b", "a[b"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("", "a"));
+        assert!(glob_matches("été*", "été.md"));
+    }
+
+    #[test]
+    fn can_find_syntax_by_file_name() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(SyntaxDefinition::load_from_str(r#"
+            name: Docker
+            scope: source.dockerfile
+            file_extensions: [Dockerfile, "Dockerfile.*", "*.dockerfile"]
+            contexts: {main: []}
+            "#, true, None).unwrap());
+        builder.add(syntax_b());
+        builder.add_file_glob("*.a.in", "B");
+        builder.add_file_glob("*.in", "Missing");
+        let ss = builder.build().into_builder().build();
+
+        let name = |file_name| ss.find_syntax_by_file_name(file_name).map(|s| s.name.as_str());
+        assert_eq!(name("Dockerfile"), Some("Docker"));
+        assert_eq!(name("Dockerfile.prod"), Some("Docker"));
+        assert_eq!(name("web.dockerfile"), Some("Docker"));
+        assert_eq!(name("x.a.in"), Some("B"));
+        assert_eq!(name("x.a"), Some("A"));
+        assert_eq!(name("x.in"), None);
+        assert_eq!(name("Dockerfile.a"), Some("Docker"));
+        assert_eq!(ss.find_syntax_for_file("Dockerfile.test").unwrap().unwrap().name, "Docker");
+    }
+
+    #[test]
+    fn can_ignore_case_of_extensions() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(SyntaxDefinition::load_from_str(r#"
+            name: Upper
+            scope: source.upper
+            file_extensions: [A, Makefile, "*.MK"]
+            contexts: {main: []}
+            "#, true, None).unwrap());
+        let ss = builder.build();
+        assert!(ss.find_syntax_by_extension("MAKEFILE").is_none());
+        assert!(ss.find_syntax_by_file_name("x.mk").is_none());
+
+        let mut builder = ss.into_builder();
+        builder.set_case_insensitive_extensions(true);
+        let ss = builder.build().into_builder().build();
+        let name = |file_name| ss.find_syntax_by_file_name(file_name).map(|s| s.name.as_str());
+        // exact matches win over later syntaxes that only match when ignoring case
+        assert_eq!(name("x.a"), Some("A"));
+        assert_eq!(name("x.A"), Some("Upper"));
+        assert_eq!(name("MAKEFILE"), Some("Upper"));
+        assert_eq!(name("rules.mk"), Some("Upper"));
+        assert_eq!(ss.find_syntax_by_extension("makefile").unwrap().name, "Upper");
+        assert!(ss.find_syntax_by_extension("b").is_none());
+    }
+
+    #[test]
+    fn can_find_syntax_by_first_bytes() {
+        assert_eq!(decode_first_line(b"\xEF\xBB\xBF#!/bin/sh\necho"), "#!/bin/sh\n");
+        assert_eq!(decode_first_line(b"\xFF\xFE#\0!\0\n\0x\0"), "#!\n");
+        assert_eq!(decode_first_line(b"\xFE\xFF\0#\0!"), "#!");
+        assert_eq!(decode_first_line(b"#!\xFF sh"), "#!\u{FFFD} sh");
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(SyntaxDefinition::load_from_str(r#"
+            name: Shell
+            scope: source.shell
+            first_line_match: '^#!.*\bsh\b'
+            contexts: {main: []}
+            "#, true, None).unwrap());
+        let ss = builder.build();
+        let name = |bytes: &[u8]| ss.find_syntax_by_first_bytes(bytes).map(|s| s.name.as_str());
+        assert_eq!(name(b"\xEF\xBB\xBF#!/bin/sh\n"), Some("Shell"));
+        assert_eq!(name(b"\xFF\xFE#\0!\0/\0b\0i\0n\0/\0s\0h\0\r\0\n\0"), Some("Shell"));
+        assert_eq!(name(b"#!/bin/sh -e\n\x80\x81"), Some("Shell"));
+        assert_eq!(name(b"\n#!/bin/sh"), None);
+    }
+
+    #[test]
+    fn can_find_syntax_by_mime_type() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax_a());
+        builder.add(SyntaxDefinition::load_from_str(r#"
+            name: JSON
+            scope: source.json
+            contexts: {main: []}
+            "#, true, None).unwrap());
+        builder.add(SyntaxDefinition::load_from_str(r#"
+            name: B
+            scope: source.b
+            mime_types: [Application/JSON, text/x-b-lang]
+            contexts: {main: []}
+            "#, true, None).unwrap());
+        let ss = builder.build();
+
+        let name = |mime| ss.find_syntax_by_mime_type(mime).map(|s| s.name.as_str());
+        assert_eq!(name("application/json"), Some("B"));
+        assert_eq!(name("text/x-b-lang"), Some("B"));
+        assert_eq!(name("application/ld+json"), Some("JSON"));
+        assert_eq!(name("application/vnd.api+json ; charset=UTF-8"), Some("B"));
+        assert_eq!(name("text/x-a"), Some("A"));
+        assert_eq!(name("image/x-a"), None);
+        assert_eq!(name("text/html"), None);
+        assert_eq!(name("json"), None);
+    }
+
+    #[test]
+    fn can_find_syntax_by_normalized_shebang() {
+        let mut builder = SyntaxSetBuilder::new();
+        for (name, first_line_match) in &[("Julia", r"^#!.*\bjulia\s*$"), ("Shell", r"^#!.*\b(bash|sh)\b")] {
+            builder.add(SyntaxDefinition::load_from_str(&format!(
+                "name: {}\nscope: source.{}\nfirst_line_match: '{}'\ncontexts: {{main: []}}",
+                name, name.to_lowercase(), first_line_match,
+            ), true, None).unwrap());
+        }
+        let ss = builder.build();
+
+        let name = |line| ss.find_syntax_by_first_line(line).map(|s| s.name.as_str());
+        assert_eq!(name("#!/usr/bin/julia\n"), Some("Julia"));
+        assert_eq!(name("#!/usr/bin/env -S julia --threads 4\n"), Some("Julia"));
+        assert_eq!(name("#!/opt/julia-1.6/bin/julia1.6\n"), Some("Julia"));
+        assert_eq!(name("#!/bin/busybox sh\n"), Some("Shell"));
+        assert_eq!(name("#!/usr/bin/env -S bash -c 'exec julia'\n"), Some("Shell"));
+        assert_eq!(name("#!/usr/bin/python\n"), None);
+    }
+
+    #[test]
+    fn can_precompile_in_background() {
+        let syntax_set = {
+            let mut builder = SyntaxSetBuilder::new();
+            builder.add(syntax_a());
+            builder.add(syntax_b());
+            Arc::new(builder.build())
+        };
+        let compiled = |syntax: &SyntaxReference| {
+            syntax.contexts.values().all(|id| {
+                syntax_set.get_context(id).patterns.iter().all(|pattern| match *pattern {
+                    Pattern::Match(ref match_pat) => match_pat.regex.is_compiled(),
+                    Pattern::Include(_) => true,
+                })
+            })
+        };
+        let a = syntax_set.find_syntax_by_name("A").unwrap();
+        let b = syntax_set.find_syntax_by_name("B").unwrap();
+        assert!(!compiled(a));
+
+        SyntaxSet::precompile_in_background(&syntax_set, &["a", "unknown"], 3).join();
+        assert!(compiled(a));
+        assert!(!compiled(b));
+    }
+
+    #[test]
+    fn can_precompile_all() {
+        let syntax_set = {
+            let mut builder = SyntaxSetBuilder::new();
+            builder.add(syntax_a());
+            builder.add(syntax_b());
+            builder.build()
+        };
+        let compiled = || {
+            syntax_set.contexts.iter().flat_map(|context| &context.patterns).all(|pattern| match *pattern {
+                Pattern::Match(ref match_pat) => match_pat.has_captures || match_pat.regex.is_compiled(),
+                Pattern::Include(_) => true,
+            })
+        };
+        assert!(!compiled());
+        syntax_set.precompile_all();
+        assert!(compiled());
+    }
+
     #[test]
     fn can_use_in_multiple_threads() {
         use rayon::prelude::*;
@@ -998,6 +2200,27 @@ mod tests {
         assert_prototype_only_on(&["main"], &rebuilt, &rebuilt.syntaxes()[0]);
     }
 
+    #[test]
+    fn can_set_fallback_syntax() {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_plain_text_syntax();
+        builder.add(syntax_a());
+        builder.set_fallback_syntax("A");
+        let ss = builder.build();
+        assert_eq!(ss.find_syntax_plain_text().name, "A");
+
+        // the fallback is kept when adding more syntaxes
+        let mut builder = ss.into_builder();
+        builder.add(syntax_b());
+        let ss = builder.build();
+        assert_eq!(ss.find_syntax_plain_text().name, "A");
+
+        let mut builder = ss.into_builder();
+        builder.set_fallback_syntax("Missing");
+        let ss = builder.build();
+        assert_eq!(ss.find_syntax_plain_text().name, "Plain Text");
+    }
+
     fn assert_ops_contain(
         ops: &[(usize, ScopeStackOp)],
         expected: &(usize, ScopeStackOp)
@@ -1025,6 +2248,110 @@ mod tests {
 
     fn check_sync<T: Sync>() {}
 
+    #[test]
+    fn can_load_syntaxes_that_extend_others() {
+        let dir = TestDir::new("can_load_syntaxes_that_extend_others");
+        let folder = dir.path();
+        std::fs::create_dir_all(folder.join("Base")).unwrap();
+        std::fs::create_dir_all(folder.join("Derived")).unwrap();
+        std::fs::write(folder.join("Base/Base.sublime-syntax"), r#"
+        name: Base
+        scope: source.base
+        contexts:
+          main:
+            - match: a
+              scope: a
+        "#).unwrap();
+        std::fs::write(folder.join("Derived/Derived.sublime-syntax"), r#"
+        name: Derived
+        scope: source.derived
+        extends: Packages/Base/Base.sublime-syntax
+        contexts:
+          main:
+            - meta_append: true
+            - match: b
+              scope: b
+        "#).unwrap();
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_folder(&folder, true).unwrap();
+        let ss = builder.build();
+        let derived = ss.find_syntax_by_name("Derived").unwrap();
+        let mut state = ParseState::new(derived);
+        let ops = state.parse_line("ab\n", &ss);
+        assert_ops_contain(&ops, &(0, ScopeStackOp::Push(Scope::new("a").unwrap())));
+        assert_ops_contain(&ops, &(1, ScopeStackOp::Push(Scope::new("b").unwrap())));
+    }
+
+    #[cfg(feature = "tmlanguage-load")]
+    #[test]
+    fn can_load_tm_languages_from_folder() {
+        let dir = TestDir::new("can_load_tm_languages_from_folder");
+        let folder = dir.path();
+        let grammar = |name: &str, scope: &str| format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+        <plist version="1.0">
+        <dict>
+            <key>name</key><string>{}</string>
+            <key>scopeName</key><string>{}</string>
+            <key>patterns</key><array/>
+        </dict>
+        </plist>"#, name, scope);
+        std::fs::write(folder.join("A.sublime-syntax"), "name: A\nscope: source.a\ncontexts: {main: []}").unwrap();
+        std::fs::write(folder.join("A.tmLanguage"), grammar("Old A", "source.a")).unwrap();
+        std::fs::write(folder.join("T.tmLanguage"), grammar("T", "source.t")).unwrap();
+        std::fs::write(folder.join("vscode.tmLanguage.json"), r#"{"scopeName": "source.v", "patterns": []}"#).unwrap();
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add_from_folder(&folder, true).unwrap();
+        let names: Vec<_> = builder.syntaxes.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["A", "T", "vscode"]);
+    }
+
+    #[test]
+    fn loads_folders_in_a_stable_order() {
+        let dir = TestDir::new("loads_folders_in_a_stable_order");
+        let folder = dir.path();
+        // created in an order that differs from the sorted one
+        for path in &["b/Z.sublime-syntax", "a/Y.sublime-syntax", "B.sublime-syntax", "a.sublime-syntax"] {
+            let path = folder.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let name = path.file_stem().unwrap().to_str().unwrap();
+            let syntax = format!("scope: source.{}\ncontexts:\n  main:\n    - match: x\n      scope: x\n  other: []", name);
+            std::fs::write(&path, syntax).unwrap();
+        }
+
+        let load = || {
+            let mut builder = SyntaxSetBuilder::new();
+            builder.add_from_folder(&folder, true).unwrap();
+            builder.build()
+        };
+        let ss = load();
+        let names: Vec<&str> = ss.syntaxes().iter().map(|s| s.name.as_str()).collect();
+        // the folder `a` sorts before `a.sublime-syntax`
+        assert_eq!(names, vec!["B", "Y", "a", "Z"]);
+        #[cfg(any(feature = "dump-create", feature = "dump-create-rs"))]
+        {
+            // every load uses new hash maps with different seeds
+            use crate::dumps::dump_binary;
+            assert_eq!(dump_binary(&ss), dump_binary(&load()));
+        }
+    }
+
+    #[test]
+    fn errors_on_missing_base_syntax() {
+        let dir = TestDir::new("errors_on_missing_base_syntax");
+        let folder = dir.path();
+        std::fs::write(folder.join("Derived.sublime-syntax"),
+                       "scope: source.derived\nextends: Packages/Base/Base.sublime-syntax\ncontexts: {}").unwrap();
+        let result = SyntaxSetBuilder::new().add_from_folder(&folder, true);
+        match result {
+            Err(LoadingError::ParseSyntax(ParseSyntaxError::MissingBaseSyntax(_), Some(path))) => {
+                assert!(path.ends_with("Derived.sublime-syntax"));
+            }
+            other => panic!("expected missing base error, got {:?}", other),
+        }
+    }
+
     fn syntax_a() -> SyntaxDefinition {
         SyntaxDefinition::load_from_str(
             r#"
@@ -0,0 +1,600 @@
+//! Loading TextMate grammars, from `.tmLanguage` files or the `.tmLanguage.json` files used by
+//! VS Code extensions, by translating them into the structure of a `.sublime-syntax` file, which
+//! is then loaded like any other.
+//!
+//! TextMate rules map onto Sublime Text contexts quite directly:
+//!
+//! - `match` rules become match patterns, with `name` as the scope
+//! - `begin`/`end` rules become a match of `begin` that pushes an anonymous context, which has
+//!   `name` as its `meta_scope`, `contentName` as its `meta_content_scope`, and pops on `end`
+//!   before trying its own `patterns`, or after them if `applyEndPatternLast` is set
+//! - `begin`/`while` rules are treated like `begin`/`end` rules that end at the start of the
+//!   first line not matching `while`
+//! - rules with only `patterns` become an include of an anonymous context
+//! - the `repository` becomes named contexts, including the ones nested in rules
+//! - includes of `$self` and `$base` include `main`, `#name` includes the repository entry, and
+//!   `source.x#name` includes that entry of another syntax
+//! - the patterns of `injections` are included in `main` and every pushed context where the
+//!   injection selector matches, before the other patterns if it starts with `L:` and after
+//!   them otherwise. Since contexts don't know how they were reached, the selector is matched
+//!   against the scopes of the context and the rules it is nested in, not the actual scope
+//!   stack at parse time.
+//!
+//! Captures that have their own `patterns` only get their `name`, and grammars that only
+//! consist of an `injectionSelector` for injecting into other grammars aren't supported.
+use super::scope::*;
+use super::syntax_definition::*;
+use super::yaml_load::ParseSyntaxError;
+use crate::highlighting::ScopeSelectors;
+use fnv::FnvHasher;
+use plist::{Dictionary, Value};
+use std::hash::Hasher;
+use std::io::Cursor;
+use std::ops::DerefMut;
+use std::str::FromStr;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+impl SyntaxDefinition {
+    /// Loads a TextMate grammar from the contents of a `.tmLanguage` file, in either the XML or
+    /// the binary plist format.
+    ///
+    /// The `lines_include_newline` and `fallback_name` parameters work like for
+    /// [`load_from_str`]. See the [module docs] for how the grammar is translated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxDefinition;
+    ///
+    /// let grammar = r#"<?xml version="1.0" encoding="UTF-8"?>
+    /// <plist version="1.0">
+    /// <dict>
+    ///     <key>scopeName</key>
+    ///     <string>source.ini</string>
+    ///     <key>fileTypes</key>
+    ///     <array><string>ini</string></array>
+    ///     <key>patterns</key>
+    ///     <array>
+    ///         <dict>
+    ///             <key>match</key>
+    ///             <string>^\s*;.*$</string>
+    ///             <key>name</key>
+    ///             <string>comment.line.semicolon.ini</string>
+    ///         </dict>
+    ///     </array>
+    /// </dict>
+    /// </plist>"#;
+    /// let syntax = SyntaxDefinition::load_from_tm_language(grammar.as_bytes(), true, Some("INI")).unwrap();
+    /// assert_eq!(syntax.name, "INI");
+    /// assert_eq!(syntax.file_extensions, vec!["ini"]);
+    /// ```
+    ///
+    /// [`load_from_str`]: #method.load_from_str
+    /// [module docs]: tm_language/index.html
+    pub fn load_from_tm_language(
+        bytes: &[u8],
+        lines_include_newline: bool,
+        fallback_name: Option<&str>,
+    ) -> Result<SyntaxDefinition, ParseSyntaxError> {
+        let grammar = Value::from_reader(Cursor::new(bytes)).map_err(ParseSyntaxError::InvalidPlist)?;
+        SyntaxDefinition::load_from_tm_grammar(&grammar, bytes, lines_include_newline, fallback_name)
+    }
+
+    /// Loads a TextMate grammar in the JSON format, as used by the `.tmLanguage.json` files of
+    /// VS Code extensions.
+    ///
+    /// Works like [`load_from_tm_language`] otherwise. VS Code extensions declare the file
+    /// extensions of a language in their `package.json` instead of the grammar, those can be
+    /// added with [`SyntaxSetBuilder::add_extensions`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::SyntaxDefinition;
+    ///
+    /// let grammar = r##"{
+    ///     "name": "INI",
+    ///     "scopeName": "source.ini",
+    ///     "patterns": [{ "include": "#comment" }],
+    ///     "repository": {
+    ///         "comment": { "match": "^\\s*;.*$", "name": "comment.line.semicolon.ini" }
+    ///     }
+    /// }"##;
+    /// let syntax = SyntaxDefinition::load_from_tm_language_json(grammar, true, None).unwrap();
+    /// assert_eq!(syntax.name, "INI");
+    /// assert!(syntax.contexts.contains_key("comment"));
+    /// ```
+    ///
+    /// [`load_from_tm_language`]: #method.load_from_tm_language
+    /// [`SyntaxSetBuilder::add_extensions`]: ../struct.SyntaxSetBuilder.html#method.add_extensions
+    pub fn load_from_tm_language_json(
+        s: &str,
+        lines_include_newline: bool,
+        fallback_name: Option<&str>,
+    ) -> Result<SyntaxDefinition, ParseSyntaxError> {
+        let grammar: serde_json::Value = serde_json::from_str(s).map_err(ParseSyntaxError::InvalidJson)?;
+        SyntaxDefinition::load_from_tm_grammar(&json_to_plist(grammar), s.as_bytes(), lines_include_newline, fallback_name)
+    }
+
+    fn load_from_tm_grammar(
+        grammar: &Value,
+        source: &[u8],
+        lines_include_newline: bool,
+        fallback_name: Option<&str>,
+    ) -> Result<SyntaxDefinition, ParseSyntaxError> {
+        let doc = convert_grammar(grammar.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?)?;
+
+        let mut hasher = FnvHasher::default();
+        hasher.write(source);
+        let mut scope_repo = SCOPE_REPO.lock().unwrap();
+        let mut defn = SyntaxDefinition::parse_top_level(&doc, scope_repo.deref_mut(), lines_include_newline, fallback_name)?;
+        defn.content_hash = hasher.finish();
+        Ok(defn)
+    }
+}
+
+/// JSON grammars are converted to plist values to be translated the same way, `null`s are
+/// dropped since plists don't have them
+fn json_to_plist(value: serde_json::Value) -> Value {
+    use serde_json::Value as Json;
+    match value {
+        Json::Null => Value::String(String::new()),
+        Json::Bool(b) => Value::Boolean(b),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i.into()),
+            None => Value::Real(n.as_f64().unwrap_or(0.0)),
+        },
+        Json::String(s) => Value::String(s),
+        Json::Array(values) => Value::Array(values.into_iter().filter(|v| !v.is_null()).map(json_to_plist).collect()),
+        Json::Object(map) => {
+            let mut dict = Dictionary::new();
+            for (key, value) in map {
+                if !value.is_null() {
+                    dict.insert(key, json_to_plist(value));
+                }
+            }
+            Value::Dictionary(dict)
+        }
+    }
+}
+
+fn key(s: &str) -> Yaml {
+    Yaml::String(s.to_owned())
+}
+
+fn get_str<'a>(rule: &'a Dictionary, name: &str) -> Option<&'a str> {
+    rule.get(name).and_then(Value::as_string)
+}
+
+fn is_set(rule: &Dictionary, name: &str) -> bool {
+    rule.get(name)
+        .map(|v| v.as_boolean().unwrap_or(false) || v.as_signed_integer().unwrap_or(0) != 0)
+        .unwrap_or(false)
+}
+
+/// Repository entries that would clash with the contexts Sublime Text syntaxes treat specially
+/// get renamed
+fn context_name(name: &str) -> String {
+    if name == "main" || name == "prototype" || name.starts_with("__") {
+        format!("_{}", name)
+    } else {
+        name.to_owned()
+    }
+}
+
+struct Injection {
+    selector: ScopeSelectors,
+    context: String,
+    before: bool,
+}
+
+struct Converter {
+    contexts: Hash,
+    injections: Vec<Injection>,
+}
+
+fn convert_grammar(grammar: &Dictionary) -> Result<Yaml, ParseSyntaxError> {
+    let mut doc = Hash::new();
+    if let Some(name) = get_str(grammar, "name") {
+        doc.insert(key("name"), key(name));
+    }
+    let scope = get_str(grammar, "scopeName").ok_or(ParseSyntaxError::MissingMandatoryKey("scopeName"))?;
+    doc.insert(key("scope"), key(scope));
+    if let Some(file_types) = grammar.get("fileTypes").and_then(Value::as_array) {
+        let file_types = file_types.iter().filter_map(Value::as_string).map(key).collect();
+        doc.insert(key("file_extensions"), Yaml::Array(file_types));
+    }
+    if let Some(first_line_match) = get_str(grammar, "firstLineMatch") {
+        doc.insert(key("first_line_match"), key(first_line_match));
+    }
+    if let Some(hidden) = grammar.get("hideFromUser").and_then(Value::as_boolean) {
+        doc.insert(key("hidden"), Yaml::Boolean(hidden));
+    }
+
+    let mut converter = Converter { contexts: Hash::new(), injections: Vec::new() };
+    let scopes = vec![scope.to_owned()];
+    let injections = match grammar.get("injections") {
+        Some(injections) => injections.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?.clone(),
+        None => Dictionary::new(),
+    };
+    // all selectors have to be known before converting any patterns, including the injected ones
+    for (index, selector) in injections.keys().enumerate() {
+        let selector = selector.trim();
+        let (before, selector) = if let Some(rest) = selector.strip_prefix("L:") {
+            (true, rest)
+        } else {
+            (false, selector.strip_prefix("R:").unwrap_or(selector))
+        };
+        let selector = selector.replace("L:", "").replace("R:", "");
+        converter.injections.push(Injection {
+            selector: ScopeSelectors::from_str(&selector).map_err(ParseSyntaxError::InvalidScope)?,
+            context: format!("__injection_{}", index),
+            before,
+        });
+    }
+    for (index, injection) in injections.values().enumerate() {
+        let injection = injection.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?;
+        let context = converter.convert_patterns(injection, &scopes)?;
+        converter.contexts.insert(key(&format!("__injection_{}", index)), Yaml::Array(context));
+    }
+
+    let mut main = converter.convert_patterns(grammar, &scopes)?;
+    converter.inject(&mut main, &scopes);
+    converter.add_repository(grammar, &scopes)?;
+    converter.contexts.insert(key("main"), Yaml::Array(main));
+    doc.insert(key("contexts"), Yaml::Hash(converter.contexts));
+    Ok(Yaml::Hash(doc))
+}
+
+impl Converter {
+    /// Adds the `repository` of a grammar or rule to the contexts, entries from outer
+    /// repositories take precedence over nested ones with the same name
+    fn add_repository(&mut self, rule: &Dictionary, scopes: &[String]) -> Result<(), ParseSyntaxError> {
+        let repository = match rule.get("repository") {
+            Some(repository) => repository.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?,
+            None => return Ok(()),
+        };
+        let mut entries = Vec::with_capacity(repository.len());
+        for (name, entry) in repository {
+            let entry = entry.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?;
+            let context = if entry.contains_key("match") || entry.contains_key("begin") || entry.contains_key("include") {
+                self.convert_rule(entry, scopes)?.into_iter().collect()
+            } else {
+                self.convert_patterns(entry, scopes)?
+            };
+            entries.push((context_name(name), context));
+        }
+        for (name, context) in entries {
+            self.contexts.insert(key(&name), Yaml::Array(context));
+        }
+        Ok(())
+    }
+
+    /// Converts the `patterns` of a rule, `scopes` are the ones of the pushed contexts the
+    /// patterns are nested in
+    fn convert_patterns(&mut self, rule: &Dictionary, scopes: &[String]) -> Result<Vec<Yaml>, ParseSyntaxError> {
+        let mut items = Vec::new();
+        if let Some(patterns) = rule.get("patterns") {
+            for pattern in patterns.as_array().ok_or(ParseSyntaxError::TypeMismatch)? {
+                let pattern = pattern.as_dictionary().ok_or(ParseSyntaxError::TypeMismatch)?;
+                items.extend(self.convert_rule(pattern, scopes)?);
+            }
+        }
+        Ok(items)
+    }
+
+    /// Converts a rule into an item of a context, `None` if it is disabled or empty
+    fn convert_rule(&mut self, rule: &Dictionary, scopes: &[String]) -> Result<Option<Yaml>, ParseSyntaxError> {
+        if is_set(rule, "disabled") {
+            return Ok(None);
+        }
+        self.add_repository(rule, scopes)?;
+
+        let mut item = Hash::new();
+        if let Some(include) = get_str(rule, "include") {
+            item.insert(key("include"), key(&convert_include(include)));
+        } else if let Some(regex) = get_str(rule, "match") {
+            item.insert(key("match"), key(regex));
+            if let Some(name) = get_str(rule, "name") {
+                item.insert(key("scope"), key(name));
+            }
+            if let Some(captures) = convert_captures(rule, "captures") {
+                item.insert(key("captures"), captures);
+            }
+        } else if let Some(begin) = get_str(rule, "begin") {
+            let end = match (get_str(rule, "end"), get_str(rule, "while")) {
+                (Some(end), _) => end.to_owned(),
+                (None, Some(while_regex)) => format!("^(?!(?:{}))", while_regex),
+                (None, None) => return Err(ParseSyntaxError::MissingMandatoryKey("end")),
+            };
+
+            let mut pushed = Vec::new();
+            let mut pushed_scopes = scopes.to_vec();
+            if let Some(name) = get_str(rule, "name") {
+                let mut meta = Hash::new();
+                meta.insert(key("meta_scope"), key(name));
+                pushed.push(Yaml::Hash(meta));
+                pushed_scopes.push(name.to_owned());
+            }
+            if let Some(content_name) = get_str(rule, "contentName") {
+                let mut meta = Hash::new();
+                meta.insert(key("meta_content_scope"), key(content_name));
+                pushed.push(Yaml::Hash(meta));
+                pushed_scopes.push(content_name.to_owned());
+            }
+
+            let mut end_item = Hash::new();
+            end_item.insert(key("match"), key(&end));
+            end_item.insert(key("pop"), Yaml::Boolean(true));
+            if let Some(captures) = convert_captures(rule, "endCaptures").or_else(|| convert_captures(rule, "captures")) {
+                end_item.insert(key("captures"), captures);
+            }
+            let patterns = self.convert_patterns(rule, &pushed_scopes)?;
+            if is_set(rule, "applyEndPatternLast") {
+                pushed.extend(patterns);
+                pushed.push(Yaml::Hash(end_item));
+            } else {
+                pushed.push(Yaml::Hash(end_item));
+                pushed.extend(patterns);
+            }
+            self.inject(&mut pushed, &pushed_scopes);
+
+            item.insert(key("match"), key(begin));
+            if let Some(captures) = convert_captures(rule, "beginCaptures").or_else(|| convert_captures(rule, "captures")) {
+                item.insert(key("captures"), captures);
+            }
+            item.insert(key("push"), Yaml::Array(pushed));
+        } else if rule.contains_key("patterns") {
+            item.insert(key("include"), Yaml::Array(self.convert_patterns(rule, scopes)?));
+        } else {
+            return Ok(None);
+        }
+        Ok(Some(Yaml::Hash(item)))
+    }
+
+    /// Includes the injections whose selectors match the scopes into a pushed context
+    fn inject(&self, items: &mut Vec<Yaml>, scopes: &[String]) {
+        if self.injections.is_empty() {
+            return;
+        }
+        let stack: Vec<Scope> = scopes.iter()
+            .flat_map(|s| s.split_whitespace())
+            .filter_map(|s| Scope::new(s).ok())
+            .collect();
+        // meta entries are kept in front so injected patterns come before the end pattern
+        let mut first_pattern = items.iter()
+            .take_while(|item| !item["meta_scope"].is_badvalue() || !item["meta_content_scope"].is_badvalue())
+            .count();
+        for injection in &self.injections {
+            if injection.selector.does_match(&stack).is_none() {
+                continue;
+            }
+            let mut include = Hash::new();
+            include.insert(key("include"), key(&injection.context));
+            if injection.before {
+                items.insert(first_pattern, Yaml::Hash(include));
+                first_pattern += 1;
+            } else {
+                items.push(Yaml::Hash(include));
+            }
+        }
+    }
+}
+
+fn convert_include(include: &str) -> String {
+    match include {
+        "$self" | "$base" => "main".to_owned(),
+        _ if include.starts_with('#') => context_name(&include[1..]),
+        _ => match include.find('#') {
+            Some(index) => format!("scope:{}#{}", &include[..index], context_name(&include[index + 1..])),
+            None => format!("scope:{}", include),
+        },
+    }
+}
+
+fn convert_captures(rule: &Dictionary, name: &str) -> Option<Yaml> {
+    let captures = rule.get(name)?;
+    let mut converted = Hash::new();
+    let mut add = |index: i64, capture: &Value| {
+        if let Some(scope) = capture.as_dictionary().and_then(|c| get_str(c, "name")) {
+            converted.insert(Yaml::Integer(index), key(scope));
+        }
+    };
+    // JSON grammars sometimes use arrays instead of objects with numeric keys
+    if let Some(captures) = captures.as_array() {
+        for (index, capture) in captures.iter().enumerate() {
+            add(index as i64, capture);
+        }
+    } else {
+        for (index, capture) in captures.as_dictionary()? {
+            if let Ok(index) = index.parse::<i64>() {
+                add(index, capture);
+            }
+        }
+    }
+    Some(Yaml::Hash(converted))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{ParseState, ScopeStack, ScopeStackOp, SyntaxSetBuilder};
+
+    const GRAMMAR: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Test</string>
+    <key>scopeName</key>
+    <string>source.tmtest</string>
+    <key>fileTypes</key>
+    <array><string>tmtest</string></array>
+    <key>patterns</key>
+    <array>
+        <dict><key>include</key><string>#strings</string></dict>
+        <dict><key>include</key><string>#missing</string></dict>
+        <dict><key>include</key><string>#cycle</string></dict>
+        <dict>
+            <key>match</key>
+            <string>\b(let)\s+(\w+)</string>
+            <key>captures</key>
+            <dict>
+                <key>1</key><dict><key>name</key><string>keyword.tmtest</string></dict>
+                <key>2</key><dict><key>name</key><string>variable.tmtest</string></dict>
+            </dict>
+        </dict>
+        <dict>
+            <key>begin</key>
+            <string>\(</string>
+            <key>end</key>
+            <string>\)</string>
+            <key>name</key>
+            <string>meta.group.tmtest</string>
+            <key>patterns</key>
+            <array><dict><key>include</key><string>$self</string></dict></array>
+        </dict>
+        <dict>
+            <key>disabled</key>
+            <integer>1</integer>
+            <key>match</key>
+            <string>let</string>
+            <key>name</key>
+            <string>invalid.tmtest</string>
+        </dict>
+    </array>
+    <key>repository</key>
+    <dict>
+        <key>strings</key>
+        <dict>
+            <key>begin</key>
+            <string>(['"])</string>
+            <key>beginCaptures</key>
+            <dict><key>0</key><dict><key>name</key><string>punctuation.begin.tmtest</string></dict></dict>
+            <key>end</key>
+            <string>\1</string>
+            <key>name</key>
+            <string>string.tmtest</string>
+            <key>contentName</key>
+            <string>content.tmtest</string>
+        </dict>
+        <key>main</key>
+        <dict><key>match</key><string>x</string></dict>
+        <key>cycle</key>
+        <dict>
+            <key>patterns</key>
+            <array>
+                <dict><key>include</key><string>#cycle</string></dict>
+                <dict><key>match</key><string>cycle</string><key>name</key><string>cycle.tmtest</string></dict>
+            </array>
+        </dict>
+    </dict>
+</dict>
+</plist>"#;
+
+    #[test]
+    fn can_load_tm_language() {
+        let syntax = SyntaxDefinition::load_from_tm_language(GRAMMAR.as_bytes(), true, None).unwrap();
+        assert_eq!(syntax.name, "Test");
+        assert_eq!(syntax.scope, Scope::new("source.tmtest").unwrap());
+        assert_eq!(syntax.file_extensions, vec!["tmtest"]);
+        assert!(syntax.contexts.contains_key("strings"));
+        assert!(syntax.contexts.contains_key("_main"));
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax);
+        let ss = builder.build();
+        let syntax = ss.find_syntax_by_extension("tmtest").unwrap();
+        let scopes_at = |line: &str, pos: usize| {
+            let ops = ParseState::new(syntax).parse_line(line, &ss);
+            let mut stack = ScopeStack::new();
+            for (_, op) in ops.iter().take_while(|(index, _)| *index <= pos) {
+                stack.apply(op);
+            }
+            format!("{:?}", stack)
+        };
+
+        let scopes = scopes_at("(let a = 'b')\n", 2);
+        assert!(scopes.contains("meta.group.tmtest") && scopes.contains("keyword.tmtest"), "{}", scopes);
+        let scopes = scopes_at("(let a = 'b')\n", 10);
+        assert!(scopes.contains("string.tmtest") && scopes.contains("content.tmtest"), "{}", scopes);
+        let scopes = scopes_at("(let a = 'b')\n", 9);
+        assert!(scopes.contains("punctuation.begin.tmtest") && !scopes.contains("content"), "{}", scopes);
+        // the end of the string refers to the capture of its beginning
+        let scopes = scopes_at("\"it's\" let\n", 3);
+        assert!(scopes.contains("content.tmtest"), "{}", scopes);
+        let scopes = scopes_at("\"it's\" let\n", 8);
+        assert!(!scopes.contains("string"), "{}", scopes);
+        // includes that end up including themselves are skipped
+        let scopes = scopes_at("cycle\n", 0);
+        assert!(scopes.contains("cycle.tmtest"), "{}", scopes);
+    }
+
+    #[test]
+    fn can_load_tm_language_json_with_injections() {
+        let grammar = r##"{
+            "name": "JSON Test",
+            "scopeName": "source.jsontest",
+            "patterns": [
+                { "include": "#comment" },
+                { "begin": "\"", "end": "\"", "name": "string.jsontest", "patterns": [{ "include": "#escape" }] },
+                { "match": "todo", "name": "plain.todo.jsontest" }
+            ],
+            "repository": {
+                "comment": {
+                    "begin": "/\\*",
+                    "end": "\\*/",
+                    "name": "comment.block.jsontest",
+                    "endCaptures": [{ "name": "punctuation.end.jsontest" }]
+                },
+                "escape": { "match": "\\\\.", "name": "constant.escape.jsontest" }
+            },
+            "injections": {
+                "L:source.jsontest - string": {
+                    "patterns": [{ "match": "todo", "name": "keyword.todo.jsontest" }]
+                },
+                "string.jsontest": {
+                    "patterns": [{ "match": "todo", "name": "string.todo.jsontest" }]
+                }
+            },
+            "nothing": null
+        }"##;
+        let syntax = SyntaxDefinition::load_from_tm_language_json(grammar, true, None).unwrap();
+        assert_eq!(syntax.name, "JSON Test");
+        assert!(syntax.contexts.contains_key("__injection_0"));
+
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(syntax);
+        let ss = builder.build();
+        let syntax = ss.find_syntax_by_name("JSON Test").unwrap();
+        let ops = ParseState::new(syntax).parse_line("todo /* todo */ \"todo\\\"\"\n", &ss);
+        let pushes: Vec<_> = ops.iter().filter_map(|(index, op)| match op {
+            ScopeStackOp::Push(scope) => Some((*index, scope.build_string())),
+            _ => None,
+        }).collect();
+        assert_eq!(pushes, vec![
+            (0, "source.jsontest".to_owned()),
+            // the injection goes before the other patterns of main
+            (0, "keyword.todo.jsontest".to_owned()),
+            (5, "comment.block.jsontest".to_owned()),
+            (8, "keyword.todo.jsontest".to_owned()),
+            (13, "punctuation.end.jsontest".to_owned()),
+            (16, "string.jsontest".to_owned()),
+            // it doesn't match in strings, where the other injection does
+            (17, "string.todo.jsontest".to_owned()),
+            (21, "constant.escape.jsontest".to_owned()),
+        ]);
+
+        assert!(SyntaxDefinition::load_from_tm_language_json("{", true, None).is_err());
+    }
+
+    #[test]
+    fn converts_includes() {
+        assert_eq!(convert_include("$self"), "main");
+        assert_eq!(convert_include("#main"), "_main");
+        assert_eq!(convert_include("#expression"), "expression");
+        assert_eq!(convert_include("source.js"), "scope:source.js");
+        assert_eq!(convert_include("source.js#expression"), "scope:source.js#expression");
+    }
+}
@@ -1,10 +1,12 @@
-use super::regex::{Regex, Region};
+use super::regex::{count_capture_groups, Regex, Region};
 use super::scope::*;
 use super::syntax_definition::*;
 use yaml_rust::{YamlLoader, Yaml, ScanError};
 use yaml_rust::yaml::Hash;
 use std::collections::HashMap;
 use std::error::Error;
+use std::hash::Hasher;
+use fnv::FnvHasher;
 use std::fmt;
 use std::path::Path;
 use std::ops::DerefMut;
@@ -29,6 +31,19 @@ pub enum ParseSyntaxError {
     /// Sorry this doesn't give you any way to narrow down where this is.
     /// Maybe use Sublime Text to figure it out.
     TypeMismatch,
+    /// The syntax `extends` a base syntax that couldn't be found
+    MissingBaseSyntax(String),
+    /// The chain of `extends` contains a cycle
+    RecursiveExtends(String),
+    /// A variable refers to itself, directly or through other variables. Contains the cycle, like
+    /// `a -> b -> a`.
+    RecursiveVariable(String),
+    /// Invalid plist file syntax in a `.tmLanguage` grammar
+    #[cfg(feature = "tmlanguage-load")]
+    InvalidPlist(plist::Error),
+    /// Invalid JSON syntax in a `.tmLanguage.json` grammar
+    #[cfg(feature = "tmlanguage-load")]
+    InvalidJson(serde_json::Error),
 }
 
 impl fmt::Display for ParseSyntaxError {
@@ -46,6 +61,13 @@ impl fmt::Display for ParseSyntaxError {
             BadFileRef => write!(f, "Invalid file reference"),
             MainMissing => write!(f, "Context 'main' is missing"),
             TypeMismatch => write!(f, "Type mismatch"),
+            MissingBaseSyntax(ref path) => write!(f, "Base syntax '{}' not found", path),
+            RecursiveExtends(ref path) => write!(f, "Syntax '{}' extends itself", path),
+            RecursiveVariable(ref cycle) => write!(f, "Variables refer to themselves: {}", cycle),
+            #[cfg(feature = "tmlanguage-load")]
+            InvalidPlist(_) => write!(f, "Invalid plist file syntax"),
+            #[cfg(feature = "tmlanguage-load")]
+            InvalidJson(_) => write!(f, "Invalid JSON file syntax"),
         }
     }
 }
@@ -57,6 +79,10 @@ impl Error for ParseSyntaxError {
         match self {
             InvalidYaml(ref error) => Some(error),
             RegexCompileError(_, error) => Some(error.as_ref()),
+            #[cfg(feature = "tmlanguage-load")]
+            InvalidPlist(ref error) => Some(error),
+            #[cfg(feature = "tmlanguage-load")]
+            InvalidJson(ref error) => Some(error),
             _ => None,
         }
     }
@@ -71,6 +97,108 @@ fn get_key<'a, R, F: FnOnce(&'a Yaml) -> Option<R>>(map: &'a Hash,
         .and_then(|x| f(x).ok_or(ParseSyntaxError::TypeMismatch))
 }
 
+fn load_yaml_doc(s: &str) -> Result<Yaml, ParseSyntaxError> {
+    let mut docs = YamlLoader::load_from_str(s).map_err(ParseSyntaxError::InvalidYaml)?;
+    if docs.is_empty() {
+        return Err(ParseSyntaxError::EmptyFile);
+    }
+    Ok(docs.swap_remove(0))
+}
+
+/// Returns the document with the variables and contexts of the syntaxes it `extends` merged in
+fn resolve_extends(doc: Yaml,
+                   base_source: &mut dyn FnMut(&str) -> Option<String>,
+                   resolving: &mut Vec<String>)
+                   -> Result<Yaml, ParseSyntaxError> {
+    let mut derived = match doc {
+        Yaml::Hash(h) => h,
+        _ => return Err(ParseSyntaxError::TypeMismatch),
+    };
+    let bases: Vec<String> = match derived.remove(&Yaml::String("extends".to_owned())) {
+        None => return Ok(Yaml::Hash(derived)),
+        Some(Yaml::String(path)) => vec![path],
+        Some(Yaml::Array(paths)) => paths.into_iter()
+            .map(|p| p.into_string().ok_or(ParseSyntaxError::TypeMismatch))
+            .collect::<Result<_, _>>()?,
+        Some(_) => return Err(ParseSyntaxError::TypeMismatch),
+    };
+
+    let mut variables = Hash::new();
+    let mut contexts = Hash::new();
+    for path in bases {
+        if resolving.contains(&path) {
+            return Err(ParseSyntaxError::RecursiveExtends(path));
+        }
+        let source = base_source(&path).ok_or_else(|| ParseSyntaxError::MissingBaseSyntax(path.clone()))?;
+        resolving.push(path);
+        let base = resolve_extends(load_yaml_doc(&source)?, base_source, resolving)?;
+        resolving.pop();
+
+        if let Yaml::Hash(mut base) = base {
+            if let Some(Yaml::Hash(map)) = base.remove(&Yaml::String("variables".to_owned())) {
+                variables.extend(map);
+            }
+            if let Some(Yaml::Hash(map)) = base.remove(&Yaml::String("contexts".to_owned())) {
+                contexts.extend(map);
+            }
+        }
+    }
+
+    if let Some(Yaml::Hash(map)) = derived.remove(&Yaml::String("variables".to_owned())) {
+        variables.extend(map);
+    }
+    if let Some(Yaml::Hash(map)) = derived.remove(&Yaml::String("contexts".to_owned())) {
+        for (name, context) in map {
+            let merged = match (contexts.remove(&name), context) {
+                (Some(Yaml::Array(base)), Yaml::Array(own)) => merge_contexts(base, own),
+                (_, own) => own,
+            };
+            contexts.insert(name, merged);
+        }
+    }
+    derived.insert(Yaml::String("variables".to_owned()), Yaml::Hash(variables));
+    derived.insert(Yaml::String("contexts".to_owned()), Yaml::Hash(contexts));
+    Ok(Yaml::Hash(derived))
+}
+
+/// Combines a context from a base syntax with the one overriding it in a derived syntax
+fn merge_contexts(base: Vec<Yaml>, own: Vec<Yaml>) -> Yaml {
+    let mut prepend = false;
+    let mut append = false;
+    let mut own_items = Vec::with_capacity(own.len());
+    for item in own {
+        if let Yaml::Hash(mut map) = item {
+            prepend |= map.remove(&Yaml::String("meta_prepend".to_owned())) == Some(Yaml::Boolean(true));
+            append |= map.remove(&Yaml::String("meta_append".to_owned())) == Some(Yaml::Boolean(true));
+            if !map.is_empty() {
+                own_items.push(Yaml::Hash(map));
+            }
+        } else {
+            own_items.push(item);
+        }
+    }
+
+    if !prepend && !append {
+        return Yaml::Array(own_items);
+    }
+    // meta entries that come later win, so the ones of the derived context go after the base ones
+    let (base_meta, base_patterns): (Vec<Yaml>, Vec<Yaml>) = base.into_iter().partition(is_meta_entry);
+    let mut items = base_meta;
+    if prepend {
+        items.extend(own_items);
+        items.extend(base_patterns);
+    } else {
+        items.extend(base_patterns);
+        items.extend(own_items);
+    }
+    Yaml::Array(items)
+}
+
+fn is_meta_entry(item: &Yaml) -> bool {
+    ["meta_scope", "meta_content_scope", "meta_include_prototype", "clear_scopes"].iter()
+        .any(|key| !item[*key].is_badvalue())
+}
+
 fn str_to_scopes(s: &str, repo: &mut ScopeRepository) -> Result<Vec<Scope>, ParseSyntaxError> {
     s.split_whitespace()
         .map(|scope| repo.build(scope).map_err(ParseSyntaxError::InvalidScope))
@@ -79,12 +207,86 @@ fn str_to_scopes(s: &str, repo: &mut ScopeRepository) -> Result<Vec<Scope>, Pars
 
 struct ParserState<'a> {
     scope_repo: &'a mut ScopeRepository,
+    /// The variables with the variables they refer to already expanded
     variables: HashMap<String, String>,
     variable_regex: Regex,
     backref_regex: Regex,
     lines_include_newline: bool,
 }
 
+/// Expands the variables that each variable refers to, so that regexes only need a single pass
+fn resolve_variables(raw: &HashMap<String, String>, variable_regex: &Regex)
+                     -> Result<HashMap<String, String>, ParseSyntaxError> {
+    let mut resolved = HashMap::new();
+    let mut path = Vec::new();
+    // sorted so that the same cycle is reported every time
+    let mut names: Vec<&String> = raw.keys().collect();
+    names.sort();
+    for name in names {
+        resolve_variable(name, raw, variable_regex, &mut resolved, &mut path)?;
+    }
+    Ok(resolved)
+}
+
+/// Resolves a variable after the ones it refers to. `path` holds the variables that are being
+/// resolved, so that a cycle is reported instead of recursing forever.
+fn resolve_variable<'a>(name: &'a str,
+                        raw: &'a HashMap<String, String>,
+                        variable_regex: &Regex,
+                        resolved: &mut HashMap<String, String>,
+                        path: &mut Vec<&'a str>)
+                        -> Result<(), ParseSyntaxError> {
+    if resolved.contains_key(name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|n| *n == name) {
+        let mut cycle = path[start..].to_vec();
+        cycle.push(name);
+        return Err(ParseSyntaxError::RecursiveVariable(cycle.join(" -> ")));
+    }
+    // undefined variables expand to nothing
+    let value = match raw.get(name) {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+
+    path.push(name);
+    let mut index = 0;
+    let mut region = Region::new();
+    while variable_regex.search(value, index, value.len(), Some(&mut region)) {
+        let (start, end) = region.pos(1).unwrap();
+        resolve_variable(&value[start..end], raw, variable_regex, resolved, path)?;
+        index = region.pos(0).unwrap().1;
+    }
+    path.pop();
+
+    let value = substitute_variables(value, variable_regex, resolved);
+    resolved.insert(name.to_owned(), value);
+    Ok(())
+}
+
+/// Replaces the `{{name}}` references in the text with the values of the variables
+fn substitute_variables(text: &str, variable_regex: &Regex, variables: &HashMap<String, String>) -> String {
+    let mut result = String::new();
+    let mut index = 0;
+    let mut region = Region::new();
+    while variable_regex.search(text, index, text.len(), Some(&mut region)) {
+        let (begin, end) = region.pos(0).unwrap();
+
+        result.push_str(&text[index..begin]);
+
+        let var_pos = region.pos(1).unwrap();
+        let var_name = &text[var_pos.0..var_pos.1];
+        result.push_str(variables.get(var_name).map(String::as_ref).unwrap_or(""));
+
+        index = end;
+    }
+    if index < text.len() {
+        result.push_str(&text[index..]);
+    }
+    result
+}
+
 // `__start` must not include prototypes from the actual syntax definition,
 // otherwise it's possible that a prototype makes us pop out of `__start`.
 static START_CONTEXT: &'static str = "
@@ -103,25 +305,70 @@ impl SyntaxDefinition {
     ///
     /// `fallback_name` is an optional name to use when the YAML doesn't provide a `name` key.
     ///
+    /// Syntaxes that use `extends` can't be loaded this way, use [`load_from_str_with_bases`]
+    /// instead.
+    ///
     /// [`SyntaxSet`]: ../struct.SyntaxSet.html
+    /// [`load_from_str_with_bases`]: #method.load_from_str_with_bases
     pub fn load_from_str(
         s: &str,
         lines_include_newline: bool,
         fallback_name: Option<&str>,
     ) -> Result<SyntaxDefinition, ParseSyntaxError> {
-        let docs = match YamlLoader::load_from_str(s) {
-            Ok(x) => x,
-            Err(e) => return Err(ParseSyntaxError::InvalidYaml(e)),
-        };
-        if docs.is_empty() {
-            return Err(ParseSyntaxError::EmptyFile);
-        }
-        let doc = &docs[0];
+        SyntaxDefinition::load_from_str_with_bases(s, lines_include_newline, fallback_name, |_| None)
+    }
+
+    /// Like [`load_from_str`], but supports syntaxes that inherit from other syntaxes with the
+    /// `extends` key.
+    ///
+    /// `base_source` is called with each value of `extends`, like
+    /// `Packages/C++/C.sublime-syntax`, and should return the source of that syntax. The derived
+    /// syntax gets the variables and contexts of its bases. Its own contexts replace the ones of
+    /// the base with the same name, unless they set `meta_prepend` or `meta_append`, which add
+    /// their patterns before or after the ones of the base context. Because the variables are
+    /// merged before the regexes are built, overriding a variable also affects inherited patterns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use syntect::parsing::syntax_definition::SyntaxDefinition;
+    ///
+    /// let base = "name: Base\nscope: source.base\ncontexts: {main: [{match: a, scope: a}]}";
+    /// let derived = "name: Derived\nscope: source.derived\nextends: Packages/Base/Base.sublime-syntax";
+    /// let syntax = SyntaxDefinition::load_from_str_with_bases(derived, true, None, |path| {
+    ///     if path == "Packages/Base/Base.sublime-syntax" { Some(base.to_owned()) } else { None }
+    /// }).unwrap();
+    /// assert!(syntax.contexts.contains_key("main"));
+    /// ```
+    ///
+    /// [`load_from_str`]: #method.load_from_str
+    pub fn load_from_str_with_bases<F>(
+        s: &str,
+        lines_include_newline: bool,
+        fallback_name: Option<&str>,
+        mut base_source: F,
+    ) -> Result<SyntaxDefinition, ParseSyntaxError>
+        where F: FnMut(&str) -> Option<String>
+    {
+        // FNV is used because unlike the default hasher its output is stable across releases
+        let mut hasher = FnvHasher::default();
+        hasher.write(s.as_bytes());
+        let doc = load_yaml_doc(s)?;
+        let doc = resolve_extends(doc, &mut |path: &str| {
+            let source = base_source(path);
+            if let Some(ref source) = source {
+                hasher.write(source.as_bytes());
+            }
+            source
+        }, &mut Vec::new())?;
+
         let mut scope_repo = SCOPE_REPO.lock().unwrap();
-        SyntaxDefinition::parse_top_level(doc, scope_repo.deref_mut(), lines_include_newline, fallback_name)
+        let mut defn = SyntaxDefinition::parse_top_level(&doc, scope_repo.deref_mut(), lines_include_newline, fallback_name)?;
+        defn.content_hash = hasher.finish();
+        Ok(defn)
     }
 
-    fn parse_top_level(doc: &Yaml,
+    pub(super) fn parse_top_level(doc: &Yaml,
                        scope_repo: &mut ScopeRepository,
                        lines_include_newline: bool,
                        fallback_name: Option<&str>)
@@ -139,10 +386,11 @@ impl SyntaxDefinition {
         let contexts_hash = get_key(h, "contexts", |x| x.as_hash())?;
         let top_level_scope = scope_repo.build(get_key(h, "scope", |x| x.as_str())?)
             .map_err(ParseSyntaxError::InvalidScope)?;
+        let variable_regex = Regex::new(r"\{\{([A-Za-z0-9_]+)\}\}".into());
         let mut state = ParserState {
             scope_repo,
-            variables,
-            variable_regex: Regex::new(r"\{\{([A-Za-z0-9_]+)\}\}".into()),
+            variables: resolve_variables(&variables, &variable_regex)?,
+            variable_regex,
             backref_regex: Regex::new(r"\\\d".into()),
             lines_include_newline,
         };
@@ -172,8 +420,24 @@ impl SyntaxDefinition {
                 .map(|s| s.to_owned()),
             hidden: get_key(h, "hidden", |x| x.as_bool()).unwrap_or(false),
 
-            variables: state.variables.clone(),
+            variables,
             contexts,
+            version: match h.get(&Yaml::String("version".to_owned())) {
+                Some(&Yaml::String(ref s)) | Some(&Yaml::Real(ref s)) => Some(s.clone()),
+                Some(&Yaml::Integer(i)) => Some(i.to_string()),
+                _ => None,
+            },
+            content_hash: 0,
+            hidden_file_extensions: {
+                get_key(h, "hidden_file_extensions", |x| x.as_vec())
+                    .map(|v| v.iter().filter_map(|y| y.as_str()).map(|x| x.to_owned()).collect())
+                    .unwrap_or_else(|_| Vec::new())
+            },
+            mime_types: {
+                get_key(h, "mime_types", |x| x.as_vec())
+                    .map(|v| v.iter().filter_map(|y| y.as_str()).map(|x| x.to_ascii_lowercase()).collect())
+                    .unwrap_or_else(|_| Vec::new())
+            },
         };
         Ok(defn)
     }
@@ -231,13 +495,35 @@ impl SyntaxDefinition {
                 if let Ok(x) = get_key(map, "include", Some) {
                     let reference = SyntaxDefinition::parse_reference(
                         x, state, contexts, namer)?;
+                    // The prototype of another syntax is included in front of its context, if
+                    // the syntax doesn't have one the reference isn't linked and gets skipped.
+                    if let Ok(true) = get_key(map, "apply_prototype", |x| x.as_bool()) {
+                        let prototype = match reference {
+                            ContextReference::ByScope { scope, .. } => Some(ContextReference::ByScope {
+                                scope,
+                                sub_context: Some("prototype".to_owned()),
+                            }),
+                            ContextReference::File { ref name, .. } => Some(ContextReference::File {
+                                name: name.clone(),
+                                sub_context: Some("prototype".to_owned()),
+                            }),
+                            // contexts of the same syntax already get its prototype
+                            _ => None,
+                        };
+                        if let Some(prototype) = prototype {
+                            context.patterns.push(Pattern::Include(prototype));
+                        }
+                    }
                     context.patterns.push(Pattern::Include(reference));
                 } else {
-                    let pattern = SyntaxDefinition::parse_match_pattern(
+                    let (pattern, dropped_captures) = SyntaxDefinition::parse_match_pattern(
                         map, state, contexts, namer)?;
                     if pattern.has_captures {
                         context.uses_backrefs = true;
                     }
+                    for group in dropped_captures {
+                        context.add_dropped_capture(context.patterns.len(), group);
+                    }
                     context.patterns.push(Pattern::Match(pattern));
                 }
             }
@@ -287,11 +573,12 @@ impl SyntaxDefinition {
         }
     }
 
+    /// Parses a match pattern, also returning the captured groups that the regex doesn't have
     fn parse_match_pattern(map: &Hash,
                            state: &mut ParserState<'_>,
                            contexts: &mut HashMap<String, Context>,
                            namer: &mut ContextNamer)
-                           -> Result<MatchPattern, ParseSyntaxError> {
+                           -> Result<(MatchPattern, Vec<usize>), ParseSyntaxError> {
         let raw_regex = get_key(map, "match", |x| x.as_str())?;
         let regex_str = Self::parse_regex(raw_regex, state)?;
         // println!("{:?}", regex_str);
@@ -301,8 +588,9 @@ impl SyntaxDefinition {
             .map(|s| str_to_scopes(s, state.scope_repo))
             .unwrap_or_else(|| Ok(vec![]))?;
 
+        let mut dropped_captures = Vec::new();
         let captures = if let Ok(map) = get_key(map, "captures", |x| x.as_hash()) {
-            Some(Self::parse_captures(map, &regex_str, state)?)
+            Some(Self::parse_captures(map, &regex_str, state, &mut dropped_captures)?)
         } else {
             None
         };
@@ -316,8 +604,20 @@ impl SyntaxDefinition {
             MatchOperation::Push(SyntaxDefinition::parse_pushargs(y, state, contexts, namer)?)
         } else if let Ok(y) = get_key(map, "set", Some) {
             MatchOperation::Set(SyntaxDefinition::parse_pushargs(y, state, contexts, namer)?)
+        } else if let Ok(y) = get_key(map, "branch", Some) {
+            MatchOperation::Branch {
+                name: get_key(map, "branch_point", |x| x.as_str())?.to_owned(),
+                alternatives: SyntaxDefinition::parse_pushargs(y, state, contexts, namer)?,
+            }
+        } else if let Ok(name) = get_key(map, "fail", |x| x.as_str()) {
+            MatchOperation::Fail(name.to_owned())
         } else if let Ok(y) = get_key(map, "embed", Some) {
-            // Same as push so we translate it to what it would be
+            // Same as push so we translate it to what it would be: the embedded context is
+            // pushed on top of an inline context that applies `embed_scope` and pops on `escape`.
+            // The `escape` key also becomes a `with_prototype` lookahead below, so that it pops
+            // every context the embedded syntax pushes or sets on top.
+            // Note that unlike Sublime Text, a match in the embedded syntax that starts before
+            // the escape can still run past it.
             let mut embed_escape_context_yaml = vec!();
             let mut commands = Hash::new();
             commands.insert(Yaml::String("meta_include_prototype".to_string()), Yaml::Boolean(false));
@@ -363,7 +663,7 @@ impl SyntaxDefinition {
             let mut match_map = Hash::new();
             match_map.insert(Yaml::String("match".to_string()), Yaml::String(format!("(?={})", v.as_str().unwrap())));
             match_map.insert(Yaml::String("pop".to_string()), Yaml::Boolean(true));
-            let pattern = SyntaxDefinition::parse_match_pattern(&match_map, state, contexts, namer)?;
+            let (pattern, _) = SyntaxDefinition::parse_match_pattern(&match_map, state, contexts, namer)?;
             if pattern.has_captures {
                 context.uses_backrefs = true;
             }
@@ -384,7 +684,7 @@ impl SyntaxDefinition {
             with_prototype,
         );
 
-        Ok(pattern)
+        Ok((pattern, dropped_captures))
     }
 
     fn parse_pushargs(y: &Yaml,
@@ -407,7 +707,7 @@ impl SyntaxDefinition {
     }
 
     fn parse_regex(raw_regex: &str, state: &ParserState<'_>) -> Result<String, ParseSyntaxError> {
-        let regex = Self::resolve_variables(raw_regex, state);
+        let regex = substitute_variables(raw_regex, &state.variable_regex, &state.variables);
         let regex = replace_posix_char_classes(regex);
         let regex = if state.lines_include_newline {
             regex_for_newlines(regex)
@@ -421,29 +721,6 @@ impl SyntaxDefinition {
         Ok(regex)
     }
 
-    fn resolve_variables(raw_regex: &str, state: &ParserState<'_>) -> String {
-        let mut result = String::new();
-        let mut index = 0;
-        let mut region = Region::new();
-        while state.variable_regex.search(raw_regex, index, raw_regex.len(), Some(&mut region)) {
-            let (begin, end) = region.pos(0).unwrap();
-
-            result.push_str(&raw_regex[index..begin]);
-
-            let var_pos = region.pos(1).unwrap();
-            let var_name = &raw_regex[var_pos.0..var_pos.1];
-            let var_raw = state.variables.get(var_name).map(String::as_ref).unwrap_or("");
-            let var_resolved = Self::resolve_variables(var_raw, state);
-            result.push_str(&var_resolved);
-
-            index = end;
-        }
-        if index < raw_regex.len() {
-            result.push_str(&raw_regex[index..]);
-        }
-        result
-    }
-
     fn try_compile_regex(regex_str: &str) -> Result<(), ParseSyntaxError> {
         // Replace backreferences with a placeholder value that will also appear in errors
         let regex_str = substitute_backrefs_in_regex(regex_str, |i| Some(format!("<placeholder_{}>", i)));
@@ -455,17 +732,23 @@ impl SyntaxDefinition {
         }
     }
 
+    /// Parses the captures of a pattern, adding the groups the regex doesn't have to `dropped`
+    /// for `SyntaxDefinition::lint`
     fn parse_captures(
         map: &Hash,
         regex_str: &str,
         state: &mut ParserState<'_>,
+        dropped: &mut Vec<usize>,
     ) -> Result<CaptureMapping, ParseSyntaxError> {
         let valid_indexes = get_consuming_capture_indexes(regex_str);
+        let groups = count_capture_groups(regex_str);
         let mut captures = Vec::new();
         for (key, value) in map.iter() {
             if let (Some(key_int), Some(val_str)) = (key.as_i64(), value.as_str()) {
                 if valid_indexes.contains(&(key_int as usize)) {
                     captures.push((key_int as usize, str_to_scopes(val_str, state.scope_repo)?));
+                } else if key_int as usize > groups {
+                    dropped.push(key_int as usize);
                 }
             }
         }
@@ -875,6 +1158,7 @@ mod tests {
         name: C
         scope: source.c
         file_extensions: [c, h]
+        hidden_file_extensions: [inc]
         hidden: true
         variables:
           ident: '[QY]+'
@@ -910,6 +1194,7 @@ mod tests {
         let exts: Vec<String> = vec![String::from("c"), String::from("h")];
         assert_eq!(defn2.file_extensions, exts);
         assert_eq!(defn2.hidden, true);
+        assert_eq!(defn2.hidden_file_extensions, vec![String::from("inc")]);
         assert_eq!(defn2.variables.get("ident").unwrap(), "[QY]+");
 
         let n: Vec<Scope> = Vec::new();
@@ -930,6 +1215,9 @@ mod tests {
             &Pattern::Match(ref match_pat) => {
                 let m: &CaptureMapping = match_pat.captures.as_ref().expect("test failed");
                 assert_eq!(&m[0], &(1,vec![Scope::new("meta.preprocessor.c++").unwrap()]));
+                // the regex doesn't have group 2, that capture is only remembered for linting
+                assert_eq!(m.len(), 1);
+                assert_eq!(main.dropped_captures(), &[(0, 2)]);
                 use crate::parsing::syntax_definition::ContextReference::*;
 
                 // this is sadly necessary because Context is not Eq because of the Regex
@@ -954,6 +1242,118 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_extend_syntax() {
+        let base = r#"
+        name: Base
+        scope: source.base
+        file_extensions: [base]
+        variables:
+          ident: '[a-z]+'
+        contexts:
+          main:
+            - match: '{{ident}}'
+              scope: ident
+          strings:
+            - meta_scope: string
+            - match: '"'
+              pop: true
+        "#;
+        let derived = r#"
+        name: Derived
+        scope: source.derived
+        extends: Packages/Base/Base.sublime-syntax
+        variables:
+          ident: '[A-Z]+'
+        contexts:
+          main:
+            - meta_prepend: true
+            - match: '#'
+              scope: comment
+          strings:
+            - meta_append: true
+            - meta_scope: string.derived
+            - match: '\.'
+              scope: escape
+        "#;
+        let mut requested = Vec::new();
+        let def = SyntaxDefinition::load_from_str_with_bases(derived, false, None, |path| {
+            requested.push(path.to_owned());
+            Some(base.to_owned())
+        }).unwrap();
+        assert_eq!(requested, vec!["Packages/Base/Base.sublime-syntax"]);
+        assert_eq!(def.name, "Derived");
+        assert!(def.file_extensions.is_empty());
+        assert_eq!(def.variables["ident"], "[A-Z]+");
+
+        let main = &def.contexts["main"];
+        assert_eq!(main.match_at(0).regex.regex_str(), "#");
+        assert_eq!(main.match_at(1).regex.regex_str(), "[A-Z]+");
+        let strings = &def.contexts["strings"];
+        assert_eq!(strings.meta_scope, vec![Scope::new("string.derived").unwrap()]);
+        assert_eq!(strings.match_at(0).regex.regex_str(), "\"");
+        assert_eq!(strings.match_at(1).regex.regex_str(), "\\.");
+
+        match SyntaxDefinition::load_from_str(derived, false, None) {
+            Err(ParseSyntaxError::MissingBaseSyntax(path)) => assert_eq!(path, "Packages/Base/Base.sublime-syntax"),
+            other => panic!("expected missing base error, got {:?}", other),
+        }
+        let cyclic = SyntaxDefinition::load_from_str_with_bases(derived, false, None, |_| Some(derived.to_owned()));
+        match cyclic {
+            Err(ParseSyntaxError::RecursiveExtends(_)) => (),
+            other => panic!("expected recursive extends error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_parse_branches() {
+        let def = SyntaxDefinition::load_from_str(r#"
+        name: C
+        scope: source.c
+        contexts:
+          main:
+            - match: (?=\()
+              branch_point: paren
+              branch: [group, tuple]
+          group:
+            - match: ','
+              fail: paren
+          tuple:
+            - match: \)
+              pop: true
+        "#, false, None).unwrap();
+        assert_eq!(def.contexts["main"].match_at(0).operation, MatchOperation::Branch {
+            name: "paren".to_owned(),
+            alternatives: vec![ContextReference::Named("group".to_owned()), ContextReference::Named("tuple".to_owned())],
+        });
+        assert_eq!(def.contexts["group"].match_at(0).operation, MatchOperation::Fail("paren".to_owned()));
+
+        let missing_name = SyntaxDefinition::load_from_str(
+            "scope: source.c\ncontexts: {main: [{match: a, branch: [main]}]}", false, None);
+        match missing_name {
+            Err(ParseSyntaxError::MissingMandatoryKey(key)) => assert_eq!(key, "branch_point"),
+            other => panic!("expected missing key error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_identify_syntax_revision() {
+        let source = "name: C\nscope: source.c\nversion: 2\ncontexts: {main: []}";
+        let def = SyntaxDefinition::load_from_str(source, false, None).unwrap();
+        assert_eq!(def.version, Some("2".to_owned()));
+        assert_eq!(def.content_hash, SyntaxDefinition::load_from_str(source, true, None).unwrap().content_hash);
+
+        let changed = source.replace("C", "C2");
+        let def2 = SyntaxDefinition::load_from_str(&changed, false, None).unwrap();
+        assert_ne!(def.content_hash, def2.content_hash);
+
+        let mut builder = crate::parsing::SyntaxSetBuilder::new();
+        builder.add(def.clone());
+        let ss = builder.build();
+        assert_eq!(ss.syntaxes()[0].content_hash, def.content_hash);
+        assert_eq!(ss.syntaxes()[0].version, def.version);
+    }
+
     #[test]
     fn can_parse_embed_as_with_prototypes() {
         let old_def = SyntaxDefinition::load_from_str(r#"
@@ -1035,6 +1435,46 @@ mod tests {
         }
     }
 
+    #[test]
+    fn can_resolve_nested_variables() {
+        let def = SyntaxDefinition::load_from_str(r#"
+        name: C
+        scope: source.c
+        variables:
+          ident: '{{start}}{{rest}}*'
+          start: '[a-z{{upper}}]'
+          rest: '{{start}}|[0-9]'
+          upper: A-Z
+        contexts:
+          main:
+            - match: '\b{{ident}}\b{{undefined}}'
+              scope: variable
+        "#, false, None).unwrap();
+        assert_eq!(def.variables["ident"], "{{start}}{{rest}}*");
+        assert_eq!(def.contexts["main"].match_at(0).regex.regex_str(),
+                   "\\b[a-zA-Z][a-zA-Z]|[0-9]*\\b");
+    }
+
+    #[test]
+    fn errors_on_recursive_variables() {
+        let def = SyntaxDefinition::load_from_str(r#"
+        name: C
+        scope: source.c
+        variables:
+          a: 'x{{b}}'
+          b: '{{c}}y'
+          c: '({{a}})'
+          d: '{{d}}'
+        contexts:
+          main:
+            - match: '{{a}}'
+        "#, false, None);
+        match def.unwrap_err() {
+            ParseSyntaxError::RecursiveVariable(ref cycle) => assert_eq!(cycle, "a -> b -> c -> a"),
+            _ => assert!(false, "Got unexpected ParseSyntaxError"),
+        }
+    }
+
     #[test]
     fn can_parse_ugly_yaml() {
         let defn: SyntaxDefinition =
@@ -0,0 +1,310 @@
+//! A simple request/response protocol for running syntect as a separate process.
+//!
+//! This lets applications that aren't written in Rust use syntect by starting a sidecar process
+//! and talking to it over its stdin and stdout, see the `synserver` example for such a program.
+//!
+//! # Protocol
+//!
+//! Every message, in both directions, is a line with the length in bytes of a UTF-8 JSON
+//! document as a decimal number, followed by the document itself:
+//!
+//! ```text
+//! 27
+//! {"id":1,"method":"version"}
+//! ```
+//!
+//! The newline after the document is optional, and documents can't be longer than
+//! [`MAX_MESSAGE_LEN`] bytes. Requests are objects with a `method` field, and
+//! an `id` that is copied into the response, so it can be any JSON value. The server answers
+//! every request in order with either `{"id": ..., "result": ...}` or
+//! `{"id": ..., "error": "message"}`.
+//!
+//! These are the methods for version 1 of the protocol, see [`PROTOCOL_VERSION`]:
+//!
+//! - `version`: returns `{"protocol": 1, "syntect": "4.4.0"}`. Clients should check the
+//!   protocol version before sending anything else.
+//! - `list_syntaxes`: returns the visible syntaxes as `[{"name": ..., "file_extensions": [...]}]`
+//! - `list_themes`: returns the theme names as an array of strings
+//! - `load_syntaxes` with `folder`: adds the `.sublime-syntax` files in the folder, loaded for
+//!   lines with newlines, and returns the new number of syntaxes
+//! - `load_themes` with `folder`: adds the `.tmTheme` files in the folder and returns the new
+//!   number of themes
+//! - `highlight` with `text`, `syntax`, `theme` and `format`: highlights the text with the syntax
+//!   found by [`SyntaxSet::find_syntax_by_token`], falling back to plain text. With the `"html"`
+//!   format, the default, the result is a string with the same HTML as
+//!   [`highlighted_html_for_string`]. With the `"tokens"` format it is an array of lines, each an
+//!   array of `{"text": ..., "foreground": "#rrggbb", "background": "#rrggbb", "font_style": [...]}`
+//!   tokens, where colors that aren't opaque get an alpha component and `font_style` contains
+//!   `"bold"`, `"italic"` and `"underline"`.
+//! - `shutdown`: returns `null` and stops the server
+//!
+//! Requests that can't be parsed get an error response with a `null` id.
+//!
+//! [`PROTOCOL_VERSION`]: constant.PROTOCOL_VERSION.html
+//! [`MAX_MESSAGE_LEN`]: constant.MAX_MESSAGE_LEN.html
+//! [`SyntaxSet::find_syntax_by_token`]: ../parsing/struct.SyntaxSet.html#method.find_syntax_by_token
+//! [`highlighted_html_for_string`]: ../html/fn.highlighted_html_for_string.html
+use crate::easy::HighlightLines;
+use crate::highlighting::{Color, FontStyle, Style, ThemeSet};
+use crate::html::highlighted_html_for_string;
+use crate::parsing::SyntaxSet;
+use crate::util::LinesWithEndings;
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+
+/// The version of the protocol, returned by the `version` method.
+///
+/// It changes whenever a change to the protocol isn't backwards compatible.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The largest message in bytes that [`read_message`] accepts, so a bad length in a header
+/// can't make it allocate an arbitrary amount of memory.
+///
+/// [`read_message`]: fn.read_message.html
+pub const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    Version,
+    ListSyntaxes,
+    ListThemes,
+    LoadSyntaxes { folder: String },
+    LoadThemes { folder: String },
+    Highlight {
+        text: String,
+        syntax: String,
+        theme: String,
+        format: Option<Format>,
+    },
+    Shutdown,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Format {
+    Html,
+    Tokens,
+}
+
+/// Answers protocol requests with a syntax set and a theme set, see the [module docs].
+///
+/// The syntax set should be one for lines with newlines, like the one from
+/// [`SyntaxSet::load_defaults_newlines`].
+///
+/// # Examples
+///
+/// ```no_run
+/// use syntect::parsing::SyntaxSet;
+/// use syntect::highlighting::ThemeSet;
+/// use syntect::server::Server;
+/// use std::io;
+///
+/// let mut server = Server::new(SyntaxSet::load_defaults_newlines(), ThemeSet::load_defaults());
+/// let stdin = io::stdin();
+/// server.serve(stdin.lock(), io::stdout()).unwrap();
+/// ```
+///
+/// [module docs]: index.html
+/// [`SyntaxSet::load_defaults_newlines`]: ../parsing/struct.SyntaxSet.html#method.load_defaults_newlines
+#[derive(Debug)]
+pub struct Server {
+    syntax_set: SyntaxSet,
+    theme_set: ThemeSet,
+}
+
+impl Server {
+    pub fn new(syntax_set: SyntaxSet, theme_set: ThemeSet) -> Server {
+        Server { syntax_set, theme_set }
+    }
+
+    /// Answers requests from `input` until it ends or a `shutdown` request is handled.
+    ///
+    /// Only fails for IO errors and messages that aren't framed correctly, since the rest of the
+    /// stream can't be read after those.
+    pub fn serve<R: BufRead, W: Write>(&mut self, mut input: R, mut output: W) -> io::Result<()> {
+        while let Some(message) = read_message(&mut input)? {
+            let (response, shutdown) = self.handle_message(&message);
+            write_message(&mut output, &response)?;
+            output.flush()?;
+            if shutdown {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a single request, returning the response and whether the request was `shutdown`
+    pub fn handle_message(&mut self, message: &[u8]) -> (Vec<u8>, bool) {
+        let (id, request) = match serde_json::from_slice::<Value>(message) {
+            Ok(value) => {
+                let id = value.get("id").cloned().unwrap_or(Value::Null);
+                (id, serde_json::from_value::<Request>(value).map_err(|e| e.to_string()))
+            }
+            Err(e) => (Value::Null, Err(e.to_string())),
+        };
+        let shutdown = matches!(request, Ok(Request::Shutdown));
+        let response = match request.and_then(|request| self.handle(request)) {
+            Ok(result) => json!({ "id": id, "result": result }),
+            Err(error) => json!({ "id": id, "error": error }),
+        };
+        (response.to_string().into_bytes(), shutdown)
+    }
+
+    fn handle(&mut self, request: Request) -> Result<Value, String> {
+        match request {
+            Request::Version => Ok(json!({
+                "protocol": PROTOCOL_VERSION,
+                "syntect": env!("CARGO_PKG_VERSION"),
+            })),
+            Request::ListSyntaxes => Ok(self.syntax_set.visible_syntaxes()
+                .map(|s| json!({ "name": s.name, "file_extensions": s.file_extensions }))
+                .collect()),
+            Request::ListThemes => Ok(self.theme_set.themes.keys().cloned().collect()),
+            Request::LoadSyntaxes { folder } => {
+                let mut builder = std::mem::take(&mut self.syntax_set).into_builder();
+                let result = builder.add_from_folder(&folder, true);
+                self.syntax_set = builder.build();
+                result.map_err(|e| e.to_string())?;
+                Ok(json!(self.syntax_set.syntaxes().len()))
+            }
+            Request::LoadThemes { folder } => {
+                self.theme_set.add_from_folder(&folder).map_err(|e| e.to_string())?;
+                Ok(json!(self.theme_set.themes.len()))
+            }
+            Request::Highlight { text, syntax, theme, format } => {
+                let syntax = self.syntax_set.find_syntax_by_token(&syntax)
+                    .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+                let theme = self.theme_set.themes.get(&theme)
+                    .ok_or_else(|| format!("unknown theme: {}", theme))?;
+                match format.unwrap_or(Format::Html) {
+                    Format::Html => Ok(json!(highlighted_html_for_string(&text, &self.syntax_set, syntax, theme))),
+                    Format::Tokens => {
+                        let mut highlighter = HighlightLines::new(syntax, theme);
+                        Ok(LinesWithEndings::from(&text).map(|line| {
+                            highlighter.highlight(line, &self.syntax_set).into_iter()
+                                .map(|(style, text)| token_json(style, text))
+                                .collect::<Value>()
+                        }).collect())
+                    }
+                }
+            }
+            Request::Shutdown => Ok(Value::Null),
+        }
+    }
+}
+
+fn token_json(style: Style, text: &str) -> Value {
+    let font_style: Vec<&str> = [
+        (FontStyle::BOLD, "bold"),
+        (FontStyle::ITALIC, "italic"),
+        (FontStyle::UNDERLINE, "underline"),
+    ].iter().filter(|(flag, _)| style.font_style.contains(*flag)).map(|&(_, name)| name).collect();
+    json!({
+        "text": text,
+        "foreground": color_hex(style.foreground),
+        "background": color_hex(style.background),
+        "font_style": font_style,
+    })
+}
+
+fn color_hex(c: Color) -> String {
+    if c.a == 0xFF {
+        format!("#{:02x}{:02x}{:02x}", c.r, c.g, c.b)
+    } else {
+        format!("#{:02x}{:02x}{:02x}{:02x}", c.r, c.g, c.b, c.a)
+    }
+}
+
+/// Reads one message, returning `None` if the input ended before it.
+///
+/// Messages longer than [`MAX_MESSAGE_LEN`] are rejected with an `InvalidData` error.
+///
+/// [`MAX_MESSAGE_LEN`]: constant.MAX_MESSAGE_LEN.html
+pub fn read_message<R: BufRead>(input: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = String::new();
+    loop {
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        // skips the optional newline after the previous message
+        if !header.trim().is_empty() {
+            break;
+        }
+        header.clear();
+    }
+    let len: usize = header.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("invalid message length: {:?}", header.trim()))
+    })?;
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("message too long: {} bytes", len)));
+    }
+    let mut message = vec![0; len];
+    input.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+/// Writes one message, followed by a newline
+pub fn write_message<W: Write>(output: &mut W, message: &[u8]) -> io::Result<()> {
+    writeln!(output, "{}", message.len())?;
+    output.write_all(message)?;
+    writeln!(output)
+}
+
+#[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(input: &mut Vec<u8>, request: Value) {
+        write_message(input, request.to_string().as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn can_serve_requests() {
+        let mut input = Vec::new();
+        request(&mut input, json!({ "id": 1, "method": "version" }));
+        request(&mut input, json!({
+            "id": "a",
+            "method": "highlight",
+            "text": "fn main() {}\n",
+            "syntax": "rs",
+            "theme": "InspiredGitHub",
+            "format": "tokens",
+        }));
+        request(&mut input, json!({ "id": 3, "method": "highlight", "text": "", "syntax": "rs", "theme": "nope" }));
+        input.extend_from_slice(b"8\nnot json");
+        request(&mut input, json!({ "id": 5, "method": "shutdown" }));
+        request(&mut input, json!({ "id": 6, "method": "version" }));
+
+        let mut server = Server::new(SyntaxSet::load_defaults_newlines(), ThemeSet::load_defaults());
+        let mut output = Vec::new();
+        server.serve(&input[..], &mut output).unwrap();
+
+        let mut output = &output[..];
+        let mut responses = Vec::new();
+        while let Some(message) = read_message(&mut output).unwrap() {
+            responses.push(serde_json::from_slice::<Value>(&message).unwrap());
+        }
+        assert_eq!(responses.len(), 5);
+        assert_eq!(responses[0], json!({ "id": 1, "result": { "protocol": 1, "syntect": env!("CARGO_PKG_VERSION") } }));
+
+        let lines = responses[1]["result"].as_array().unwrap();
+        assert_eq!(responses[1]["id"], "a");
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0][0]["text"], "fn");
+        assert!(lines[0][0]["foreground"].as_str().unwrap().starts_with('#'));
+
+        assert_eq!(responses[2], json!({ "id": 3, "error": "unknown theme: nope" }));
+        assert_eq!(responses[3]["id"], Value::Null);
+        assert!(responses[3]["error"].is_string());
+        assert_eq!(responses[4], json!({ "id": 5, "result": null }));
+    }
+
+    #[test]
+    fn rejects_oversized_messages() {
+        let mut input = &b"99999999999999\n{}"[..];
+        let err = read_message(&mut input).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
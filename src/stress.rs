@@ -0,0 +1,366 @@
+//! Checking how highlighting scales over threads that share a syntax set and a theme set.
+//!
+//! A [`StressTest`] highlights a corpus of samples on several threads at once, all using the same
+//! [`SyntaxSet`] and [`ThemeSet`], the way a server or an editor with a thread pool would. The
+//! resulting [`StressReport`] has the throughput, how it compares to a single thread, and the
+//! number of samples that came out differently on different threads, which should always be zero.
+//!
+//! Since regexes are compiled lazily by whichever thread needs them first, the first pass over
+//! the corpus is reported separately from the following ones: it shows the cost of warming up
+//! a fresh syntax set under contention, while the later passes show the steady state.
+//!
+//! # Examples
+//!
+//! ```
+//! use std::sync::Arc;
+//! use syntect::highlighting::ThemeSet;
+//! use syntect::parsing::SyntaxSet;
+//! use syntect::stress::{Sample, StressOptions, StressTest};
+//!
+//! let corpus = vec![
+//!     Sample::new("py", "def f(x):\n    return x + 1\n"),
+//!     Sample::new("json", "{\"a\": [1, 2, 3]}\n"),
+//! ];
+//! let test = StressTest::new(
+//!     Arc::new(SyntaxSet::load_defaults_newlines()),
+//!     Arc::new(ThemeSet::load_defaults()),
+//!     corpus,
+//! );
+//! let report = test.run(&StressOptions { threads: 2, passes: 3, ..StressOptions::default() });
+//! assert_eq!(report.mismatches, 0);
+//! println!("{:.0} bytes/s, {:?} efficiency", report.bytes_per_second(), report.efficiency());
+//! ```
+//!
+//! [`StressTest`]: struct.StressTest.html
+//! [`StressReport`]: struct.StressReport.html
+//! [`SyntaxSet`]: ../parsing/struct.SyntaxSet.html
+//! [`ThemeSet`]: ../highlighting/struct.ThemeSet.html
+use crate::easy::HighlightLines;
+use crate::highlighting::{Style, Theme, ThemeSet};
+use crate::parsing::{SyntaxReference, SyntaxSet};
+use crate::util::LinesWithEndings;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A text to highlight, with the syntax to highlight it with
+#[derive(Debug, Clone)]
+pub struct Sample {
+    /// A token for [`SyntaxSet::find_syntax_by_token`] or a file name for
+    /// [`SyntaxSet::find_syntax_by_file_name`], the plain text syntax is used if neither finds
+    /// anything.
+    ///
+    /// [`SyntaxSet::find_syntax_by_token`]: ../parsing/struct.SyntaxSet.html#method.find_syntax_by_token
+    /// [`SyntaxSet::find_syntax_by_file_name`]: ../parsing/struct.SyntaxSet.html#method.find_syntax_by_file_name
+    pub syntax: String,
+    pub text: String,
+}
+
+impl Sample {
+    pub fn new(syntax: &str, text: &str) -> Sample {
+        Sample {
+            syntax: syntax.to_owned(),
+            text: text.to_owned(),
+        }
+    }
+
+    /// Reads a sample from a file, using its file name to find the syntax
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Sample> {
+        let path = path.as_ref();
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        Ok(Sample {
+            syntax: file_name.to_owned(),
+            text: fs::read_to_string(path)?,
+        })
+    }
+
+    fn find_syntax<'a>(&self, syntax_set: &'a SyntaxSet) -> &'a SyntaxReference {
+        syntax_set.find_syntax_by_token(&self.syntax)
+            .or_else(|| syntax_set.find_syntax_by_file_name(&self.syntax))
+            .unwrap_or_else(|| syntax_set.find_syntax_plain_text())
+    }
+}
+
+/// How a [`StressTest`] is run
+///
+/// [`StressTest`]: struct.StressTest.html
+#[derive(Debug, Clone)]
+pub struct StressOptions {
+    /// The number of threads highlighting at the same time, 4 by default
+    pub threads: usize,
+    /// How many times each thread highlights the whole corpus, 5 by default
+    pub passes: usize,
+    /// The name of the theme to use from the theme set, `base16-ocean.dark` by default
+    pub theme: String,
+    /// Whether to also run the corpus on a single thread after the others are done, to compare
+    /// the throughput with, true by default
+    pub baseline: bool,
+}
+
+impl Default for StressOptions {
+    fn default() -> StressOptions {
+        StressOptions {
+            threads: 4,
+            passes: 5,
+            theme: "base16-ocean.dark".to_owned(),
+            baseline: true,
+        }
+    }
+}
+
+/// Highlights a corpus on several threads sharing a syntax set and a theme set
+///
+/// The syntax set should be one for lines with newlines, since the samples are highlighted with
+/// them. See the [module docs] for an example.
+///
+/// [module docs]: index.html
+#[derive(Debug, Clone)]
+pub struct StressTest {
+    syntax_set: Arc<SyntaxSet>,
+    theme_set: Arc<ThemeSet>,
+    corpus: Arc<Vec<Sample>>,
+}
+
+/// What a single thread did during a [`StressTest`]
+///
+/// [`StressTest`]: struct.StressTest.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadReport {
+    /// The time each pass over the corpus took
+    pub passes: Vec<Duration>,
+    /// The number of bytes in a pass
+    pub bytes_per_pass: usize,
+    /// The number of lines in a pass
+    pub lines_per_pass: usize,
+    /// The longest time highlighting a single line took
+    pub slowest_line: Duration,
+}
+
+/// The results of a [`StressTest`]
+///
+/// [`StressTest`]: struct.StressTest.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StressReport {
+    /// The time from starting the threads to the last one finishing
+    pub wall_time: Duration,
+    /// A report for every thread
+    pub threads: Vec<ThreadReport>,
+    /// The single thread run, if [`StressOptions::baseline`] was set
+    ///
+    /// [`StressOptions::baseline`]: struct.StressOptions.html#structfield.baseline
+    pub baseline: Option<ThreadReport>,
+    /// The number of times a sample was highlighted differently than on the first thread's
+    /// first pass. Anything other than zero means highlighting isn't deterministic across
+    /// threads.
+    pub mismatches: usize,
+}
+
+impl StressTest {
+    pub fn new(syntax_set: Arc<SyntaxSet>, theme_set: Arc<ThemeSet>, corpus: Vec<Sample>) -> StressTest {
+        StressTest {
+            syntax_set,
+            theme_set,
+            corpus: Arc::new(corpus),
+        }
+    }
+
+    /// Runs the test, blocking until all the threads are done.
+    ///
+    /// # Panics
+    ///
+    /// If the theme in the options isn't in the theme set, or `threads` or `passes` are zero.
+    pub fn run(&self, options: &StressOptions) -> StressReport {
+        assert!(options.threads > 0 && options.passes > 0, "threads and passes must be at least 1");
+        assert!(self.theme_set.themes.contains_key(&options.theme), "unknown theme: {}", options.theme);
+
+        // all threads start highlighting at the same time to maximize contention
+        let barrier = Arc::new(Barrier::new(options.threads + 1));
+        let handles: Vec<_> = (0..options.threads).map(|index| {
+            let test = self.clone();
+            let theme = options.theme.clone();
+            let passes = options.passes;
+            let barrier = Arc::clone(&barrier);
+            thread::spawn(move || {
+                barrier.wait();
+                test.highlight_corpus(&test.theme_set.themes[&theme], passes, index)
+            })
+        }).collect();
+        barrier.wait();
+        let start = Instant::now();
+        let results: Vec<_> = handles.into_iter()
+            .map(|handle| handle.join().expect("highlighting thread panicked"))
+            .collect();
+        let wall_time = start.elapsed();
+
+        let expected = &results[0].1[0];
+        let mismatches = results.iter()
+            .flat_map(|(_, digests)| digests)
+            .map(|pass| pass.iter().zip(expected).filter(|(a, b)| a != b).count())
+            .sum();
+        let baseline = if options.baseline {
+            Some(self.highlight_corpus(&self.theme_set.themes[&options.theme], options.passes, 0).0)
+        } else {
+            None
+        };
+
+        StressReport {
+            wall_time,
+            threads: results.into_iter().map(|(report, _)| report).collect(),
+            baseline,
+            mismatches,
+        }
+    }
+
+    /// Highlights the corpus `passes` times, starting at a different sample on every thread so
+    /// they don't all compile the same regexes at once. Also returns a digest of the output for
+    /// every sample in every pass, in corpus order.
+    fn highlight_corpus(&self, theme: &Theme, passes: usize, thread: usize) -> (ThreadReport, Vec<Vec<u64>>) {
+        let mut report = ThreadReport {
+            passes: Vec::with_capacity(passes),
+            bytes_per_pass: self.corpus.iter().map(|sample| sample.text.len()).sum(),
+            lines_per_pass: 0,
+            slowest_line: Duration::default(),
+        };
+        let mut digests = Vec::with_capacity(passes);
+        for _ in 0..passes {
+            let mut pass_digests = vec![0; self.corpus.len()];
+            let mut lines = 0;
+            let pass_start = Instant::now();
+            for offset in 0..self.corpus.len() {
+                let index = (offset + thread) % self.corpus.len();
+                let sample = &self.corpus[index];
+                let mut highlighter = HighlightLines::new(sample.find_syntax(&self.syntax_set), theme);
+                let mut hasher = DefaultHasher::new();
+                for line in LinesWithEndings::from(&sample.text) {
+                    let line_start = Instant::now();
+                    let regions = highlighter.highlight(line, &self.syntax_set);
+                    report.slowest_line = report.slowest_line.max(line_start.elapsed());
+                    for (style, text) in regions {
+                        hash_style(&mut hasher, style);
+                        hasher.write(text.as_bytes());
+                    }
+                    lines += 1;
+                }
+                pass_digests[index] = hasher.finish();
+            }
+            report.passes.push(pass_start.elapsed());
+            report.lines_per_pass = lines;
+            digests.push(pass_digests);
+        }
+        (report, digests)
+    }
+}
+
+fn hash_style(hasher: &mut DefaultHasher, style: Style) {
+    for color in &[style.foreground, style.background] {
+        hasher.write(&[color.r, color.g, color.b, color.a]);
+    }
+    hasher.write_u8(style.font_style.bits());
+}
+
+fn per_second(amount: usize, time: Duration) -> f64 {
+    amount as f64 / time.as_secs_f64().max(f64::MIN_POSITIVE)
+}
+
+impl ThreadReport {
+    /// The total time spent highlighting
+    pub fn busy_time(&self) -> Duration {
+        self.passes.iter().sum()
+    }
+
+    /// Bytes highlighted per second after the first pass, which also includes compiling regexes.
+    /// Uses all passes if there is only one.
+    pub fn steady_bytes_per_second(&self) -> f64 {
+        let steady = if self.passes.len() > 1 { &self.passes[1..] } else { &self.passes[..] };
+        per_second(self.bytes_per_pass * steady.len(), steady.iter().sum())
+    }
+}
+
+impl StressReport {
+    /// The number of bytes highlighted by all threads
+    pub fn bytes(&self) -> usize {
+        self.threads.iter().map(|t| t.bytes_per_pass * t.passes.len()).sum()
+    }
+
+    /// The number of lines highlighted by all threads
+    pub fn lines(&self) -> usize {
+        self.threads.iter().map(|t| t.lines_per_pass * t.passes.len()).sum()
+    }
+
+    /// Bytes highlighted per second by all threads together, including the first pass
+    pub fn bytes_per_second(&self) -> f64 {
+        per_second(self.bytes(), self.wall_time)
+    }
+
+    /// Lines highlighted per second by all threads together, including the first pass
+    pub fn lines_per_second(&self) -> f64 {
+        per_second(self.lines(), self.wall_time)
+    }
+
+    /// How many times the steady state throughput of all threads together is higher than that
+    /// of the single thread baseline, ideally the number of threads
+    pub fn scaling(&self) -> Option<f64> {
+        let baseline = self.baseline.as_ref()?.steady_bytes_per_second();
+        let total: f64 = self.threads.iter().map(ThreadReport::steady_bytes_per_second).sum();
+        Some(total / baseline)
+    }
+
+    /// The scaling divided by the number of threads, from 0 to 1 where lower values mean
+    /// threads are slowing each other down, for example by contending for memory bandwidth
+    /// or for fewer cores than threads
+    pub fn efficiency(&self) -> Option<f64> {
+        self.scaling().map(|scaling| scaling / self.threads.len() as f64)
+    }
+
+    /// How much longer the slowest thread was busy than the fastest one, 1 if they took
+    /// equally long
+    pub fn imbalance(&self) -> f64 {
+        let busy = self.threads.iter().map(ThreadReport::busy_time);
+        let slowest = busy.clone().max().unwrap_or_default();
+        let fastest = busy.min().unwrap_or_default();
+        slowest.as_secs_f64() / fastest.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+}
+
+#[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_run_stress_test() {
+        let corpus = vec![
+            Sample::new("py", "import os\n\ndef f(x):\n    return os.path.join(x, 'a')\n"),
+            Sample::new("Cargo.toml", "[package]\nname = \"a\"\n"),
+            Sample::new("index.html", "<p class=\"a\">text</p>\n"),
+            Sample::new("unknown", "plain text\n"),
+        ];
+        let test = StressTest::new(
+            Arc::new(SyntaxSet::load_defaults_newlines()),
+            Arc::new(ThemeSet::load_defaults()),
+            corpus,
+        );
+        let report = test.run(&StressOptions { threads: 3, passes: 2, ..StressOptions::default() });
+
+        assert_eq!(report.mismatches, 0);
+        assert_eq!(report.threads.len(), 3);
+        for thread in report.threads.iter().chain(&report.baseline) {
+            assert_eq!(thread.passes.len(), 2);
+            assert_eq!(thread.lines_per_pass, 8);
+            assert_eq!(thread.bytes_per_pass, 107);
+        }
+        assert_eq!(report.lines(), 48);
+        assert!(report.bytes_per_second() > 0.0);
+        assert!(report.scaling().unwrap() > 0.0);
+        assert!(report.imbalance() >= 1.0);
+
+        let report = test.run(&StressOptions { threads: 1, passes: 1, baseline: false, ..StressOptions::default() });
+        assert_eq!(report.mismatches, 0);
+        assert!(report.scaling().is_none());
+    }
+}
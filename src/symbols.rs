@@ -0,0 +1,318 @@
+//! Extracting a list of symbols, like Sublime Text's Goto Symbol, from the scopes of parsed text.
+//!
+//! Syntax definitions scope the names of functions, classes and so on as `entity.name.*`, so the
+//! scopes are enough to index the symbols of any language syntect can parse, without a parser
+//! for each language. With the `metadata` feature, the `showInSymbolList` setting of the
+//! metadata of a syntax set is used as well, which lets packages add or hide symbols.
+//!
+//! # Examples
+//!
+//! ```
+//! use syntect::parsing::SyntaxSet;
+//! use syntect::symbols::{extract_symbols, SymbolKind};
+//!
+//! let ss = SyntaxSet::load_defaults_newlines();
+//! let syntax = ss.find_syntax_by_extension("py").unwrap();
+//! let symbols = extract_symbols("class Point:\n    def norm(self):\n        pass\n", syntax, &ss);
+//!
+//! let names: Vec<(&str, SymbolKind, usize)> = symbols.iter()
+//!     .map(|s| (s.name.as_str(), s.kind, s.line))
+//!     .collect();
+//! assert_eq!(names, vec![("Point", SymbolKind::Type, 0), ("norm", SymbolKind::Function, 1)]);
+//! ```
+
+use crate::parsing::{ParseState, Scope, ScopeStack, ScopeStackOp, SyntaxReference, SyntaxSet};
+use crate::util::LinesWithEndings;
+use std::ops::Range;
+
+lazy_static! {
+    static ref ENTITY_NAME: Scope = Scope::new("entity.name").unwrap();
+    static ref ENTITY_NAME_TAG: Scope = Scope::new("entity.name.tag").unwrap();
+}
+
+/// What kind of thing a [`Symbol`] names, from the third atom of its `entity.name` scope
+///
+/// [`Symbol`]: struct.Symbol.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SymbolKind {
+    /// `entity.name.function`, including methods and macros
+    Function,
+    /// `entity.name.class`, `struct`, `enum`, `union`, `trait`, `interface` and `type`
+    Type,
+    /// `entity.name.namespace`, `module` and `package`
+    Namespace,
+    /// `entity.name.constant`
+    Constant,
+    /// `entity.name.variable`
+    Variable,
+    /// `entity.name.section`, like headings in markup
+    Section,
+    /// `entity.name.label`
+    Label,
+    /// Any other `entity.name` scope, or a scope that only the metadata makes a symbol
+    Other,
+}
+
+impl SymbolKind {
+    /// The kind of symbol for a scope, `None` if it isn't an `entity.name` scope or is an
+    /// `entity.name.tag`, which markup languages use for every element.
+    pub fn from_scope(scope: Scope) -> Option<SymbolKind> {
+        if !ENTITY_NAME.is_prefix_of(scope) || ENTITY_NAME_TAG.is_prefix_of(scope) {
+            return None;
+        }
+        let name = scope.build_string();
+        let kind = match name.split('.').nth(2) {
+            Some("function") | Some("method") | Some("macro") => SymbolKind::Function,
+            Some("class") | Some("struct") | Some("enum") | Some("union") | Some("trait")
+            | Some("interface") | Some("type") => SymbolKind::Type,
+            Some("namespace") | Some("module") | Some("package") => SymbolKind::Namespace,
+            Some("constant") => SymbolKind::Constant,
+            Some("variable") => SymbolKind::Variable,
+            Some("section") => SymbolKind::Section,
+            Some("label") => SymbolKind::Label,
+            _ => SymbolKind::Other,
+        };
+        Some(kind)
+    }
+}
+
+/// A named thing defined in a file, found by [`SymbolExtractor`].
+///
+/// [`SymbolExtractor`]: struct.SymbolExtractor.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    /// The text of the symbol with surrounding whitespace removed
+    pub name: String,
+    pub kind: SymbolKind,
+    /// The scope that made the text a symbol
+    pub scope: Scope,
+    /// The byte range of the symbol in the whole text
+    pub range: Range<usize>,
+    /// The index of the line the symbol starts on
+    pub line: usize,
+}
+
+/// A symbol whose scope hasn't been popped yet
+#[derive(Debug)]
+struct OpenSymbol {
+    scope: Scope,
+    kind: SymbolKind,
+    /// The length of the scope stack with the scope pushed
+    depth: usize,
+    start: usize,
+    line: usize,
+    text: String,
+}
+
+/// Walks the scope stack operations of the lines of a file and collects its symbols.
+///
+/// Use this to reuse the operations of a parse that is done for other reasons, like
+/// highlighting, otherwise [`extract_symbols`] is simpler. The lines have to be fed in order
+/// with their newlines, and with the operations of the syntax set passed to [`new`].
+///
+/// [`extract_symbols`]: fn.extract_symbols.html
+/// [`new`]: #method.new
+#[derive(Debug)]
+pub struct SymbolExtractor<'a> {
+    #[cfg_attr(not(feature = "metadata"), allow(dead_code))]
+    syntax_set: &'a SyntaxSet,
+    stack: ScopeStack,
+    /// The byte offset of the next line in the whole text
+    offset: usize,
+    line: usize,
+    open: Option<OpenSymbol>,
+    symbols: Vec<Symbol>,
+}
+
+impl<'a> SymbolExtractor<'a> {
+    pub fn new(syntax_set: &'a SyntaxSet) -> SymbolExtractor<'a> {
+        SymbolExtractor {
+            syntax_set,
+            stack: ScopeStack::new(),
+            offset: 0,
+            line: 0,
+            open: None,
+            symbols: Vec::new(),
+        }
+    }
+
+    /// Applies the operations of the next line, as returned by `ParseState::parse_line`
+    pub fn add_line(&mut self, line: &str, ops: &[(usize, ScopeStackOp)]) {
+        let mut index = 0;
+        for &(i, ref op) in ops {
+            if let Some(ref mut open) = self.open {
+                open.text.push_str(&line[index..i]);
+            }
+            index = i;
+            self.stack.apply(op);
+            self.update(i);
+        }
+        if let Some(ref mut open) = self.open {
+            open.text.push_str(&line[index..]);
+        }
+        self.offset += line.len();
+        self.line += 1;
+    }
+
+    /// Returns the symbols in the order they appear in the text. A symbol whose scope is still
+    /// on the stack ends at the end of the text.
+    pub fn finish(mut self) -> Vec<Symbol> {
+        self.close(self.offset);
+        self.symbols
+    }
+
+    /// Closes the open symbol if the operation at `index` of the line popped its scope, or opens
+    /// one if it pushed a symbol scope
+    fn update(&mut self, index: usize) {
+        let position = self.offset + index;
+        if let Some(ref open) = self.open {
+            let slice = self.stack.as_slice();
+            if slice.len() >= open.depth && slice[open.depth - 1] == open.scope {
+                return;
+            }
+            self.close(position);
+        }
+        let scope = match self.stack.as_slice().last() {
+            Some(&scope) => scope,
+            None => return,
+        };
+        if let Some(kind) = self.symbol_kind(scope) {
+            self.open = Some(OpenSymbol {
+                scope,
+                kind,
+                depth: self.stack.len(),
+                start: position,
+                line: self.line,
+                text: String::new(),
+            });
+        }
+    }
+
+    #[cfg(feature = "metadata")]
+    fn symbol_kind(&self, scope: Scope) -> Option<SymbolKind> {
+        let metadata = self.syntax_set.metadata().metadata_for_scope(self.stack.as_slice());
+        match metadata.show_in_symbol_list() {
+            Some(true) => Some(SymbolKind::from_scope(scope).unwrap_or(SymbolKind::Other)),
+            Some(false) => None,
+            None => SymbolKind::from_scope(scope),
+        }
+    }
+
+    #[cfg(not(feature = "metadata"))]
+    fn symbol_kind(&self, scope: Scope) -> Option<SymbolKind> {
+        SymbolKind::from_scope(scope)
+    }
+
+    fn close(&mut self, end: usize) {
+        if let Some(open) = self.open.take() {
+            let name = open.text.trim();
+            if !name.is_empty() {
+                self.symbols.push(Symbol {
+                    name: name.to_owned(),
+                    kind: open.kind,
+                    scope: open.scope,
+                    range: open.start..end,
+                    line: open.line,
+                });
+            }
+        }
+    }
+}
+
+/// Parses the text with the syntax and returns its symbols, see [`SymbolExtractor`]
+///
+/// The syntax set has to be one with newlines, like `SyntaxSet::load_defaults_newlines`.
+///
+/// [`SymbolExtractor`]: struct.SymbolExtractor.html
+pub fn extract_symbols(text: &str, syntax: &SyntaxReference, syntax_set: &SyntaxSet) -> Vec<Symbol> {
+    let mut state = ParseState::new(syntax);
+    let mut extractor = SymbolExtractor::new(syntax_set);
+    for line in LinesWithEndings::from(text) {
+        let ops = state.parse_line(line, syntax_set);
+        extractor.add_line(line, &ops);
+    }
+    extractor.finish()
+}
+
+#[cfg(feature = "yaml-load")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parsing::{SyntaxDefinition, SyntaxSetBuilder};
+
+    fn syntax_set() -> SyntaxSet {
+        let mut builder = SyntaxSetBuilder::new();
+        builder.add(SyntaxDefinition::load_from_str(r#"
+name: Symbols
+scope: source.symbols
+contexts:
+  main:
+    - match: '\b(fn)\s+(\w+)'
+      captures:
+        1: keyword
+        2: entity.name.function
+    - match: '\b(mod)\s+(\w+)'
+      captures:
+        1: keyword
+        2: entity.name.namespace.inner
+    - match: '<(\w+)>'
+      captures:
+        1: entity.name.tag
+    - match: '"'
+      push:
+        - meta_scope: entity.name.section
+        - match: '"'
+          pop: true
+"#, true, None).unwrap());
+        builder.build()
+    }
+
+    #[test]
+    fn can_extract_symbols() {
+        let ss = syntax_set();
+        let syntax = ss.find_syntax_by_name("Symbols").unwrap();
+        let text = "mod a\n<b> fn cc fn\n\"multi\nline\"\n\" \"";
+        let symbols = extract_symbols(text, syntax, &ss);
+
+        let found: Vec<_> = symbols.iter()
+            .map(|s| (s.name.as_str(), s.kind, s.range.clone(), s.line))
+            .collect();
+        assert_eq!(found, vec![
+            ("a", SymbolKind::Namespace, 4..5, 0),
+            ("cc", SymbolKind::Function, 13..15, 1),
+            ("\"multi\nline\"", SymbolKind::Section, 19..31, 2),
+            ("\" \"", SymbolKind::Section, 32..35, 4),
+        ]);
+        assert_eq!(symbols[1].scope, Scope::new("entity.name.function").unwrap());
+        assert_eq!(&text[symbols[2].range.clone()], symbols[2].name);
+    }
+
+    #[cfg(feature = "metadata")]
+    #[test]
+    fn uses_symbol_list_metadata() {
+        use crate::parsing::{Metadata, MetadataSet};
+
+        let mut ss = syntax_set();
+        let items = |show| serde_json::json!({ "showInSymbolList": show }).as_object().cloned().unwrap();
+        ss.metadata = Metadata {
+            scoped_metadata: vec![
+                MetadataSet::from_raw(("entity.name.tag".into(), items(1))).unwrap(),
+                MetadataSet::from_raw(("entity.name.section".into(), items(0))).unwrap(),
+            ],
+        };
+        let syntax = ss.find_syntax_by_name("Symbols").unwrap();
+        let symbols = extract_symbols("<b> \"c\" fn d", syntax, &ss);
+        let found: Vec<_> = symbols.iter().map(|s| (s.name.as_str(), s.kind)).collect();
+        assert_eq!(found, vec![("b", SymbolKind::Other), ("d", SymbolKind::Function)]);
+    }
+
+    #[test]
+    fn can_tell_symbol_kinds() {
+        let kind = |s| SymbolKind::from_scope(Scope::new(s).unwrap());
+        assert_eq!(kind("entity.name.struct.rust"), Some(SymbolKind::Type));
+        assert_eq!(kind("entity.name.function.constructor"), Some(SymbolKind::Function));
+        assert_eq!(kind("entity.name"), Some(SymbolKind::Other));
+        assert_eq!(kind("entity.name.tag.html"), None);
+        assert_eq!(kind("entity.other.inherited-class"), None);
+    }
+}
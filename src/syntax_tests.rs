@@ -0,0 +1,334 @@
+//! Running the `syntax_test_*` files of Sublime Text packages against syntect's parser.
+//!
+//! A syntax test file starts with a header line naming the syntax it tests, in a comment:
+//!
+//! ```text
+//! // SYNTAX TEST "Packages/JavaScript/JavaScript.sublime-syntax"
+//! ```
+//!
+//! Every other line that contains the comment token of the header followed by `^` or `<-` is an
+//! assertion about the last line before it that isn't an assertion. The carets mark the columns
+//! that are tested and `<-` marks the column of the comment token, the rest of the line is a scope
+//! selector that the scopes of those columns have to match. This works the same way as in Sublime
+//! Text, so the test files of existing packages can be run with [`run_syntax_test_file`]. The
+//! `syntest` example runs all the test files in a folder.
+//!
+//! # Examples
+//!
+//! ```
+//! use syntect::parsing::SyntaxSet;
+//! use syntect::syntax_tests::run_syntax_test;
+//!
+//! let ss = SyntaxSet::load_defaults_newlines();
+//! let test = r#"// SYNTAX TEST "Packages/JavaScript/JavaScript.sublime-syntax"
+//! var x = 1;
+//! // <- storage.type
+//! //      ^ constant.numeric
+//! "#;
+//! let report = run_syntax_test(test, &ss).unwrap();
+//! assert!(report.passed());
+//! assert_eq!(report.assertions, 2);
+//! ```
+//!
+//! [`run_syntax_test_file`]: fn.run_syntax_test_file.html
+use crate::easy::ScopeRegionIterator;
+use crate::highlighting::ScopeSelectors;
+use crate::parsing::{ParseState, Scope, ScopeStack, SyntaxSet};
+use crate::util::LinesWithEndings;
+use std::cmp::{max, min};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Errors that keep a syntax test file from being run at all
+#[derive(Debug)]
+pub enum SyntaxTestError {
+    /// The file couldn't be read
+    Io(io::Error),
+    /// The first line isn't a valid syntax test header
+    MalformedHeader,
+    /// The syntax named by the header isn't in the syntax set
+    SyntaxNotFound(String),
+    /// The scope selector of an assertion couldn't be parsed
+    InvalidSelector {
+        /// The line of the assertion, starting at 1
+        line: usize,
+        selector: String,
+    },
+}
+
+impl fmt::Display for SyntaxTestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            SyntaxTestError::Io(ref error) => write!(f, "Error reading syntax test: {}", error),
+            SyntaxTestError::MalformedHeader => write!(f, "Malformed syntax test header"),
+            SyntaxTestError::SyntaxNotFound(ref path) => write!(f, "Syntax definition not found: {}", path),
+            SyntaxTestError::InvalidSelector { line, ref selector } =>
+                write!(f, "Invalid scope selector {:?} on line {}", selector, line),
+        }
+    }
+}
+
+impl Error for SyntaxTestError {
+    fn cause(&self) -> Option<&dyn Error> {
+        match *self {
+            SyntaxTestError::Io(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SyntaxTestError {
+    fn from(error: io::Error) -> SyntaxTestError {
+        SyntaxTestError::Io(error)
+    }
+}
+
+/// The outcome of running a syntax test file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxTestReport {
+    /// The path of the syntax definition from the header
+    pub syntax_file: String,
+    /// The number of tested columns
+    pub assertions: usize,
+    pub failures: Vec<AssertionFailure>,
+}
+
+impl SyntaxTestReport {
+    /// Whether all the assertions passed
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// The number of tested columns that failed, which is how Sublime Text counts failures
+    pub fn failed_assertions(&self) -> usize {
+        self.failures.iter().map(|failure| failure.columns.len()).sum()
+    }
+}
+
+/// Columns of a line whose scopes didn't match the selector of an assertion
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssertionFailure {
+    /// The line of the assertion, starting at 1
+    pub line: usize,
+    /// The line that the assertion tests, starting at 1
+    pub tested_line: usize,
+    /// The failed columns of the tested line
+    pub columns: Range<usize>,
+    /// The scope selector of the assertion
+    pub selector: String,
+    /// The text of the tested line in the failed columns
+    pub text: String,
+    /// The scope stack at the start of the failed columns
+    pub scopes: Vec<Scope>,
+}
+
+/// Reads and runs a syntax test file, see [`run_syntax_test`].
+///
+/// [`run_syntax_test`]: fn.run_syntax_test.html
+pub fn run_syntax_test_file<P: AsRef<Path>>(path: P, syntax_set: &SyntaxSet) -> Result<SyntaxTestReport, SyntaxTestError> {
+    run_syntax_test(&fs::read_to_string(path)?, syntax_set)
+}
+
+/// Parses the contents of a syntax test file with the syntax named in its header and checks all
+/// its assertions.
+///
+/// The syntax is found with [`SyntaxSet::find_syntax_by_path`], and the syntax set should be one
+/// for lines with newlines. Failed assertions end up in the report, only problems with the file
+/// itself are errors.
+///
+/// [`SyntaxSet::find_syntax_by_path`]: ../parsing/struct.SyntaxSet.html#method.find_syntax_by_path
+pub fn run_syntax_test(text: &str, syntax_set: &SyntaxSet) -> Result<SyntaxTestReport, SyntaxTestError> {
+    let mut lines = LinesWithEndings::from(text).map(|line| line.replace('\r', ""));
+    let header_line = lines.next().ok_or(SyntaxTestError::MalformedHeader)?;
+    let header = parse_header(&header_line).ok_or(SyntaxTestError::MalformedHeader)?;
+    let syntax = syntax_set.find_syntax_by_path(header.syntax_file)
+        .ok_or_else(|| SyntaxTestError::SyntaxNotFound(header.syntax_file.to_owned()))?;
+
+    let mut report = SyntaxTestReport {
+        syntax_file: header.syntax_file.to_owned(),
+        assertions: 0,
+        failures: Vec::new(),
+    };
+    let mut state = ParseState::new(syntax);
+    let mut stack = ScopeStack::new();
+    let mut tested_line = 1;
+    let mut tested_text = header_line.clone();
+    let mut tested_scopes = Vec::new();
+
+    // the header is parsed like any other line
+    for (line_number, line) in Some(header_line.clone()).into_iter().chain(lines).enumerate().map(|(i, l)| (i + 1, l)) {
+        let assertion = parse_assertion(&header, &line);
+        if let Some(ref assertion) = assertion {
+            let selector = ScopeSelectors::from_str(&format!(" {}", assertion.selector))
+                .map_err(|_| SyntaxTestError::InvalidSelector { line: line_number, selector: assertion.selector.trim().to_owned() })?;
+            report.assertions += assertion.columns.len();
+            for columns in failed_columns(&selector, assertion.columns.clone(), &tested_scopes) {
+                let scopes = tested_scopes.iter()
+                    .find(|s| s.columns.end > columns.start)
+                    .or_else(|| tested_scopes.last())
+                    .map(|s| s.scopes.clone())
+                    .unwrap_or_default();
+                report.failures.push(AssertionFailure {
+                    line: line_number,
+                    tested_line,
+                    text: tested_text.chars().skip(columns.start).take(columns.len()).collect(),
+                    columns,
+                    selector: assertion.selector.trim().to_owned(),
+                    scopes,
+                });
+            }
+        } else {
+            // Sublime Text ignores lines with assertions when working out which line they test
+            tested_scopes.clear();
+            tested_line = line_number;
+            tested_text = line.clone();
+        }
+
+        let ops = state.parse_line(&line, syntax_set);
+        let mut column = 0;
+        for (text, op) in ScopeRegionIterator::new(&ops, &line) {
+            stack.apply(op);
+            if text.is_empty() || assertion.is_some() {
+                continue;
+            }
+            let len = text.chars().count();
+            tested_scopes.push(ScopedColumns {
+                columns: column..column + len,
+                scopes: stack.as_slice().to_vec(),
+            });
+            column += len;
+        }
+    }
+    Ok(report)
+}
+
+struct Header<'a> {
+    /// The comment token, including the whitespace before it
+    token_start: &'a str,
+    syntax_file: &'a str,
+    /// The token that ends comments, for syntaxes with block comments only
+    token_end: Option<&'a str>,
+}
+
+/// Parses a header like `<!-- SYNTAX TEST "Packages/HTML/HTML.sublime-syntax" -->`
+fn parse_header(line: &str) -> Option<Header<'_>> {
+    let line = line.trim_end_matches('\n');
+    let keyword = line.find(" SYNTAX TEST ")?;
+    let token_start = line[..keyword].trim_end();
+    let token = token_start.trim_start();
+    if token.is_empty() || token.contains(char::is_whitespace) {
+        return None;
+    }
+    let rest = line[keyword + " SYNTAX TEST ".len()..].trim_start().strip_prefix('"')?;
+    let quote = rest.find('"')?;
+    let syntax_file = &rest[..quote];
+    let token_end = rest[quote + 1..].trim();
+    if syntax_file.is_empty() || token_end.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Header {
+        token_start,
+        syntax_file,
+        token_end: if token_end.is_empty() { None } else { Some(token_end) },
+    })
+}
+
+struct Assertion<'a> {
+    columns: Range<usize>,
+    selector: &'a str,
+}
+
+/// Finds the first `^` or `<-` after the comment token on a line
+fn parse_assertion<'a>(header: &Header<'_>, line: &'a str) -> Option<Assertion<'a>> {
+    let index = line.find(header.token_start)?;
+    let after_token = index + header.token_start.len();
+    let rest = &line[after_token..];
+    let (columns, selector) = rest.char_indices().find_map(|(i, c)| {
+        if rest[i..].starts_with("<-") {
+            // `<-` tests the column of the comment token
+            Some((index..index + 1, &rest[i + 2..]))
+        } else if c == '^' {
+            let carets = rest[i..].len() - rest[i..].trim_start_matches('^').len();
+            Some((after_token + i..after_token + i + carets, &rest[i + carets..]))
+        } else {
+            None
+        }
+    })?;
+    let mut selector = selector.trim_end_matches('\n');
+    if let Some(token_end) = header.token_end {
+        if let Some(end) = selector.find(token_end) {
+            selector = &selector[..end];
+        }
+    }
+    Some(Assertion { columns, selector })
+}
+
+struct ScopedColumns {
+    columns: Range<usize>,
+    scopes: Vec<Scope>,
+}
+
+/// Checks the selector against the scopes of the columns, returning the ranges that don't match
+fn failed_columns(selector: &ScopeSelectors, columns: Range<usize>, scopes: &[ScopedColumns]) -> Vec<Range<usize>> {
+    let mut failed = Vec::new();
+    let tested = scopes.iter()
+        .skip_while(|s| s.columns.end <= columns.start)
+        .take_while(|s| s.columns.start < columns.end);
+    for scoped in tested {
+        if selector.does_match(&scoped.scopes).is_none() {
+            failed.push(max(scoped.columns.start, columns.start)..min(scoped.columns.end, columns.end));
+        }
+    }
+    // columns after the end of the line test the newline
+    if let Some(last) = scopes.last() {
+        if last.columns.end < columns.end && selector.does_match(&last.scopes).is_none() {
+            failed.push(max(last.columns.end, columns.start)..columns.end);
+        }
+    }
+    failed
+}
+
+#[cfg(all(feature = "assets", any(feature = "dump-load", feature = "dump-load-rs")))]
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_run_syntax_tests() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        let test = r#"<!-- SYNTAX TEST "Packages/HTML/HTML.sublime-syntax" -->
+<p       class="x">hi</p>
+<!-- <- meta.tag punctuation.definition.tag.begin -->
+<!--     ^^^^^ entity.other.attribute-name -->
+<!--         ^ string.quoted -->
+<!--               ^^ text.html - meta.tag -->
+"#;
+        let report = run_syntax_test(test, &ss).unwrap();
+        assert_eq!(report.syntax_file, "Packages/HTML/HTML.sublime-syntax");
+        assert_eq!(report.assertions, 1 + 5 + 1 + 2);
+        assert_eq!(report.failed_assertions(), 1);
+        let failure = &report.failures[0];
+        assert_eq!((failure.line, failure.tested_line, failure.columns.clone()), (5, 2, 13..14));
+        assert_eq!(failure.selector, "string.quoted");
+        assert_eq!(failure.text, "s");
+    }
+
+    #[test]
+    fn reports_broken_test_files() {
+        let ss = SyntaxSet::load_defaults_newlines();
+        assert!(matches!(run_syntax_test("x = 1\n", &ss), Err(SyntaxTestError::MalformedHeader)));
+        assert!(matches!(
+            run_syntax_test("# SYNTAX TEST \"Packages/Nope/Nope.sublime-syntax\"\n", &ss),
+            Err(SyntaxTestError::SyntaxNotFound(ref path)) if path == "Packages/Nope/Nope.sublime-syntax"
+        ));
+        assert!(matches!(
+            run_syntax_test("# SYNTAX TEST \"Python.sublime-syntax\"\nx\n# ^ (source\n", &ss),
+            Err(SyntaxTestError::InvalidSelector { line: 3, .. })
+        ));
+    }
+}
@@ -2,10 +2,15 @@
 //! * Printing to terminal
 //! * Iterating lines with `\n`s
 //! * Modifying ranges of highlighted output
+//! * Drawing selections and computing columns for terminal UIs, with the `tui` feature
 
-use crate::highlighting::{Style, StyleModifier};
+use crate::highlighting::{Color, Style, StyleModifier};
+#[cfg(feature = "tui")]
+use crate::highlighting::Theme;
 use std::fmt::Write;
 use std::ops::Range;
+#[cfg(feature = "tui")]
+use unicode_width::UnicodeWidthChar;
 #[cfg(feature = "parsing")]
 use crate::parsing::ScopeStackOp;
 
@@ -22,26 +27,164 @@ use crate::parsing::ScopeStackOp;
 pub fn as_24_bit_terminal_escaped(v: &[(Style, &str)], bg: bool) -> String {
     let mut s: String = String::new();
     for &(ref style, text) in v.iter() {
-        if bg {
-            write!(s,
-                   "\x1b[48;2;{};{};{}m",
-                   style.background.r,
-                   style.background.g,
-                   style.background.b)
-                .unwrap();
-        }
-        write!(s,
-               "\x1b[38;2;{};{};{}m{}",
-               style.foreground.r,
-               style.foreground.g,
-               style.foreground.b,
-               text)
-            .unwrap();
+        write_24_bit_style(&mut s, style, bg);
+        s.push_str(text);
     }
     // s.push_str("\x1b[0m");
     s
 }
 
+/// Like [`as_24_bit_terminal_escaped`], but replaces tabs with spaces up to the next multiple of
+/// `tab_width` columns, so that styles like a selection background cover the whole tab.
+///
+/// Columns are counted like [`column_at`] does, so wide characters count as two.
+///
+/// [`as_24_bit_terminal_escaped`]: fn.as_24_bit_terminal_escaped.html
+/// [`column_at`]: fn.column_at.html
+#[cfg(feature = "tui")]
+pub fn as_24_bit_terminal_escaped_with_tabs(v: &[(Style, &str)], bg: bool, tab_width: usize) -> String {
+    let mut s: String = String::new();
+    let mut column = 0;
+    for &(ref style, text) in v.iter() {
+        write_24_bit_style(&mut s, style, bg);
+        for c in text.chars() {
+            let next = advance_column(column, c, tab_width);
+            if c == '\t' {
+                for _ in column..next {
+                    s.push(' ');
+                }
+            } else {
+                s.push(c);
+            }
+            column = next;
+        }
+    }
+    s
+}
+
+fn write_24_bit_style(s: &mut String, style: &Style, bg: bool) {
+    write_terminal_style(s, style, bg, TerminalColors::TrueColor);
+}
+
+/// The colors a terminal can show, see [`as_terminal_escaped`].
+///
+/// [`as_terminal_escaped`]: fn.as_terminal_escaped.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalColors {
+    /// Any 24-bit color
+    TrueColor,
+    /// The 256 colors of xterm, see [`ansi_256_color`]
+    ///
+    /// [`ansi_256_color`]: fn.ansi_256_color.html
+    Ansi256,
+    /// The 16 basic colors, see [`ansi_16_color`]
+    ///
+    /// [`ansi_16_color`]: fn.ansi_16_color.html
+    Ansi16,
+}
+
+/// Like [`as_24_bit_terminal_escaped`], for terminals that may only show 256 or 16 colors.
+///
+/// The colors of the styles are replaced with the closest ones the terminal has.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{Color, Style};
+/// use syntect::util::{as_terminal_escaped, TerminalColors};
+///
+/// let style = Style { foreground: Color { r: 0xD7, g: 0x5F, b: 0x00, a: 0xFF }, ..Style::default() };
+/// assert_eq!(as_terminal_escaped(&[(style, "x")], false, TerminalColors::Ansi256), "\x1b[38;5;166mx");
+/// assert_eq!(as_terminal_escaped(&[(style, "x")], false, TerminalColors::Ansi16), "\x1b[31mx");
+/// ```
+///
+/// [`as_24_bit_terminal_escaped`]: fn.as_24_bit_terminal_escaped.html
+pub fn as_terminal_escaped(v: &[(Style, &str)], bg: bool, colors: TerminalColors) -> String {
+    let mut s: String = String::new();
+    for &(ref style, text) in v.iter() {
+        write_terminal_style(&mut s, style, bg, colors);
+        s.push_str(text);
+    }
+    s
+}
+
+fn write_terminal_style(s: &mut String, style: &Style, bg: bool, colors: TerminalColors) {
+    let mut write_color = |color: Color, background: bool| {
+        match colors {
+            TerminalColors::TrueColor => {
+                let kind = if background { 48 } else { 38 };
+                write!(s, "\x1b[{};2;{};{};{}m", kind, color.r, color.g, color.b).unwrap();
+            }
+            TerminalColors::Ansi256 => {
+                let kind = if background { 48 } else { 38 };
+                write!(s, "\x1b[{};5;{}m", kind, ansi_256_color(color)).unwrap();
+            }
+            TerminalColors::Ansi16 => {
+                let index = ansi_16_color(color);
+                let code = if index < 8 { 30 + index } else { 90 + index - 8 };
+                write!(s, "\x1b[{}m", if background { code + 10 } else { code }).unwrap();
+            }
+        }
+    };
+    if bg {
+        write_color(style.background, true);
+    }
+    write_color(style.foreground, false);
+}
+
+/// The levels of the red, green and blue in the color cube of the 256 colors of xterm
+const CUBE_LEVELS: [u8; 6] = [0x00, 0x5F, 0x87, 0xAF, 0xD7, 0xFF];
+
+/// The 16 basic colors as xterm shows them by default
+const ANSI_16_COLORS: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00), (0xCD, 0x00, 0x00), (0x00, 0xCD, 0x00), (0xCD, 0xCD, 0x00),
+    (0x00, 0x00, 0xEE), (0xCD, 0x00, 0xCD), (0x00, 0xCD, 0xCD), (0xE5, 0xE5, 0xE5),
+    (0x7F, 0x7F, 0x7F), (0xFF, 0x00, 0x00), (0x00, 0xFF, 0x00), (0xFF, 0xFF, 0x00),
+    (0x5C, 0x5C, 0xFF), (0xFF, 0x00, 0xFF), (0x00, 0xFF, 0xFF), (0xFF, 0xFF, 0xFF),
+];
+
+/// How different two colors look, a distance that weighs red, green and blue like the eye does
+/// (the "redmean" approximation), which picks much better replacements than plain RGB distance
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> i32 {
+    let red_mean = (i32::from(a.0) + i32::from(b.0)) / 2;
+    let (dr, dg, db) = (i32::from(a.0) - i32::from(b.0), i32::from(a.1) - i32::from(b.1), i32::from(a.2) - i32::from(b.2));
+    (((512 + red_mean) * dr * dr) >> 8) + 4 * dg * dg + (((767 - red_mean) * db * db) >> 8)
+}
+
+/// The index of the color closest to the given one among the 240 fixed colors of the xterm
+/// palette, the 6×6×6 color cube (16 to 231) and the grays (232 to 255).
+///
+/// The first 16 colors are left out, since terminals let users change them. The alpha is
+/// ignored.
+pub fn ansi_256_color(color: Color) -> u8 {
+    let rgb = (color.r, color.g, color.b);
+    let level = |value: u8| match value {
+        0..=47 => 0,
+        48..=114 => 1,
+        _ => (value - 35) / 40,
+    };
+    let (r, g, b) = (level(color.r), level(color.g), level(color.b));
+    let cube = (CUBE_LEVELS[r as usize], CUBE_LEVELS[g as usize], CUBE_LEVELS[b as usize]);
+
+    let average = (u32::from(color.r) + u32::from(color.g) + u32::from(color.b)) / 3;
+    let gray_index = (average.saturating_sub(3) / 10).min(23) as u8;
+    let gray_level = 8 + 10 * gray_index;
+
+    if color_distance(rgb, (gray_level, gray_level, gray_level)) < color_distance(rgb, cube) {
+        232 + gray_index
+    } else {
+        16 + 36 * r + 6 * g + b
+    }
+}
+
+/// The index of the color closest to the given one among the 16 basic terminal colors, using
+/// the colors xterm shows for them by default: 0 to 7 for black, red, green, yellow, blue,
+/// magenta, cyan and white and 8 to 15 for their bright versions. The alpha is ignored.
+pub fn ansi_16_color(color: Color) -> u8 {
+    let rgb = (color.r, color.g, color.b);
+    (0..16u8).min_by_key(|&index| color_distance(rgb, ANSI_16_COLORS[index as usize])).unwrap()
+}
+
 const LATEX_REPLACE: [(&'static str, &'static str); 3] = [
     ("\\", "\\\\"),
     ("{", "\\{"),
@@ -152,6 +295,90 @@ pub fn debug_print_ops(line: &str, ops: &[(usize, ScopeStackOp)]) {
     }
 }
 
+/// Converts the byte offsets of the operations for a line into offsets counted in Unicode
+/// scalar values (`char`s), for user interfaces that index text by character.
+///
+/// The operations have to be sorted by offset, like the ones returned by `parse_line`.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{Scope, ScopeStackOp};
+/// use syntect::util::ops_with_char_offsets;
+///
+/// let line = "é = 1";
+/// let ops = vec![(3, ScopeStackOp::Push(Scope::new("keyword").unwrap())), (4, ScopeStackOp::Pop(1))];
+/// let char_ops = ops_with_char_offsets(line, &ops);
+/// assert_eq!(char_ops[0].0, 2);
+/// assert_eq!(char_ops[1].0, 3);
+/// ```
+#[cfg(feature = "parsing")]
+pub fn ops_with_char_offsets(line: &str, ops: &[(usize, ScopeStackOp)]) -> Vec<(usize, ScopeStackOp)> {
+    convert_op_offsets(line, ops, |_| 1)
+}
+
+/// Converts the byte offsets of the operations for a line into offsets in UTF-16 code units,
+/// which is how the Language Server Protocol counts positions in a line.
+///
+/// The operations have to be sorted by offset, like the ones returned by `parse_line`.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::parsing::{Scope, ScopeStackOp};
+/// use syntect::util::ops_with_utf16_offsets;
+///
+/// // the crab takes 4 bytes in UTF-8 but 2 code units in UTF-16
+/// let line = "🦀 = 1";
+/// let ops = vec![(5, ScopeStackOp::Push(Scope::new("keyword").unwrap())), (6, ScopeStackOp::Pop(1))];
+/// let utf16_ops = ops_with_utf16_offsets(line, &ops);
+/// assert_eq!(utf16_ops[0].0, 3);
+/// assert_eq!(utf16_ops[1].0, 4);
+/// ```
+#[cfg(feature = "parsing")]
+pub fn ops_with_utf16_offsets(line: &str, ops: &[(usize, ScopeStackOp)]) -> Vec<(usize, ScopeStackOp)> {
+    convert_op_offsets(line, ops, char::len_utf16)
+}
+
+/// Re-counts sorted byte offsets into a line using `unit_len` to get the length of each char
+#[cfg(feature = "parsing")]
+fn convert_op_offsets<F>(line: &str, ops: &[(usize, ScopeStackOp)], unit_len: F) -> Vec<(usize, ScopeStackOp)>
+    where F: Fn(char) -> usize
+{
+    let mut chars = line.char_indices().peekable();
+    let mut units = 0;
+    ops.iter().map(|&(byte_index, ref op)| {
+        while let Some(&(i, c)) = chars.peek() {
+            if i >= byte_index {
+                break;
+            }
+            units += unit_len(c);
+            chars.next();
+        }
+        (units, op.clone())
+    }).collect()
+}
+
+/// Turns styled fragments of a line, like the output of `HighlightLines::highlight`, into the
+/// ranges they cover in UTF-16 code units, e.g. for reporting semantic tokens over the Language
+/// Server Protocol.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::util::utf16_ranges;
+/// let regions = [(1, "ä"), (2, "𝄞x")];
+/// assert_eq!(utf16_ranges(&regions), vec![(1, 0..1), (2, 1..4)]);
+/// ```
+pub fn utf16_ranges<A: Clone>(v: &[(A, &str)]) -> Vec<(A, Range<usize>)> {
+    let mut start = 0;
+    v.iter().map(|&(ref style, text)| {
+        let end = start + text.encode_utf16().count();
+        let range = start..end;
+        start = end;
+        (style.clone(), range)
+    }).collect()
+}
 
 /// An iterator over the lines of a string, including the line endings.
 ///
@@ -270,6 +497,296 @@ pub fn modify_range<'a>(v: &[(Style, &'a str)], r: Range<usize>, modifier: Style
     result
 }
 
+/// Adjusts the foregrounds of a highlighted line to have at least the given contrast ratio
+/// against the background they are drawn on, e.g. `4.5` for the WCAG AA level.
+///
+/// The background of each style is composited over `background`, the color of the page or
+/// terminal, to find the background the text is really drawn on. See [`Color::with_contrast`]
+/// for how the foregrounds are adjusted.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{Color, Style};
+/// use syntect::util::ensure_contrast;
+///
+/// let faint = Style { foreground: Color { r: 0xDD, g: 0xDD, b: 0xDD, a: 0xFF }, ..Style::default() };
+/// let adjusted = ensure_contrast(&[(faint, "// comment")], Color::WHITE, 4.5);
+/// assert!(adjusted[0].0.foreground.contrast_ratio(Color::WHITE) >= 4.5);
+/// ```
+///
+/// [`Color::with_contrast`]: ../highlighting/struct.Color.html#method.with_contrast
+pub fn ensure_contrast<'a>(v: &[(Style, &'a str)], background: Color, min_ratio: f64) -> Vec<(Style, &'a str)> {
+    v.iter().map(|&(style, text)| (style.resolve_alpha(background).with_contrast(min_ratio), text)).collect()
+}
+
+/// The colors [`apply_selection`] uses for selected text and the caret.
+///
+/// [`apply_selection`]: fn.apply_selection.html
+#[cfg(feature = "tui")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SelectionStyle {
+    /// The background of selected text, if `None` the foreground and background are swapped
+    pub selection: Option<Color>,
+    /// The foreground of selected text, if `None` the highlighted foreground is kept
+    pub selection_foreground: Option<Color>,
+    /// The background of the character under the caret, which gets the previous background as
+    /// its foreground. If `None` the foreground and background are swapped.
+    pub caret: Option<Color>,
+}
+
+#[cfg(feature = "tui")]
+impl SelectionStyle {
+    /// Uses the `selection`, `selectionForeground` and `caret` colors of a theme
+    pub fn from_theme(theme: &Theme) -> SelectionStyle {
+        SelectionStyle {
+            selection: theme.settings.selection,
+            selection_foreground: theme.settings.selection_foreground,
+            caret: theme.settings.caret,
+        }
+    }
+
+    fn select(&self, style: Style) -> Style {
+        match self.selection {
+            Some(background) => Style {
+                foreground: self.selection_foreground.unwrap_or(style.foreground),
+                background,
+                ..style
+            },
+            None => swap_colors(style),
+        }
+    }
+
+    fn caret(&self, style: Style) -> Style {
+        match self.caret {
+            Some(background) => Style { foreground: style.background, background, ..style },
+            None => swap_colors(style),
+        }
+    }
+}
+
+#[cfg(feature = "tui")]
+fn swap_colors(style: Style) -> Style {
+    Style { foreground: style.background, background: style.foreground, ..style }
+}
+
+/// Restyles the selected parts of a highlighted line and the character under the caret, for
+/// drawing an editor's selections in a terminal.
+///
+/// `selections` and `caret` are byte indexes into the line, which may overlap and don't have
+/// to be sorted. The newline at the end of the line is never selected. A caret at the end of
+/// the line is drawn as an extra space, before the newline if there is one.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::highlighting::{Color, Style};
+/// use syntect::util::{apply_selection, SelectionStyle};
+///
+/// let plain = Style::default();
+/// let selection = SelectionStyle { selection: Some(Color::WHITE), ..SelectionStyle::default() };
+/// let selected = Style { background: Color::WHITE, ..plain };
+///
+/// let line = apply_selection(&[(plain, "let x;\n")], &[4..8], Some(6), &selection);
+/// assert_eq!(line.len(), 4);
+/// assert_eq!(line[1], (selected, "x;"));
+/// assert_eq!(line[2].1, " ");
+/// assert_eq!(line[3], (plain, "\n"));
+/// ```
+#[cfg(feature = "tui")]
+pub fn apply_selection<'a>(v: &[(Style, &'a str)],
+                           selections: &[Range<usize>],
+                           caret: Option<usize>,
+                           style: &SelectionStyle)
+                           -> Vec<(Style, &'a str)> {
+    let line_len: usize = v.iter().map(|(_, s)| s.len()).sum();
+    let newline_len = v.last().map(|(_, s)| s.len() - s.trim_end_matches(&['\r', '\n'][..]).len()).unwrap_or(0);
+    let text_end = line_len - newline_len;
+
+    let mut ranges: Vec<Range<usize>> = selections.iter()
+        .map(|r| r.start.min(text_end)..r.end.min(text_end))
+        .filter(|r| r.start < r.end)
+        .collect();
+    ranges.sort_by_key(|r| r.start);
+    let mut result = v.to_vec();
+    let mut selected_until = 0;
+    for r in ranges {
+        let start = r.start.max(selected_until);
+        if start < r.end {
+            result = map_range(&result, start..r.end, |s| style.select(s));
+            selected_until = r.end;
+        }
+    }
+
+    match caret {
+        Some(caret) if caret < text_end => {
+            let (_, after) = split_at(&result, caret);
+            let len = after.first().and_then(|(_, s)| s.chars().next()).map(char::len_utf8).unwrap_or(0);
+            result = map_range(&result, caret..caret + len, |s| style.caret(s));
+        }
+        Some(caret) if caret == text_end => {
+            let (mut before, after) = split_at(&result, text_end);
+            let base = before.last().or_else(|| after.first()).map(|&(s, _)| s).unwrap_or_default();
+            before.push((style.caret(base), " "));
+            before.extend(after);
+            result = before;
+        }
+        _ => {}
+    }
+    result
+}
+
+#[cfg(feature = "tui")]
+fn map_range<'a, F: Fn(Style) -> Style>(v: &[(Style, &'a str)], r: Range<usize>, f: F) -> Vec<(Style, &'a str)> {
+    let (mut result, in_and_after) = split_at(v, r.start);
+    let (inside, mut after) = split_at(&in_and_after, r.end - r.start);
+    result.extend(inside.iter().map(|&(style, s)| (f(style), s)));
+    result.append(&mut after);
+    result
+}
+
+#[cfg(feature = "tui")]
+fn advance_column(column: usize, c: char, tab_width: usize) -> usize {
+    match c {
+        '\t' if tab_width > 0 => (column / tab_width + 1) * tab_width,
+        _ => column + c.width().unwrap_or(0),
+    }
+}
+
+/// The terminal column at which the character at a byte index of a line is drawn.
+///
+/// Tabs advance to the next multiple of `tab_width`, wide characters like most CJK characters
+/// and emoji take up two columns, and combining and control characters none.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::util::column_at;
+/// assert_eq!(column_at("\tx", 1, 4), 4);
+/// assert_eq!(column_at("日本x", 6, 4), 4);
+/// ```
+#[cfg(feature = "tui")]
+pub fn column_at(line: &str, byte_index: usize, tab_width: usize) -> usize {
+    line[..byte_index].chars().fold(0, |column, c| advance_column(column, c, tab_width))
+}
+
+/// The byte index of the character drawn at a terminal column, the inverse of [`column_at`].
+///
+/// Columns in the middle of a tab or wide character give the index of that character, and
+/// columns past the end of the line give the index of the newline, or the length of the line
+/// if there is none.
+///
+/// [`column_at`]: fn.column_at.html
+#[cfg(feature = "tui")]
+pub fn byte_index_at_column(line: &str, column: usize, tab_width: usize) -> usize {
+    let text = line.trim_end_matches(&['\r', '\n'][..]);
+    let mut current = 0;
+    for (i, c) in text.char_indices() {
+        current = advance_column(current, c, tab_width);
+        if current > column {
+            return i;
+        }
+    }
+    text.len()
+}
+
+/// How many bytes at the start of some content [`looks_binary`] inspects
+///
+/// [`looks_binary`]: fn.looks_binary.html
+pub const BINARY_SNIFF_LEN: usize = 8000;
+
+/// Guesses whether some content is binary data rather than text, by looking at its first
+/// [`BINARY_SNIFF_LEN`] bytes.
+///
+/// Content is considered binary if it contains a NUL byte, or if lots of it consists of control
+/// characters that don't usually occur in text. Highlighting binary content is slow and produces
+/// garbage, so tools can use this to skip it.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::util::looks_binary;
+/// assert!(!looks_binary("fn main() {}\n".as_bytes()));
+/// assert!(looks_binary(b"\x7fELF\x02\x01\x01\x00\x00"));
+/// ```
+///
+/// [`BINARY_SNIFF_LEN`]: constant.BINARY_SNIFF_LEN.html
+pub fn looks_binary(content: &[u8]) -> bool {
+    let sample = &content[..content.len().min(BINARY_SNIFF_LEN)];
+    if sample.contains(&0) {
+        return true;
+    }
+    let control = sample.iter()
+        .filter(|&&b| (b < 0x20 && !b"\t\n\r\x0c\x1b".contains(&b)) || b == 0x7f)
+        .count();
+    control * 10 > sample.len()
+}
+
+/// Finds the name of the interpreter in a shebang line, without any version number.
+///
+/// This looks through `env`, including its options like `-S` and variable assignments, and
+/// through `busybox`, so that the name can be matched against the interpreters syntaxes know.
+/// Returns `None` if the line isn't a shebang.
+///
+/// # Examples
+///
+/// ```
+/// use syntect::util::shebang_interpreter;
+/// assert_eq!(shebang_interpreter("#!/usr/bin/env -S python3.11 -u\n"), Some("python"));
+/// assert_eq!(shebang_interpreter("#! /bin/busybox ash"), Some("ash"));
+/// assert_eq!(shebang_interpreter("// not a script"), None);
+/// ```
+pub fn shebang_interpreter(line: &str) -> Option<&str> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?;
+    loop {
+        let name = program.rsplit('/').next().unwrap_or(program);
+        program = match name {
+            "env" => loop {
+                let word = words.next()?;
+                match word {
+                    // options that take an argument
+                    "-u" | "--unset" | "-C" | "--chdir" => { words.next(); }
+                    _ if word.starts_with('-') || word.contains('=') => {}
+                    _ => break word,
+                }
+            },
+            "busybox" => words.next()?,
+            "" => return None,
+            _ => {
+                let unversioned = name.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.' || c == '-');
+                return Some(if unversioned.is_empty() { name } else { unversioned });
+            }
+        };
+    }
+}
+
+/// A directory for the files of a test, named after the test and the process so that tests
+/// running at the same time don't share it, which is removed again when it's dropped
+#[cfg(test)]
+pub(crate) struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    pub(crate) fn new(test_name: &str) -> TestDir {
+        let path = std::env::temp_dir().join(format!("syntect_{}_{}", test_name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    pub(crate) fn path(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -315,4 +832,133 @@ mod tests {
         let (before, after) = split_at(l, 10); // out of bounds
         assert_eq!((&before[..], &after[..]), (&[(0u8, "abc"), (1u8, "def"), (2u8, "ghi")][..], &[][..]));
     }
+
+    #[cfg(all(feature = "assets", feature = "parsing", any(feature = "dump-load", feature = "dump-load-rs")))]
+    #[test]
+    fn test_ops_with_char_offsets() {
+        use crate::parsing::{ParseState, SyntaxSet};
+        let ss = SyntaxSet::load_defaults_nonewlines();
+        let mut state = ParseState::new(ss.find_syntax_by_extension("py").unwrap());
+        let line = "s = \"日本語\" + \"🦀\" # ✓";
+        let ops = state.parse_line(line, &ss);
+        let char_ops = ops_with_char_offsets(line, &ops);
+        assert_eq!(ops.len(), char_ops.len());
+        for ((byte_index, op), (char_index, char_op)) in ops.iter().zip(char_ops.iter()) {
+            assert_eq!(op, char_op);
+            assert_eq!(*char_index, line[..*byte_index].chars().count());
+        }
+        // the comment starts after 16 chars but 25 bytes
+        assert!(char_ops.iter().any(|&(i, _)| i == 16));
+        assert!(ops.iter().any(|&(i, _)| i == 25));
+
+        // and after 17 UTF-16 code units because of the crab
+        let utf16_ops = ops_with_utf16_offsets(line, &ops);
+        for ((byte_index, _), (utf16_index, _)) in ops.iter().zip(utf16_ops.iter()) {
+            assert_eq!(*utf16_index, line[..*byte_index].encode_utf16().count());
+        }
+        assert!(utf16_ops.iter().any(|&(i, _)| i == 17));
+    }
+
+    #[test]
+    fn test_utf16_ranges() {
+        let l: &[(u8, &str)] = &[];
+        assert!(utf16_ranges(l).is_empty());
+        let l = &[(0u8, "ab"), (1u8, ""), (2u8, "日🦀"), (3u8, "c")];
+        assert_eq!(utf16_ranges(l), vec![(0u8, 0..2), (1u8, 2..2), (2u8, 2..5), (3u8, 5..6)]);
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn test_apply_selection() {
+        let plain = Style::default();
+        let red = Color { r: 255, g: 0, b: 0, a: 255 };
+        let inverted = swap_colors(plain);
+        let l = &[(plain, "ab"), (plain, "cd"), (plain, "\r\n")];
+
+        let style = SelectionStyle::default();
+        assert_eq!(apply_selection(l, &[], None, &style), l.to_vec());
+        // overlapping selections, and the newline isn't selected
+        assert_eq!(apply_selection(l, &[3..9, 1..4], None, &style),
+                   vec![(plain, "a"), (inverted, "b"), (inverted, "cd"), (plain, "\r\n")]);
+        // a caret inside a selection swaps the colors back
+        assert_eq!(apply_selection(l, &[0..4], Some(1), &style),
+                   vec![(inverted, "a"), (plain, "b"), (inverted, "cd"), (plain, "\r\n")]);
+
+        let style = SelectionStyle { caret: Some(red), ..SelectionStyle::default() };
+        let caret = Style { foreground: plain.background, background: red, ..plain };
+        assert_eq!(apply_selection(l, &[], Some(4), &style),
+                   vec![(plain, "ab"), (plain, "cd"), (caret, " "), (plain, "\r\n")]);
+        let wide = &[(plain, "日本")];
+        assert_eq!(apply_selection(wide, &[], Some(3), &style), vec![(plain, "日"), (caret, "本")]);
+        assert_eq!(apply_selection(&[], &[0..1], Some(0), &style), vec![(caret, " ")]);
+    }
+
+    #[cfg(feature = "tui")]
+    #[test]
+    fn test_columns() {
+        assert_eq!(column_at("a\tb", 2, 4), 4);
+        assert_eq!(column_at("abcd\tb", 5, 4), 8);
+        assert_eq!(column_at("日\tb", 4, 4), 4);
+        assert_eq!(column_at("e\u{301}x", 3, 4), 1);
+        assert_eq!(byte_index_at_column("a\tb\n", 0, 4), 0);
+        assert_eq!(byte_index_at_column("a\tb\n", 3, 4), 1);
+        assert_eq!(byte_index_at_column("a\tb\n", 4, 4), 2);
+        assert_eq!(byte_index_at_column("a\tb\n", 9, 4), 3);
+        assert_eq!(byte_index_at_column("日本", 3, 4), 3);
+
+        let plain = Style::default();
+        let escaped = as_24_bit_terminal_escaped_with_tabs(&[(plain, "日\t"), (plain, "\tx")], false, 4);
+        assert!(escaped.ends_with("m日  \x1b[38;2;0;0;0m    x"), "{:?}", escaped);
+    }
+
+    #[test]
+    fn test_shebang_interpreter() {
+        assert_eq!(shebang_interpreter("#!/bin/sh\n"), Some("sh"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/python3.11"), Some("python"));
+        assert_eq!(shebang_interpreter("#!/usr/local/bin/ruby-2.7 -w"), Some("ruby"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env node"), Some("node"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env -S julia --threads 4"), Some("julia"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env -u HOME LANG=C perl"), Some("perl"));
+        assert_eq!(shebang_interpreter("#!/bin/busybox sh"), Some("sh"));
+        assert_eq!(shebang_interpreter("#!/usr/bin/env"), None);
+        assert_eq!(shebang_interpreter("#!"), None);
+        assert_eq!(shebang_interpreter("#!/"), None);
+        assert_eq!(shebang_interpreter("# comment"), None);
+    }
+
+    #[test]
+    fn test_looks_binary() {
+        assert!(!looks_binary(b""));
+        assert!(!looks_binary("plain text\r\n\twith tabs and \x1b[1mescapes\x1b[0m, and ünicode".as_bytes()));
+        assert!(looks_binary(b"GIF89a\x01\x00\x01\x00"));
+        assert!(looks_binary(b"\x01\x02\x03\x04 mostly control"));
+        // only the start is inspected
+        let mut long_text = vec![b'a'; BINARY_SNIFF_LEN];
+        long_text.push(0);
+        assert!(!looks_binary(&long_text));
+    }
+
+    #[test]
+    fn can_approximate_terminal_colors() {
+        let color = |r, g, b| Color { r, g, b, a: 0xFF };
+        assert_eq!(ansi_256_color(Color::BLACK), 16);
+        assert_eq!(ansi_256_color(Color::WHITE), 231);
+        assert_eq!(ansi_256_color(color(0xFF, 0, 0)), 196);
+        assert_eq!(ansi_256_color(color(0x5F, 0x87, 0xAF)), 67);
+        assert_eq!(ansi_256_color(color(0x80, 0x80, 0x80)), 244);
+        assert_eq!(ansi_256_color(color(0x2B, 0x30, 0x3B)), 236);
+        assert_eq!(ansi_256_color(color(0xC0, 0xC5, 0xCE)), 251);
+
+        assert_eq!(ansi_16_color(Color::BLACK), 0);
+        assert_eq!(ansi_16_color(Color::WHITE), 15);
+        assert_eq!(ansi_16_color(color(0xB0, 0x10, 0x10)), 1);
+        assert_eq!(ansi_16_color(color(0x10, 0x10, 0xC0)), 4);
+        assert_eq!(ansi_16_color(color(0x90, 0x90, 0x90)), 8);
+
+        let style = Style { foreground: color(0xFF, 0, 0), background: color(0, 0, 0xEE), ..Style::default() };
+        let v = [(style, "a"), (Style::default(), "b")];
+        assert_eq!(as_terminal_escaped(&v, true, TerminalColors::TrueColor), as_24_bit_terminal_escaped(&v, true));
+        assert_eq!(as_terminal_escaped(&v, true, TerminalColors::Ansi256), "\x1b[48;5;21m\x1b[38;5;196ma\x1b[48;5;231m\x1b[38;5;16mb");
+        assert_eq!(as_terminal_escaped(&v, true, TerminalColors::Ansi16), "\x1b[44m\x1b[91ma\x1b[107m\x1b[30mb");
+    }
 }
@@ -0,0 +1,275 @@
+//! Reloading themes and syntaxes when their files change, for previewing them while they are
+//! being written.
+//!
+//! A [`Reloader`] watches folders with the `notify` crate. When a `.tmTheme` file changes, the
+//! theme is loaded again and replaced in a shared [`ThemeSet`]. When a syntax file changes, all
+//! the syntaxes of its folder are loaded again and the shared [`SyntaxSet`] is swapped for the
+//! new one. Every reload, and every file that couldn't be loaded, is reported as a
+//! [`ReloadEvent`] on a channel, so the tool can highlight the text again.
+//!
+//! The sets are shared as `Arc<RwLock<_>>`, which the highlighting code locks for reading while it
+//! highlights. Editors often save a file in several steps, so a half written file can fail to
+//! load; the old version is kept then and the next change loads it again.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! use syntect::highlighting::ThemeSet;
+//! use syntect::parsing::SyntaxSet;
+//! use syntect::watch::{ReloadEvent, Reloader};
+//! use std::sync::{Arc, RwLock};
+//!
+//! let themes = Arc::new(RwLock::new(ThemeSet::load_from_folder("themes").unwrap()));
+//! let syntaxes = Arc::new(RwLock::new(SyntaxSet::load_defaults_newlines()));
+//!
+//! let mut reloader = Reloader::new().unwrap();
+//! reloader.watch_themes("themes", themes.clone()).unwrap();
+//! let base = SyntaxSet::load_defaults_newlines().into_builder();
+//! reloader.watch_syntaxes("syntaxes", base, true, syntaxes.clone()).unwrap();
+//!
+//! for event in reloader.events() {
+//!     match event {
+//!         ReloadEvent::Failed(path, error) => eprintln!("{}: {}", path.display(), error),
+//!         _ => { /* highlight the preview again */ }
+//!     }
+//! }
+//! ```
+//!
+//! [`Reloader`]: struct.Reloader.html
+//! [`ThemeSet`]: ../highlighting/struct.ThemeSet.html
+//! [`SyntaxSet`]: ../parsing/struct.SyntaxSet.html
+//! [`ReloadEvent`]: enum.ReloadEvent.html
+use crate::highlighting::ThemeSet;
+use crate::parsing::{SyntaxSet, SyntaxSetBuilder};
+use crate::LoadingError;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+pub use notify::Error as WatchError;
+
+/// A change a [`Reloader`] made to a shared set
+///
+/// [`Reloader`]: struct.Reloader.html
+#[derive(Debug)]
+pub enum ReloadEvent {
+    /// A theme file was changed or added, and the theme with this name was replaced or added
+    ThemeReloaded(String),
+    /// A theme file was removed, and so was the theme with this name
+    ThemeRemoved(String),
+    /// A syntax file in this folder changed, and the syntax set was replaced
+    SyntaxesReloaded(PathBuf),
+    /// The changed file couldn't be loaded, the set still has the old version
+    Failed(PathBuf, LoadingError),
+}
+
+/// A watched folder and the set its files are loaded into
+enum Target {
+    Themes {
+        /// The folder as given, for loading
+        folder: PathBuf,
+        /// The canonical path of the folder, for comparing with the paths of events
+        canonical: PathBuf,
+        themes: Arc<RwLock<ThemeSet>>,
+    },
+    Syntaxes {
+        folder: PathBuf,
+        canonical: PathBuf,
+        /// The syntaxes the set has besides the ones in the folder
+        base: SyntaxSetBuilder,
+        lines_include_newline: bool,
+        syntaxes: Arc<RwLock<SyntaxSet>>,
+    },
+}
+
+/// Watches folders of themes and syntaxes and reloads them into shared sets, see the
+/// [module documentation](index.html).
+///
+/// Changes are handled on a thread of the watcher until the `Reloader` is dropped.
+pub struct Reloader {
+    watcher: RecommendedWatcher,
+    targets: Arc<Mutex<Vec<Target>>>,
+    events: Receiver<ReloadEvent>,
+}
+
+impl Reloader {
+    /// Creates a reloader that doesn't watch anything yet
+    pub fn new() -> Result<Reloader, WatchError> {
+        let targets = Arc::new(Mutex::new(Vec::<Target>::new()));
+        let (sender, events) = channel();
+        let handler_targets = targets.clone();
+        let watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            let event = match result {
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            if let EventKind::Access(_) = event.kind {
+                return;
+            }
+            for target in handler_targets.lock().unwrap().iter() {
+                target.reload(&event.paths, &sender);
+            }
+        })?;
+        Ok(Reloader { watcher, targets, events })
+    }
+
+    /// Watches the `.tmTheme` files in a folder and its subfolders, and replaces the themes in
+    /// the set when they change, using the name of the file without the extension like
+    /// [`ThemeSet::load_from_folder`] does.
+    ///
+    /// The themes aren't loaded until they change, so the set should already have them.
+    ///
+    /// [`ThemeSet::load_from_folder`]: ../highlighting/struct.ThemeSet.html#method.load_from_folder
+    pub fn watch_themes<P: AsRef<Path>>(&mut self, folder: P, themes: Arc<RwLock<ThemeSet>>) -> Result<(), WatchError> {
+        let folder = folder.as_ref().to_owned();
+        let canonical = folder.canonicalize().map_err(WatchError::io)?;
+        self.watcher.watch(&folder, RecursiveMode::Recursive)?;
+        self.targets.lock().unwrap().push(Target::Themes { folder, canonical, themes });
+        Ok(())
+    }
+
+    /// Watches the syntax files in a folder and its subfolders. When one changes, the set is
+    /// replaced with the syntaxes of `base` and the syntaxes of the folder, loaded with
+    /// [`SyntaxSetBuilder::add_from_folder`].
+    ///
+    /// Use an empty builder as the base if the set should only have the syntaxes of the folder,
+    /// or for example `SyntaxSet::load_defaults_newlines().into_builder()` to keep the default
+    /// syntaxes.
+    ///
+    /// [`SyntaxSetBuilder::add_from_folder`]: ../parsing/struct.SyntaxSetBuilder.html#method.add_from_folder
+    pub fn watch_syntaxes<P: AsRef<Path>>(
+        &mut self,
+        folder: P,
+        base: SyntaxSetBuilder,
+        lines_include_newline: bool,
+        syntaxes: Arc<RwLock<SyntaxSet>>,
+    ) -> Result<(), WatchError> {
+        let folder = folder.as_ref().to_owned();
+        let canonical = folder.canonicalize().map_err(WatchError::io)?;
+        self.watcher.watch(&folder, RecursiveMode::Recursive)?;
+        self.targets.lock().unwrap().push(Target::Syntaxes { folder, canonical, base, lines_include_newline, syntaxes });
+        Ok(())
+    }
+
+    /// The channel the changes are reported on. Iterating it blocks until the next change.
+    pub fn events(&self) -> &Receiver<ReloadEvent> {
+        &self.events
+    }
+}
+
+impl Target {
+    /// Reloads the files with these paths if they belong to this target
+    fn reload(&self, paths: &[PathBuf], sender: &Sender<ReloadEvent>) {
+        // a dropped receiver just means nobody is interested in the events
+        match *self {
+            Target::Themes { ref folder, ref canonical, ref themes } => {
+                for path in paths {
+                    if !path.starts_with(canonical) || path.extension() != Some(OsStr::new("tmTheme")) {
+                        continue;
+                    }
+                    let name = match path.file_stem().and_then(|s| s.to_str()) {
+                        Some(name) => name.to_owned(),
+                        None => continue,
+                    };
+                    if !path.exists() {
+                        if themes.write().unwrap().themes.remove(&name).is_some() {
+                            let _ = sender.send(ReloadEvent::ThemeRemoved(name));
+                        }
+                        continue;
+                    }
+                    // load from the folder as given, for the same error messages as when loading
+                    let given = folder.join(path.strip_prefix(canonical).unwrap());
+                    match ThemeSet::get_theme(&given) {
+                        Ok(theme) => {
+                            themes.write().unwrap().themes.insert(name.clone(), theme);
+                            let _ = sender.send(ReloadEvent::ThemeReloaded(name));
+                        }
+                        Err(error) => {
+                            let _ = sender.send(ReloadEvent::Failed(given, error));
+                        }
+                    }
+                }
+            }
+            Target::Syntaxes { ref folder, ref canonical, ref base, lines_include_newline, ref syntaxes } => {
+                let changed = match paths.iter().find(|p| p.starts_with(canonical) && is_syntax_file(p)) {
+                    Some(path) => folder.join(path.strip_prefix(canonical).unwrap()),
+                    None => return,
+                };
+                let mut builder = base.clone();
+                match builder.add_from_folder(folder, lines_include_newline) {
+                    Ok(()) => {
+                        *syntaxes.write().unwrap() = builder.build();
+                        let _ = sender.send(ReloadEvent::SyntaxesReloaded(folder.clone()));
+                    }
+                    Err(error) => {
+                        let _ = sender.send(ReloadEvent::Failed(changed, error));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// True for the files `SyntaxSetBuilder::add_from_folder` loads
+fn is_syntax_file(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.ends_with(".sublime-syntax")
+        || (cfg!(feature = "tmlanguage-load") && (name.ends_with(".tmLanguage") || name.ends_with(".tmLanguage.json")))
+        || (cfg!(feature = "metadata") && name.ends_with(".tmPreferences"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::highlighting::ThemeBuilder;
+    use crate::util::TestDir;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    /// Waits for an event that passes the check, giving up after a few seconds
+    fn wait_for(reloader: &Reloader, check: impl Fn(&ReloadEvent) -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            match reloader.events().recv_timeout(timeout) {
+                Ok(ref event) if check(event) => return true,
+                Ok(_) => {}
+                Err(_) => return false,
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn can_reload_changed_files() {
+        let dir = TestDir::new("can_reload_changed_files");
+        let folder = dir.path();
+        let theme = |name| ThemeBuilder::new().name(name).build().unwrap().to_tm_theme();
+        fs::write(folder.join("watched.tmTheme"), theme("Before")).unwrap();
+        let syntax = |name| format!("name: {}\nscope: source.watched\ncontexts:\n  main: []\n", name);
+        fs::write(folder.join("watched.sublime-syntax"), syntax("Before")).unwrap();
+
+        let themes = Arc::new(RwLock::new(ThemeSet::load_from_folder(&folder).unwrap()));
+        let syntaxes = Arc::new(RwLock::new(SyntaxSet::new()));
+        let mut reloader = Reloader::new().unwrap();
+        reloader.watch_themes(&folder, themes.clone()).unwrap();
+        reloader.watch_syntaxes(&folder, SyntaxSetBuilder::new(), true, syntaxes.clone()).unwrap();
+
+        fs::write(folder.join("watched.tmTheme"), theme("After")).unwrap();
+        assert!(wait_for(&reloader, |event| matches!(event, ReloadEvent::ThemeReloaded(ref name) if name == "watched")
+            && themes.read().unwrap().themes["watched"].name.as_ref().unwrap() == "After"));
+
+        fs::write(folder.join("watched.sublime-syntax"), syntax("After")).unwrap();
+        assert!(wait_for(&reloader, |event| matches!(event, ReloadEvent::SyntaxesReloaded(_))
+            && syntaxes.read().unwrap().find_syntax_by_name("After").is_some()));
+
+        fs::write(folder.join("broken.sublime-syntax"), "name: [").unwrap();
+        assert!(wait_for(&reloader, |event| matches!(event, ReloadEvent::Failed(ref path, _) if path.ends_with("broken.sublime-syntax"))));
+        assert!(syntaxes.read().unwrap().find_syntax_by_name("After").is_some());
+
+        fs::remove_file(folder.join("watched.tmTheme")).unwrap();
+        assert!(wait_for(&reloader, |event| matches!(event, ReloadEvent::ThemeRemoved(_))));
+        assert!(themes.read().unwrap().themes.is_empty());
+    }
+}